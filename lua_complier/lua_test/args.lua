@@ -0,0 +1,5 @@
+-- run as: lua_LLVM lua_test/args.lua one two
+print(arg[0], arg[1], arg[2], #arg)
+print(...)
+local a, b, c = ...
+print(a, b, c)
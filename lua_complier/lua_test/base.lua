@@ -0,0 +1,65 @@
+print(type(nil), type(true), type(1), type(1.5), type("s"), type({}), type(print))
+print(tostring(nil), tostring(false), tostring(10), tostring("x"))
+
+local point = setmetatable({x = 1, y = 2}, {
+   __tostring = function(p) return "(" .. p.x .. ", " .. p.y .. ")" end,
+})
+print(tostring(point), point)
+local named = setmetatable({}, {__name = "Named"})
+print(string.sub(tostring(named), 1, 7))
+
+print(tonumber("  42  "), tonumber("0x10"), tonumber("1.5"), tonumber("z"), tonumber({}))
+print(tonumber("ff", 16), tonumber("  -zz ", 36), tonumber("777", 8), tonumber("8", 8), tonumber("1e1", 16))
+print(tonumber("10", 2), tonumber("", 10), tonumber("-", 10))
+
+print(select("#"), select("#", nil, nil), select(2, "a", "b", "c"), select(-1, "a", "b", "c"))
+print(select(5, "a", "b"))
+
+local t = setmetatable({}, {__index = function() return "meta" end, __newindex = function() end, __len = function() return 9 end})
+t.a = 1
+rawset(t, "b", 2)
+print(t.a, rawget(t, "a"), t.b, rawget(t, "b"), #t, rawlen(t), rawlen("abc"))
+print(rawequal(t, t), rawequal(t, {}), rawequal(1, 1.0), rawequal("a", "a"))
+
+local mt = {__metatable = "locked"}
+local locked = setmetatable({}, mt)
+print(getmetatable(locked), getmetatable({}), getmetatable("s").__index == string)
+print(pcall(setmetatable, locked, {}))
+print(getmetatable(setmetatable(point, nil)))
+
+local keys = {}
+for k, v in pairs({10, 20, 30}) do keys[#keys + 1] = k .. "=" .. v end
+print(table.concat(keys, " "))
+for i, v in ipairs({"a", "b", nil, "d"}) do print(i, v) end
+local proxy = setmetatable({}, {__index = function(_, i) if i <= 3 then return i * i end end})
+for i, v in ipairs(proxy) do print(i, v) end
+local custom = setmetatable({}, {__pairs = function(self)
+   return function(_, k) if k < 3 then return k + 1, "v" .. k + 1 end end, self, 0
+end})
+for k, v in pairs(custom) do print(k, v) end
+print(next({}), next({5}), next({5}, 1))
+local full = {1, 2, 3, 4, x = 5}
+for k in pairs(full) do full[k] = nil end
+print(next(full), #full)
+
+print(pcall(error, "msg", 0))
+print(pcall(error, {code = 1}))
+print(pcall(error))
+print(pcall(function() error("with position") end))
+print(pcall(function() local function f() error("level 2", 2) end f() end))
+print(pcall(function() return 1, 2, 3 end))
+print(pcall(function() local x = nil; return x.y end))
+print(select("#", pcall(print)))
+print(xpcall(function() error("oops") end, function(m) return "handled: " .. m end))
+print(xpcall(function(a, b) return a + b end, print, 3, 4))
+print(pcall(assert, 1 == 1, "fine"))
+print(pcall(assert, false))
+print(pcall(assert, nil, "custom"))
+print(pcall(assert, false, 42))
+
+print(collectgarbage("isrunning"), collectgarbage("stop"), collectgarbage("isrunning"))
+print(collectgarbage("restart"), collectgarbage(), collectgarbage("step"), collectgarbage("isrunning"))
+print(collectgarbage("generational"), collectgarbage("incremental"), type(collectgarbage("count")))
+print(pcall(collectgarbage, "bogus"))
+
+print(_G._G == _G, _G.print == print, _VERSION)
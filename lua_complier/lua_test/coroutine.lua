@@ -0,0 +1,140 @@
+local co = coroutine.create(function(a, b)
+   print("start", a, b)
+   local c = coroutine.yield(a + b)
+   print("got", c)
+   local d, e = coroutine.yield(c * 2)
+   print("got", d, e)
+   return "done", 99
+end)
+print(coroutine.status(co))
+print(coroutine.resume(co, 1, 2))
+print(coroutine.status(co))
+print(coroutine.resume(co, 10))
+print(coroutine.resume(co, "x", "y"))
+print(coroutine.status(co), coroutine.resume(co))
+
+-- generators with wrap
+local function range(n)
+   return coroutine.wrap(function() for i = 1, n do coroutine.yield(i) end end)
+end
+for i in range(4) do io.write(i, " ") end print()
+
+-- yield across nested Lua calls and pcall
+local function inner(x) return coroutine.yield(x) + 1 end
+local function middle(x) return inner(x) * 2 end
+co = coroutine.wrap(function(x)
+   local ok, v = pcall(middle, x)
+   print("pcall", ok, v)
+   local ok2, err = pcall(function() coroutine.yield("in pcall") error("after yield") end)
+   print("pcall2", ok2, err)
+   return "end"
+end)
+print(co(5)); print(co(7)); print(co()); 
+print(pcall(co))
+
+-- errors
+co = coroutine.create(function() error("oops") end)
+print(coroutine.resume(co))
+print(coroutine.status(co), coroutine.resume(co))
+print(coroutine.close(co))
+co = coroutine.create(function() error({code = 1}) end)
+local ok, e = coroutine.resume(co); print(ok, type(e), e.code)
+print(pcall(coroutine.wrap(function() error("wrapped") end)))
+print(pcall(coroutine.yield, 1))
+print(coroutine.resume(coroutine.running()))
+print(coroutine.isyieldable(), select(2, coroutine.running()))
+co = coroutine.create(function()
+   print(coroutine.isyieldable(), coroutine.status(coroutine.running()))
+   table.sort({3, 1, 2}, function(a, b) return coroutine.yield() end)
+end)
+print(coroutine.resume(co))
+print(pcall(coroutine.resume, 42))
+print(pcall(coroutine.create, 42))
+
+-- upvalues shared between threads
+local counter = 0
+local function make()
+   local n = 0
+   co = coroutine.wrap(function()
+      local inc = function() n = n + 1; counter = counter + 1 end
+      while true do inc(); coroutine.yield(function() return n end) end
+   end)
+   return co
+end
+local w = make()
+local get = w(); w(); w()
+print(get(), counter)
+
+-- normal status, nested
+local outer
+outer = coroutine.create(function()
+   local inner = coroutine.create(function() print("outer is", coroutine.status(outer)) coroutine.yield() end)
+   coroutine.resume(inner)
+   print("inner is", coroutine.status(inner))
+   print(coroutine.close(inner), coroutine.status(inner))
+   print(pcall(coroutine.close, outer))
+end)
+coroutine.resume(outer)
+print(coroutine.close(coroutine.create(print)))
+print(select("#", coroutine.resume(coroutine.create(function() return end))))
+print(coroutine.resume(coroutine.create(coroutine.yield), 1, 2))
+local y = coroutine.create(coroutine.yield)
+coroutine.resume(y, 1)
+print(coroutine.resume(y, 3, 4))
+print(coroutine.wrap(pcall)(error, "x"))
+print(pcall(pcall))
+print(xpcall(function() error("e") end, function(m) return "handled: " .. m end))
+co = coroutine.wrap(function() return xpcall(function() coroutine.yield(1); error("later") end, function(m) return "H " .. m end) end)
+print(co()); print(co())
+
+-- a chunk run by dofile yields through it
+local chunk = os.tmpname()
+local f = io.open(chunk, "w")
+f:write("local a = coroutine.yield('in chunk')\nreturn a * 2, 'done'\n")
+f:close()
+co = coroutine.wrap(function() return "dofile:", dofile(chunk) end)
+print(co())
+print(co(21))
+print(pcall(dofile, chunk))
+
+-- a syntax error is an error as others
+f = io.open(chunk, "w")
+f:write("x = = 1\n")
+f:close()
+print(select(2, coroutine.resume(coroutine.create(function() dofile(chunk) end))) == chunk .. ":1: unexpected symbol near '='")
+print(select(2, pcall(coroutine.wrap(function() dofile(chunk) end))) == chunk .. ":1: unexpected symbol near '='")
+os.remove(chunk)
+
+
+-- metamethods written in Lua, or native ones, yield as other functions do
+local mt = {}
+mt.__add = function(a, b) return coroutine.yield("add") end
+mt.__index = function(t, k) return coroutine.yield("index " .. k) end
+mt.__newindex = function(t, k, v) coroutine.yield("newindex " .. k); rawset(t, k, v) end
+mt.__lt = function(a, b) return coroutine.yield("lt") end
+mt.__le = function(a, b) return coroutine.yield("le") end
+mt.__eq = function(a, b) return coroutine.yield("eq") end
+mt.__concat = function(a, b) return coroutine.yield("concat") end
+mt.__len = function(a) return coroutine.yield("len") end
+mt.__unm = function(a) return coroutine.yield("unm") end
+local a, b = setmetatable({}, mt), setmetatable({}, mt)
+co = coroutine.wrap(function()
+   local x, y = a + 1, a.foo
+   a.bar = 5
+   local lt, le, eq, ne = a < b, a <= b, a == b, a ~= b
+   local c, l, u = a .. "s", #a, -a
+   return x, y, rawget(a, "bar"), lt, le, eq, ne, c, l, u, a:twice(2)
+end)
+print(co())
+print(co(10)); print(co("v")); print(co()); print(co(false)); print(co(1))
+print(co(nil)); print(co(true)); print(co("cat")); print(co(3)); print(co(-1))
+print(co(function(self, n) return n * 2 end))
+local t = setmetatable({}, {__index = coroutine.yield})
+co = coroutine.wrap(function() return pcall(function() return t.x end) end)
+print(select(2, co()), co("resumed"))
+local boom = setmetatable({}, {__add = function() coroutine.yield(); error("boom") end})
+co = coroutine.wrap(function() return pcall(function() return boom + 1 end) end)
+co(); print(co())
+print(pcall(function() return a + 1 end))
+
+print(type(coroutine.wrap(print)), type(io.lines("lua_test/hello.lua")))
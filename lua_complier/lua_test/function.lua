@@ -0,0 +1,57 @@
+local function fact(n)
+  if n <= 1 then return 1 end
+  return n * fact(n - 1)
+end
+print(fact(10))
+
+local function counter()
+  local n = 0
+  return function() n = n + 1; return n end
+end
+local c1, c2 = counter(), counter()
+print(c1(), c1(), c2())
+
+local fs = {}
+for i = 1, 3 do fs[i] = function() return i end end
+print(fs[1](), fs[2](), fs[3]())
+
+local function values(...) return select and 0, ... end
+print(values(1, 2, 3))
+
+local sum = 0
+for i = 10, 1, -2 do sum = sum + i end
+for _, v in function(t, i) i = i + 1; if t[i] then return i, t[i] end end, {1, 2, 3}, 0 do
+  sum = sum + v
+end
+print(sum)
+
+local i = 0
+while true do
+  i = i + 1
+  if i == 3 then goto continue end
+  if i > 5 then break end
+  ::continue::
+end
+repeat i = i - 1 until i < 4
+print(i)
+
+local point = {x = 1}
+function point:move(dx) self.x = self.x + dx; return self end
+print(point:move(2):move(3).x)
+
+local function loop(n) if n == 0 then return "tail calls" end return loop(n - 1) end
+print(loop(100000))
+
+-- native functions called in tail position keep the caller's frame
+local function show(...) return print(...) end
+show("native", "tail call")
+print("results:", show("no results"))
+local function fails() return error("tail error") end
+print(pcall(fails))
+local function level2() return error("caller's fault", 2) end
+local function calls_level2() level2() end
+print(pcall(calls_level2))
+print(pcall(function() return string.rep() end))
+local callable = setmetatable({}, {__call = function(_, a) return a * 2 end})
+local function countdown(n) if n > 0 then return countdown(n - 1) end return callable(21) end
+print(countdown(100000))
@@ -0,0 +1,66 @@
+local name = "/tmp/lua_io_test.txt"
+
+io.write("write ", 1, " ", 2.5, " ", 1.0, "\n")
+print(io.write("") == io.stdout, io.type(io.stdout), io.type(42))
+
+local f = assert(io.open(name, "w"))
+print(io.type(f), tostring(f):match("^file %(") ~= nil)
+print(f:write("first line\n", "second line\n", 42, " ", 3.25, "\n", "0x1F -7 abc\n") == f)
+print(f:seek("cur"), f:seek("set", 6), f:seek("end"))
+f:write("no newline")
+print(f:close(), io.type(f), tostring(f))
+print(pcall(f.write, f, "x"))
+
+f = assert(io.open(name))
+print(f:read())
+print(f:read("L"))
+print(f:read("n", "n"))
+print(f:read("n", "n", "n"))
+print(f:read("l"))
+print(f:read(3), f:read(0), f:read("a"))
+print(f:read("a"), f:read(0), f:read("l"), f:read(1))
+print(f:seek("set", 0), f:read("*l"))
+print(pcall(f.read, f, "x"))
+print(pcall(f.read, f, {}))
+f:close()
+
+for line in io.lines(name) do io.write("[", line, "]") end
+print()
+for a, b in io.lines(name, 1, 2) do io.write(a, b, "|") break end
+print()
+f = io.open(name)
+for n in f:lines("n") do print(n) end
+print(io.type(f), f:read("l"))
+f:close()
+
+f = assert(io.open(name, "a+"))
+f:write("\nappended")
+f:seek("set")
+print(f:read("a"))
+f:close()
+
+f = assert(io.open(name, "r+"))
+f:write("FIRST")
+f:seek("set")
+print(f:read("l"))
+print(f:setvbuf("no"), f:setvbuf("line"), pcall(f.setvbuf, f, "all"))
+f:close()
+
+print(io.open("/nonexistent/dir/file"))
+print(pcall(io.open, name, "rw"))
+print(pcall(io.lines, "/nonexistent/dir/file"))
+print(io.close(io.stdout))
+print(io.stdin:write("x"))
+
+local old = io.output()
+io.output(name)
+io.write("through default output\n")
+print(io.output() ~= old)
+io.close()
+print(pcall(io.write, "x"))
+io.output(old)
+io.input(name)
+print(io.read("L"))
+print(io.read())
+io.input(io.stdin)
+print(pcall(io.lines(name, "l"), nil))
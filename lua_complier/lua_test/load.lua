@@ -0,0 +1,38 @@
+local f = load("return 1 + 2")
+print(f())
+print(load("x = ...; return x * 2")(21), x)
+
+local env = {}
+local g = load("y = 5; return y", "=setter", "t", env)
+print(g(), env.y, y)
+print(load("return _ENV", "chunk", "t", nil)())
+
+print(load("return +"))
+print(load("return +", "=named"))
+print(load("return +", "@file.lua"))
+print(load("x = \n+"))
+print(load(string.rep("x", 50) .. " +"))
+print(load("return 1", "binary only", "b"))
+print(load("\27Lua"))
+print(load("\27Lua", "bin", "t"))
+print(pcall(load, nil))
+
+local parts = {"return ", "'from ", "pieces'", nil}
+local i = 0
+print(load(function() i = i + 1; return parts[i] end)())
+print(load(function() return {} end))
+print(load(function() error("reader failed") end))
+
+print(pcall(load("error('boom')", "=inner")))
+local n = 0
+local numbers = {"return ", 1, 2.5}
+print(load(function() n = n + 1; return numbers[n] end)())
+
+print(loadfile("/nonexistent/file.lua"))
+print(pcall(dofile, "/nonexistent/file.lua"))
+print(type(loadfile("lua_test/hello.lua")), loadfile("lua_test/hello.lua", "b"))
+print(select("#", dofile("lua_test/hello.lua")))
+print(load("(a) = 1"))
+print(load("a, (b) = 1, 2"))
+print(load("::a:: ::a::"))
+print(load("::a:: do ::a:: end"))
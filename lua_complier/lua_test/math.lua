@@ -0,0 +1,23 @@
+print(math.abs(-3), math.abs(-3.5), math.abs(math.mininteger), math.floor(3.7), math.ceil(3.2), math.floor(-3.5))
+print(math.floor(5), math.ceil(-0.5), math.floor(2^70), math.floor(-0.0), math.ceil(math.huge))
+print(math.fmod(7, 3), math.fmod(-7, 3), math.fmod(7, -3), math.fmod(7.5, 2), math.fmod(math.mininteger, -1))
+print(math.modf(3.7), math.modf(-3.7), math.modf(5), math.modf(math.huge))
+print(math.sqrt(16), math.exp(0), math.log(1), math.log(8, 2), math.log(100, 10), math.log(27, 3))
+print(math.sin(0), math.cos(0), math.tan(0), math.asin(1) * 2 == math.pi, math.acos(1), math.atan(1, 1) * 4 == math.pi)
+print(math.atan(-1, -1) < 0, math.max(1, 2.5, 2), math.min(3, 1, 2), math.max(2, 2.0), math.min(-0.0, 0))
+print(math.huge, -math.huge, math.pi, math.maxinteger, math.mininteger, math.maxinteger + 1 == math.mininteger)
+print(math.tointeger(3.0), math.tointeger(3.5), math.tointeger("8"), math.tointeger({}), math.tointeger(2^63))
+print(math.type(1), math.type(1.0), math.type("1"), math.ult(1, -1), math.ult(-1, 1))
+
+math.randomseed(42)
+local a = {math.random(100), math.random(100), math.random(100), math.random()}
+math.randomseed(42)
+local b = {math.random(100), math.random(100), math.random(100), math.random()}
+print(a[1] == b[1] and a[2] == b[2] and a[3] == b[3] and a[4] == b[4])
+local ok = true
+for i = 1, 1000 do
+  local r, f, n = math.random(3, 7), math.random(), math.random(-2, 2)
+  ok = ok and r >= 3 and r <= 7 and math.type(r) == "integer" and f >= 0 and f < 1 and n >= -2 and n <= 2
+end
+print(ok, math.random(5, 5), math.type(math.random(0)))
+print(math.randomseed(7, 9))
@@ -0,0 +1,5 @@
+-- a module returning a table; counts how often it was loaded
+loads = (loads or 0) + 1
+local M = {name = ..., file = select(2, ...)}
+function M.inc(n) return n + 1 end
+return M
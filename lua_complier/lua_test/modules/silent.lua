@@ -0,0 +1 @@
+silent_ran = true
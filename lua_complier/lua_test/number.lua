@@ -0,0 +1,10 @@
+print(1e10, 1E-3, 2.5e+2, .5, 3., 08)
+print(0x10, 0xff, 0XA, 0xffffffffffffffff, 0x7fffffffffffffff + 1)
+print(0x1p4, 0x.8, 0xA.8p1, 0x1P-2, 0x1p-1074, 0x1p1024, 0x0p99999)
+print(9223372036854775807, 9223372036854775808, math.type(9223372036854775807), math.type(9223372036854775808))
+
+print("10" + 1, " 0x10 " * 2, "1e2" + 0, " .5 " + 0, "-0x10" + 0, "+7" + 0, "0x1p4" + 0)
+print(tonumber("0x1p4"), tonumber(" 10 "), tonumber("\t12\n"), tonumber("1.5e3"))
+print(tonumber("1e"), tonumber("inf"), tonumber("nan"), tonumber("0x"), tonumber(""), tonumber("1 2"), tonumber("- 1"))
+print(tonumber("9223372036854775808"), tonumber("-9223372036854775808"), tonumber("0x123456789abcdef01"))
+print(math.type(tonumber("10")), math.type(tonumber("10.")), math.type(tonumber("1e1")), math.type("0x10" + 0))
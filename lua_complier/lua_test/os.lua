@@ -0,0 +1,36 @@
+print(os.date("!%Y-%m-%d %H:%M:%S", 0))
+print(os.date("!%A %B %j %y %%", 86400 * 365))
+print(os.date("!%Ey %Od", 0))
+print(pcall(os.date, "%Ez"))
+print(pcall(os.date, "%Q and more"))
+
+local t = os.date("!*t", 1234567890)
+print(t.year, t.month, t.day, t.hour, t.min, t.sec, t.yday, t.wday, t.isdst)
+
+-- only differences, which do not depend on the time zone
+local jan1 = os.time{year = 2024, month = 1, day = 1, hour = 0}
+local date = {year = 2024, month = 2, day = 30, hour = 0}
+print((os.time(date) - jan1) // 86400)
+print(date.month, date.day, date.hour, date.min, date.yday, date.wday)
+print(os.time{year = 2024, month = 1, day = 1} - jan1)
+print(os.difftime(jan1 + 90, jan1), math.type(os.time()))
+print(pcall(os.time, {year = 2024}))
+print(pcall(os.time, {year = 2024, month = "x", day = 1}))
+print(pcall(os.time, {year = 2024, month = 2^40, day = 1}))
+print(pcall(os.difftime, 1))
+
+print(math.type(os.clock()), os.getenv("LUA_TEST_SURELY_UNSET"))
+
+local name = os.tmpname()
+print(name:match("^/tmp/lua_") ~= nil, io.type(io.open(name)))
+print(os.rename(name, name .. ".moved"))
+print(os.remove(name .. ".moved"))
+print(select("#", os.remove(name)), select(3, os.remove(name)))
+print(os.rename("/nonexistent/dir/a", "b"))
+
+-- os.exit unwinds through pcall
+print(pcall(function()
+   io.write("exiting\n")
+   os.exit(true, true)
+end))
+print("not reached")
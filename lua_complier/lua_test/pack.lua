@@ -0,0 +1,14 @@
+print(string.pack("i4", 100):byte(1, -1))
+print(string.pack(">I2", 258):byte(1, -1))
+print(string.unpack("<i2", "\xff\xff"), string.unpack("<I2", "\xff\xff"))
+print(string.packsize("i4i8"), string.packsize("!i4i8"), string.packsize("!bXi4"), string.packsize("<!4 i2 d"))
+print(string.pack("s1", "hi") == "\2hi", #string.pack("z", "ab"), string.pack("c5", "ab") == "ab\0\0\0")
+print(string.unpack("z B", "ab\0\7"))
+print(string.unpack("c2", "abc", 2))
+print(string.unpack("i16", string.pack("i16", -1)), #string.pack("i16", -1))
+print(string.unpack(">j", string.pack(">j", -9223372036854775807 - 1)))
+print(string.unpack("d", string.pack("d", 1.5)), string.unpack("f", string.pack("f", 0.25)))
+print(string.unpack(">s2", string.pack(">s2", "binary\0data")))
+local packed = string.pack("<!4 B i4 h z", 7, -2, 300, "end")
+print(#packed, string.unpack("<!4 B i4 h z", packed))
+print(string.unpack("i2", "\1\0\2\0", 3), string.unpack("B", "\200", -1))
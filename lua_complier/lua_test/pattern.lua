@@ -0,0 +1,32 @@
+-- classes, sets and anchors
+print(("hello world 42"):find("%d+"), ("hello world"):find("o w"), ("hello"):find("l+"))
+print(("key = value"):match("(%w+)%s*=%s*(%w+)"), ("  trim  "):match("^%s*(.-)%s*$") .. "|")
+print(("0x1F"):match("^0[xX](%x+)$"), ("abc"):match("^b"), ("abc"):match("c$"), ("a$c"):match("$c"))
+print(("THE (quick) fox"):find("%f[%a]%a+%f[%A]"), ("[x]"):match("[]x[]+"), ("a-b"):match("[a%-]+"))
+print(("hello"):match(".-l"), ("hello"):match(".*l"), ("color colour"):match("colou?r", 2))
+print(("f(a(b)c)d"):match("%b()"), ("A1b2"):match("%u%d%l%d"), ("x=1, y=2"):find("(%a)=(%d)", 3))
+
+-- position captures and back references
+print(("hello"):match("()ll()"), ("say 'hi' or \"bye\""):match("([\"'])(.-)%1"))
+print(("aaa"):find(""), ("aaa"):find("", 10), ("aaa"):match("()", 4))
+
+-- gmatch
+for k, v in ("a=1, b=2, c=3"):gmatch("(%w+)=(%w+)") do print(k, v) end
+local words = {}
+for w in ("one two  three"):gmatch("%a+") do words[#words + 1] = w end
+print(#words, words[1], words[3])
+local it = ("abc"):gmatch(".")
+print(it(), it(), it(), it())
+print(type(it), pcall(string.gmatch, "abc"))
+for e in ("abc"):gmatch("x*") do print("[" .. e .. "]") end
+
+-- gsub
+print(("hello world"):gsub("o", "0"))
+print(("hello world"):gsub("(%w+)", "<%1>"))
+print(("hello world"):gsub("%w+", "%0 %0", 1))
+print(("abc"):gsub("", "-"))
+print(("hello"):gsub("^h", "H"), ("100%"):gsub("%%", "%%%%"))
+print(("$name is $age"):gsub("%$(%w+)", {name = "Lua", age = 30}))
+print(("1 2 3"):gsub("%d", function(d) return d * 2 end))
+print(("keep"):gsub("%w+", function() return nil end))
+print(("abc"):gsub("()", "%1"))
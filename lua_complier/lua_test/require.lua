@@ -0,0 +1,26 @@
+package.path = "lua_test/modules/?.lua;lua_test/modules/?/init.lua"
+
+local counter, file = require("counter")
+print(counter.name, counter.file, file, counter.inc(1), loads)
+print(require("counter") == counter, loads, package.loaded.counter == counter)
+
+local shapes = require("shapes")
+print(shapes.kind, shapes.name)
+print(require("silent"), silent_ran, package.loaded.silent)
+
+package.preload.virtual = function(name, extra) return {from = name .. extra} end
+print(require("virtual").from)
+
+print(package.loaded.string == string, package.loaded._G == _G, package.loaded.package == package)
+print(require("math") == math, type(package.searchers), #package.searchers)
+
+print(pcall(require, "missing"))
+print(pcall(require, "broken"))
+print(package.searchpath("counter", package.path))
+print(package.searchpath("a.b.c", "x/?.lua;y/?.so"))
+print(package.searchpath("a_b", "x/?.lua", "_", "-"))
+print(package.config:sub(1, 1), package.loadlib("lib.so", "f"))
+
+package.loaded.counter = nil
+require("counter")
+print(loads)
@@ -0,0 +1,25 @@
+local s = "Hello, Lua"
+print(#s, s:len(), string.len(s), s:upper(), s:lower(), s:reverse())
+print(s:sub(1, 5), s:sub(-3), s:sub(8, 100), s:sub(0), s:sub(5, 2) == "")
+print(s:byte(), s:byte(-1), s:byte(1, 3))
+print(string.char(76, 117, 97), ("ab"):rep(3), ("ab"):rep(3, ", "), ("x"):rep(0) == "")
+print(s:find("Lua"), s:find("l"), s:find("l", 5), s:find("xyz"), s:find(".", 1, true))
+
+print(string.format("%d|%5d|%-5d|%05d|%+d|%x|%X|%#x|%o", 42, 42, 42, -42, 7, 255, 255, 255, 8))
+print(string.format("%.2f|%8.3f|%-8.1f|%c%c|%s|%10s|%-10s|%.2s|%%", 3.14159, 2.5, 2.5, 76, 97, "str", "right", "left", "cut"))
+print(string.format("%d %s", "10", 1.5))
+
+print("tab\tnew\\n \"quoted\" \65\066\x43 \u{48}\u{49}", 'single \'quote\'')
+print([[
+first line skipped]], [==[with ]] inside]==])
+--[[ a long
+comment ]] print("after a long comment")
+
+print(string.format("%e|%.3E|%10.2e|%-10.1e|%+.0e|%#.0e", 12345.678, 0.000123, 5, 5, 5, 5))
+print(string.format("%g|%g|%g|%g|%g|%g", 100000, 1000000, 0.0001, 0.00001, 3.14159265, 0))
+print(string.format("%#g|%.3g|%10.4g|%-8g|%G|%g", 1, 1234.5, 3.14159, 2.5, 0.0000000001, 2^1000))
+print(string.format("%a|%a|%a|%A|%.1a|%010a|%a|%.3a", 1, 0.5, 1.5, 255.5, 1.96875, 1, 0.0, 1/3))
+print(string.format("%5.1f|%u|%i|%-5u|", -0.05, -1, 7, 3))
+print(string.format("%q", 'a\n"b"\0' .. "1\r\\"))
+print(string.format("%q|%q|%q|%q|%q|%q", 42, -9223372036854775807 - 1, 1.5, 0.1, 1/0, -1/0))
+print(string.format("%q|%q|%s|%s|%5s", true, nil, nil, 12, false))
@@ -0,0 +1,16 @@
+local t = {1, 2, 3, x = "a", [10] = 5, n = {y = 2}}
+print(#t, t.x, t[10], t.n.y, t[2] + 1)
+
+t.z = 3 * 4 // 5 .. "s"
+t[4] = t.z
+print(t.z, #t, t[4])
+
+print(7 % -3, 2^10, 7 // 2.0, 10 / 4)
+print(1 < 2, "a" < "b", not nil, 1 == 1.0, 3 & 5 | 8, ~0, 1 << 63)
+print(2^63 == 9223372036854775807, -2^63 == -9223372036854775807 - 1, 2^53 == 2^53 | 0)
+print(pcall(function() return 1 % 0 end))
+
+local a, b, c = 1, nil and 2 or 3
+print(a, b, c, -t[1], #"abc")
+a, b = b, a
+print(a, b)
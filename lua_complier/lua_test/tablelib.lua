@@ -0,0 +1,24 @@
+local t = {10, 20, 30}
+table.insert(t, 40)
+table.insert(t, 1, 5)
+print(#t, table.concat(t, ","))
+print(table.remove(t), table.remove(t, 1), table.concat(t, ","), table.remove({}))
+print(table.concat({1, 2.5, "x"}), table.concat({"a", "b", "c"}, "-", 2), table.concat({}, ","))
+local p = table.pack(1, nil, 3)
+print(p.n, p[1], p[2], p[3])
+print(table.unpack({1, 2, 3}))
+print(table.unpack({1, 2, 3}, 2), table.unpack({1, 2, 3}, 2, 5))
+print(table.concat(table.move({1, 2, 3, 4, 5}, 1, 3, 3), ","), table.concat(table.move({1, 2, 3}, 1, 3, 2, {}), ",", 2, 4))
+print(table.concat(table.move({1, 2, 3, 4, 5}, 2, 5, 1), ","))
+
+local words = {"pear", "apple", "fig", "banana", "cherry"}
+table.sort(words)
+print(table.concat(words, " "))
+table.sort(words, function(a, b) return #a < #b or (#a == #b and a < b) end)
+print(table.concat(words, " "))
+local nums = {}
+for i = 1, 500 do nums[i] = (i * 7919) % 1009 end
+table.sort(nums, function(a, b) return a > b end)
+local ok = true
+for i = 2, #nums do ok = ok and nums[i - 1] >= nums[i] end
+print(ok, nums[1], nums[500])
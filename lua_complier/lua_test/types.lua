@@ -2,4 +2,8 @@ print(nil)
 print(false)
 print(123)
 print(123456)
-print(123456.0)
\ No newline at end of file
+print(123456.0)
+print(0.1, 1/3, -2.5, 100.0, -0.0, 2^53, 2^63, 2^-20, 10 / 2)
+print(1/0, -1/0, 0/0 ~= 0/0, math.pi, -math.pi, 123456789012345.0)
+print(3 .. "", 3.0 .. "", 2^24 .. "|" .. 0.5, tostring(2^70))
+print(string.format("%s %s", 7.0, 1/3), table.concat({1.5, 2, 3.0}, ","))
@@ -0,0 +1,11 @@
+local s = "héllo wörld €𝄞"
+print(#s, utf8.len(s), utf8.len(s, 3), utf8.len(s, -4), utf8.len(""), utf8.len("abc", 4))
+print(utf8.char(72, 233, 8364, 119070), utf8.char(), utf8.char(2147483647):byte(1, -1))
+print(utf8.codepoint(s, 1, 3), utf8.codepoint("€"), utf8.codepoint(s, -4))
+for p, c in utf8.codes("aé€") do print(p, c) end
+print(utf8.offset(s, 3), utf8.offset(s, -1), utf8.offset(s, 0, 3), utf8.offset(s, 20), utf8.offset("abc", 4))
+print(utf8.len("ab\xffcd"), utf8.len("\xed\xa0\x80"), utf8.len("\xed\xa0\x80", 1, -1, true), utf8.len("\xc0\x80"))
+print(utf8.codepoint("\u{7FFFFFFF}", 1, 1, true), utf8.charpattern == "[\0-\x7F\xC2-\xFD][\x80-\xBF]*")
+local n = 0
+for c in s:gmatch(utf8.charpattern) do n = n + 1 end
+print(n)
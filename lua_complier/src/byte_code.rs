@@ -1,15 +1,84 @@
-#[derive(Debug)]
+// count operand meaning "all values up to the stack top": open arguments
+// or results of Call, and open counts of Return, VarArgs and SetList
+pub const MULTI: u8 = u8::MAX;
+
+// positional items stored by one SetList
+pub const FIELDS_PER_FLUSH: usize = 50;
+
+#[derive(Debug, Clone, Copy)]
 pub enum ByteCode {
-    GetGlobal(u8,u8),
-    LoadConst(u8,u8),
-    Call(u8,u8),
+    // upvalues; global variables are fields of the `_ENV` upvalue:
+    // GetUpField(dst, upvalue, key constant), SetUpField(upvalue, key constant, src)
+    GetUpvalue(u8,u8),
+    SetUpvalue(u8,u8),
+    GetUpField(u8,u8,u8),
+    SetUpField(u8,u8,u8),
+
+    // load values into a register
+    LoadConst(u8,u16),
     LoadBool(u8, bool),
     LoadInt(u8, i16),
-    Move(u8,u8),
-    SetGlobalConst(u8,u8),
-    SetGlobal(u8,u8),
-    SetGlobalGlobal(u8,u8),
     LoadNil(u8),
-}
+    Move(u8,u8),
+    VarArgs(u8,u8),
 
+    // tables: NewTable(dst, array size, map size),
+    // SetList(table, count, batch) stores the items following the table,
+    // Method(dst, object, key constant) loads object[key] and object
+    // into dst and dst+1
+    NewTable(u8,u8,u8),
+    SetTable(u8,u8,u8),
+    SetField(u8,u8,u8),
+    SetList(u8,u8,u8),
+    GetTable(u8,u8,u8),
+    GetField(u8,u8,u8),
+    Method(u8,u8,u8),
 
+    // jumps are relative to the next instruction
+    Jump(i16),
+    TestAndJump(u8,i16),
+    TestOrJump(u8,i16),
+
+    // numeric for: the registers from the first operand hold the index,
+    // limit, step and the loop variable. ForPrepare jumps forward past
+    // the loop if it runs zero times, ForLoop jumps back to the body.
+    ForPrepare(u8,u16),
+    ForLoop(u8,u16),
+    // generic for: iterator function, state, control variable and closing
+    // value, followed by the loop variables
+    TForCall(u8,u8),
+    TForLoop(u8,u16),
+
+    // functions: Closure(dst, prototype), Close(first register) closes
+    // the upvalues of the registers from the operand up
+    Closure(u8,u16),
+    Call(u8,u8,u8),
+    TailCall(u8,u8),
+    Return(u8,u8),
+    Close(u8),
+
+    // unary operators: dst, operand
+    Neg(u8,u8),
+    Not(u8,u8),
+    BitNot(u8,u8),
+    Len(u8,u8),
+
+    // binary operators: dst, left, right
+    Add(u8,u8,u8),
+    Sub(u8,u8,u8),
+    Mul(u8,u8,u8),
+    Div(u8,u8,u8),
+    Idiv(u8,u8,u8),
+    Mod(u8,u8,u8),
+    Pow(u8,u8,u8),
+    BitAnd(u8,u8,u8),
+    BitXor(u8,u8,u8),
+    BitOr(u8,u8,u8),
+    ShiftL(u8,u8,u8),
+    ShiftR(u8,u8,u8),
+    Concat(u8,u8,u8),
+    Equal(u8,u8,u8),
+    NotEq(u8,u8,u8),
+    Less(u8,u8,u8),
+    LesEq(u8,u8,u8),
+}
@@ -0,0 +1,494 @@
+use std::{cell::RefCell, collections::HashMap, ffi::c_void, hash::Hash, rc::Rc};
+
+use crate::{value::{Value, Table, AnyUserData, float_to_int}, vm::{ExeState, to_number}, error::LuaError};
+
+
+// A Rust type a Lua value converts to, as Lua::eval and the like return.
+// Numbers and strings convert to each other as the standard functions
+// convert their arguments; a value that does not fit is an error, with
+// the message of a bad argument ("number expected, got nil" and so on).
+pub trait FromLua: Sized {
+   fn from_lua(value: Value) -> Result<Self, LuaError>;
+}
+
+// A Rust type that converts to a Lua value. Only maps with keys Lua
+// rejects, nil or NaN, fail.
+pub trait IntoLua {
+   fn into_lua(self) -> Result<Value, LuaError>;
+}
+
+// Rust types for a list of Lua values: the arguments or the results of
+// a call. A single value is a list of one, () is the empty list, and
+// tuples take one value per element, nil if missing, but for their last
+// element which takes all the rest.
+pub trait FromLuaMulti: Sized {
+   fn from_lua_multi(values: Vec<Value>) -> Result<Self, LuaError>;
+
+   // As from_lua_multi, for the arguments of the running native function
+   // from number `first` on: an error is one of a bad argument, which
+   // names it.
+   fn from_lua_args(state: &ExeState, values: Vec<Value>, first: usize) -> Result<Self, LuaError> {
+      Self::from_lua_multi(values).map_err(|err| bad_argument(state, first, err))
+   }
+}
+
+pub trait IntoLuaMulti {
+   fn into_lua_multi(self) -> Result<Vec<Value>, LuaError>;
+}
+
+// A string as its bytes, which need not be UTF-8 as with String. A
+// Vec<u8> is a table of numbers instead.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct LuaString(pub Vec<u8>);
+
+// Any number of values of a type, as `...` in Lua.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Variadic<T>(pub Vec<T>);
+
+
+fn expected(what: &str, value: &Value) -> LuaError {
+   LuaError::Runtime(Value::from(format!("{what} expected, got {}", value.type_name())))
+}
+
+fn out_of_range() -> LuaError {
+   LuaError::Runtime(Value::from("value out of range"))
+}
+
+// "bad argument #i to 'f' (msg)" for a conversion error of argument i
+fn bad_argument(state: &ExeState, i: usize, err: LuaError) -> LuaError {
+   match err {
+      LuaError::Runtime(msg) if msg.is_string() => state.arg_error(i, &msg.to_string_lossy()),
+      err => err,
+   }
+}
+
+impl FromLua for Value {
+   fn from_lua(value: Value) -> Result<Self, LuaError> {
+      Ok(value)
+   }
+}
+
+impl IntoLua for Value {
+   fn into_lua(self) -> Result<Value, LuaError> {
+      Ok(self)
+   }
+}
+
+// as a condition: false only for nil and false
+impl FromLua for bool {
+   fn from_lua(value: Value) -> Result<Self, LuaError> {
+      Ok(!value.is_false())
+   }
+}
+
+impl IntoLua for bool {
+   fn into_lua(self) -> Result<Value, LuaError> {
+      Ok(Value::Boolean(self))
+   }
+}
+
+impl FromLua for i64 {
+   fn from_lua(value: Value) -> Result<Self, LuaError> {
+      match to_number(&value) {
+         Some(Value::Integer(n)) => Ok(n),
+         Some(Value::Float(f)) => float_to_int(f)
+            .ok_or_else(|| LuaError::Runtime(Value::from("number has no integer representation"))),
+         _ => Err(expected("number", &value)),
+      }
+   }
+}
+
+impl IntoLua for i64 {
+   fn into_lua(self) -> Result<Value, LuaError> {
+      Ok(Value::Integer(self))
+   }
+}
+
+// The other integer types go through i64, and must fit in both.
+macro_rules! integer_conversions {
+   ($($t:ty)*) => {$(
+      impl FromLua for $t {
+         fn from_lua(value: Value) -> Result<Self, LuaError> {
+            <$t>::try_from(i64::from_lua(value)?).map_err(|_| out_of_range())
+         }
+      }
+
+      impl IntoLua for $t {
+         fn into_lua(self) -> Result<Value, LuaError> {
+            i64::try_from(self).map(Value::Integer).map_err(|_| out_of_range())
+         }
+      }
+   )*};
+}
+
+integer_conversions!(i8 i16 i32 i128 isize u8 u16 u32 u64 u128 usize);
+
+impl FromLua for f64 {
+   fn from_lua(value: Value) -> Result<Self, LuaError> {
+      match to_number(&value) {
+         Some(Value::Integer(n)) => Ok(n as f64),
+         Some(Value::Float(f)) => Ok(f),
+         _ => Err(expected("number", &value)),
+      }
+   }
+}
+
+impl IntoLua for f64 {
+   fn into_lua(self) -> Result<Value, LuaError> {
+      Ok(Value::Float(self))
+   }
+}
+
+impl FromLua for f32 {
+   fn from_lua(value: Value) -> Result<Self, LuaError> {
+      f64::from_lua(value).map(|f| f as f32)
+   }
+}
+
+impl IntoLua for f32 {
+   fn into_lua(self) -> Result<Value, LuaError> {
+      Ok(Value::Float(self as f64))
+   }
+}
+
+// bytes that are not UTF-8 are replaced, as with String::from_utf8_lossy;
+// LuaString keeps them
+impl FromLua for String {
+   fn from_lua(value: Value) -> Result<Self, LuaError> {
+      match &value {
+         v if v.is_string() => Ok(v.to_string_lossy()),
+         v @ (Value::Integer(_) | Value::Float(_)) => Ok(format!("{v:?}")),
+         v => Err(expected("string", v)),
+      }
+   }
+}
+
+impl IntoLua for String {
+   fn into_lua(self) -> Result<Value, LuaError> {
+      Ok(Value::from(self))
+   }
+}
+
+impl IntoLua for &str {
+   fn into_lua(self) -> Result<Value, LuaError> {
+      Ok(Value::from(self))
+   }
+}
+
+// a string of any bytes
+impl IntoLua for &[u8] {
+   fn into_lua(self) -> Result<Value, LuaError> {
+      Ok(Value::from(self))
+   }
+}
+
+impl FromLua for LuaString {
+   fn from_lua(value: Value) -> Result<Self, LuaError> {
+      match &value {
+         v if v.is_string() => Ok(LuaString(v.as_bytes().to_vec())),
+         v @ (Value::Integer(_) | Value::Float(_)) => Ok(LuaString(format!("{v:?}").into_bytes())),
+         v => Err(expected("string", v)),
+      }
+   }
+}
+
+impl IntoLua for LuaString {
+   fn into_lua(self) -> Result<Value, LuaError> {
+      Ok(Value::from(self.0))
+   }
+}
+
+// whatever the value, which is dropped
+impl FromLua for () {
+   fn from_lua(_value: Value) -> Result<Self, LuaError> {
+      Ok(())
+   }
+}
+
+// nil is None
+impl<T: FromLua> FromLua for Option<T> {
+   fn from_lua(value: Value) -> Result<Self, LuaError> {
+      match value {
+         Value::Nil => Ok(None),
+         v => T::from_lua(v).map(Some),
+      }
+   }
+}
+
+impl<T: IntoLua> IntoLua for Option<T> {
+   fn into_lua(self) -> Result<Value, LuaError> {
+      match self {
+         Some(v) => v.into_lua(),
+         None => Ok(Value::Nil),
+      }
+   }
+}
+
+impl FromLua for Rc<RefCell<Table>> {
+   fn from_lua(value: Value) -> Result<Self, LuaError> {
+      match value {
+         Value::Table(t) => Ok(t),
+         v => Err(expected("table", &v)),
+      }
+   }
+}
+
+impl IntoLua for Rc<RefCell<Table>> {
+   fn into_lua(self) -> Result<Value, LuaError> {
+      Ok(Value::Table(self))
+   }
+}
+
+// a full userdata, to borrow as its Rust type
+impl FromLua for Rc<AnyUserData> {
+   fn from_lua(value: Value) -> Result<Self, LuaError> {
+      match value {
+         Value::UserData(u) => Ok(u),
+         v => Err(expected("userdata", &v)),
+      }
+   }
+}
+
+impl IntoLua for Rc<AnyUserData> {
+   fn into_lua(self) -> Result<Value, LuaError> {
+      Ok(Value::UserData(self))
+   }
+}
+
+// a light userdata
+impl FromLua for *mut c_void {
+   fn from_lua(value: Value) -> Result<Self, LuaError> {
+      match value {
+         Value::LightUserData(p) => Ok(p),
+         v => Err(expected("light userdata", &v)),
+      }
+   }
+}
+
+impl IntoLua for *mut c_void {
+   fn into_lua(self) -> Result<Value, LuaError> {
+      Ok(Value::LightUserData(self))
+   }
+}
+
+// The sequence 1..#t of a table. Metamethods are not called, as for
+// all conversions.
+impl<T: FromLua> FromLua for Vec<T> {
+   fn from_lua(value: Value) -> Result<Self, LuaError> {
+      let t = Rc::<RefCell<Table>>::from_lua(value)?;
+      let t = t.borrow();
+      (1..=t.len()).map(|i| T::from_lua(t.get_int(i))).collect()
+   }
+}
+
+impl<T: IntoLua> IntoLua for Vec<T> {
+   fn into_lua(self) -> Result<Value, LuaError> {
+      let mut t = Table::new(self.len(), 0);
+      for (i, v) in self.into_iter().enumerate() {
+         t.set_int(i as i64 + 1, v.into_lua()?);
+      }
+      Ok(Value::Table(Rc::new(RefCell::new(t))))
+   }
+}
+
+// all the entries of a table
+impl<K: FromLua + Eq + Hash, V: FromLua> FromLua for HashMap<K, V> {
+   fn from_lua(value: Value) -> Result<Self, LuaError> {
+      let t = Rc::<RefCell<Table>>::from_lua(value)?;
+      let t = t.borrow();
+      let mut map = HashMap::new();
+      let mut key = Value::Nil;
+      while let Some((k, v)) = t.next(&key).map_err(|msg| LuaError::Runtime(Value::from(msg)))? {
+         map.insert(K::from_lua(k.clone())?, V::from_lua(v)?);
+         key = k;
+      }
+      Ok(map)
+   }
+}
+
+impl<K: IntoLua, V: IntoLua> IntoLua for HashMap<K, V> {
+   fn into_lua(self) -> Result<Value, LuaError> {
+      let mut t = Table::new(0, self.len());
+      for (k, v) in self {
+         t.set(k.into_lua()?, v.into_lua()?)
+            .map_err(|msg| LuaError::Runtime(Value::from(msg)))?;
+      }
+      Ok(Value::Table(Rc::new(RefCell::new(t))))
+   }
+}
+
+
+impl<T: FromLua> FromLuaMulti for T {
+   fn from_lua_multi(values: Vec<Value>) -> Result<Self, LuaError> {
+      T::from_lua(values.into_iter().next().unwrap_or(Value::Nil))
+   }
+}
+
+impl<T: IntoLua> IntoLuaMulti for T {
+   fn into_lua_multi(self) -> Result<Vec<Value>, LuaError> {
+      Ok(vec![self.into_lua()?])
+   }
+}
+
+impl IntoLuaMulti for () {
+   fn into_lua_multi(self) -> Result<Vec<Value>, LuaError> {
+      Ok(Vec::new())
+   }
+}
+
+impl<T: FromLua> FromLuaMulti for Variadic<T> {
+   fn from_lua_multi(values: Vec<Value>) -> Result<Self, LuaError> {
+      values.into_iter().map(T::from_lua).collect::<Result<_, _>>().map(Variadic)
+   }
+
+   fn from_lua_args(state: &ExeState, values: Vec<Value>, first: usize) -> Result<Self, LuaError> {
+      values.into_iter().enumerate()
+         .map(|(i, v)| T::from_lua(v).map_err(|err| bad_argument(state, first + i, err)))
+         .collect::<Result<_, _>>().map(Variadic)
+   }
+}
+
+impl<T: IntoLua> IntoLuaMulti for Variadic<T> {
+   fn into_lua_multi(self) -> Result<Vec<Value>, LuaError> {
+      self.0.into_iter().map(T::into_lua).collect()
+   }
+}
+
+// tuples of up to 8 values
+macro_rules! tuple_conversions {
+   ($($name:ident)* ; $last:ident) => {
+      impl<$($name: FromLua,)* $last: FromLuaMulti> FromLuaMulti for ($($name,)* $last,) {
+         #[allow(non_snake_case, unused_mut)]
+         fn from_lua_multi(values: Vec<Value>) -> Result<Self, LuaError> {
+            let mut values = values.into_iter();
+            $(let $name = $name::from_lua(values.next().unwrap_or(Value::Nil))?;)*
+            let $last = $last::from_lua_multi(values.collect())?;
+            Ok(($($name,)* $last,))
+         }
+
+         #[allow(non_snake_case, unused_mut)]
+         fn from_lua_args(state: &ExeState, values: Vec<Value>, first: usize) -> Result<Self, LuaError> {
+            let mut values = values.into_iter();
+            let mut i = first;
+            $(
+               let $name = $name::from_lua(values.next().unwrap_or(Value::Nil))
+                  .map_err(|err| bad_argument(state, i, err))?;
+               i += 1;
+            )*
+            let $last = $last::from_lua_args(state, values.collect(), i)?;
+            Ok(($($name,)* $last,))
+         }
+      }
+
+      impl<$($name: IntoLua,)* $last: IntoLuaMulti> IntoLuaMulti for ($($name,)* $last,) {
+         #[allow(non_snake_case)]
+         fn into_lua_multi(self) -> Result<Vec<Value>, LuaError> {
+            let ($($name,)* $last,) = self;
+            let mut values = vec![$($name.into_lua()?),*];
+            values.extend($last.into_lua_multi()?);
+            Ok(values)
+         }
+      }
+   };
+}
+
+tuple_conversions!(; A);
+tuple_conversions!(A ; B);
+tuple_conversions!(A B ; C);
+tuple_conversions!(A B C ; D);
+tuple_conversions!(A B C D ; E);
+tuple_conversions!(A B C D E ; F);
+tuple_conversions!(A B C D E F ; G);
+tuple_conversions!(A B C D E F G ; H);
+
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn round_trip<T: IntoLua + FromLua + PartialEq + std::fmt::Debug + Clone>(v: T) {
+      assert_eq!(T::from_lua(v.clone().into_lua().unwrap()).unwrap(), v);
+   }
+
+   fn message(err: LuaError) -> String {
+      err.to_string()
+   }
+
+   #[test]
+   fn integers() {
+      round_trip(i8::MIN);
+      round_trip(u8::MAX);
+      round_trip(i32::MIN);
+      round_trip(i64::MAX);
+      round_trip(i64::MAX as u64);
+      round_trip(usize::MAX >> 1);
+      round_trip(-1i128);
+
+      // out of range either way, with no lossy float
+      assert_eq!(message(u64::MAX.into_lua().unwrap_err()), "value out of range");
+      assert_eq!(message((i64::MIN as i128 - 1).into_lua().unwrap_err()), "value out of range");
+      assert_eq!(message(u8::from_lua(Value::Integer(256)).unwrap_err()), "value out of range");
+      assert_eq!(message(u32::from_lua(Value::Integer(-1)).unwrap_err()), "value out of range");
+
+      // floats with an integer value, and strings, convert
+      assert_eq!(i64::from_lua(Value::Float(2f64.powi(53))).unwrap(), 1 << 53);
+      assert_eq!(u16::from_lua(Value::from(" 0x10 ")).unwrap(), 16);
+      assert_eq!(message(i64::from_lua(Value::Float(1.5)).unwrap_err()), "number has no integer representation");
+      assert_eq!(message(i64::from_lua(Value::from("1e100")).unwrap_err()), "number has no integer representation");
+      assert_eq!(message(i32::from_lua(Value::Nil).unwrap_err()), "number expected, got nil");
+      assert_eq!(message(i32::from_lua(Value::from("ten")).unwrap_err()), "number expected, got string");
+   }
+
+   #[test]
+   fn floats() {
+      round_trip(0.1f64);
+      round_trip(-2.5f32);
+      assert_eq!(f64::from_lua(Value::Integer(3)).unwrap(), 3.0);
+      assert_eq!(f64::from_lua(Value::from("1e3")).unwrap(), 1000.0);
+      assert_eq!(message(f64::from_lua(Value::Boolean(true)).unwrap_err()), "number expected, got boolean");
+   }
+
+   #[test]
+   fn strings() {
+      round_trip(String::from("héllo"));
+      assert_eq!(String::from_lua(Value::Integer(10)).unwrap(), "10");
+      assert_eq!(String::from_lua(Value::Float(1.5)).unwrap(), "1.5");
+      assert_eq!(String::from_lua(Value::from(&b"a\xffb"[..])).unwrap(), "a\u{fffd}b");
+      assert_eq!(message(String::from_lua(Value::new_table(0, 0)).unwrap_err()), "string expected, got table");
+
+      // bytes are kept as they are
+      let bytes = LuaString(b"\xff\0a long string, not a short one: \x80".to_vec());
+      round_trip(bytes.clone());
+      assert_eq!(<&[u8]>::try_from(&bytes.clone().into_lua().unwrap()).unwrap(), &bytes.0[..]);
+      assert_eq!(LuaString::from_lua(Value::Integer(-7)).unwrap(), LuaString(b"-7".to_vec()));
+      assert_eq!(message(LuaString::from_lua(Value::Nil).unwrap_err()), "string expected, got nil");
+      assert_eq!(message(<&[u8]>::try_from(&Value::Boolean(false)).unwrap_err()), "string expected, got boolean");
+   }
+
+   #[test]
+   fn containers() {
+      round_trip(Some(3i64));
+      round_trip(None::<String>);
+      round_trip(vec![1u8, 2, 3]);
+      round_trip(vec![String::from("a"), String::from("b")]);
+      round_trip(HashMap::from([(String::from("x"), 1.5), (String::from("y"), -2.0)]));
+      round_trip(HashMap::from([(1i64, true), (-1, false)]));
+
+      assert_eq!(message(Vec::<i64>::from_lua(Value::Integer(1)).unwrap_err()), "table expected, got number");
+      let t = vec![Value::Integer(1), Value::from("x")].into_lua().unwrap();
+      assert_eq!(message(Vec::<i64>::from_lua(t).unwrap_err()), "number expected, got string");
+   }
+
+   #[test]
+   fn multiple_values() {
+      let values = (1i64, "two", 3.5, Variadic(vec![true, false])).into_lua_multi().unwrap();
+      assert_eq!(values, [Value::Integer(1), Value::from("two"), Value::Float(3.5), Value::Boolean(true), Value::Boolean(false)]);
+
+      // the last element takes the rest, and missing values are nil
+      let (a, b, Variadic(rest)) = <(i64, String, Variadic<Value>)>::from_lua_multi(values).unwrap();
+      assert_eq!((a, b, rest.len()), (1, String::from("two"), 3));
+      let (a, b) = <(Option<i64>, Option<i64>)>::from_lua_multi(vec![Value::Integer(1)]).unwrap();
+      assert_eq!((a, b), (Some(1), None));
+      assert_eq!(().into_lua_multi().unwrap(), []);
+   }
+}
@@ -0,0 +1,70 @@
+use std::{fmt, io};
+
+use crate::value::Value;
+
+
+// Errors raised while compiling or running a chunk.
+//
+// Syntax errors carry the full "chunk:line: message" text. Runtime errors
+// carry the raised value, which is usually (but not always) a string.
+// Exit is not an error but a call to os.exit: it unwinds every call, past
+// pcall too, so that the host can end the program with its status. Yield
+// is a coroutine yielding, which unwinds to the resume that ran it with
+// its frames left in place.
+#[derive(Debug, Clone)]
+pub enum LuaError {
+    Syntax(String),
+    Runtime(Value),
+    Exit(i32),
+    Yield,
+}
+
+impl LuaError {
+    // syntax error whose message ends with "<eof>", i.e. the input stopped
+    // in the middle of a statement and more of it may follow
+    pub fn is_incomplete(&self) -> bool {
+        matches!(self, LuaError::Syntax(msg) if msg.ends_with("<eof>"))
+    }
+
+    pub fn is_exit(&self) -> bool {
+        matches!(self, LuaError::Exit(_))
+    }
+
+    // whether pcall lets it through: os.exit and yields are not errors
+    pub fn is_catchable(&self) -> bool {
+        !matches!(self, LuaError::Exit(_) | LuaError::Yield)
+    }
+
+    // the value a protected call or a resume catches: the raised value, or
+    // the message of a syntax error
+    pub fn into_value(self) -> Value {
+        match self {
+            LuaError::Syntax(msg) => Value::from(msg),
+            LuaError::Runtime(v) => v,
+            LuaError::Exit(_) | LuaError::Yield => unreachable!("{self} caught"),
+        }
+    }
+}
+
+impl fmt::Display for LuaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LuaError::Syntax(msg) => write!(f, "{msg}"),
+            LuaError::Runtime(v) => write!(f, "{v:?}"),
+            LuaError::Exit(status) => write!(f, "exit with status {status}"),
+            LuaError::Yield => write!(f, "yield"),
+        }
+    }
+}
+
+impl std::error::Error for LuaError {}
+
+// an I/O error as C's strerror() words it, without the " (os error 2)"
+// that Rust adds
+pub fn io_error_text(err: &io::Error) -> String {
+    let text = err.to_string();
+    match text.rfind(" (os error ") {
+        Some(i) => text[..i].to_string(),
+        None => text,
+    }
+}
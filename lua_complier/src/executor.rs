@@ -0,0 +1,117 @@
+use std::{future::Future, pin::pin, sync::Arc, task::{Context, Poll, Wake, Waker}, thread::{self, Thread}};
+
+
+// A minimal executor for ExeState::call_async: run `future` to completion
+// on the current thread, which sleeps while it is pending until its
+// waker is called. Futures that need a reactor (timers, sockets) want a
+// real runtime instead.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+   let mut future = pin!(future);
+   let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+   let mut cx = Context::from_waker(&waker);
+   loop {
+      match future.as_mut().poll(&mut cx) {
+         Poll::Ready(output) => return output,
+         Poll::Pending => thread::park(),
+      }
+   }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+   fn wake(self: Arc<Self>) {
+      self.0.unpark();
+   }
+}
+
+
+#[cfg(test)]
+mod tests {
+   use std::{pin::Pin, time::Duration};
+
+   use super::*;
+   use crate::{value::Value, vm::ExeState, error::LuaError};
+
+   // Ready with `result` after being polled pending `polls` times. The
+   // waker is called from another thread, as by an I/O driver.
+   struct Delayed {
+      polls: usize,
+      result: Option<Result<Vec<Value>, LuaError>>,
+      waker: Option<thread::JoinHandle<()>>,
+   }
+
+   impl Future for Delayed {
+      type Output = Result<Vec<Value>, LuaError>;
+
+      fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+         if let Some(handle) = self.waker.take() {
+            handle.join().unwrap();
+         }
+         if self.polls == 0 {
+            return Poll::Ready(self.result.take().unwrap());
+         }
+         self.polls -= 1;
+         let waker = cx.waker().clone();
+         self.waker = Some(thread::spawn(move || {
+            thread::sleep(Duration::from_millis(1));
+            waker.wake();
+         }));
+         Poll::Pending
+      }
+   }
+
+   fn delayed(polls: usize, result: Result<Vec<Value>, LuaError>) -> Delayed {
+      Delayed { polls, result: Some(result), waker: None }
+   }
+
+   // answer(n): 42, once n pending polls are over
+   fn answer(state: &mut ExeState) -> Result<i32, LuaError> {
+      let polls = state.check_integer(1)? as usize;
+      state.await_future(delayed(polls, Ok(vec![Value::Integer(42)])))
+   }
+
+   // fail(): an error, after a pending poll
+   fn fail(state: &mut ExeState) -> Result<i32, LuaError> {
+      state.await_future(delayed(1, Err(LuaError::Runtime(Value::from("boom")))))
+   }
+
+   fn run(source: &str) -> Result<Vec<Value>, LuaError> {
+      let mut state = ExeState::new();
+      let Value::Table(globals) = state.globals() else { unreachable!() };
+      globals.borrow_mut().set_str("answer", Value::Function(answer));
+      globals.borrow_mut().set_str("fail", Value::Function(fail));
+      let f = state.load(source.as_bytes(), "=test", "t", None)?;
+      block_on(state.call_async(&f, &[]))
+   }
+
+   #[test]
+   fn resolves_after_pending() {
+      assert_eq!(run("return answer(0) + 1").unwrap(), vec![Value::Integer(43)]);
+      assert_eq!(run("return answer(3), answer(1)").unwrap(), vec![Value::Integer(42), Value::Integer(42)]);
+   }
+
+   #[test]
+   fn pcall_around_await() {
+      assert_eq!(run("return pcall(answer, 2)").unwrap(), vec![Value::Boolean(true), Value::Integer(42)]);
+      assert_eq!(run("return pcall(function() return fail() end)").unwrap(),
+         vec![Value::Boolean(false), Value::from("boom")]);
+   }
+
+   #[test]
+   fn error_from_future() {
+      let err = run("local x = answer(1); fail(); return x").unwrap_err();
+      assert_eq!(err.to_string(), "boom");
+   }
+
+   #[test]
+   fn await_outside_call_async() {
+      let mut state = ExeState::new();
+      let err = state.call(&Value::Function(answer), &[Value::Integer(0)]).unwrap_err();
+      assert_eq!(err.to_string(), "attempt to await outside an async call");
+
+      // nor in a coroutine of the script
+      let results = run("return coroutine.resume(coroutine.create(answer), 0)").unwrap();
+      assert_eq!(results, vec![Value::Boolean(false), Value::from("attempt to await outside an async call")]);
+   }
+}
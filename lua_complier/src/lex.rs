@@ -1,12 +1,18 @@
-use core::panic;
-use std::{io::{Read, Bytes}, mem, iter::Peekable, char};
+use std::{io::{Read, Bytes, BufReader}, mem, iter::Peekable, char, fmt};
+
+use crate::{error::LuaError, value::Value};
 
 #[derive(Debug)]
 pub struct Lex<R:Read>{
-    input: Peekable::<Bytes::<R>>,
+    input: Peekable::<Bytes::<BufReader<R>>>,
     head:Token,
+    chunk: String,
+    line: usize,
+    // line where the peeked token ends, and where the last token read ends
+    head_line: usize,
+    last_line: usize,
 }
-#[derive(Debug,PartialEq)]
+#[derive(Debug,Clone,PartialEq)]
 pub enum Token {
     //keywords
     And,    Break,  Do,         Else,   Elseif, End,
@@ -32,34 +38,102 @@ pub enum Token {
     Eos,
 }
 
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Token::And => "and", Token::Break => "break", Token::Do => "do",
+            Token::Else => "else", Token::Elseif => "elseif", Token::End => "end",
+            Token::False => "false", Token::For => "for", Token::Function => "function",
+            Token::Goto => "goto", Token::If => "if", Token::In => "in",
+            Token::Local => "local", Token::Nil => "nil", Token::Not => "not",
+            Token::Or => "or", Token::Repeat => "repeat", Token::Return => "return",
+            Token::Then => "then", Token::True => "true", Token::Until => "until",
+            Token::While => "while",
+            Token::Add => "+", Token::Sub => "-", Token::Mul => "*", Token::Div => "/",
+            Token::Mod => "%", Token::Pow => "^", Token::Len => "#",
+            Token::BitAnd => "&", Token::BitXor => "~", Token::BitOr => "|",
+            Token::ShiftL => "<<", Token::ShiftR => ">>", Token::Idiv => "//",
+            Token::Equal => "==", Token::NotEq => "~=", Token::LesEq => "<=",
+            Token::GreEq => ">=", Token::Less => "<", Token::Greater => ">",
+            Token::Assign => "=",
+            Token::ParL => "(", Token::ParR => ")", Token::CurlyL => "{",
+            Token::CurlyR => "}", Token::SqurL => "[", Token::SqurR => "]",
+            Token::DoubColon => "::", Token::SemiColon => ";", Token::Colon => ":",
+            Token::Comma => ",", Token::Dot => ".", Token::Concat => "..",
+            Token::Dots => "...",
+            Token::Integer(i) => return write!(f, "'{i}'"),
+            Token::Float(n) => return write!(f, "'{n:?}'"),
+            Token::Name(name) => return write!(f, "'{name}'"),
+            Token::Strng(s) => return write!(f, "'{}'", String::from_utf8_lossy(s)),
+            Token::Eos => return write!(f, "<eof>"),
+        };
+        write!(f, "'{s}'")
+    }
+}
+
 impl<R:Read> Lex<R> {
-   pub fn new(input : R) ->Self{
+   pub fn new(input : R, chunk: &str) ->Self{
         Lex {
-            input:input.bytes().peekable(),
+            input:BufReader::new(input).bytes().peekable(),
             head:Token::Eos,
+            chunk: chunk.to_string(),
+            line: 1,
+            head_line: 1,
+            last_line: 1,
         } 
    }
-   pub fn next(&mut self)->Token{
+   pub fn next(&mut self)->Result<Token, LuaError>{
         if self.head == Token::Eos{
-            self.do_next()
+            let t = self.do_next();
+            self.last_line = self.line;
+            t
         }
         else{
-            mem::replace(&mut self.head, Token::Eos)
+            self.last_line = self.head_line;
+            Ok(mem::replace(&mut self.head, Token::Eos))
         }
    }
 
-   pub fn peek(&mut self)->&Token {
+   pub fn peek(&mut self)->Result<&Token, LuaError> {
     // this function can use Some(x).take() to instead
       if self.head == Token::Eos{
-        self.head = self.do_next();
+        self.head = self.do_next()?;
+        self.head_line = self.line;
       } 
-      &self.head
+      Ok(&self.head)
    }
-   fn do_next(&mut self)->Token {
 
-        if let Some(ch) = self.next_byte(){
-        match ch {
-           b' ' | b'\r' | b'\n' | b'\t' => self.do_next(),
+   pub fn line(&self) -> usize {
+      self.line
+   }
+
+   // the line of the last token read, not counting a peeked one
+   pub fn last_line(&self) -> usize {
+      self.last_line
+   }
+
+   // "chunk:line: msg near 'token'", the format of Lua's syntax errors
+   pub fn error_near(&self, msg: &str, near: &Token) -> LuaError {
+      LuaError::Syntax(format!("{}:{}: {} near {}", self.chunk, self.line, msg, near))
+   }
+
+   pub fn error(&self, msg: &str) -> LuaError {
+      LuaError::Syntax(format!("{}:{}: {}", self.chunk, self.line, msg))
+   }
+
+   pub fn chunk(&self) -> &str {
+      &self.chunk
+   }
+
+   fn do_next(&mut self)->Result<Token, LuaError> {
+
+        if let Some(ch) = self.next_byte()?{
+        let t = match ch {
+           b'\n' => {
+                self.line += 1;
+                return self.do_next();
+           }
+           b' ' | b'\r' | b'\t' => return self.do_next(),
            b'+'=>Token::Add,
            b'*'=>Token::Mul,
            b'%'=>Token::Mod,
@@ -71,79 +145,84 @@ impl<R:Read> Lex<R> {
            b')'=>Token::ParR,
            b'{'=>Token::CurlyL,
            b'}'=>Token::CurlyR,
-           b'['=>Token::SqurL,
+           b'['=>match self.long_bracket()? {
+                Some(level) => Token::Strng(self.read_long_string(level, "string")?),
+                None => Token::SqurL,
+           },
            b']'=>Token::SqurR,
            b';'=>Token::SemiColon,
            b','=>Token::Comma,
-           b'/'=>self.check_ahead(b'/', Token::Idiv, Token::Div),
-           b'='=>self.check_ahead(b'/', Token::Equal, Token::Assign),
-           b'~'=>self.check_ahead(b'=', Token::NotEq, Token::BitXor),
-           b':'=>self.check_ahead(b':', Token::DoubColon, Token::Colon),
-           b'<'=>self.check_ahead2(b'=', Token::LesEq, b'<', Token::ShiftL, Token::Less),
-           b'>'=>self.check_ahead2(b'=', Token::GreEq, b'>', Token::ShiftR, Token::Greater),
-           b'.'=>match self.read_char() {
+           b'/'=>self.check_ahead(b'/', Token::Idiv, Token::Div)?,
+           b'='=>self.check_ahead(b'=', Token::Equal, Token::Assign)?,
+           b'~'=>self.check_ahead(b'=', Token::NotEq, Token::BitXor)?,
+           b':'=>self.check_ahead(b':', Token::DoubColon, Token::Colon)?,
+           b'<'=>self.check_ahead2(b'=', Token::LesEq, b'<', Token::ShiftL, Token::Less)?,
+           b'>'=>self.check_ahead2(b'=', Token::GreEq, b'>', Token::ShiftR, Token::Greater)?,
+           b'.'=>match self.read_char()? {
                 b'.'=>{
-                    self.next_byte();
-                    if self.read_char() == b'.'{
-                        self.next_byte();
+                    self.next_byte()?;
+                    if self.read_char()? == b'.'{
+                        self.next_byte()?;
                         Token::Dots
                     }
                     else {
                         Token::Concat
                     }
                 },
-                b'0'..=b'9'=>{
-                    self.read_digit_fraction(0)
-                },
+                b'0'..=b'9'=>self.read_number(b'.')?,
                 _=>{
                     Token::Dot
                 },
            },
            b'-'=>{
-                if self.read_char() == b'-'{
-                    self.next_byte();
-                    self.read_comment();
-                    self.do_next()
+                if self.read_char()? == b'-'{
+                    self.next_byte()?;
+                    self.read_comment()?;
+                    return self.do_next();
                 }
                 else {
                     Token::Sub
                 }
            },
-           b'\''| b'"' => self.read_string(ch),
-           b'A'..=b'Z' | b'a'..=b'z' | b'_'=>self.read_name(ch),
-           b'0'..=b'9'=>self.read_number(ch),
+           b'\''| b'"' => self.read_string(ch)?,
+           b'A'..=b'Z' | b'a'..=b'z' | b'_'=>self.read_name(ch)?,
+           b'0'..=b'9'=>self.read_number(ch)?,
            b'\0' => Token::Eos,
-           _ => panic!("unexpected char: {ch}"),
-        }
+           _ => return Err(self.error(&format!("unexpected symbol near '{}'", ch.escape_ascii()))),
+        };
+        Ok(t)
       }
       else{
-        Token::Eos
+        Ok(Token::Eos)
       }
 
    }
 
-   fn read_char(&mut self) -> u8{
+   fn read_char(&mut self) -> Result<u8, LuaError>{
 
       match self.input.peek() {
-         Some(Ok(ch)) => *ch,
-         Some(_) =>panic!("lex read error"),
-         None => b'\0',
+         Some(Ok(ch)) => Ok(*ch),
+         Some(Err(e)) => {
+            let msg = format!("read error: {e}");
+            Err(self.error(&msg))
+         }
+         None => Ok(b'\0'),
       }
 
    }
 
-   fn next_byte(&mut self)->Option<u8>{
-    self.input.next().and_then(|r|Some(r.unwrap()))
+   fn next_byte(&mut self)->Result<Option<u8>, LuaError>{
+    self.input.next().transpose().map_err(|e| self.error(&format!("read error: {e}")))
    }
 
-   fn read_name(&mut self,first:u8)->Token{
+   fn read_name(&mut self,first:u8)->Result<Token, LuaError>{
         let mut s = String::new(); 
         s.push(first as char);
 
         loop {
-           let ch = self.read_char() as char;
-           if ch.is_alphanumeric() || ch == '_'{
-            self.next_byte();
+           let ch = self.read_char()? as char;
+           if ch.is_ascii_alphanumeric() || ch == '_'{
+            self.next_byte()?;
             s.push(ch);
            }
            else {
@@ -151,7 +230,7 @@ impl<R:Read> Lex<R> {
            }
         } 
 
-        match &s as &str {
+        let t = match &s as &str {
             "nil"=>Token::Nil,
             "true"=>Token::True,
             "false"=>Token::False,
@@ -175,134 +254,471 @@ impl<R:Read> Lex<R> {
             "until"=>Token::Until,
             "while"=>Token::While,
             _=>Token::Name(s),
-        }
+        };
+        Ok(t)
 
    }
-   fn read_number(&mut self,first:u8)->Token {
-      let mut num = (first - b'0') as i64;
-
-      /*heximal */
-      if first == b'0'{
-        let second = self.read_char();
-        if second == b'x' || second == b'X'{
-          return self.read_heximal();
-        }
+   // Read a numeral the way Lua does: take every character that may
+   // belong to one, then convert the whole text, so that "3x" or "1..2"
+   // is reported as malformed instead of split into tokens.
+   fn read_number(&mut self,first:u8)->Result<Token, LuaError> {
+      let mut text = vec![first];
+      let mut expo = *b"Ee";
+      if first == b'0' && matches!(self.read_char()?, b'x' | b'X') {
+         expo = *b"Pp";
+         text.push(self.read_char()?);
+         self.next_byte()?;
       }
-
-      /*decima */
       loop {
-         let ch = self.read_char();
-         if let Some(num1) = char::to_digit(ch as char, 10){
-            self.next_byte();
-            num = num * 10 + num1 as i64;
-         }
-         else if ch == b'.'{
-          return self.read_digit_fraction(num); 
-         }
-         else if ch == b'e' || ch == b'E'{
-          return self.read_number_exp(ch as f64);
-         }
-         else{
-          break;
+         let ch = self.read_char()?;
+         if expo.contains(&ch) {
+            text.push(ch);
+            self.next_byte()?;
+            if matches!(self.read_char()?, b'+' | b'-') {
+               text.push(self.read_char()?);
+               self.next_byte()?;
+            }
+         } else if ch.is_ascii_hexdigit() || ch == b'.' {
+            text.push(ch);
+            self.next_byte()?;
+         } else {
+            break;
          }
-      } 
-
-      let fcn = self.read_char();
-      if(fcn as char).is_alphabetic() || fcn == b'.'{
-        panic!("malformat number")
       }
+      // a numeral touching a letter is malformed
+      let ch = self.read_char()?;
+      if ch.is_ascii_alphanumeric() || ch == b'_' {
+         text.push(ch);
+         self.next_byte()?;
+      }
+      match str2number(&text) {
+         Some(Value::Integer(i)) => Ok(Token::Integer(i)),
+         Some(Value::Float(f)) => Ok(Token::Float(f)),
+         _ => Err(self.error(&format!("malformed number near '{}'", text.escape_ascii()))),
+      }
+   }
 
-      Token::Integer(num)
+   fn read_string(&mut self,quote:u8)->Result<Token, LuaError>{
+
+    let mut s = Vec::new();
+    loop {
+        match self.next_byte()? {
+            None => return Err(self.error_near("unfinished string", &Token::Eos)),
+            Some(b'\n') => return Err(self.string_error("unfinished string", quote, &s)),
+            Some(b'\\') => self.read_escape(quote, &mut s)?,
+            Some(ch) if ch == quote => break,
+            Some(ch) => s.push(ch),
+        }    
+    } 
+    Ok(Token::Strng(s))
    }
 
-   fn read_digit_fraction(&mut self,n:i64)->Token{
-      self.next_byte();
-      let mut num_i:i64 = 0;
-      let mut x = 1.0;
-      loop {
-        let ch = self.read_char();
-        if let Some(num1) = char::to_digit(ch as char,10){
-          self.next_byte();
-          num_i = num_i * 10 + num1 as i64; 
-          x *=  10.0;
-        }      
-        else{
-          break;
+   // the escape sequence following a '\\' in a string, appended to `s`
+   fn read_escape(&mut self, quote:u8, s: &mut Vec<u8>)->Result<(), LuaError>{
+    let Some(ch) = self.next_byte()? else {
+        return Err(self.error_near("unfinished string", &Token::Eos));
+    };
+    let byte = match ch {
+        b'a' => 0x07,
+        b'b' => 0x08,
+        b'f' => 0x0c,
+        b'n' => b'\n',
+        b'r' => b'\r',
+        b't' => b'\t',
+        b'v' => 0x0b,
+        b'\\' | b'"' | b'\'' => ch,
+        b'\n' | b'\r' => {
+            // an escaped line break is a newline; "\r\n" and "\n\r" count once
+            self.line += 1;
+            let next = self.read_char()?;
+            if (next == b'\n' || next == b'\r') && next != ch {
+                self.next_byte()?;
+            }
+            b'\n'
+        }
+        b'x' => {
+            let mut v = 0;
+            for _ in 0..2 {
+                let d = self.read_char()?;
+                let Some(d) = (d as char).to_digit(16) else {
+                    return Err(self.escape_error("hexadecimal digit expected", quote, s, &[b'x', d]));
+                };
+                self.next_byte()?;
+                v = v * 16 + d as u8;
+            }
+            v
+        }
+        b'z' => {
+            // skip the following white space, line breaks included
+            loop {
+                match self.read_char()? {
+                    b'\n' => self.line += 1,
+                    b' ' | b'\r' | b'\t' | 0x0b | 0x0c => (),
+                    _ => return Ok(()),
+                }
+                self.next_byte()?;
+            }
+        }
+        b'u' => return self.read_utf8_escape(quote, s),
+        b'0'..=b'9' => {
+            let mut v = (ch - b'0') as u32;
+            for _ in 0..2 {
+                let d = self.read_char()?;
+                if !d.is_ascii_digit() {
+                    break;
+                }
+                self.next_byte()?;
+                v = v * 10 + (d - b'0') as u32;
+            }
+            if v > 255 {
+                return Err(self.escape_error("decimal escape too large", quote, s, v.to_string().as_bytes()));
+            }
+            v as u8
         }
+        _ => return Err(self.escape_error("invalid escape sequence", quote, s, &[ch])),
+    };
+    s.push(byte);
+    Ok(())
+   }
 
-      }
-      let  num_f = num_i as f64 / x;
-      dbg!(&n);
-      dbg!(&(num_i));
-      dbg!(&num_f);
-      Token::Float(n as f64 + num_f)
+   // "\u{XXX}", encoded as UTF-8; Lua accepts values up to 2^31
+   fn read_utf8_escape(&mut self, quote:u8, s: &mut Vec<u8>)->Result<(), LuaError>{
+    let mut seq = vec![b'u'];
+    let ch = self.read_char()?;
+    if ch != b'{' {
+        seq.push(ch);
+        return Err(self.escape_error("missing '{' in \\u{xxxx}", quote, s, &seq));
+    }
+    self.next_byte()?;
+    seq.push(ch);
+
+    let mut v: u32 = 0;
+    let mut digits = 0;
+    loop {
+        let ch = self.read_char()?;
+        seq.push(ch);
+        match (ch as char).to_digit(16) {
+            Some(d) => {
+                if v >= 0x8000000 {
+                    return Err(self.escape_error("UTF-8 value too large", quote, s, &seq));
+                }
+                v = v * 16 + d;
+                digits += 1;
+            }
+            None if digits == 0 =>
+                return Err(self.escape_error("hexadecimal digit expected", quote, s, &seq)),
+            None if ch != b'}' =>
+                return Err(self.escape_error("missing '}' in \\u{xxxx}", quote, s, &seq)),
+            None => {
+                self.next_byte()?;
+                break;
+            }
+        }
+        self.next_byte()?;
+    }
+    utf8_encode(v, s);
+    Ok(())
+   }
+
+   fn string_error(&self, msg: &str, quote: u8, s: &[u8]) -> LuaError {
+      self.error(&format!("{msg} near '{}{}'", quote as char, String::from_utf8_lossy(s)))
+   }
+
+   // an error in an escape sequence, quoting the string up to the bad escape
+   fn escape_error(&self, msg: &str, quote: u8, s: &[u8], seq: &[u8]) -> LuaError {
+      let mut text = s.to_vec();
+      text.push(b'\\');
+      text.extend(seq.iter().filter(|&&b| b != b'\0'));
+      self.string_error(msg, quote, &text)
    }
 
-   fn read_string(&mut self,quote:u8)->Token{
+   // After a '[', count the '='s up to a second '[' and return their
+   // number. None if this is no long bracket, which is an error only if
+   // some '=' had to be consumed already.
+   fn long_bracket(&mut self)->Result<Option<usize>, LuaError>{
+    let mut level = 0;
+    while self.read_char()? == b'=' {
+        self.next_byte()?;
+        level += 1;
+    }
+    if self.read_char()? == b'[' {
+        self.next_byte()?;
+        Ok(Some(level))
+    } else if level == 0 {
+        Ok(None)
+    } else {
+        Err(self.error(&format!("invalid long string delimiter near '[{}'", "=".repeat(level))))
+    }
+   }
 
+   // the content of a long string or comment, after its opening bracket
+   fn read_long_string(&mut self, level: usize, what: &str)->Result<Vec<u8>, LuaError>{
     let mut s = Vec::new();
+    let mut first = true;
     loop {
-        match self.next_byte().expect("infinished string") {
-            b'\n' | b'\0' => panic!("unfinished string") ,
-            b'\\'=>todo!("escape"),
-            ch if ch == quote => break,
-            ch => s.push(ch),
-        }    
-    } 
-    Token::Strng(s)
+        let Some(ch) = self.next_byte()? else {
+            return Err(self.error_near(&format!("unfinished long {what}"), &Token::Eos));
+        };
+        match ch {
+            b'\n' | b'\r' => {
+                self.line += 1;
+                let next = self.read_char()?;
+                if (next == b'\n' || next == b'\r') && next != ch {
+                    self.next_byte()?;
+                }
+                // a line break right after the opening bracket is skipped
+                if !first {
+                    s.push(b'\n');
+                }
+            }
+            b']' => {
+                // the closing bracket, or else content; a ']' ending a
+                // wrong number of '='s may start the closing bracket
+                let mut l = 0;
+                while self.read_char()? == b'=' {
+                    self.next_byte()?;
+                    l += 1;
+                }
+                if l == level && self.read_char()? == b']' {
+                    self.next_byte()?;
+                    break;
+                }
+                s.push(b']');
+                s.extend(std::iter::repeat_n(b'=', l));
+            }
+            _ => s.push(ch),
+        }
+        first = false;
+    }
+    Ok(s)
    }
 
-   fn check_ahead(&mut self,ch:u8,short:Token,long:Token)->Token {
+   fn check_ahead(&mut self,ch:u8,short:Token,long:Token)->Result<Token, LuaError> {
 
-           if self.read_char() == ch{
+           if self.read_char()? == ch{
                 // short 
-                self.next_byte();
-                short
+                self.next_byte()?;
+                Ok(short)
            } 
            else {
                 // self.pullback_char();
-                long 
+                Ok(long)
            }
    }
 
-   fn check_ahead2(&mut self,ch:u8,short1:Token,ch1:u8,short2:Token,long:Token)->Token{
+   fn check_ahead2(&mut self,ch:u8,short1:Token,ch1:u8,short2:Token,long:Token)->Result<Token, LuaError>{
 
-            let t = self.read_char();
+            let t = self.read_char()?;
             if t == ch{
-                self.next_byte();
-                short1
+                self.next_byte()?;
+                Ok(short1)
             }
             else if t == ch1{
-                self.next_byte();
-                short2 
+                self.next_byte()?;
+                Ok(short2)
             }
             else {
                 // self.pullback_char();
-                long
+                Ok(long)
             }
    }
 
-   fn read_comment(&mut self){
-    match self.read_char() {
-       b'['=>todo!("long comment"),
-       _=>{
-            loop {
-                let ch = self.read_char();
-                if ch == b'\n' || ch == b'\0'{
-                    break;
+   fn read_comment(&mut self)->Result<(), LuaError>{
+    if self.read_char()? == b'[' {
+        self.next_byte()?;
+        let mut level = 0;
+        while self.read_char()? == b'=' {
+            self.next_byte()?;
+            level += 1;
+        }
+        if self.read_char()? == b'[' {
+            self.next_byte()?;
+            self.read_long_string(level, "comment")?;
+            return Ok(());
+        }
+    }
+    // the newline is left for do_next() to count
+    loop {
+        let ch = self.read_char()?;
+        if ch == b'\n' || ch == b'\0'{
+            break Ok(());
+        }
+        self.next_byte()?;
+    }
+   }
+}
+
+
+fn is_space(c: u8) -> bool {
+    matches!(c, b' ' | b'\t' | b'\n' | b'\r' | b'\x0b' | b'\x0c')
+}
+
+// The number written in `s`, with optional surrounding whitespace and
+// a '-' sign, by the rules of Lua numerals: an integer if it has neither
+// a point nor an exponent and fits (hex integers wrap around instead),
+// else a float. Used for literals and for coercing strings.
+pub fn str2number(s: &[u8]) -> Option<Value> {
+    let start = s.iter().position(|&c| !is_space(c))?;
+    let end = s.iter().rposition(|&c| !is_space(c))? + 1;
+    let s = &s[start..end];
+    str2int(s).map(Value::Integer)
+        .or_else(|| str2float(s).map(Value::Float))
+}
+
+fn str2int(s: &[u8]) -> Option<i64> {
+    let (neg, s) = match s.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        Some((b'+', rest)) => (false, rest),
+        _ => (false, s),
+    };
+    let mut n: u64 = 0;
+    match s {
+        [b'0', b'x' | b'X', digits @ ..] => {
+            if digits.is_empty() {
+                return None;
+            }
+            for &c in digits {
+                n = n.wrapping_mul(16).wrapping_add((c as char).to_digit(16)? as u64);
+            }
+        }
+        _ => {
+            if s.is_empty() {
+                return None;
+            }
+            // the largest magnitude that fits, 2^63 for a negative number
+            let max = i64::MAX as u64 + neg as u64;
+            for &c in s {
+                let d = (c as char).to_digit(10)? as u64;
+                n = n.checked_mul(10)?.checked_add(d).filter(|&n| n <= max)?;
+            }
+        }
+    }
+    Some(if neg { (n as i64).wrapping_neg() } else { n as i64 })
+}
+
+fn str2float(s: &[u8]) -> Option<f64> {
+    // "inf" and "nan" are not numerals
+    if s.iter().any(|&c| c == b'n' || c == b'N') {
+        return None;
+    }
+    let (neg, body) = match s.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        Some((b'+', rest)) => (false, rest),
+        _ => (false, s),
+    };
+    let f = match body {
+        [b'0', b'x' | b'X', hex @ ..] => hex2float(hex)?,
+        _ => {
+            // Rust's syntax is a superset of the decimal numerals here
+            if !s.iter().all(|c| b"0123456789.eE+-".contains(c)) {
+                return None;
+            }
+            return std::str::from_utf8(s).ok()?.parse().ok();
+        }
+    };
+    Some(if neg { -f } else { f })
+}
+
+// the float of hex digits with an optional point and binary exponent
+// ('p'), as C's strtod reads them
+fn hex2float(s: &[u8]) -> Option<f64> {
+    // digits beyond these cannot change the result
+    const MAX_SIG_DIGITS: usize = 30;
+    let mut r = 0.0;
+    let mut exp: i64 = 0;
+    let mut sig_digits = 0;
+    let mut any_digit = false;
+    let mut seen_point = false;
+    let mut i = 0;
+    while i < s.len() {
+        let c = s[i];
+        if c == b'.' {
+            if seen_point {
+                return None;
+            }
+            seen_point = true;
+        } else if let Some(d) = (c as char).to_digit(16) {
+            any_digit = true;
+            if sig_digits == 0 && d == 0 {
+                // leading zeros do not count
+                if seen_point {
+                    exp -= 4;
                 }
+            } else if sig_digits < MAX_SIG_DIGITS {
+                sig_digits += 1;
+                r = r * 16.0 + d as f64;
+                if seen_point {
+                    exp -= 4;
+                }
+            } else if !seen_point {
+                // too many digits: ignore them, but keep their weight
+                exp += 4;
             }
-       }
+        } else {
+            break;
+        }
+        i += 1;
     }
-   }
+    if !any_digit {
+        return None;
+    }
+    if i < s.len() {
+        // the exponent
+        if !matches!(s[i], b'p' | b'P') {
+            return None;
+        }
+        let (neg, digits) = match &s[i + 1..] {
+            [b'-', rest @ ..] => (true, rest),
+            [b'+', rest @ ..] => (false, rest),
+            rest => (false, rest),
+        };
+        if digits.is_empty() {
+            return None;
+        }
+        let mut e: i64 = 0;
+        for &c in digits {
+            e = e.saturating_mul(10).saturating_add((c as char).to_digit(10)? as i64);
+        }
+        exp += if neg { -e } else { e };
+    }
+    Some(ldexp(r, exp))
+}
 
-   fn read_number_exp(&mut self,_:f64)->Token{
-    self.next_byte();
-    todo!("lex number exp")
-   }
+// r * 2^exp, scaling in steps so the power of 2 cannot overflow on its own
+fn ldexp(mut r: f64, exp: i64) -> f64 {
+    if r == 0.0 {
+        return r;
+    }
+    // far beyond the range of floats for any r here
+    let mut exp = exp.clamp(-10_000, 10_000);
+    while exp > 1000 && r.is_finite() {
+        r *= 2f64.powi(1000);
+        exp -= 1000;
+    }
+    while exp < -1000 {
+        r *= 2f64.powi(-1000);
+        exp += 1000;
+    }
+    r * 2f64.powi(exp as i32)
+}
 
-   fn read_heximal(&mut self)->Token{
-    self.next_byte();
-    todo!("lex number heximal")
-   }
-}
\ No newline at end of file
+// UTF-8 encoding of `v`, with the 5 and 6 byte forms Lua allows beyond
+// the Unicode range
+pub fn utf8_encode(v: u32, s: &mut Vec<u8>) {
+    if v < 0x80 {
+        s.push(v as u8);
+        return;
+    }
+    let mut buf = [0u8; 6];
+    let mut n = 0;
+    let mut v = v;
+    let mut limit = 0x3f; // largest value fitting in the first byte
+    while v > limit {
+        buf[5 - n] = 0x80 | (v & 0x3f) as u8;
+        v >>= 6;
+        limit >>= 1;
+        n += 1;
+    }
+    buf[5 - n] = ((!limit << 1) | v) as u8;
+    s.extend_from_slice(&buf[5 - n..]);
+}
@@ -0,0 +1,33 @@
+// The interpreter as a library, for Rust programs to embed: see Lua.
+
+mod value;
+mod byte_code;
+mod error;
+mod lex;
+mod parse;
+mod verify;
+mod vm;
+mod executor;
+mod pattern;
+mod convert;
+mod lua;
+mod userdata;
+mod lib_base;
+mod lib_package;
+mod lib_string;
+mod lib_table;
+mod lib_math;
+mod lib_coroutine;
+mod lib_utf8;
+mod lib_io;
+mod lib_os;
+
+pub use crate::{
+    lua::{Lua, Chunk},
+    vm::{ExeState, Coroutine, CoStatus},
+    value::{Value, Table, AnyUserData},
+    userdata::{UserData, UserDataMethods, UserDataFields},
+    error::LuaError,
+    convert::{FromLua, IntoLua, FromLuaMulti, IntoLuaMulti, Variadic, LuaString},
+    executor::block_on,
+};
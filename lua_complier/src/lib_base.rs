@@ -0,0 +1,560 @@
+use std::io::{self, Write};
+
+use crate::{value::Value, vm::{ExeState, to_number}, error::LuaError};
+
+
+// the basic functions, which are globals rather than fields of a table
+pub fn open(state: &mut ExeState) {
+   state.set_global("assert", Value::Function(lib_assert));
+   state.set_global("collectgarbage", Value::Function(lib_collectgarbage));
+   state.set_global("dofile", Value::Function(lib_dofile));
+   state.set_global("error", Value::Function(lib_error));
+   state.set_global("getmetatable", Value::Function(lib_getmetatable));
+   state.set_global("ipairs", Value::Function(lib_ipairs));
+   state.set_global("load", Value::Function(lib_load));
+   state.set_global("loadfile", Value::Function(lib_loadfile));
+   state.set_global("next", Value::Function(lib_next));
+   state.set_global("pairs", Value::Function(lib_pairs));
+   state.set_global("pcall", Value::Function(lib_pcall));
+   state.set_global("print", Value::Function(lib_print));
+   state.set_global("warn", Value::Function(lib_warn));
+   state.set_global("rawequal", Value::Function(lib_rawequal));
+   state.set_global("rawget", Value::Function(lib_rawget));
+   state.set_global("rawlen", Value::Function(lib_rawlen));
+   state.set_global("rawset", Value::Function(lib_rawset));
+   state.set_global("select", Value::Function(lib_select));
+   state.set_global("setmetatable", Value::Function(lib_setmetatable));
+   state.set_global("tonumber", Value::Function(lib_tonumber));
+   state.set_global("tostring", Value::Function(lib_tostring));
+   state.set_global("type", Value::Function(lib_type));
+   state.set_global("xpcall", Value::Function(lib_xpcall));
+   state.set_global("_G", state.globals());
+   state.set_global("_VERSION", Value::from("Lua 5.4"));
+}
+
+fn lib_print(state: &mut ExeState) -> Result<i32, LuaError> {
+   let mut line = Vec::new();
+   for i in 1..=state.get_top() {
+      if i > 1 {
+         line.push(b'\t');
+      }
+      let v = state.get(i).clone();
+      line.extend_from_slice(state.tostring(&v)?.as_bytes());
+   }
+   line.push(b'\n');
+   // a closed stdout (as in `lua script | head`) is not an error
+   let _ = io::stdout().write_all(&line);
+   Ok(0)
+}
+
+// warn(msg1, ...): "@on" and "@off" switch warnings, other messages are
+// written to stderr while they are on
+fn lib_warn(state: &mut ExeState) -> Result<i32, LuaError> {
+   state.check_string(1)?;
+   let mut msg = Vec::new();
+   for i in 1..=state.get_top() {
+      msg.extend_from_slice(state.check_string(i)?.as_bytes());
+   }
+   match &msg[..] {
+      b"@on" if state.get_top() == 1 => state.set_warnings(true),
+      b"@off" if state.get_top() == 1 => state.set_warnings(false),
+      [b'@', ..] => (), // unknown control message
+      _ if state.warnings() => {
+         let mut stderr = io::stderr();
+         let _ = stderr.write_all(b"Lua warning: ");
+         let _ = stderr.write_all(&msg);
+         let _ = stderr.write_all(b"\n");
+      }
+      _ => (),
+   }
+   Ok(0)
+}
+
+fn lib_type(state: &mut ExeState) -> Result<i32, LuaError> {
+   state.check_any(1)?;
+   let name = state.get(1).type_name();
+   state.push(Value::from(name));
+   Ok(1)
+}
+
+fn lib_tostring(state: &mut ExeState) -> Result<i32, LuaError> {
+   state.check_any(1)?;
+   let v = state.get(1).clone();
+   let s = state.tostring(&v)?;
+   state.push(s);
+   Ok(1)
+}
+
+// tonumber(v): v converted as arithmetic does, or nil;
+// tonumber(s, base): the integer written in s with digits of that base
+fn lib_tonumber(state: &mut ExeState) -> Result<i32, LuaError> {
+   let v = if state.get(2) == &Value::Nil {
+      state.check_any(1)?;
+      to_number(state.get(1)).unwrap_or(Value::Nil)
+   } else {
+      let base = state.check_integer(2)?;
+      if !state.get(1).is_string() {
+         return Err(state.type_error(1, "string"));
+      }
+      if !(2..=36).contains(&base) {
+         return Err(state.arg_error(2, "base out of range"));
+      }
+      str_to_int(state.get(1).as_bytes(), base as u32).map_or(Value::Nil, Value::Integer)
+   };
+   state.push(v);
+   Ok(1)
+}
+
+// an integer in the given base, wrapping around on overflow as the
+// reference implementation does
+fn str_to_int(s: &[u8], base: u32) -> Option<i64> {
+   let is_space = |c: &u8| b" \t\n\r\x0b\x0c".contains(c);
+   let start = s.iter().position(|c| !is_space(c))?;
+   let end = s.iter().rposition(|c| !is_space(c))? + 1;
+   let mut digits = &s[start..end];
+   let neg = digits.first() == Some(&b'-');
+   if neg || digits.first() == Some(&b'+') {
+      digits = &digits[1..];
+   }
+   if digits.is_empty() {
+      return None;
+   }
+   let mut n: u64 = 0;
+   for &c in digits {
+      let digit = (c as char).to_digit(base)?;
+      n = n.wrapping_mul(base as u64).wrapping_add(digit as u64);
+   }
+   let n = n as i64;
+   Some(if neg { n.wrapping_neg() } else { n })
+}
+
+// select(n, ...): the arguments after the n-th one, counting from the
+// end if n is negative; select('#', ...): how many arguments there are
+fn lib_select(state: &mut ExeState) -> Result<i32, LuaError> {
+   let top = state.get_top() as i64;
+   if state.get(1).is_string() && state.get(1).as_bytes().first() == Some(&b'#') {
+      state.push(Value::Integer(top - 1));
+      return Ok(1);
+   }
+   let n = match state.check_integer(1)? {
+      n if n < 0 => top + n,
+      n => n.min(top),
+   };
+   if n < 1 {
+      return Err(state.arg_error(1, "index out of range"));
+   }
+   for i in n + 1..=top {
+      let v = state.get(i as usize).clone();
+      state.push(v);
+   }
+   Ok((top - n) as i32)
+}
+
+fn check_table(state: &ExeState, i: usize) -> Result<(), LuaError> {
+   match state.get(i) {
+      Value::Table(_) => Ok(()),
+      _ => Err(state.type_error(i, "table")),
+   }
+}
+
+fn lib_rawequal(state: &mut ExeState) -> Result<i32, LuaError> {
+   state.check_any(1)?;
+   state.check_any(2)?;
+   let eq = state.get(1) == state.get(2);
+   state.push(Value::Boolean(eq));
+   Ok(1)
+}
+
+fn lib_rawlen(state: &mut ExeState) -> Result<i32, LuaError> {
+   let len = match state.get(1) {
+      Value::Table(t) => t.borrow().len(),
+      v if v.is_string() => v.as_bytes().len() as i64,
+      _ => return Err(state.arg_error(1, "table or string expected")),
+   };
+   state.push(Value::Integer(len));
+   Ok(1)
+}
+
+fn lib_rawget(state: &mut ExeState) -> Result<i32, LuaError> {
+   check_table(state, 1)?;
+   state.check_any(2)?;
+   let Value::Table(t) = state.get(1) else { unreachable!() };
+   let v = t.borrow().get(state.get(2));
+   state.push(v);
+   Ok(1)
+}
+
+fn lib_rawset(state: &mut ExeState) -> Result<i32, LuaError> {
+   check_table(state, 1)?;
+   state.check_any(2)?;
+   state.check_any(3)?;
+   let Value::Table(t) = state.get(1).clone() else { unreachable!() };
+   t.borrow_mut().set(state.get(2).clone(), state.get(3).clone())
+      .map_err(|msg| LuaError::Runtime(Value::from(msg)))?;
+   state.push(Value::Table(t));
+   Ok(1)
+}
+
+// getmetatable(v): the __metatable field of the metatable of v if it
+// has one, else the metatable itself
+fn lib_getmetatable(state: &mut ExeState) -> Result<i32, LuaError> {
+   state.check_any(1)?;
+   let v = match state.metatable(state.get(1)) {
+      Some(meta) => match meta.borrow().get_str("__metatable") {
+         Value::Nil => Value::Table(meta.clone()),
+         protected => protected,
+      },
+      None => Value::Nil,
+   };
+   state.push(v);
+   Ok(1)
+}
+
+fn lib_setmetatable(state: &mut ExeState) -> Result<i32, LuaError> {
+   let Value::Table(t) = state.get(1).clone() else {
+      return Err(state.type_error(1, "table"));
+   };
+   let meta = match state.get(2) {
+      Value::Nil if state.get_top() >= 2 => None,
+      Value::Table(m) => Some(m.clone()),
+      _ => return Err(state.type_error(2, "nil or table")),
+   };
+   let protected = t.borrow().metatable.as_ref()
+      .is_some_and(|m| m.borrow().get_str("__metatable") != Value::Nil);
+   if protected {
+      return Err(state.error("cannot change a protected metatable"));
+   }
+   t.borrow_mut().metatable = meta;
+   state.push(Value::Table(t));
+   Ok(1)
+}
+
+// next(t [, k]): the entry of t after key k, or nil at the end
+fn lib_next(state: &mut ExeState) -> Result<i32, LuaError> {
+   check_table(state, 1)?;
+   let Value::Table(t) = state.get(1) else { unreachable!() };
+   let entry = t.borrow().next(state.get(2))
+      .map_err(|msg| LuaError::Runtime(Value::from(msg)))?;
+   match entry {
+      Some((k, v)) => {
+         state.push(k);
+         state.push(v);
+         Ok(2)
+      }
+      None => {
+         state.push(Value::Nil);
+         Ok(1)
+      }
+   }
+}
+
+// pairs(t): the __pairs metamethod's first three results, else next, t, nil
+fn lib_pairs(state: &mut ExeState) -> Result<i32, LuaError> {
+   state.check_any(1)?;
+   let t = state.get(1).clone();
+   let handler = state.metamethod(&t, "__pairs");
+   let results = if handler == Value::Nil {
+      vec![Value::Function(lib_next), t, Value::Nil]
+   } else {
+      state.call(&handler, &[t])?
+   };
+   let mut results = results.into_iter();
+   for _ in 0..3 {
+      state.push(results.next().unwrap_or(Value::Nil));
+   }
+   Ok(3)
+}
+
+// ipairs(t): an iterator over t[1], t[2], ... up to the first nil
+fn lib_ipairs(state: &mut ExeState) -> Result<i32, LuaError> {
+   state.check_any(1)?;
+   let t = state.get(1).clone();
+   state.push(Value::Function(ipairs_aux));
+   state.push(t);
+   state.push(Value::Integer(0));
+   Ok(3)
+}
+
+fn ipairs_aux(state: &mut ExeState) -> Result<i32, LuaError> {
+   let i = state.check_integer(2)?.wrapping_add(1);
+   let t = state.get(1).clone();
+   match state.index(&t, &Value::Integer(i))? {
+      Value::Nil => {
+         state.push(Value::Nil);
+         Ok(1)
+      }
+      v => {
+         state.push(Value::Integer(i));
+         state.push(v);
+         Ok(2)
+      }
+   }
+}
+
+// error(v [, level]): raise v; a string gets the position of the
+// function `level` calls up prepended, 1 being the caller of error
+fn lib_error(state: &mut ExeState) -> Result<i32, LuaError> {
+   let level = state.opt_integer(2, 1)?;
+   Err(raise(state, state.get(1).clone(), level))
+}
+
+fn raise(state: &ExeState, v: Value, level: i64) -> LuaError {
+   if v.is_string() && level > 0 {
+      let mut msg = state.location(level as usize).into_bytes();
+      msg.extend_from_slice(v.as_bytes());
+      return LuaError::Runtime(Value::from(msg));
+   }
+   LuaError::Runtime(v)
+}
+
+// assert(v [, msg, ...]): all the arguments if v is true, else an error
+// with msg, "assertion failed!" by default
+fn lib_assert(state: &mut ExeState) -> Result<i32, LuaError> {
+   if !state.get(1).is_false() {
+      let n = state.get_top();
+      for i in 1..=n {
+         let v = state.get(i).clone();
+         state.push(v);
+      }
+      return Ok(n as i32);
+   }
+   state.check_any(1)?;
+   let msg = match state.get(2) {
+      Value::Nil => Value::from("assertion failed!"),
+      msg => msg.clone(),
+   };
+   Err(raise(state, msg, 1))
+}
+
+// push the status of a protected call and its results or error value
+fn finish_pcall(state: &mut ExeState, result: Result<Vec<Value>, Value>) -> i32 {
+   match result {
+      Ok(results) => {
+         let n = results.len() as i32 + 1;
+         state.push(Value::Boolean(true));
+         for v in results {
+            state.push(v);
+         }
+         n
+      }
+      Err(v) => {
+         state.push(Value::Boolean(false));
+         state.push(v);
+         2
+      }
+   }
+}
+
+// pcall(f, ...): call f in protected mode; true and its results, or
+// false and the error value
+fn lib_pcall(state: &mut ExeState) -> Result<i32, LuaError> {
+   state.check_any(1)?;
+   let func = state.get(1).clone();
+   let args: Vec<Value> = (2..=state.get_top()).map(|i| state.get(i).clone()).collect();
+   state.pcall_k(func, &args, |state, result| Ok(finish_pcall(state, result.map_err(LuaError::into_value))))
+}
+
+// xpcall(f, msgh, ...): as pcall, but the error value is what msgh
+// returns for it. The handler runs once the stack is unwound, so it
+// cannot inspect the frames that failed.
+fn lib_xpcall(state: &mut ExeState) -> Result<i32, LuaError> {
+   let handler = state.get(2).clone();
+   if !handler.is_function() {
+      return Err(state.type_error(2, "function"));
+   }
+   let func = state.get(1).clone();
+   let args: Vec<Value> = (3..=state.get_top()).map(|i| state.get(i).clone()).collect();
+   state.pcall_k(func, &args, move |state, result| {
+      let result = match result {
+         Ok(results) => Ok(results),
+         Err(err) => Err(match state.call(&handler, &[err.into_value()]) {
+            Ok(results) => results.into_iter().next().unwrap_or(Value::Nil),
+            Err(err) if !err.is_catchable() => return Err(err),
+            Err(_) => Value::from("error in error handling"),
+         }),
+      };
+      Ok(finish_pcall(state, result))
+   })
+}
+
+// push the function of a load, or nil and the error message
+fn finish_load(state: &mut ExeState, result: Result<Value, LuaError>) -> i32 {
+   match result {
+      Ok(func) => {
+         state.push(func);
+         1
+      }
+      Err(err) => {
+         state.push(Value::Nil);
+         state.push(err.into_value());
+         2
+      }
+   }
+}
+
+// the `_ENV` given to a load function in argument i, if any (nil counts)
+fn env_arg(state: &ExeState, i: usize) -> Option<Value> {
+   (state.get_top() >= i).then(|| state.get(i).clone())
+}
+
+// load(chunk [, chunkname [, mode [, env]]]): compile a string, or the
+// pieces a function returns until it gives nil or "", into a function
+fn lib_load(state: &mut ExeState) -> Result<i32, LuaError> {
+   let chunk = state.get(1).clone();
+   let (source, default_name) = match chunk {
+      Value::Integer(_) | Value::Float(_) => {
+         let s = state.check_string(1)?;
+         (s.as_bytes().to_vec(), s)
+      }
+      ref s if s.is_string() => (s.as_bytes().to_vec(), s.clone()),
+      Value::Function(_) | Value::LuaFunction(_) | Value::NativeClosure(_) => {
+         let mut source = Vec::new();
+         loop {
+            let piece = match state.call(&chunk, &[]) {
+               Ok(results) => results.into_iter().next().unwrap_or(Value::Nil),
+               Err(err) if !err.is_catchable() => return Err(err),
+               Err(err) => return Ok(finish_load(state, Err(err))),
+            };
+            match piece {
+               Value::Nil => break,
+               s if s.is_string() => {
+                  if s.as_bytes().is_empty() {
+                     break;
+                  }
+                  source.extend_from_slice(s.as_bytes());
+               }
+               n @ (Value::Integer(_) | Value::Float(_)) => source.extend_from_slice(format!("{n:?}").as_bytes()),
+               _ => return Ok(finish_load(state, Err(state.error("reader function must return a string")))),
+            }
+         }
+         (source, Value::from("=(load)"))
+      }
+      _ => return Err(state.type_error(1, "function")),
+   };
+   let name = match state.get(2) {
+      Value::Nil => default_name,
+      _ => state.check_string(2)?,
+   };
+   let mode = match state.get(3) {
+      Value::Nil => Value::from("bt"),
+      _ => state.check_string(3)?,
+   };
+   let env = env_arg(state, 4);
+   let name = name.to_string_lossy();
+   let mode = mode.to_string_lossy();
+   let result = state.load(&source, &name, &mode, env);
+   Ok(finish_load(state, result))
+}
+
+// a file name argument, None for the standard input
+fn opt_path(state: &ExeState, i: usize) -> Result<Option<String>, LuaError> {
+   match state.get(i) {
+      Value::Nil => Ok(None),
+      _ => {
+         let path = state.check_string(i)?;
+         Ok(Some(path.to_string_lossy()))
+      }
+   }
+}
+
+// loadfile([filename [, mode [, env]]]): as load, for the contents of
+// a file or of the standard input
+fn lib_loadfile(state: &mut ExeState) -> Result<i32, LuaError> {
+   let path = opt_path(state, 1)?;
+   let mode = match state.get(2) {
+      Value::Nil => Value::from("bt"),
+      _ => state.check_string(2)?,
+   };
+   let env = env_arg(state, 3);
+   let mode = mode.to_string_lossy();
+   let result = state.load_file(path.as_deref(), &mode, env);
+   Ok(finish_load(state, result))
+}
+
+// dofile([filename]): run a file, or the standard input, and return
+// its results; errors are raised, not returned
+fn lib_dofile(state: &mut ExeState) -> Result<i32, LuaError> {
+   let path = opt_path(state, 1)?;
+   let func = state.load_file(path.as_deref(), "bt", None)?;
+   // the chunk can yield, as if it ran in the caller
+   state.call_k(func, &[], |state, results| {
+      let n = results.len() as i32;
+      for v in results {
+         state.push(v);
+      }
+      Ok(n)
+   })
+}
+
+
+// What collectgarbage() reports and tunes. Values are reference counted
+// and freed as soon as they become unreachable, so there is no collector
+// to drive; the settings are only remembered. A collection only runs the
+// __gc metamethods of the userdata dropped so far.
+#[derive(Debug)]
+pub struct Collector {
+   running: bool,
+   generational: bool,
+   pause: i64,
+   step_mul: i64,
+}
+
+impl Collector {
+   pub fn new() -> Self {
+      Collector { running: true, generational: false, pause: 200, step_mul: 100 }
+   }
+
+   fn mode(&self) -> &'static str {
+      if self.generational { "generational" } else { "incremental" }
+   }
+}
+
+// collectgarbage([opt [, arg]])
+//
+// A stub, since there is no collector (see Collector): "count" always
+// reports 0 because memory in use is not tracked, and "collect" and "step"
+// only run the pending __gc metamethods. Reference cycles are never freed.
+fn lib_collectgarbage(state: &mut ExeState) -> Result<i32, LuaError> {
+   let opt = match state.get(1) {
+      Value::Nil => Value::from("collect"),
+      _ => state.check_string(1)?,
+   };
+   let arg = state.opt_integer(2, 0)?;
+   if let b"collect" | b"step" = opt.as_bytes() {
+      state.run_finalizers();
+   }
+   let gc = state.collector();
+   let v = match opt.as_bytes() {
+      b"collect" => Value::Integer(0),
+      b"stop" => {
+         gc.running = false;
+         Value::Integer(0)
+      }
+      b"restart" => {
+         gc.running = true;
+         Value::Integer(0)
+      }
+      b"count" => Value::Float(0.0),
+      // a step always finishes a cycle
+      b"step" => Value::Boolean(true),
+      b"isrunning" => Value::Boolean(gc.running),
+      b"setpause" => Value::Integer(std::mem::replace(&mut gc.pause, arg)),
+      b"setstepmul" => Value::Integer(std::mem::replace(&mut gc.step_mul, arg)),
+      b"incremental" => {
+         let old = gc.mode();
+         gc.generational = false;
+         Value::from(old)
+      }
+      b"generational" => {
+         let old = gc.mode();
+         gc.generational = true;
+         Value::from(old)
+      }
+      name => {
+         let msg = format!("invalid option '{}'", String::from_utf8_lossy(name));
+         return Err(state.arg_error(1, &msg));
+      }
+   };
+   state.push(v);
+   Ok(1)
+}
@@ -0,0 +1,207 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{value::{Value, Table}, vm::{ExeState, Coroutine, CoStatus}, error::LuaError};
+
+
+pub fn open(state: &mut ExeState) {
+   let mut lib = Table::new(0, 8);
+   lib.set_str("close", Value::Function(co_close));
+   lib.set_str("create", Value::Function(co_create));
+   lib.set_str("isyieldable", Value::Function(co_isyieldable));
+   lib.set_str("resume", Value::Function(co_resume));
+   lib.set_str("running", Value::Function(co_running));
+   lib.set_str("status", Value::Function(co_status));
+   lib.set_str("wrap", Value::Function(co_wrap));
+   lib.set_str("yield", Value::Function(co_yield));
+   state.set_global("coroutine", Value::Table(Rc::new(RefCell::new(lib))));
+}
+
+fn check_co(state: &ExeState, i: usize) -> Result<Rc<RefCell<Coroutine>>, LuaError> {
+   match state.get(i) {
+      Value::Thread(co) => Ok(co.clone()),
+      _ => Err(state.type_error(i, "coroutine")),
+   }
+}
+
+fn new_co(state: &ExeState) -> Result<Rc<RefCell<Coroutine>>, LuaError> {
+   let func = state.get(1).clone();
+   if !func.is_function() {
+      return Err(state.type_error(1, "function"));
+   }
+   Ok(Rc::new(RefCell::new(Coroutine::new(func))))
+}
+
+fn args_from(state: &ExeState, first: usize) -> Vec<Value> {
+   (first..=state.get_top()).map(|i| state.get(i).clone()).collect()
+}
+
+// create(f): a new coroutine running f
+fn co_create(state: &mut ExeState) -> Result<i32, LuaError> {
+   let co = new_co(state)?;
+   state.push(Value::Thread(co));
+   Ok(1)
+}
+
+// resume(co, ...): true and what co returns or yields, or false and the
+// error that stopped it
+fn co_resume(state: &mut ExeState) -> Result<i32, LuaError> {
+   let co = check_co(state, 1)?;
+   let args = args_from(state, 2);
+   match state.resume(&co, args) {
+      Ok(values) => {
+         let n = values.len() as i32 + 1;
+         state.push(Value::Boolean(true));
+         for v in values {
+            state.push(v);
+         }
+         Ok(n)
+      }
+      Err(err) if err.is_catchable() => {
+         state.push(Value::Boolean(false));
+         state.push(err.into_value());
+         Ok(2)
+      }
+      Err(err) => Err(err),
+   }
+}
+
+// yield(...): suspend the running coroutine; the arguments are the
+// results of the resume, and the arguments of the next resume are the
+// results of the yield
+fn co_yield(state: &mut ExeState) -> Result<i32, LuaError> {
+   let values = args_from(state, 1);
+   state.yield_values(values)
+}
+
+fn status_name(state: &ExeState, co: &Rc<RefCell<Coroutine>>) -> &'static str {
+   if Rc::ptr_eq(co, &state.running()) {
+      return "running";
+   }
+   match co.borrow().status() {
+      CoStatus::Suspended => "suspended",
+      CoStatus::Running => "running",
+      CoStatus::Normal => "normal",
+      CoStatus::Dead => "dead",
+   }
+}
+
+// status(co): "running", "suspended", "normal" or "dead"
+fn co_status(state: &mut ExeState) -> Result<i32, LuaError> {
+   let co = check_co(state, 1)?;
+   let name = status_name(state, &co);
+   state.push(Value::from(name));
+   Ok(1)
+}
+
+// running(): the running coroutine, and whether it is the main thread
+fn co_running(state: &mut ExeState) -> Result<i32, LuaError> {
+   let co = state.running();
+   let is_main = co.borrow().is_main();
+   state.push(Value::Thread(co));
+   state.push(Value::Boolean(is_main));
+   Ok(2)
+}
+
+// isyieldable([co]): whether co, by default the running coroutine, can
+// yield
+fn co_isyieldable(state: &mut ExeState) -> Result<i32, LuaError> {
+   let yieldable = match state.get(1) {
+      Value::Nil if state.get_top() == 0 => state.is_yieldable(),
+      _ => {
+         let co = check_co(state, 1)?;
+         if Rc::ptr_eq(&co, &state.running()) {
+            state.is_yieldable()
+         } else {
+            !co.borrow().is_main()
+         }
+      }
+   };
+   state.push(Value::Boolean(yieldable));
+   Ok(1)
+}
+
+// close(co): kill a suspended or dead coroutine; true, or false and the
+// error that killed it
+fn co_close(state: &mut ExeState) -> Result<i32, LuaError> {
+   let co = check_co(state, 1)?;
+   match status_name(state, &co) {
+      "suspended" | "dead" => (),
+      name => return Err(state.error(&format!("cannot close a {name} coroutine"))),
+   }
+   match state.close_thread(&co) {
+      Ok(()) => {
+         state.push(Value::Boolean(true));
+         Ok(1)
+      }
+      Err(err) => {
+         state.push(Value::Boolean(false));
+         state.push(err);
+         Ok(2)
+      }
+   }
+}
+
+// wrap(f): a function resuming a new coroutine running f, which returns
+// what it returns or yields, and raises the errors it raises
+fn co_wrap(state: &mut ExeState) -> Result<i32, LuaError> {
+   let co = new_co(state)?;
+   let wrapper = move |state: &mut ExeState| {
+      let args = args_from(state, 1);
+      match state.resume(&co, args) {
+         Ok(values) => {
+            let n = values.len() as i32;
+            for v in values {
+               state.push(v);
+            }
+            Ok(n)
+         }
+         // a message with the position of the call added, as an error of
+         // the wrapper
+         Err(err) if err.is_catchable() => {
+            let v = err.into_value();
+            if !v.is_string() {
+               return Err(LuaError::Runtime(v));
+            }
+            let mut msg = state.location(1).into_bytes();
+            msg.extend_from_slice(v.as_bytes());
+            Err(LuaError::Runtime(Value::from(msg)))
+         }
+         Err(err) => Err(err),
+      }
+   };
+   state.push(Value::NativeClosure(Rc::new(wrapper)));
+   Ok(1)
+}
+
+
+#[cfg(test)]
+mod tests {
+   use crate::{value::Value, vm::ExeState, error::LuaError};
+
+   fn run(source: &str) -> Result<Vec<Value>, LuaError> {
+      let mut state = ExeState::new();
+      let Value::Table(globals) = state.globals() else { unreachable!() };
+      globals.borrow_mut().set_str("syntax", Value::Function(|_| {
+         Err(LuaError::Syntax(String::from("chunk:1: unexpected symbol near '='")))
+      }));
+      let f = state.load(source.as_bytes(), "=test", "t", None)?;
+      state.call(&f, &[])
+   }
+
+   #[test]
+   fn syntax_error_inside_coroutine() {
+      let results = run("return coroutine.resume(coroutine.create(syntax))").unwrap();
+      assert_eq!(results, [Value::Boolean(false), Value::from("chunk:1: unexpected symbol near '='")]);
+      let results = run("return pcall(function() coroutine.wrap(syntax)() end)").unwrap();
+      assert_eq!(results, [Value::Boolean(false), Value::from("test:1: chunk:1: unexpected symbol near '='")]);
+   }
+
+   // os.exit is not an error: it goes through the resume to the host
+   #[test]
+   fn exit_inside_coroutine() {
+      let err = run("coroutine.resume(coroutine.create(function() os.exit(3) end))").unwrap_err();
+      assert!(matches!(err, LuaError::Exit(3)));
+      let err = run("pcall(coroutine.wrap(function() os.exit(false) end))").unwrap_err();
+      assert!(matches!(err, LuaError::Exit(1)));
+   }
+}
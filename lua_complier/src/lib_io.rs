@@ -0,0 +1,794 @@
+use std::{cell::RefCell, fs::{File, OpenOptions}, rc::{Rc, Weak},
+          io::{self, BufRead, Read, Seek, SeekFrom, Write}};
+
+use crate::{value::{Value, Table, AnyUserData}, vm::ExeState, error::{LuaError, io_error_text},
+            lex::str2number, lib_string::format_general};
+
+
+// size of the buffer of a file, as C's BUFSIZ
+const BUF_SIZE: usize = 8192;
+// formats io.lines and file:lines take
+const MAX_LINES_FORMATS: usize = 250;
+// longest numeral read by the "n" format
+const MAX_NUMERAL: usize = 200;
+
+// registry keys
+const FILE_META: &str = "FILE*";
+const INPUT: &str = "_IO_input";
+const OUTPUT: &str = "_IO_output";
+
+
+// the `io` table, and the metatable of files
+pub fn open(state: &mut ExeState) {
+   let mut methods = Table::new(0, 8);
+   methods.set_str("close", Value::Function(f_close));
+   methods.set_str("flush", Value::Function(f_flush));
+   methods.set_str("lines", Value::Function(f_lines));
+   methods.set_str("read", Value::Function(f_read));
+   methods.set_str("seek", Value::Function(f_seek));
+   methods.set_str("setvbuf", Value::Function(f_setvbuf));
+   methods.set_str("write", Value::Function(f_write));
+   let mut meta = Table::new(0, 4);
+   meta.set_str("__index", Value::Table(Rc::new(RefCell::new(methods))));
+   meta.set_str("__name", Value::from(FILE_META));
+   meta.set_str("__tostring", Value::Function(f_tostring));
+   state.registry().borrow_mut().set_str(FILE_META, Value::Table(Rc::new(RefCell::new(meta))));
+
+   let stdin = new_file(state, Stream::Stdin);
+   let stdout = new_file(state, Stream::Stdout);
+   let stderr = new_file(state, Stream::Stderr);
+   let mut lib = Table::new(0, 16);
+   lib.set_str("close", Value::Function(io_close));
+   lib.set_str("flush", Value::Function(io_flush));
+   lib.set_str("input", Value::Function(io_input));
+   lib.set_str("lines", Value::Function(io_lines));
+   lib.set_str("open", Value::Function(io_open));
+   lib.set_str("output", Value::Function(io_output));
+   lib.set_str("read", Value::Function(io_read));
+   lib.set_str("type", Value::Function(io_type));
+   lib.set_str("write", Value::Function(io_write));
+   lib.set_str("stdin", stdin.clone());
+   lib.set_str("stdout", stdout.clone());
+   lib.set_str("stderr", stderr);
+   let registry = state.registry();
+   registry.borrow_mut().set_str(INPUT, stdin);
+   registry.borrow_mut().set_str(OUTPUT, stdout);
+   state.set_global("io", Value::Table(Rc::new(RefCell::new(lib))));
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Buffering {
+   No,
+   Full,
+   Line,
+}
+
+// A file opened by io.open, with one buffer used either for reading or
+// for writing: switching between the two moves the file position back
+// to where the script sees it.
+struct FileStream {
+   file: File,
+   rbuf: Vec<u8>,
+   rpos: usize,
+   wbuf: Vec<u8>,
+   buffering: Buffering,
+   size: usize,
+}
+
+impl FileStream {
+   fn new(file: File) -> Self {
+      FileStream { file, rbuf: Vec::new(), rpos: 0, wbuf: Vec::new(), buffering: Buffering::Full, size: BUF_SIZE }
+   }
+
+   fn flush_writes(&mut self) -> io::Result<()> {
+      if !self.wbuf.is_empty() {
+         let result = self.file.write_all(&self.wbuf);
+         self.wbuf.clear();
+         result?;
+      }
+      Ok(())
+   }
+
+   // forget the data read ahead, moving back over it
+   fn drop_reads(&mut self) -> io::Result<()> {
+      let unread = self.rbuf.len() - self.rpos;
+      self.rbuf.clear();
+      self.rpos = 0;
+      if unread > 0 {
+         self.file.seek(SeekFrom::Current(-(unread as i64)))?;
+      }
+      Ok(())
+   }
+}
+
+impl Read for FileStream {
+   fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      let available = self.fill_buf()?;
+      let n = available.len().min(buf.len());
+      buf[..n].copy_from_slice(&available[..n]);
+      self.consume(n);
+      Ok(n)
+   }
+}
+
+impl BufRead for FileStream {
+   fn fill_buf(&mut self) -> io::Result<&[u8]> {
+      if self.rpos >= self.rbuf.len() {
+         self.flush_writes()?;
+         self.rbuf.resize(BUF_SIZE, 0);
+         let n = loop {
+            match self.file.read(&mut self.rbuf) {
+               Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+               result => break result,
+            }
+         };
+         self.rbuf.truncate(*n.as_ref().unwrap_or(&0));
+         self.rpos = 0;
+         n?;
+      }
+      Ok(&self.rbuf[self.rpos..])
+   }
+
+   fn consume(&mut self, n: usize) {
+      self.rpos += n;
+   }
+}
+
+impl Write for FileStream {
+   fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.drop_reads()?;
+      self.wbuf.extend_from_slice(buf);
+      let full = self.wbuf.len() >= self.size;
+      match self.buffering {
+         Buffering::No => self.flush_writes()?,
+         Buffering::Line if full || buf.contains(&b'\n') => self.flush_writes()?,
+         Buffering::Full | Buffering::Line if full => self.flush_writes()?,
+         _ => (),
+      }
+      Ok(buf.len())
+   }
+
+   fn flush(&mut self) -> io::Result<()> {
+      self.flush_writes()?;
+      self.file.flush()
+   }
+}
+
+impl Seek for FileStream {
+   fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+      self.flush_writes()?;
+      let unread = (self.rbuf.len() - self.rpos) as i64;
+      self.rbuf.clear();
+      self.rpos = 0;
+      let pos = match pos {
+         SeekFrom::Current(offset) => SeekFrom::Current(offset - unread),
+         pos => pos,
+      };
+      self.file.seek(pos)
+   }
+}
+
+impl Drop for FileStream {
+   fn drop(&mut self) {
+      let _ = self.flush_writes();
+   }
+}
+
+// The standard streams go through those of Rust, so that what print
+// writes and what io.write writes come out in order.
+enum Stream {
+   Stdin,
+   Stdout,
+   Stderr,
+   File(FileStream),
+}
+
+// the data of a file handle; no stream once it is closed
+pub struct LuaFile {
+   stream: Option<Stream>,
+}
+
+impl LuaFile {
+   fn reader<T>(&mut self, f: impl FnOnce(&mut dyn BufRead) -> io::Result<T>) -> io::Result<T> {
+      match self.stream.as_mut() {
+         Some(Stream::Stdin) => {
+            // show a prompt written without a newline
+            let _ = io::stdout().flush();
+            f(&mut io::stdin().lock())
+         }
+         Some(Stream::File(file)) => f(file),
+         _ => Err(io::Error::from_raw_os_error(9)), // EBADF
+      }
+   }
+
+   fn writer<T>(&mut self, f: impl FnOnce(&mut dyn Write) -> io::Result<T>) -> io::Result<T> {
+      match self.stream.as_mut() {
+         Some(Stream::Stdout) => f(&mut io::stdout()),
+         Some(Stream::Stderr) => f(&mut io::stderr()),
+         Some(Stream::File(file)) => f(file),
+         _ => Err(io::Error::from_raw_os_error(9)), // EBADF
+      }
+   }
+}
+
+// Files whose buffers ExeState flushes when it goes away, since a file
+// kept in a global variable would never be dropped.
+#[derive(Debug, Default)]
+pub struct OpenFiles(Vec<Weak<AnyUserData>>);
+
+impl OpenFiles {
+   fn add(&mut self, file: &Rc<AnyUserData>) {
+      self.0.retain(|f| f.strong_count() > 0);
+      self.0.push(Rc::downgrade(file));
+   }
+}
+
+// flush all files still open
+pub fn flush_all(state: &mut ExeState) {
+   for file in &state.open_files().0 {
+      if let Some(file) = file.upgrade() {
+         if let Ok(mut file) = file.borrow_mut::<LuaFile>() {
+            let _ = file.writer(|w| w.flush());
+         }
+      }
+   }
+}
+
+fn new_file(state: &mut ExeState, stream: Stream) -> Value {
+   let is_file = matches!(stream, Stream::File(_));
+   let meta = match state.registry().borrow().get_str(FILE_META) {
+      Value::Table(meta) => Some(meta),
+      _ => None,
+   };
+   let file = Rc::new(AnyUserData::new(LuaFile { stream: Some(stream) }, meta));
+   if is_file {
+      state.open_files().add(&file);
+   }
+   Value::UserData(file)
+}
+
+fn is_file(v: &Value) -> bool {
+   matches!(v, Value::UserData(u) if u.is::<LuaFile>())
+}
+
+// argument i, a file handle that is still open
+fn check_file(state: &ExeState, i: usize) -> Result<Rc<AnyUserData>, LuaError> {
+   match state.get(i) {
+      Value::UserData(u) if u.is::<LuaFile>() => {
+         if with_file(u, |f| f.stream.is_none()) {
+            return Err(state.error("attempt to use a closed file"));
+         }
+         Ok(u.clone())
+      }
+      _ => Err(state.type_error(i, FILE_META)),
+   }
+}
+
+fn with_file<T>(u: &Rc<AnyUserData>, f: impl FnOnce(&mut LuaFile) -> T) -> T {
+   f(&mut u.borrow_mut::<LuaFile>().unwrap())
+}
+
+// true, or nil, the message and the error number
+pub fn file_result(state: &mut ExeState, result: io::Result<()>, name: Option<&str>) -> i32 {
+   match result {
+      Ok(()) => {
+         state.push(Value::Boolean(true));
+         1
+      }
+      Err(err) => push_error(state, &err, name),
+   }
+}
+
+fn push_error(state: &mut ExeState, err: &io::Error, name: Option<&str>) -> i32 {
+   let msg = match name {
+      Some(name) => format!("{name}: {}", io_error_text(err)),
+      None => io_error_text(err),
+   };
+   state.push(Value::Nil);
+   state.push(Value::from(msg));
+   state.push(Value::Integer(err.raw_os_error().unwrap_or(0) as i64));
+   3
+}
+
+// the options of fopen(): r, w or a, then maybe '+', then maybe 'b's
+fn open_options(mode: &[u8]) -> Option<OpenOptions> {
+   let (&kind, rest) = mode.split_first()?;
+   let (update, rest) = match rest.split_first() {
+      Some((b'+', rest)) => (true, rest),
+      _ => (false, rest),
+   };
+   if !rest.iter().all(|&c| c == b'b') {
+      return None;
+   }
+   let mut options = OpenOptions::new();
+   match kind {
+      b'r' => options.read(true).write(update),
+      b'w' => options.write(true).create(true).truncate(true).read(update),
+      b'a' => options.append(true).create(true).read(update),
+      _ => return None,
+   };
+   Some(options)
+}
+
+fn open_file(path: &str, options: &OpenOptions) -> io::Result<Stream> {
+   Ok(Stream::File(FileStream::new(options.open(path)?)))
+}
+
+// io.open(filename [, mode])
+fn io_open(state: &mut ExeState) -> Result<i32, LuaError> {
+   let name = state.check_string(1)?;
+   let name = name.to_string_lossy();
+   let mode = match state.get(2) {
+      Value::Nil => Value::from("r"),
+      _ => state.check_string(2)?,
+   };
+   let Some(options) = open_options(mode.as_bytes()) else {
+      return Err(state.arg_error(2, "invalid mode"));
+   };
+   match open_file(&name, &options) {
+      Ok(stream) => {
+         let file = new_file(state, stream);
+         state.push(file);
+         Ok(1)
+      }
+      Err(err) => Ok(push_error(state, &err, Some(&name))),
+   }
+}
+
+// a file opened for io.input, io.output or io.lines; failing is an error
+fn open_check_file(state: &mut ExeState, name: &Value, mode: &[u8]) -> Result<Value, LuaError> {
+   let name = name.to_string_lossy();
+   match open_file(&name, &open_options(mode).unwrap()) {
+      Ok(stream) => Ok(new_file(state, stream)),
+      Err(err) => Err(state.error(&format!("cannot open file '{name}' ({})", io_error_text(&err)))),
+   }
+}
+
+// the default input or output file, which must be open
+fn io_file(state: &ExeState, key: &str) -> Result<Rc<AnyUserData>, LuaError> {
+   match state.registry().borrow().get_str(key) {
+      Value::UserData(u) if !with_file(&u, |f| f.stream.is_none()) => Ok(u),
+      _ => {
+         let which = if key == INPUT { "input" } else { "output" };
+         Err(state.error(&format!("default {which} file is closed")))
+      }
+   }
+}
+
+// io.input([file]) and io.output([file]): set the default file, by
+// handle or by name, and return it
+fn set_io_file(state: &mut ExeState, key: &str, mode: &[u8]) -> Result<i32, LuaError> {
+   let file = match state.get(1) {
+      Value::Nil => None,
+      v if v.is_string() || matches!(v, Value::Integer(_) | Value::Float(_)) => {
+         let name = state.check_string(1)?;
+         Some(open_check_file(state, &name, mode)?)
+      }
+      _ => {
+         check_file(state, 1)?;
+         Some(state.get(1).clone())
+      }
+   };
+   let registry = state.registry();
+   if let Some(file) = file {
+      registry.borrow_mut().set_str(key, file);
+   }
+   let current = registry.borrow().get_str(key);
+   state.push(current);
+   Ok(1)
+}
+
+fn io_input(state: &mut ExeState) -> Result<i32, LuaError> {
+   set_io_file(state, INPUT, b"r")
+}
+
+fn io_output(state: &mut ExeState) -> Result<i32, LuaError> {
+   set_io_file(state, OUTPUT, b"w")
+}
+
+// io.type(obj): "file", "closed file", or nil if obj is not a file
+fn io_type(state: &mut ExeState) -> Result<i32, LuaError> {
+   state.check_any(1)?;
+   let v = match state.get(1) {
+      Value::UserData(u) if is_file(state.get(1)) => {
+         Value::from(if with_file(u, |f| f.stream.is_none()) { "closed file" } else { "file" })
+      }
+      _ => Value::Nil,
+   };
+   state.push(v);
+   Ok(1)
+}
+
+fn f_tostring(state: &mut ExeState) -> Result<i32, LuaError> {
+   let s = match state.get(1) {
+      Value::UserData(u) if is_file(state.get(1)) => {
+         if with_file(u, |f| f.stream.is_none()) {
+            String::from("file (closed)")
+         } else {
+            format!("file ({:p})", Rc::as_ptr(u))
+         }
+      }
+      _ => return Err(state.type_error(1, FILE_META)),
+   };
+   state.push(Value::from(s));
+   Ok(1)
+}
+
+fn close_file(state: &mut ExeState, file: &Rc<AnyUserData>) -> i32 {
+   let result = with_file(file, |f| match f.stream.take() {
+      Some(Stream::File(mut stream)) => Ok(stream.flush()),
+      other => {
+         // the standard files stay open
+         f.stream = other;
+         Err(())
+      }
+   });
+   match result {
+      Ok(result) => file_result(state, result, None),
+      Err(()) => {
+         state.push(Value::Nil);
+         state.push(Value::from("cannot close standard file"));
+         2
+      }
+   }
+}
+
+fn f_close(state: &mut ExeState) -> Result<i32, LuaError> {
+   let file = check_file(state, 1)?;
+   Ok(close_file(state, &file))
+}
+
+// io.close([file]): close file, by default the default output
+fn io_close(state: &mut ExeState) -> Result<i32, LuaError> {
+   let file = match state.get(1) {
+      Value::Nil => io_file(state, OUTPUT)?,
+      _ => check_file(state, 1)?,
+   };
+   Ok(close_file(state, &file))
+}
+
+fn f_flush(state: &mut ExeState) -> Result<i32, LuaError> {
+   let file = check_file(state, 1)?;
+   let result = with_file(&file, |f| f.writer(|w| w.flush()));
+   Ok(file_result(state, result, None))
+}
+
+fn io_flush(state: &mut ExeState) -> Result<i32, LuaError> {
+   let file = io_file(state, OUTPUT)?;
+   let result = with_file(&file, |f| f.writer(|w| w.flush()));
+   Ok(file_result(state, result, None))
+}
+
+// write the arguments from `first` on; return the file, or nil, the
+// message and the error number
+fn write(state: &mut ExeState, file: Rc<AnyUserData>, first: usize) -> Result<i32, LuaError> {
+   for i in first..=state.get_top() {
+      let piece = match state.get(i) {
+         Value::Integer(n) => n.to_string().into_bytes(),
+         // "%.14g", without the ".0" of tostring
+         Value::Float(n) if n.is_finite() => {
+            let sign = if n.is_sign_negative() { "-" } else { "" };
+            format!("{sign}{}", format_general(n.abs(), Some(14), false)).into_bytes()
+         }
+         Value::Float(n) => format!("{:?}", Value::Float(*n)).into_bytes(),
+         v if v.is_string() => v.as_bytes().to_vec(),
+         _ => return Err(state.type_error(i, "string")),
+      };
+      if let Err(err) = with_file(&file, |f| f.writer(|w| w.write_all(&piece))) {
+         return Ok(push_error(state, &err, None));
+      }
+   }
+   state.push(Value::UserData(file));
+   Ok(1)
+}
+
+fn f_write(state: &mut ExeState) -> Result<i32, LuaError> {
+   let file = check_file(state, 1)?;
+   write(state, file, 2)
+}
+
+fn io_write(state: &mut ExeState) -> Result<i32, LuaError> {
+   let file = io_file(state, OUTPUT)?;
+   write(state, file, 1)
+}
+
+
+enum Format {
+   Number,
+   Line { keep_newline: bool },
+   All,
+   Chars(u64),
+}
+
+// a read format; `arg` is its argument number for errors
+fn parse_format(state: &ExeState, v: &Value, arg: usize) -> Result<Format, LuaError> {
+   match v {
+      Value::Integer(_) | Value::Float(_) => {
+         let Some(Value::Integer(n)) = crate::vm::to_number(v).and_then(|n| match n {
+            Value::Float(f) => crate::value::float_to_int(f).map(Value::Integer),
+            n => Some(n),
+         }) else {
+            return Err(state.arg_error(arg, "number has no integer representation"));
+         };
+         Ok(Format::Chars(n as u64))
+      }
+      v if v.is_string() => {
+         let s = v.as_bytes();
+         // the '*' of Lua 5.1 and 5.2 is still accepted
+         let s = s.strip_prefix(b"*").unwrap_or(s);
+         match s.first() {
+            Some(b'n') => Ok(Format::Number),
+            Some(b'l') => Ok(Format::Line { keep_newline: false }),
+            Some(b'L') => Ok(Format::Line { keep_newline: true }),
+            Some(b'a') => Ok(Format::All),
+            _ => Err(state.arg_error(arg, "invalid format")),
+         }
+      }
+      _ => Err(state.type_error(arg, "string")),
+   }
+}
+
+// one value read with `format`, nil on end of file
+fn read_format(r: &mut dyn BufRead, format: &Format) -> io::Result<Value> {
+   match *format {
+      Format::Line { keep_newline } => {
+         let mut line = Vec::new();
+         if r.read_until(b'\n', &mut line)? == 0 {
+            return Ok(Value::Nil);
+         }
+         if !keep_newline && line.last() == Some(&b'\n') {
+            line.pop();
+         }
+         Ok(Value::from(line))
+      }
+      Format::All => {
+         let mut all = Vec::new();
+         r.read_to_end(&mut all)?;
+         Ok(Value::from(all))
+      }
+      // 0 tests for the end of file
+      Format::Chars(0) => Ok(if r.fill_buf()?.is_empty() { Value::Nil } else { Value::from("") }),
+      Format::Chars(n) => {
+         let mut chars = Vec::new();
+         r.take(n).read_to_end(&mut chars)?;
+         Ok(if chars.is_empty() { Value::Nil } else { Value::from(chars) })
+      }
+      Format::Number => read_number(r),
+   }
+}
+
+// Read the longest prefix of a numeral, as the reference implementation
+// does, then convert it; nil if it is not a number.
+fn read_number(r: &mut dyn BufRead) -> io::Result<Value> {
+   struct Numeral<'a> {
+      r: &'a mut dyn BufRead,
+      buf: Vec<u8>,
+      too_long: bool,
+   }
+   impl Numeral<'_> {
+      fn current(&mut self) -> io::Result<Option<u8>> {
+         Ok(self.r.fill_buf()?.first().copied())
+      }
+      // take the current char if it is in `set`
+      fn test(&mut self, set: &[u8]) -> io::Result<bool> {
+         match self.current()? {
+            Some(c) if set.contains(&c) => {
+               if self.buf.len() >= MAX_NUMERAL {
+                  self.too_long = true;
+                  return Ok(false);
+               }
+               self.buf.push(c);
+               self.r.consume(1);
+               Ok(true)
+            }
+            _ => Ok(false),
+         }
+      }
+      fn digits(&mut self, hex: bool) -> io::Result<usize> {
+         let set: &[u8] = if hex { b"0123456789abcdefABCDEF" } else { b"0123456789" };
+         let mut n = 0;
+         while self.test(set)? {
+            n += 1;
+         }
+         Ok(n)
+      }
+   }
+
+   let mut num = Numeral { r, buf: Vec::new(), too_long: false };
+   while num.current()?.is_some_and(|c| c.is_ascii_whitespace() || c == 0x0b) {
+      num.r.consume(1);
+   }
+   num.test(b"-+")?;
+   let mut count = 0;
+   let mut hex = false;
+   if num.test(b"0")? {
+      if num.test(b"xX")? {
+         hex = true;
+      } else {
+         count = 1;
+      }
+   }
+   count += num.digits(hex)?;
+   if num.test(b".")? {
+      count += num.digits(hex)?;
+   }
+   if count > 0 && num.test(if hex { b"pP" } else { b"eE" })? {
+      num.test(b"-+")?;
+      num.digits(false)?;
+   }
+   if num.too_long {
+      return Ok(Value::Nil);
+   }
+   Ok(str2number(&num.buf).unwrap_or(Value::Nil))
+}
+
+// Read with each format in turn, stopping at the first that fails,
+// which gives nil. The default format is "l".
+fn read(state: &ExeState, file: &Rc<AnyUserData>, formats: &[Value], first_arg: usize)
+   -> Result<io::Result<Vec<Value>>, LuaError> {
+   let formats = if formats.is_empty() {
+      vec![Format::Line { keep_newline: false }]
+   } else {
+      formats.iter().enumerate()
+         .map(|(i, v)| parse_format(state, v, first_arg + i))
+         .collect::<Result<_, _>>()?
+   };
+   Ok(with_file(file, |f| f.reader(|r| {
+      let mut values = Vec::new();
+      for format in &formats {
+         let v = read_format(r, format)?;
+         let failed = v == Value::Nil;
+         values.push(v);
+         if failed {
+            break;
+         }
+      }
+      Ok(values)
+   })))
+}
+
+fn push_read(state: &mut ExeState, result: io::Result<Vec<Value>>) -> i32 {
+   match result {
+      Ok(values) => {
+         let n = values.len() as i32;
+         for v in values {
+            state.push(v);
+         }
+         n
+      }
+      Err(err) => push_error(state, &err, None),
+   }
+}
+
+fn args_from(state: &ExeState, first: usize) -> Vec<Value> {
+   (first..=state.get_top()).map(|i| state.get(i).clone()).collect()
+}
+
+fn f_read(state: &mut ExeState) -> Result<i32, LuaError> {
+   let file = check_file(state, 1)?;
+   let result = read(state, &file, &args_from(state, 2), 2)?;
+   Ok(push_read(state, result))
+}
+
+fn io_read(state: &mut ExeState) -> Result<i32, LuaError> {
+   let file = io_file(state, INPUT)?;
+   let result = read(state, &file, &args_from(state, 1), 1)?;
+   Ok(push_read(state, result))
+}
+
+// An iterator reading `file` with the formats; with `close`, it closes
+// the file at the end.
+fn lines_iterator(state: &mut ExeState, file: Rc<AnyUserData>, first: usize, close: bool) -> Result<Value, LuaError> {
+   let formats = args_from(state, first);
+   if formats.len() > MAX_LINES_FORMATS {
+      return Err(state.arg_error(MAX_LINES_FORMATS + 2, "too many arguments"));
+   }
+   let iter = move |state: &mut ExeState| {
+      if with_file(&file, |f| f.stream.is_none()) {
+         return Err(state.error("file is already closed"));
+      }
+      let values = match read(state, &file, &formats, 2)? {
+         Ok(values) => values,
+         Err(err) => return Err(state.error(&io_error_text(&err))),
+      };
+      if values.first().is_some_and(|v| *v != Value::Nil) {
+         let n = values.len() as i32;
+         for v in values {
+            state.push(v);
+         }
+         return Ok(n);
+      }
+      if close {
+         close_file(state, &file);
+      }
+      Ok(0)
+   };
+   Ok(Value::NativeClosure(Rc::new(iter)))
+}
+
+// file:lines(...): iterate over the file; it stays open
+fn f_lines(state: &mut ExeState) -> Result<i32, LuaError> {
+   let file = check_file(state, 1)?;
+   let iter = lines_iterator(state, file, 2, false)?;
+   state.push(iter);
+   Ok(1)
+}
+
+// io.lines([filename, ...]): iterate over the named file, closing it at
+// the end, or over the default input
+fn io_lines(state: &mut ExeState) -> Result<i32, LuaError> {
+   let (file, close) = match state.get(1) {
+      Value::Nil => (io_file(state, INPUT)?, false),
+      _ => {
+         let name = state.check_string(1)?;
+         let Value::UserData(file) = open_check_file(state, &name, b"r")? else {
+            unreachable!("open_check_file gives a file");
+         };
+         (file, true)
+      }
+   };
+   let iter = lines_iterator(state, file.clone(), 2, close)?;
+   state.push(iter);
+   state.push(Value::Nil);
+   state.push(Value::Nil);
+   state.push(Value::UserData(file));
+   Ok(4)
+}
+
+// file:seek([whence [, offset]]): move to offset from the start ("set"),
+// the current position ("cur") or the end ("end"); return the position
+fn f_seek(state: &mut ExeState) -> Result<i32, LuaError> {
+   let file = check_file(state, 1)?;
+   let whence = match state.get(2) {
+      Value::Nil => Value::from("cur"),
+      _ => state.check_string(2)?,
+   };
+   let offset = state.opt_integer(3, 0)?;
+   let pos = match whence.as_bytes() {
+      b"set" => SeekFrom::Start(offset as u64),
+      b"cur" => SeekFrom::Current(offset),
+      b"end" => SeekFrom::End(offset),
+      name => {
+         let msg = format!("invalid option '{}'", String::from_utf8_lossy(name));
+         return Err(state.arg_error(2, &msg));
+      }
+   };
+   let result = with_file(&file, |f| match f.stream.as_mut() {
+      Some(Stream::File(stream)) => stream.seek(pos),
+      _ => Err(io::Error::from_raw_os_error(29)), // ESPIPE
+   });
+   match result {
+      Ok(pos) => {
+         state.push(Value::Integer(pos as i64));
+         Ok(1)
+      }
+      Err(err) => Ok(push_error(state, &err, None)),
+   }
+}
+
+// file:setvbuf(mode [, size]): "no", "full" or "line" buffering
+fn f_setvbuf(state: &mut ExeState) -> Result<i32, LuaError> {
+   let file = check_file(state, 1)?;
+   let mode = state.check_string(2)?;
+   let buffering = match mode.as_bytes() {
+      b"no" => Buffering::No,
+      b"full" => Buffering::Full,
+      b"line" => Buffering::Line,
+      name => {
+         let msg = format!("invalid option '{}'", String::from_utf8_lossy(name));
+         return Err(state.arg_error(2, &msg));
+      }
+   };
+   let size = state.opt_integer(3, BUF_SIZE as i64)?.max(1) as usize;
+   // the standard streams keep the buffering of Rust
+   let result = with_file(&file, |f| match f.stream.as_mut() {
+      Some(Stream::File(stream)) => {
+         stream.buffering = buffering;
+         stream.size = size;
+         stream.flush_writes()
+      }
+      _ => Ok(()),
+   });
+   Ok(file_result(state, result, None))
+}
@@ -0,0 +1,325 @@
+use std::{cell::RefCell, f64::consts::PI, rc::Rc, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::{value::{Value, Table, float_to_int}, vm::{ExeState, to_number}, error::LuaError};
+
+
+// the `math` table
+pub fn open(state: &mut ExeState) {
+   let mut lib = Table::new(0, 32);
+   lib.set_str("abs", Value::Function(math_abs));
+   lib.set_str("ceil", Value::Function(math_ceil));
+   lib.set_str("floor", Value::Function(math_floor));
+   lib.set_str("fmod", Value::Function(math_fmod));
+   lib.set_str("modf", Value::Function(math_modf));
+   lib.set_str("sqrt", Value::Function(math_sqrt));
+   lib.set_str("exp", Value::Function(math_exp));
+   lib.set_str("log", Value::Function(math_log));
+   lib.set_str("sin", Value::Function(math_sin));
+   lib.set_str("cos", Value::Function(math_cos));
+   lib.set_str("tan", Value::Function(math_tan));
+   lib.set_str("asin", Value::Function(math_asin));
+   lib.set_str("acos", Value::Function(math_acos));
+   lib.set_str("atan", Value::Function(math_atan));
+   lib.set_str("max", Value::Function(math_max));
+   lib.set_str("min", Value::Function(math_min));
+   lib.set_str("tointeger", Value::Function(math_tointeger));
+   lib.set_str("type", Value::Function(math_type));
+   lib.set_str("ult", Value::Function(math_ult));
+   lib.set_str("random", Value::Function(math_random));
+   lib.set_str("randomseed", Value::Function(math_randomseed));
+   lib.set_str("huge", Value::Float(f64::INFINITY));
+   lib.set_str("pi", Value::Float(PI));
+   lib.set_str("maxinteger", Value::Integer(i64::MAX));
+   lib.set_str("mininteger", Value::Integer(i64::MIN));
+   state.set_global("math", Value::Table(Rc::new(RefCell::new(lib))));
+}
+
+fn math_abs(state: &mut ExeState) -> Result<i32, LuaError> {
+   let v = match state.check_number(1)? {
+      Value::Integer(i) => Value::Integer(i.wrapping_abs()),
+      _ => Value::Float(state.check_float(1)?.abs()),
+   };
+   state.push(v);
+   Ok(1)
+}
+
+// floor and ceil give integers, unless the result does not fit one
+fn math_floor(state: &mut ExeState) -> Result<i32, LuaError> {
+   let v = match state.check_number(1)? {
+      Value::Integer(i) => Value::Integer(i),
+      _ => {
+         let f = state.check_float(1)?.floor();
+         float_to_int(f).map_or(Value::Float(f), Value::Integer)
+      }
+   };
+   state.push(v);
+   Ok(1)
+}
+
+fn math_ceil(state: &mut ExeState) -> Result<i32, LuaError> {
+   let v = match state.check_number(1)? {
+      Value::Integer(i) => Value::Integer(i),
+      _ => {
+         let f = state.check_float(1)?.ceil();
+         float_to_int(f).map_or(Value::Float(f), Value::Integer)
+      }
+   };
+   state.push(v);
+   Ok(1)
+}
+
+// fmod(a, b): the remainder of a / b rounded towards zero
+fn math_fmod(state: &mut ExeState) -> Result<i32, LuaError> {
+   let v = match (state.check_number(1)?, state.check_number(2)?) {
+      (Value::Integer(a), Value::Integer(b)) => match b {
+         0 => return Err(state.arg_error(2, "zero")),
+         // avoids the overflow of mininteger % -1
+         -1 => Value::Integer(0),
+         b => Value::Integer(a % b),
+      },
+      _ => Value::Float(state.check_float(1)? % state.check_float(2)?),
+   };
+   state.push(v);
+   Ok(1)
+}
+
+// modf(x): the integral part of x, as a float, and its fractional part
+fn math_modf(state: &mut ExeState) -> Result<i32, LuaError> {
+   match state.check_number(1)? {
+      Value::Integer(i) => {
+         state.push(Value::Integer(i));
+         state.push(Value::Float(0.0));
+      }
+      _ => {
+         let n = state.check_float(1)?;
+         let int = n.trunc();
+         // infinities have no fractional part
+         let fract = if n == int { 0.0 } else { n - int };
+         state.push(Value::Float(int));
+         state.push(Value::Float(fract));
+      }
+   }
+   Ok(2)
+}
+
+// a float function of one float argument
+fn float_fn(state: &mut ExeState, f: fn(f64) -> f64) -> Result<i32, LuaError> {
+   let x = state.check_float(1)?;
+   state.push(Value::Float(f(x)));
+   Ok(1)
+}
+
+fn math_sqrt(state: &mut ExeState) -> Result<i32, LuaError> {
+   float_fn(state, f64::sqrt)
+}
+
+fn math_exp(state: &mut ExeState) -> Result<i32, LuaError> {
+   float_fn(state, f64::exp)
+}
+
+fn math_sin(state: &mut ExeState) -> Result<i32, LuaError> {
+   float_fn(state, f64::sin)
+}
+
+fn math_cos(state: &mut ExeState) -> Result<i32, LuaError> {
+   float_fn(state, f64::cos)
+}
+
+fn math_tan(state: &mut ExeState) -> Result<i32, LuaError> {
+   float_fn(state, f64::tan)
+}
+
+fn math_asin(state: &mut ExeState) -> Result<i32, LuaError> {
+   float_fn(state, f64::asin)
+}
+
+fn math_acos(state: &mut ExeState) -> Result<i32, LuaError> {
+   float_fn(state, f64::acos)
+}
+
+// log(x [, base]): the natural logarithm by default
+fn math_log(state: &mut ExeState) -> Result<i32, LuaError> {
+   let x = state.check_float(1)?;
+   let r = match state.get(2) {
+      Value::Nil => x.ln(),
+      _ => match state.check_float(2)? {
+         2.0 => x.log2(),
+         10.0 => x.log10(),
+         base => x.ln() / base.ln(),
+      },
+   };
+   state.push(Value::Float(r));
+   Ok(1)
+}
+
+// atan(y [, x]): the arc tangent of y/x, in the quadrant of (x, y)
+fn math_atan(state: &mut ExeState) -> Result<i32, LuaError> {
+   let y = state.check_float(1)?;
+   let x = match state.get(2) {
+      Value::Nil => 1.0,
+      _ => state.check_float(2)?,
+   };
+   state.push(Value::Float(y.atan2(x)));
+   Ok(1)
+}
+
+// max and min keep the subtype of the argument they pick
+fn math_max(state: &mut ExeState) -> Result<i32, LuaError> {
+   min_max(state, false)
+}
+
+fn math_min(state: &mut ExeState) -> Result<i32, LuaError> {
+   min_max(state, true)
+}
+
+fn min_max(state: &mut ExeState, min: bool) -> Result<i32, LuaError> {
+   let mut best = state.check_number(1)?;
+   for i in 2..=state.get_top() {
+      let v = state.check_number(i)?;
+      let better = if min { state.less_than(&v, &best)? } else { state.less_than(&best, &v)? };
+      if better {
+         best = v;
+      }
+   }
+   state.push(best);
+   Ok(1)
+}
+
+// tointeger(x): x as an integer if it has an exact one, else nil
+fn math_tointeger(state: &mut ExeState) -> Result<i32, LuaError> {
+   let v = match to_number(state.get(1)) {
+      Some(Value::Integer(i)) => Value::Integer(i),
+      Some(Value::Float(f)) => float_to_int(f).map_or(Value::Nil, Value::Integer),
+      _ if state.get_top() == 0 => return Err(state.arg_error(1, "value expected")),
+      _ => Value::Nil,
+   };
+   state.push(v);
+   Ok(1)
+}
+
+// type(x): "integer" or "float", or nil if x is not a number
+fn math_type(state: &mut ExeState) -> Result<i32, LuaError> {
+   let v = match state.get(1) {
+      Value::Integer(_) => Value::from("integer"),
+      Value::Float(_) => Value::from("float"),
+      _ if state.get_top() == 0 => return Err(state.arg_error(1, "value expected")),
+      _ => Value::Nil,
+   };
+   state.push(v);
+   Ok(1)
+}
+
+// ult(a, b): a < b as unsigned integers
+fn math_ult(state: &mut ExeState) -> Result<i32, LuaError> {
+   let a = state.check_integer(1)?;
+   let b = state.check_integer(2)?;
+   state.push(Value::Boolean((a as u64) < (b as u64)));
+   Ok(1)
+}
+
+
+// The xoshiro256** generator of the reference implementation, so that
+// seeded sequences are the same as there.
+#[derive(Debug)]
+pub struct Random([u64; 4]);
+
+impl Random {
+   // a generator with a seed that differs between runs
+   pub fn new() -> Self {
+      let mut r = Random([0; 4]);
+      r.seed_random();
+      r
+   }
+
+   fn next(&mut self) -> u64 {
+      let s = &mut self.0;
+      let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+      let t = s[1] << 17;
+      s[2] ^= s[0];
+      s[3] ^= s[1];
+      s[1] ^= s[2];
+      s[0] ^= s[3];
+      s[2] ^= t;
+      s[3] = s[3].rotate_left(45);
+      result
+   }
+
+   // a number in [0, n], without bias
+   fn project(&mut self, mut r: u64, n: u64) -> u64 {
+      if n & n.wrapping_add(1) == 0 {
+         // n + 1 is a power of 2
+         return r & n;
+      }
+      // the smallest 2^b - 1 not below n
+      let lim = u64::MAX >> n.leading_zeros();
+      loop {
+         r &= lim;
+         if r <= n {
+            return r;
+         }
+         r = self.next();
+      }
+   }
+
+   pub fn seed(&mut self, n1: u64, n2: u64) {
+      self.0 = [n1, 0xff, n2, 0];
+      // spread the seed
+      for _ in 0..16 {
+         self.next();
+      }
+   }
+
+   // seed with the time and an address; return the seed
+   fn seed_random(&mut self) -> (u64, u64) {
+      let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+      let n1 = now.as_secs() ^ (now.subsec_nanos() as u64) << 32;
+      let n2 = self as *const Random as u64;
+      self.seed(n1, n2);
+      (n1, n2)
+   }
+}
+
+// random(): a float in [0, 1); random(m): an integer in [1, m];
+// random(m, n): an integer in [m, n]; random(0): any integer
+fn math_random(state: &mut ExeState) -> Result<i32, LuaError> {
+   let r = state.random().next();
+   let (low, up) = match state.get_top() {
+      0 => {
+         // a float in [0, 1) from the top 53 bits
+         state.push(Value::Float((r >> 11) as f64 * 0.5f64.powi(53)));
+         return Ok(1);
+      }
+      1 => {
+         let up = state.check_integer(1)?;
+         if up == 0 {
+            state.push(Value::Integer(r as i64));
+            return Ok(1);
+         }
+         (1, up)
+      }
+      2 => (state.check_integer(1)?, state.check_integer(2)?),
+      _ => return Err(state.error("wrong number of arguments")),
+   };
+   if low > up {
+      return Err(state.arg_error(1, "interval is empty"));
+   }
+   let n = state.random().project(r, (up as u64).wrapping_sub(low as u64));
+   state.push(Value::Integer(n.wrapping_add(low as u64) as i64));
+   Ok(1)
+}
+
+// randomseed([n1 [, n2]]): seed the generator, by default randomly;
+// return the two seed components
+fn math_randomseed(state: &mut ExeState) -> Result<i32, LuaError> {
+   let (n1, n2) = if state.get_top() == 0 {
+      state.random().seed_random()
+   } else {
+      let n1 = state.check_integer(1)? as u64;
+      let n2 = state.opt_integer(2, 0)? as u64;
+      state.random().seed(n1, n2);
+      (n1, n2)
+   };
+   state.push(Value::Integer(n1 as i64));
+   state.push(Value::Integer(n2 as i64));
+   Ok(2)
+}
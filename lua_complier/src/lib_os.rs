@@ -0,0 +1,273 @@
+use std::{cell::RefCell, env, ffi::{CStr, CString}, fs, mem, path::Path, rc::Rc,
+          time::{SystemTime, UNIX_EPOCH}};
+
+use crate::{value::{Value, Table, float_to_int}, vm::{ExeState, to_number}, error::LuaError,
+            lib_io::file_result};
+
+
+// room for the result of one strftime() conversion
+const SIZE_TIME_FMT: usize = 250;
+
+// The conversions os.date accepts, those of C99: the one-char ones,
+// then after '|' the two-char ones.
+const STRFTIME_OPTIONS: &[u8] = b"aAbBcCdDeFgGhHIjmMnprRStTuUVwWxXyYzZ%\
+||EcECExEXEyEYOdOeOHOIOmOMOSOuOUOVOwOWOy";
+
+// template of os.tmpname, as the reference build
+const TMPNAME_TEMPLATE: &str = "/tmp/lua_XXXXXX";
+
+
+pub fn open(state: &mut ExeState) {
+   let mut lib = Table::new(0, 10);
+   lib.set_str("clock", Value::Function(os_clock));
+   lib.set_str("date", Value::Function(os_date));
+   lib.set_str("difftime", Value::Function(os_difftime));
+   lib.set_str("exit", Value::Function(os_exit));
+   lib.set_str("getenv", Value::Function(os_getenv));
+   lib.set_str("remove", Value::Function(os_remove));
+   lib.set_str("rename", Value::Function(os_rename));
+   lib.set_str("time", Value::Function(os_time));
+   lib.set_str("tmpname", Value::Function(os_tmpname));
+   state.set_global("os", Value::Table(Rc::new(RefCell::new(lib))));
+}
+
+// os.clock(): CPU time used by the program, in seconds
+fn os_clock(state: &mut ExeState) -> Result<i32, LuaError> {
+   // what C's clock() measures
+   let mut ts: libc::timespec = unsafe { mem::zeroed() };
+   unsafe { libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut ts) };
+   state.push(Value::Float(ts.tv_sec as f64 + ts.tv_nsec as f64 / 1e9));
+   Ok(1)
+}
+
+fn now() -> i64 {
+   match SystemTime::now().duration_since(UNIX_EPOCH) {
+      Ok(d) => d.as_secs() as i64,
+      Err(err) => -(err.duration().as_secs() as i64),
+   }
+}
+
+// broken-down `t`, in local time or in UTC
+fn split_time(t: i64, utc: bool) -> Option<libc::tm> {
+   let t = t as libc::time_t;
+   let mut tm: libc::tm = unsafe { mem::zeroed() };
+   let result = unsafe {
+      if utc { libc::gmtime_r(&t, &mut tm) } else { libc::localtime_r(&t, &mut tm) }
+   };
+   (!result.is_null()).then_some(tm)
+}
+
+// argument i, a time as os.time gives
+fn check_time(state: &ExeState, i: usize) -> Result<i64, LuaError> {
+   state.check_integer(i)
+}
+
+// field `key` of the date table in argument 1, less `delta`; `default`
+// if absent, or an error if there is none
+fn get_field(state: &mut ExeState, key: &str, default: Option<i32>, delta: i32) -> Result<i32, LuaError> {
+   let table = state.get(1).clone();
+   let v = state.index(&table, &Value::from(key))?;
+   let n = match to_number(&v) {
+      Some(Value::Integer(n)) => Some(n),
+      Some(Value::Float(f)) => float_to_int(f),
+      _ => None,
+   };
+   match (n, default) {
+      (Some(n), _) => {
+         let n = n.checked_sub(delta as i64).and_then(|n| i32::try_from(n).ok());
+         n.ok_or_else(|| state.error(&format!("field '{key}' is out-of-bound")))
+      }
+      (None, _) if v != Value::Nil => Err(state.error(&format!("field '{key}' is not an integer"))),
+      (None, Some(default)) => Ok(default),
+      (None, None) => Err(state.error(&format!("field '{key}' missing in date table"))),
+   }
+}
+
+// store the fields of `tm` in `table`, as os.date("*t") gives them
+fn set_all_fields(state: &mut ExeState, table: &Value, tm: &libc::tm) -> Result<(), LuaError> {
+   let fields = [
+      ("year", tm.tm_year as i64 + 1900),
+      ("month", tm.tm_mon as i64 + 1),
+      ("day", tm.tm_mday as i64),
+      ("hour", tm.tm_hour as i64),
+      ("min", tm.tm_min as i64),
+      ("sec", tm.tm_sec as i64),
+      ("yday", tm.tm_yday as i64 + 1),
+      ("wday", tm.tm_wday as i64 + 1),
+   ];
+   for (key, n) in fields {
+      state.set_index(table, Value::from(key), Value::Integer(n))?;
+   }
+   // isdst is left out when it is unknown
+   if tm.tm_isdst >= 0 {
+      state.set_index(table, Value::from("isdst"), Value::Boolean(tm.tm_isdst > 0))?;
+   }
+   Ok(())
+}
+
+// os.time([table]): the current time, or the time the date table gives,
+// whose fields are then normalized (a day 32 becomes the 1st of the next
+// month and so on)
+fn os_time(state: &mut ExeState) -> Result<i32, LuaError> {
+   if state.get(1) == &Value::Nil {
+      state.push(Value::Integer(now()));
+      return Ok(1);
+   }
+   if !matches!(state.get(1), Value::Table(_)) {
+      return Err(state.type_error(1, "table"));
+   }
+   let mut tm: libc::tm = unsafe { mem::zeroed() };
+   tm.tm_year = get_field(state, "year", None, 1900)?;
+   tm.tm_mon = get_field(state, "month", None, 1)?;
+   tm.tm_mday = get_field(state, "day", None, 0)?;
+   tm.tm_hour = get_field(state, "hour", Some(12), 0)?;
+   tm.tm_min = get_field(state, "min", Some(0), 0)?;
+   tm.tm_sec = get_field(state, "sec", Some(0), 0)?;
+   let table = state.get(1).clone();
+   tm.tm_isdst = match state.index(&table, &Value::from("isdst"))? {
+      Value::Nil => -1,
+      v => !v.is_false() as i32,
+   };
+   let t = unsafe { libc::mktime(&mut tm) };
+   set_all_fields(state, &table, &tm)?;
+   if t == -1 {
+      return Err(state.error("time result cannot be represented in this installation"));
+   }
+   state.push(Value::Integer(t as i64));
+   Ok(1)
+}
+
+// os.date([format [, time]]): the time formatted as strftime() does, in
+// UTC if the format starts with '!'; "*t" gives a table of the fields
+fn os_date(state: &mut ExeState) -> Result<i32, LuaError> {
+   let format = match state.get(1) {
+      Value::Nil => Value::from("%c"),
+      _ => state.check_string(1)?,
+   };
+   let t = match state.get(2) {
+      Value::Nil => now(),
+      _ => check_time(state, 2)?,
+   };
+   let format = format.as_bytes();
+   let (format, utc) = match format.strip_prefix(b"!") {
+      Some(format) => (format, true),
+      None => (format, false),
+   };
+   let Some(tm) = split_time(t, utc) else {
+      return Err(state.error("date result cannot be represented in this installation"));
+   };
+
+   if format == b"*t" {
+      let table = Value::new_table(0, 9);
+      set_all_fields(state, &table, &tm)?;
+      state.push(table);
+      return Ok(1);
+   }
+   let mut result = Vec::new();
+   let mut rest = format;
+   while let Some((&c, tail)) = rest.split_first() {
+      if c != b'%' {
+         result.push(c);
+         rest = tail;
+         continue;
+      }
+      let Some(len) = conversion_len(tail) else {
+         let msg = format!("invalid conversion specifier '%{}'", String::from_utf8_lossy(tail));
+         return Err(state.arg_error(1, &msg));
+      };
+      result.extend_from_slice(&strftime(&rest[..len + 1], &tm));
+      rest = &tail[len..];
+   }
+   state.push(Value::from(result));
+   Ok(1)
+}
+
+// the length of the valid conversion at the start of `conv`, if any
+fn conversion_len(conv: &[u8]) -> Option<usize> {
+   let mut len = 1;
+   let mut options = STRFTIME_OPTIONS;
+   while !options.is_empty() && len <= conv.len() {
+      // a '|' starts the options one char longer, and the pair of them
+      // is skipped as one of those
+      if options[0] == b'|' {
+         len += 1;
+      } else if options[..len] == conv[..len] {
+         return Some(len);
+      }
+      options = &options[len..];
+   }
+   None
+}
+
+// one conversion ("%Y", "%Ec", ...) of `tm`
+fn strftime(conv: &[u8], tm: &libc::tm) -> Vec<u8> {
+   let conv = CString::new(conv).unwrap();
+   let mut buf = [0u8; SIZE_TIME_FMT];
+   let len = unsafe { libc::strftime(buf.as_mut_ptr() as *mut libc::c_char, buf.len(), conv.as_ptr(), tm) };
+   buf[..len].to_vec()
+}
+
+// os.difftime(t2, t1): t2 - t1 in seconds, as a float
+fn os_difftime(state: &mut ExeState) -> Result<i32, LuaError> {
+   let t2 = check_time(state, 1)?;
+   let t1 = check_time(state, 2)?;
+   state.push(Value::Float(t2 as f64 - t1 as f64));
+   Ok(1)
+}
+
+// os.exit([code [, close]]): end the program with status code, which is
+// true (success, the default), false (failure) or a number. Instead of
+// exiting on the spot it unwinds all calls back to the host, so the
+// state is always closed whatever `close` asks.
+fn os_exit(state: &mut ExeState) -> Result<i32, LuaError> {
+   let status = match state.get(1) {
+      Value::Boolean(ok) => if *ok { 0 } else { 1 },
+      _ => state.opt_integer(1, 0)? as i32,
+   };
+   Err(LuaError::Exit(status))
+}
+
+// os.getenv(name): the environment variable, or nil
+fn os_getenv(state: &mut ExeState) -> Result<i32, LuaError> {
+   let name = state.check_string(1)?;
+   let name = name.to_string_lossy();
+   let v = match env::var_os(name) {
+      Some(v) => Value::from(v.to_string_lossy().into_owned()),
+      None => Value::Nil,
+   };
+   state.push(v);
+   Ok(1)
+}
+
+// os.remove(filename): delete a file or an empty directory
+fn os_remove(state: &mut ExeState) -> Result<i32, LuaError> {
+   let name = state.check_string(1)?;
+   let name = name.to_string_lossy();
+   let result = fs::remove_file(&name).or_else(|err| {
+      if Path::new(&name).is_dir() { fs::remove_dir(&name) } else { Err(err) }
+   });
+   Ok(file_result(state, result, Some(&name)))
+}
+
+// os.rename(oldname, newname)
+fn os_rename(state: &mut ExeState) -> Result<i32, LuaError> {
+   let from = state.check_string(1)?;
+   let to = state.check_string(2)?;
+   let from = from.to_string_lossy();
+   let to = to.to_string_lossy();
+   let result = fs::rename(from, to);
+   Ok(file_result(state, result, None))
+}
+
+// os.tmpname(): the name of a new empty file, which the script removes
+fn os_tmpname(state: &mut ExeState) -> Result<i32, LuaError> {
+   let mut template = CString::new(TMPNAME_TEMPLATE).unwrap().into_bytes_with_nul();
+   let fd = unsafe { libc::mkstemp(template.as_mut_ptr() as *mut libc::c_char) };
+   if fd == -1 {
+      return Err(state.error("unable to generate a unique filename"));
+   }
+   unsafe { libc::close(fd) };
+   let name = CStr::from_bytes_with_nul(&template).unwrap();
+   state.push(Value::from(name.to_bytes()));
+   Ok(1)
+}
@@ -0,0 +1,380 @@
+use std::{cell::RefCell, collections::HashMap, env, fmt, fs::File, rc::Rc};
+
+use crate::{value::{Value, Table}, vm::ExeState, error::LuaError};
+
+
+// the search path when LUA_PATH is not set, as in the reference build
+pub const LUA_PATH_DEFAULT: &str = "/usr/local/share/lua/5.4/?.lua;/usr/local/share/lua/5.4/?/init.lua;\
+/usr/local/lib/lua/5.4/?.lua;/usr/local/lib/lua/5.4/?/init.lua;./?.lua;./?/init.lua";
+
+// directory separator, path separator, name mark, executable-directory
+// mark and ignore mark, one per line as in package.config
+const CONFIG: &str = "/\n;\n?\n!\n-\n";
+
+// Registry keys of the tables the package functions work with, so that
+// they keep working if the `package` global is replaced.
+const PACKAGE: &str = "_PACKAGE";
+const LOADED: &str = "_LOADED";
+const PRELOAD: &str = "_PRELOAD";
+
+
+// the `package` table and `require`
+pub fn open(state: &mut ExeState) {
+   let loaded = Value::new_table(0, 16);
+   let preload = Value::new_table(0, 0);
+   let searchers = Value::new_table(3, 0);
+   if let Value::Table(t) = &searchers {
+      let mut t = t.borrow_mut();
+      t.set_int(1, Value::Function(searcher_preload));
+      // modules of the host first, so that no file can hide them
+      t.set_int(2, Value::Function(searcher_native));
+      t.set_int(3, Value::Function(searcher_lua));
+   }
+
+   let mut lib = Table::new(0, 8);
+   lib.set_str("config", Value::from(CONFIG));
+   lib.set_str("loaded", loaded.clone());
+   lib.set_str("loadlib", Value::Function(pkg_loadlib));
+   lib.set_str("path", Value::from(env_path()));
+   lib.set_str("preload", preload.clone());
+   lib.set_str("searchers", searchers);
+   lib.set_str("searchpath", Value::Function(pkg_searchpath));
+   let lib = Value::Table(Rc::new(RefCell::new(lib)));
+
+   let registry = state.registry();
+   let mut registry = registry.borrow_mut();
+   registry.set_str(PACKAGE, lib.clone());
+   registry.set_str(LOADED, loaded);
+   registry.set_str(PRELOAD, preload);
+   drop(registry);
+   state.set_global("package", lib);
+   state.set_global("require", Value::Function(pkg_require));
+}
+
+// LUA_PATH_5_4 or LUA_PATH, where ";;" stands for the default path
+fn env_path() -> String {
+   let Ok(path) = env::var("LUA_PATH_5_4").or_else(|_| env::var("LUA_PATH")) else {
+      return String::from(LUA_PATH_DEFAULT);
+   };
+   let Some(mark) = path.find(";;") else {
+      return path;
+   };
+   let mut full = String::new();
+   if mark > 0 {
+      full.push_str(&path[..mark]);
+      full.push(';');
+   }
+   full.push_str(LUA_PATH_DEFAULT);
+   if mark + 2 < path.len() {
+      full.push(';');
+      full.push_str(&path[mark + 2..]);
+   }
+   full
+}
+
+// set package.path to the default, as if LUA_PATH were not set
+pub fn ignore_env(state: &mut ExeState) {
+   if let Value::Table(lib) = registry_get(state, PACKAGE) {
+      lib.borrow_mut().set_str("path", Value::from(LUA_PATH_DEFAULT));
+   }
+}
+
+fn registry_get(state: &ExeState, key: &str) -> Value {
+   state.registry().borrow().get_str(key)
+}
+
+fn registry_table(state: &ExeState, key: &str) -> Rc<RefCell<Table>> {
+   match registry_get(state, key) {
+      Value::Table(t) => t,
+      _ => unreachable!("package library not open"),
+   }
+}
+
+// record `module` as loaded under `name`, as require does
+pub fn set_loaded(state: &mut ExeState, name: &str, module: Value) {
+   registry_table(state, LOADED).borrow_mut().set_str(name, module);
+}
+
+// require(name): the value of module `name`, running its loader the
+// first time
+fn pkg_require(state: &mut ExeState) -> Result<i32, LuaError> {
+   let name = state.check_string(1)?;
+   let loaded = registry_table(state, LOADED);
+   let module = loaded.borrow().get(&name);
+   if !module.is_false() {
+      state.push(module);
+      return Ok(1);
+   }
+
+   let (loader, extra) = find_loader(state, &name)?;
+   let result = state.call(&loader, &[name.clone(), extra.clone()])?;
+   match result.into_iter().next() {
+      Some(Value::Nil) | None => (),
+      Some(module) => loaded.borrow_mut().set(name.clone(), module).unwrap(),
+   }
+   // a module that returns nothing is still loaded
+   if loaded.borrow().get(&name) == Value::Nil {
+      loaded.borrow_mut().set(name.clone(), Value::Boolean(true)).unwrap();
+   }
+   let module = loaded.borrow().get(&name);
+   state.push(module);
+   state.push(extra);
+   Ok(2)
+}
+
+// ask each of package.searchers for a loader of `name`, and return it
+// with its extra value; the error lists what every searcher tried
+fn find_loader(state: &mut ExeState, name: &Value) -> Result<(Value, Value), LuaError> {
+   let Value::Table(lib) = registry_get(state, PACKAGE) else { unreachable!() };
+   let Value::Table(searchers) = lib.borrow().get_str("searchers") else {
+      return Err(state.error("'package.searchers' must be a table"));
+   };
+   let mut msg = Vec::new();
+   for i in 1.. {
+      let searcher = searchers.borrow().get_int(i);
+      if searcher == Value::Nil {
+         break;
+      }
+      let mut results = state.call(&searcher, std::slice::from_ref(name))?.into_iter();
+      let loader = results.next().unwrap_or(Value::Nil);
+      match loader {
+         f if f.is_function() => return Ok((f, results.next().unwrap_or(Value::Nil))),
+         s if s.is_string() => {
+            msg.extend_from_slice(b"\n\t");
+            msg.extend_from_slice(s.as_bytes());
+         }
+         _ => (),
+      }
+   }
+   let mut text = format!("module '{name:?}' not found:").into_bytes();
+   text.extend_from_slice(&msg);
+   Err(state.error(&String::from_utf8_lossy(&text)))
+}
+
+fn searcher_preload(state: &mut ExeState) -> Result<i32, LuaError> {
+   let name = state.check_string(1)?;
+   let loader = registry_table(state, PRELOAD).borrow().get(&name);
+   if loader == Value::Nil {
+      state.push(Value::from(format!("no field package.preload['{name:?}']")));
+      return Ok(1);
+   }
+   state.push(loader);
+   state.push(Value::from(":preload:"));
+   Ok(2)
+}
+
+fn searcher_lua(state: &mut ExeState) -> Result<i32, LuaError> {
+   let name = state.check_string(1)?;
+   let name = name.to_string_lossy();
+   let Value::Table(lib) = registry_get(state, PACKAGE) else { unreachable!() };
+   let path = lib.borrow().get_str("path");
+   if !path.is_string() {
+      return Err(state.error("'package.path' must be a string"));
+   }
+   let path = path.to_string_lossy();
+   let filename = match search_path(&name, &path, ".", "/") {
+      Ok(filename) => filename,
+      Err(tried) => {
+         state.push(Value::from(tried));
+         return Ok(1);
+      }
+   };
+   match state.load_file(Some(&filename), "bt", None) {
+      Ok(loader) => {
+         state.push(loader);
+         state.push(Value::from(filename));
+         Ok(2)
+      }
+      Err(err) => Err(state.error(&format!("error loading module '{name}' from file '{filename}':\n\t{err}"))),
+   }
+}
+
+// modules registered from Rust, loaded by native_loader
+fn searcher_native(state: &mut ExeState) -> Result<i32, LuaError> {
+   let name = state.check_string(1)?;
+   let name = name.to_string_lossy();
+   if state.native_modules().get(&name).is_none() {
+      state.push(Value::from(format!("no native module '{name}'")));
+      return Ok(1);
+   }
+   state.push(Value::Function(native_loader));
+   state.push(Value::from(":native:"));
+   Ok(2)
+}
+
+fn native_loader(state: &mut ExeState) -> Result<i32, LuaError> {
+   let name = state.check_string(1)?;
+   let name = name.to_string_lossy();
+   let Some(loader) = state.native_modules().get(&name) else {
+      return Err(state.error(&format!("no native module '{name}'")));
+   };
+   let module = loader(state)?;
+   state.push(module);
+   Ok(1)
+}
+
+// builds the value of a module from Rust; see ExeState::register_module
+pub type ModuleLoader = Rc<dyn Fn(&mut ExeState) -> Result<Value, LuaError>>;
+
+// the modules registered from Rust, by name
+#[derive(Default)]
+pub struct NativeModules(HashMap<String, ModuleLoader>);
+
+impl NativeModules {
+   pub fn insert(&mut self, name: &str, loader: ModuleLoader) {
+      self.0.insert(name.to_string(), loader);
+   }
+
+   pub fn get(&self, name: &str) -> Option<ModuleLoader> {
+      self.0.get(name).cloned()
+   }
+}
+
+impl fmt::Debug for NativeModules {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      f.debug_set().entries(self.0.keys()).finish()
+   }
+}
+
+// The first file of `path` (templates separated by ';') that can be
+// read, with '?' replaced by `name`, in which every `sep` is first
+// replaced by `rep`. On failure, the "no file" lines of all the tries.
+fn search_path(name: &str, path: &str, sep: &str, rep: &str) -> Result<String, String> {
+   let name = if sep.is_empty() { name.to_string() } else { name.replace(sep, rep) };
+   let mut tried = Vec::new();
+   for template in path.split(';').filter(|t| !t.is_empty()) {
+      let filename = template.replace('?', &name);
+      if File::open(&filename).is_ok() {
+         return Ok(filename);
+      }
+      tried.push(format!("no file '{filename}'"));
+   }
+   Err(tried.join("\n\t"))
+}
+
+// searchpath(name, path [, sep [, rep]])
+fn pkg_searchpath(state: &mut ExeState) -> Result<i32, LuaError> {
+   let mut args = Vec::new();
+   for (i, default) in [(1, None), (2, None), (3, Some(".")), (4, Some("/"))] {
+      let arg = match (state.get(i), default) {
+         (Value::Nil, Some(default)) => String::from(default),
+         _ => state.check_string(i)?.to_string_lossy(),
+      };
+      args.push(arg);
+   }
+   match search_path(&args[0], &args[1], &args[2], &args[3]) {
+      Ok(filename) => {
+         state.push(Value::from(filename));
+         Ok(1)
+      }
+      Err(tried) => {
+         state.push(Value::Nil);
+         state.push(Value::from(tried));
+         Ok(2)
+      }
+   }
+}
+
+// loadlib(path, funcname): there are no C libraries to load
+fn pkg_loadlib(state: &mut ExeState) -> Result<i32, LuaError> {
+   state.check_string(1)?;
+   state.check_string(2)?;
+   state.push(Value::Nil);
+   state.push(Value::from("dynamic libraries not enabled; check your Lua installation"));
+   state.push(Value::from("absent"));
+   Ok(3)
+}
+
+
+#[cfg(test)]
+mod tests {
+   use std::{cell::Cell, path::{Path, PathBuf}};
+
+   use super::*;
+
+   fn eval(state: &mut ExeState, source: &str) -> Result<Vec<Value>, LuaError> {
+      let func = state.load(source.as_bytes(), "=test", "t", None)?;
+      state.call(&func, &[])
+   }
+
+   // a directory of its own for the Lua files of a test
+   fn module_dir(test: &str) -> PathBuf {
+      let dir = env::temp_dir().join(format!("lua_llvm-{test}-{}", std::process::id()));
+      std::fs::create_dir_all(&dir).unwrap();
+      dir
+   }
+
+   fn state_with_path(dir: &Path) -> ExeState {
+      let state = ExeState::new();
+      let Value::Table(package) = state.get_global("package") else { panic!("no package table") };
+      package.borrow_mut().set_str("path", Value::from(format!("{}/?.lua", dir.display())));
+      state
+   }
+
+   fn double(state: &mut ExeState) -> Result<i32, LuaError> {
+      let n = state.check_integer(1)?;
+      state.push(Value::Integer(n * 2));
+      Ok(1)
+   }
+
+   #[test]
+   fn require_native_module() {
+      let mut state = ExeState::new();
+      state.register_module("native", |_| {
+         let mut t = Table::new(0, 1);
+         t.set_str("double", Value::Function(double));
+         Ok(Value::Table(Rc::new(RefCell::new(t))))
+      });
+      let r = eval(&mut state, "local m = require('native')
+         return m.double(21), package.loaded.native == m").unwrap();
+      assert_eq!(r, [Value::Integer(42), Value::Boolean(true)]);
+   }
+
+   #[test]
+   fn native_module_loads_once() {
+      let mut state = ExeState::new();
+      let count = Rc::new(Cell::new(0));
+      let c = count.clone();
+      state.register_module("once", move |_| {
+         c.set(c.get() + 1);
+         Ok(Value::from("module"))
+      });
+      let r = eval(&mut state, "return require('once') == require('once')").unwrap();
+      assert_eq!(r, [Value::Boolean(true)]);
+      assert_eq!(count.get(), 1);
+   }
+
+   #[test]
+   fn native_module_before_files() {
+      let dir = module_dir("shadow");
+      std::fs::write(dir.join("shadowed.lua"), "return 'from file'").unwrap();
+      std::fs::write(dir.join("plain.lua"), "return 'from file'").unwrap();
+
+      let mut state = state_with_path(&dir);
+      state.register_module("shadowed", |_| Ok(Value::from("from rust")));
+      let r = eval(&mut state, "return require('shadowed'), (require('plain'))").unwrap();
+      assert_eq!(r, [Value::from("from rust"), Value::from("from file")]);
+
+      // package.preload still comes first
+      let mut state = state_with_path(&dir);
+      state.register_module("shadowed", |_| Ok(Value::from("from rust")));
+      let r = eval(&mut state, "package.preload.shadowed = function() return 'preloaded' end
+         return (require('shadowed'))").unwrap();
+      assert_eq!(r, [Value::from("preloaded")]);
+
+      std::fs::remove_dir_all(&dir).unwrap();
+   }
+
+   #[test]
+   fn missing_module_lists_searches() {
+      let dir = module_dir("missing");
+      let mut state = state_with_path(&dir);
+      let err = eval(&mut state, "require('nowhere')").unwrap_err().to_string();
+      let expected = format!("test:1: module 'nowhere' not found:\n\
+         \tno field package.preload['nowhere']\n\
+         \tno native module 'nowhere'\n\
+         \tno file '{}/nowhere.lua'", dir.display());
+      assert_eq!(err, expected);
+      std::fs::remove_dir_all(&dir).unwrap();
+   }
+}
@@ -0,0 +1,1066 @@
+use std::{cell::{Cell, RefCell}, rc::Rc};
+
+use crate::{value::{Value, Table}, vm::ExeState, error::LuaError, pattern::{self, Capture, Matcher}};
+
+
+// longest string rep() and format() may build
+const MAX_STRING: usize = i32::MAX as usize;
+
+// characters with a meaning in patterns
+const SPECIALS: &[u8] = b"^$*+?.([%-";
+
+
+// the `string` table, also the __index of the metatable of all strings,
+// so that `s:upper()` works
+pub fn open(state: &mut ExeState) {
+   let mut lib = Table::new(0, 16);
+   lib.set_str("len", Value::Function(str_len));
+   lib.set_str("sub", Value::Function(str_sub));
+   lib.set_str("upper", Value::Function(str_upper));
+   lib.set_str("lower", Value::Function(str_lower));
+   lib.set_str("rep", Value::Function(str_rep));
+   lib.set_str("reverse", Value::Function(str_reverse));
+   lib.set_str("byte", Value::Function(str_byte));
+   lib.set_str("char", Value::Function(str_char));
+   lib.set_str("format", Value::Function(str_format));
+   lib.set_str("find", Value::Function(str_find));
+   lib.set_str("match", Value::Function(str_match));
+   lib.set_str("gmatch", Value::Function(str_gmatch));
+   lib.set_str("gsub", Value::Function(str_gsub));
+   lib.set_str("pack", Value::Function(str_pack));
+   lib.set_str("unpack", Value::Function(str_unpack));
+   lib.set_str("packsize", Value::Function(str_packsize));
+   let lib = Value::Table(Rc::new(RefCell::new(lib)));
+
+   let mut meta = Table::new(0, 1);
+   meta.set_str("__index", lib.clone());
+   state.set_string_metatable(meta);
+   state.set_global("string", lib);
+}
+
+// Start of a substring from a position given to a string function:
+// negative positions count from the end, and out-of-range ones are clipped.
+fn start_pos(pos: i64, len: usize) -> usize {
+   let len = len as i64;
+   if pos > 0 {
+      pos as usize
+   } else if pos == 0 || pos < -len {
+      1
+   } else {
+      (len + pos + 1) as usize
+   }
+}
+
+// end of a substring, inclusive; 0 for an empty one
+fn end_pos(pos: i64, len: usize) -> usize {
+   let ilen = len as i64;
+   if pos > ilen {
+      len
+   } else if pos >= 0 {
+      pos as usize
+   } else if pos < -ilen {
+      0
+   } else {
+      (ilen + pos + 1) as usize
+   }
+}
+
+fn str_len(state: &mut ExeState) -> Result<i32, LuaError> {
+   let s = state.check_string(1)?;
+   let len = s.as_bytes().len();
+   state.push(Value::Integer(len as i64));
+   Ok(1)
+}
+
+// sub(s, i [, j]): the bytes from i to j
+fn str_sub(state: &mut ExeState) -> Result<i32, LuaError> {
+   let s = state.check_string(1)?;
+   let s = s.as_bytes();
+   let start = start_pos(state.check_integer(2)?, s.len());
+   let end = end_pos(state.opt_integer(3, -1)?, s.len());
+   let sub = if start > end { &[][..] } else { &s[start - 1 .. end] };
+   state.push(Value::from(sub));
+   Ok(1)
+}
+
+// case conversions follow the C locale: only ASCII letters change
+fn str_upper(state: &mut ExeState) -> Result<i32, LuaError> {
+   let s = state.check_string(1)?;
+   state.push(Value::from(s.as_bytes().to_ascii_uppercase()));
+   Ok(1)
+}
+
+fn str_lower(state: &mut ExeState) -> Result<i32, LuaError> {
+   let s = state.check_string(1)?;
+   state.push(Value::from(s.as_bytes().to_ascii_lowercase()));
+   Ok(1)
+}
+
+// rep(s, n [, sep]): n copies of s separated by sep
+fn str_rep(state: &mut ExeState) -> Result<i32, LuaError> {
+   let s = state.check_string(1)?;
+   let n = state.check_integer(2)?;
+   let sep = match state.get(3) {
+      Value::Nil => Value::from(""),
+      _ => state.check_string(3)?,
+   };
+   let (s, sep) = (s.as_bytes(), sep.as_bytes());
+   if n <= 0 {
+      state.push(Value::from(""));
+      return Ok(1);
+   }
+
+   let n = n as usize;
+   let total = match (s.len() + sep.len()).checked_mul(n) {
+      Some(t) if t - sep.len() <= MAX_STRING => t - sep.len(),
+      _ => return Err(state.error("resulting string too large")),
+   };
+   let mut result = Vec::with_capacity(total);
+   for i in 0..n {
+      if i > 0 {
+         result.extend_from_slice(sep);
+      }
+      result.extend_from_slice(s);
+   }
+   state.push(Value::from(result));
+   Ok(1)
+}
+
+fn str_reverse(state: &mut ExeState) -> Result<i32, LuaError> {
+   let s = state.check_string(1)?;
+   let mut s = s.as_bytes().to_vec();
+   s.reverse();
+   state.push(Value::from(s));
+   Ok(1)
+}
+
+// byte(s [, i [, j]]): the codes of the bytes from i (default 1) to j
+// (default i)
+fn str_byte(state: &mut ExeState) -> Result<i32, LuaError> {
+   let s = state.check_string(1)?;
+   let s = s.as_bytes();
+   let i = state.opt_integer(2, 1)?;
+   let end = end_pos(state.opt_integer(3, i)?, s.len());
+   let start = start_pos(i, s.len());
+   if start > end {
+      return Ok(0);
+   }
+   if end - start >= i32::MAX as usize {
+      return Err(state.error("string slice too long"));
+   }
+   for &b in &s[start - 1 .. end] {
+      state.push(Value::Integer(b as i64));
+   }
+   Ok((end - start + 1) as i32)
+}
+
+// char(...): the string of the given byte codes
+fn str_char(state: &mut ExeState) -> Result<i32, LuaError> {
+   let mut s = Vec::with_capacity(state.get_top());
+   for i in 1..=state.get_top() {
+      let c = state.check_integer(i)?;
+      match u8::try_from(c) {
+         Ok(c) => s.push(c),
+         Err(_) => return Err(state.arg_error(i, "value out of range")),
+      }
+   }
+   state.push(Value::from(s));
+   Ok(1)
+}
+
+// find(s, pattern [, init [, plain]]): the start and end of the first
+// match from `init`, followed by its captures, or nil
+fn str_find(state: &mut ExeState) -> Result<i32, LuaError> {
+   find_aux(state, true)
+}
+
+// match(s, pattern [, init]): the captures of the first match from
+// `init`, or the whole match if the pattern has none; nil if none
+fn str_match(state: &mut ExeState) -> Result<i32, LuaError> {
+   find_aux(state, false)
+}
+
+fn find_aux(state: &mut ExeState, find: bool) -> Result<i32, LuaError> {
+   let s = state.check_string(1)?;
+   let p = state.check_string(2)?;
+   let (s, p) = (s.as_bytes(), p.as_bytes());
+   let init = start_pos(state.opt_integer(3, 1)?, s.len()) - 1;
+   if init > s.len() {
+      state.push(Value::Nil);
+      return Ok(1);
+   }
+
+   if find && (!state.get(4).is_false() || !p.iter().any(|c| SPECIALS.contains(c))) {
+      let found = if p.is_empty() {
+         Some(init)
+      } else {
+         s[init..].windows(p.len()).position(|w| w == p).map(|i| i + init)
+      };
+      if let Some(i) = found {
+         state.push(Value::Integer(i as i64 + 1));
+         state.push(Value::Integer((i + p.len()) as i64));
+         return Ok(2);
+      }
+   } else {
+      let mut m = Matcher::new(s, p);
+      if let Some((start, end)) = pattern::find(&mut m, init).map_err(|e| state.error(&e))? {
+         let captures = m.captures(start, end, !find).map_err(|e| state.error(&e))?;
+         let mut n = captures.len() as i32;
+         if find {
+            state.push(Value::Integer(start as i64 + 1));
+            state.push(Value::Integer(end as i64));
+            n += 2;
+         }
+         for c in captures {
+            state.push(capture_value(s, c));
+         }
+         return Ok(n);
+      }
+   }
+   state.push(Value::Nil);
+   Ok(1)
+}
+
+fn capture_value(s: &[u8], c: Capture) -> Value {
+   match c {
+      Capture::Str(start, end) => Value::from(&s[start..end]),
+      Capture::Position(i) => Value::Integer(i as i64),
+   }
+}
+
+// gmatch(s, pattern [, init]): an iterator over the matches, giving the
+// captures of each
+fn str_gmatch(state: &mut ExeState) -> Result<i32, LuaError> {
+   let s = state.check_string(1)?;
+   let p = state.check_string(2)?;
+   let len = s.as_bytes().len();
+   let init = (start_pos(state.opt_integer(3, 1)?, len) - 1).min(len + 1);
+
+   // where to go on from, and the end of the last match
+   let pos = Cell::new(init);
+   let last = Cell::new(None);
+   let iter = move |state: &mut ExeState| {
+      let (s, p) = (s.as_bytes(), p.as_bytes());
+      // '^' is not an anchor here: it would stop the iteration
+      let mut m = Matcher::new(s, p);
+      for start in pos.get() ..= s.len() {
+         match m.match_at(start, 0).map_err(|e| state.error(&e))? {
+            // an empty match right after the previous one is skipped
+            Some(end) if Some(end) != last.get() => {
+               pos.set(end);
+               last.set(Some(end));
+               let captures = m.captures(start, end, true).map_err(|e| state.error(&e))?;
+               let n = captures.len() as i32;
+               for c in captures {
+                  state.push(capture_value(s, c));
+               }
+               return Ok(n);
+            }
+            _ => (),
+         }
+      }
+      pos.set(s.len() + 1);
+      Ok(0)
+   };
+   state.push(Value::NativeClosure(Rc::new(iter)));
+   Ok(1)
+}
+
+// gsub(s, pattern, repl [, n]): a copy of `s` with (the first `n`)
+// matches replaced, and the number of matches.
+// `repl` is a string where %0-%9 stand for captures, a table indexed by
+// the first capture, or a function called with the captures; a false or
+// nil value from the last two keeps the match unchanged.
+fn str_gsub(state: &mut ExeState) -> Result<i32, LuaError> {
+   let s = state.check_string(1)?;
+   let p = state.check_string(2)?;
+   let (s, p) = (s.as_bytes(), p.as_bytes());
+   let repl = match state.get(3) {
+      Value::Integer(_) | Value::Float(_) => state.check_string(3)?,
+      v if v.is_string() => v.clone(),
+      v @ (Value::Table(_) | Value::Function(_) | Value::LuaFunction(_) | Value::NativeClosure(_)) => v.clone(),
+      _ => return Err(state.type_error(3, "string/function/table")),
+   };
+   let max = state.opt_integer(4, s.len() as i64 + 1)?;
+
+   let anchor = p.first() == Some(&b'^');
+   let mut m = Matcher::new(s, p);
+   let mut out = Vec::new();
+   let mut pos = 0;
+   let mut last = None;
+   let mut n = 0;
+   while n < max {
+      match m.match_at(pos, anchor as usize).map_err(|e| state.error(&e))? {
+         Some(end) if Some(end) != last => {
+            n += 1;
+            add_value(state, &m, &repl, s, pos, end, &mut out)?;
+            pos = end;
+            last = Some(end);
+         }
+         _ if pos < s.len() => {
+            out.push(s[pos]);
+            pos += 1;
+         }
+         _ => break,
+      }
+      if anchor {
+         break;
+      }
+   }
+   out.extend_from_slice(&s[pos..]);
+   state.push(Value::from(out));
+   state.push(Value::Integer(n));
+   Ok(2)
+}
+
+// append the replacement of the match start..end to `out`
+fn add_value(state: &mut ExeState, m: &Matcher, repl: &Value, s: &[u8],
+             start: usize, end: usize, out: &mut Vec<u8>) -> Result<(), LuaError> {
+   let value = match repl {
+      Value::Table(_) => {
+         let key = m.capture(0, start, end).map_err(|e| state.error(&e))?;
+         state.index(repl, &capture_value(s, key))?
+      }
+      Value::Function(_) | Value::LuaFunction(_) | Value::NativeClosure(_) => {
+         let captures = m.captures(start, end, true).map_err(|e| state.error(&e))?;
+         let args: Vec<Value> = captures.into_iter().map(|c| capture_value(s, c)).collect();
+         state.call(repl, &args)?.into_iter().next().unwrap_or(Value::Nil)
+      }
+      _ => return add_string(state, m, repl.as_bytes(), s, start, end, out),
+   };
+   match value {
+      v if v.is_false() => out.extend_from_slice(&s[start..end]),
+      v if v.is_string() => out.extend_from_slice(v.as_bytes()),
+      v @ (Value::Integer(_) | Value::Float(_)) => out.extend_from_slice(format!("{v:?}").as_bytes()),
+      v => {
+         let msg = format!("invalid replacement value (a {})", v.type_name());
+         return Err(state.error(&msg));
+      }
+   }
+   Ok(())
+}
+
+// append a replacement string, with %0-%9 replaced by the captures
+fn add_string(state: &ExeState, m: &Matcher, repl: &[u8], s: &[u8],
+              start: usize, end: usize, out: &mut Vec<u8>) -> Result<(), LuaError> {
+   let mut i = 0;
+   while i < repl.len() {
+      let c = repl[i];
+      i += 1;
+      if c != b'%' {
+         out.push(c);
+         continue;
+      }
+      match repl.get(i) {
+         Some(b'%') => out.push(b'%'),
+         Some(b'0') => out.extend_from_slice(&s[start..end]),
+         Some(&d) if d.is_ascii_digit() => {
+            match m.capture((d - b'1') as usize, start, end).map_err(|e| state.error(&e))? {
+               Capture::Str(a, b) => out.extend_from_slice(&s[a..b]),
+               Capture::Position(p) => out.extend_from_slice(p.to_string().as_bytes()),
+            }
+         }
+         _ => return Err(state.error("invalid use of '%' in replacement string")),
+      }
+      i += 1;
+   }
+   Ok(())
+}
+
+
+// flags each conversion accepts, as in the reference implementation
+const FLAGS_FLOAT: &[u8] = b"-+ #0";
+const FLAGS_HEX: &[u8] = b"-#0";
+const FLAGS_INT: &[u8] = b"-+ 0";
+const FLAGS_CHAR: &[u8] = b"-";
+
+// a parsed conversion specification: %[flags][width][.precision]conv
+struct Spec {
+   left: bool,     // '-'
+   plus: bool,     // '+'
+   space: bool,    // ' '
+   alt: bool,      // '#'
+   zero: bool,     // '0'
+   width: usize,
+   precision: Option<usize>,
+}
+
+impl Spec {
+   // Check the text of a specification, following its '%', against the
+   // flags the conversion allows and parse it. Width and precision have
+   // 2 digits at most.
+   fn parse(form: &[u8], flags: &[u8], precision: bool) -> Option<Spec> {
+      let mut spec = Spec { left: false, plus: false, space: false, alt: false, zero: false,
+                            width: 0, precision: None };
+      let mut i = 0;
+      while i < form.len() && flags.contains(&form[i]) {
+         match form[i] {
+            b'-' => spec.left = true,
+            b'+' => spec.plus = true,
+            b' ' => spec.space = true,
+            b'#' => spec.alt = true,
+            _ => spec.zero = true,
+         }
+         i += 1;
+      }
+      let two_digits = |i: &mut usize| {
+         let mut n = 0;
+         for _ in 0..2 {
+            if *i < form.len() && form[*i].is_ascii_digit() {
+               n = n * 10 + (form[*i] - b'0') as usize;
+               *i += 1;
+            }
+         }
+         n
+      };
+      if form.get(i) != Some(&b'0') {
+         spec.width = two_digits(&mut i);
+         if form.get(i) == Some(&b'.') && precision {
+            i += 1;
+            spec.precision = Some(two_digits(&mut i));
+         }
+      }
+      // only the conversion itself may be left
+      (i == form.len() - 1).then_some(spec)
+   }
+
+   // pad `body` to the width; zeros go after the sign or prefix
+   fn pad(&self, out: &mut Vec<u8>, prefix: &[u8], body: &[u8], zero: bool) {
+      let len = prefix.len() + body.len();
+      let fill = self.width.saturating_sub(len);
+      if self.left {
+         out.extend_from_slice(prefix);
+         out.extend_from_slice(body);
+         out.extend(std::iter::repeat_n(b' ', fill));
+      } else if zero {
+         out.extend_from_slice(prefix);
+         out.extend(std::iter::repeat_n(b'0', fill));
+         out.extend_from_slice(body);
+      } else {
+         out.extend(std::iter::repeat_n(b' ', fill));
+         out.extend_from_slice(prefix);
+         out.extend_from_slice(body);
+      }
+   }
+
+   fn sign(&self, negative: bool) -> &'static [u8] {
+      if negative {
+         b"-"
+      } else if self.plus {
+         b"+"
+      } else if self.space {
+         b" "
+      } else {
+         b""
+      }
+   }
+}
+
+// format(fmt, ...): printf-like formatting, with the conversions of C
+// plus %q for a Lua literal of the argument
+fn str_format(state: &mut ExeState) -> Result<i32, LuaError> {
+   let fmt = state.check_string(1)?;
+   let fmt = fmt.as_bytes();
+   let mut out = Vec::new();
+   let mut arg = 1;
+
+   let mut i = 0;
+   while i < fmt.len() {
+      let c = fmt[i];
+      i += 1;
+      if c != b'%' {
+         out.push(c);
+         continue;
+      }
+      if fmt.get(i) == Some(&b'%') {
+         out.push(b'%');
+         i += 1;
+         continue;
+      }
+
+      // the specification spans flags, digits and '.', then the conversion
+      let start = i;
+      while i < fmt.len() && b"-+ #0123456789.".contains(&fmt[i]) {
+         i += 1;
+      }
+      i += 1;
+      if i - start >= 22 {
+         return Err(state.error("invalid format string to 'format'"));
+      }
+      let form = &fmt[start .. i.min(fmt.len())];
+      let conv = form.last().copied().unwrap_or(0);
+      let form_text = || format!("%{}", String::from_utf8_lossy(form));
+      let parse = |flags, precision| Spec::parse(form, flags, precision).ok_or_else(||
+         state.error(&format!("invalid conversion specification: '{}'", form_text())));
+
+      arg += 1;
+      if conv.is_ascii_alphabetic() && arg > state.get_top() {
+         return Err(state.arg_error(arg, "no value"));
+      }
+      match conv {
+         b'c' => {
+            let spec = parse(FLAGS_CHAR, false)?;
+            let c = state.check_integer(arg)?;
+            spec.pad(&mut out, b"", &[c as u8], false);
+         }
+         b'd' | b'i' => {
+            let spec = parse(FLAGS_INT, true)?;
+            let n = state.check_integer(arg)?;
+            format_int(&spec, &mut out, n.unsigned_abs().to_string().as_bytes(), spec.sign(n < 0), b"");
+         }
+         b'o' | b'u' | b'x' | b'X' => {
+            let spec = parse(FLAGS_HEX, true)?;
+            let n = state.check_integer(arg)? as u64;
+            let (digits, prefix): (String, &[u8]) = match conv {
+               b'o' => (format!("{n:o}"), b""),
+               b'u' => (n.to_string(), b""),
+               b'x' => (format!("{n:x}"), if spec.alt && n != 0 { b"0x" } else { b"" }),
+               _ => (format!("{n:X}"), if spec.alt && n != 0 { b"0X" } else { b"" }),
+            };
+            // '#' makes the first octal digit a zero
+            let digits = if conv == b'o' && spec.alt && !digits.starts_with('0') {
+               format!("0{digits}")
+            } else {
+               digits
+            };
+            format_int(&spec, &mut out, digits.as_bytes(), b"", prefix);
+         }
+         b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G' => {
+            let spec = parse(FLAGS_FLOAT, true)?;
+            let n = match state.check_number(arg)? {
+               Value::Integer(i) => i as f64,
+               Value::Float(f) => f,
+               _ => unreachable!(),
+            };
+            let mut prefix = spec.sign(n.is_sign_negative()).to_vec();
+            let body = if n.is_nan() {
+               String::from("nan")
+            } else if n.is_infinite() {
+               String::from("inf")
+            } else {
+               match conv.to_ascii_lowercase() {
+                  b'a' => {
+                     // zeros of the width go after the "0x"
+                     prefix.extend_from_slice(b"0x");
+                     format_hex_float(n.abs(), spec.precision, spec.alt)
+                  }
+                  b'e' => format_exp(n.abs(), spec.precision.unwrap_or(6), spec.alt),
+                  b'f' => {
+                     let precision = spec.precision.unwrap_or(6);
+                     let body = format!("{:.*}", precision, n.abs());
+                     if spec.alt && precision == 0 { body + "." } else { body }
+                  }
+                  _ => format_general(n.abs(), spec.precision, spec.alt),
+               }
+            };
+            let (prefix, body) = if conv.is_ascii_uppercase() {
+               (prefix.to_ascii_uppercase(), body.to_uppercase())
+            } else {
+               (prefix, body)
+            };
+            let zero = spec.zero && n.is_finite();
+            spec.pad(&mut out, &prefix, body.as_bytes(), zero);
+         }
+         b's' => {
+            let spec = parse(FLAGS_CHAR, true)?;
+            let s = state.get(arg).clone();
+            let s = state.tostring(&s)?;
+            let s = s.as_bytes();
+            if form.len() > 1 && s.contains(&0) {
+               return Err(state.arg_error(arg, "string contains zeros"));
+            }
+            let s = match spec.precision {
+               Some(p) if p < s.len() => &s[..p],
+               _ => s,
+            };
+            spec.pad(&mut out, b"", s, false);
+         }
+         b'q' => {
+            if form.len() > 1 {
+               return Err(state.error("specifier '%q' cannot have modifiers"));
+            }
+            match state.get(arg) {
+               v if v.is_string() => add_quoted(&mut out, v.as_bytes()),
+               // the smallest integer has no decimal literal
+               Value::Integer(i64::MIN) => out.extend_from_slice(b"0x8000000000000000"),
+               Value::Integer(i) => out.extend_from_slice(i.to_string().as_bytes()),
+               Value::Float(f) if f.is_nan() => out.extend_from_slice(b"(0/0)"),
+               Value::Float(f) if f.is_infinite() => {
+                  out.extend_from_slice(if *f > 0.0 { b"1e9999" } else { b"-1e9999" });
+               }
+               // hexadecimal keeps every bit of the float
+               Value::Float(f) => {
+                  let sign = if f.is_sign_negative() { "-" } else { "" };
+                  let hex = format_hex_float(f.abs(), None, false);
+                  out.extend_from_slice(format!("{sign}0x{hex}").as_bytes());
+               }
+               v @ (Value::Nil | Value::Boolean(_)) => out.extend_from_slice(format!("{v:?}").as_bytes()),
+               _ => return Err(state.arg_error(arg, "value has no literal form")),
+            }
+         }
+         _ => {
+            let msg = format!("invalid conversion '{}' to 'format'", form_text());
+            return Err(state.error(&msg));
+         }
+      }
+      if out.len() > MAX_STRING {
+         return Err(state.error("resulting string too large"));
+      }
+   }
+
+   state.push(Value::from(out));
+   Ok(1)
+}
+
+// %e: d.ddde+XX, with at least 2 digits of exponent
+fn format_exp(n: f64, precision: usize, alt: bool) -> String {
+   let s = format!("{:.*e}", precision, n);
+   let (mantissa, exp) = s.split_once('e').unwrap();
+   let exp: i32 = exp.parse().unwrap();
+   let point = if alt && precision == 0 { "." } else { "" };
+   let sign = if exp < 0 { '-' } else { '+' };
+   format!("{mantissa}{point}e{sign}{:02}", exp.abs())
+}
+
+// %g: %e or %f, whichever suits the exponent, with `precision`
+// significant digits; trailing zeros are dropped unless '#' is given
+pub fn format_general(n: f64, precision: Option<usize>, alt: bool) -> String {
+   let precision = match precision {
+      None => 6,
+      Some(0) => 1,
+      Some(p) => p,
+   };
+   // the exponent after rounding to the precision
+   let e = format!("{:.*e}", precision - 1, n);
+   let exp: i32 = e.split_once('e').unwrap().1.parse().unwrap();
+   let mut s = if exp < -4 || exp >= precision as i32 {
+      format_exp(n, precision - 1, alt)
+   } else {
+      let s = format!("{:.*}", (precision as i32 - 1 - exp) as usize, n);
+      if alt && !s.contains('.') { s + "." } else { s }
+   };
+   if !alt {
+      let exp_at = s.find('e').unwrap_or(s.len());
+      let (mantissa, exp) = s.split_at(exp_at);
+      if mantissa.contains('.') {
+         s = format!("{}{exp}", mantissa.trim_end_matches('0').trim_end_matches('.'));
+      }
+   }
+   s
+}
+
+// %a without the sign and "0x": h.hhhp+d, by default with as many hex
+// digits as needed to be exact
+fn format_hex_float(n: f64, precision: Option<usize>, alt: bool) -> String {
+   let bits = n.to_bits();
+   let biased = ((bits >> 52) & 0x7ff) as i32;
+   let mut mantissa = bits & ((1 << 52) - 1);
+   let (mut lead, exp) = match biased {
+      0 if mantissa == 0 => (0, 0),
+      0 => (0, -1022), // subnormal
+      _ => (1, biased - 1023),
+   };
+   let digits = match precision {
+      Some(p) if p < 13 => {
+         // round half to even to p digits; the carry may reach the lead digit
+         let shift = (13 - p) * 4;
+         let full = (lead << 52) | mantissa;
+         let rest = full & ((1 << shift) - 1);
+         let half = 1 << (shift - 1);
+         let mut kept = full >> shift;
+         if rest > half || (rest == half && kept & 1 == 1) {
+            kept += 1;
+         }
+         lead = kept >> (p * 4);
+         mantissa = kept & ((1 << (p * 4)) - 1);
+         if p == 0 { String::new() } else { format!("{mantissa:0p$x}") }
+      }
+      Some(p) => format!("{mantissa:013x}{}", "0".repeat(p - 13)),
+      None => format!("{mantissa:013x}").trim_end_matches('0').to_string(),
+   };
+   let point = if !digits.is_empty() || alt { "." } else { "" };
+   let sign = if exp < 0 { '-' } else { '+' };
+   format!("{lead:x}{point}{digits}p{sign}{}", exp.abs())
+}
+
+// %q of a string: a double-quoted literal that reads back the same
+fn add_quoted(out: &mut Vec<u8>, s: &[u8]) {
+   out.push(b'"');
+   for (i, &c) in s.iter().enumerate() {
+      match c {
+         b'"' | b'\\' | b'\n' => out.extend_from_slice(&[b'\\', c]),
+         c if c.is_ascii_control() => {
+            // a following digit would be read as part of the escape
+            let escape = if s.get(i + 1).is_some_and(u8::is_ascii_digit) {
+               format!("\\{c:03}")
+            } else {
+               format!("\\{c}")
+            };
+            out.extend_from_slice(escape.as_bytes());
+         }
+         c => out.push(c),
+      }
+   }
+   out.push(b'"');
+}
+
+// an integer conversion: a precision is the minimum number of digits,
+// and disables the '0' flag
+fn format_int(spec: &Spec, out: &mut Vec<u8>, digits: &[u8], sign: &[u8], prefix: &[u8]) {
+   let mut body = Vec::new();
+   body.extend_from_slice(prefix);
+   match spec.precision {
+      // "%.0d" prints nothing for 0
+      Some(0) if digits == b"0" => (),
+      Some(p) => {
+         body.extend(std::iter::repeat_n(b'0', p.saturating_sub(digits.len())));
+         body.extend_from_slice(digits);
+      }
+      None => body.extend_from_slice(digits),
+   }
+   let zero = spec.zero && spec.precision.is_none();
+   if zero {
+      // zeros go between the prefix and the digits
+      let mut head = sign.to_vec();
+      head.extend_from_slice(prefix);
+      spec.pad(out, &head, &body[prefix.len()..], true);
+   } else {
+      spec.pad(out, sign, &body, false);
+   }
+}
+
+
+// largest size, in bytes, of the integers of pack() and unpack()
+const MAX_INT_SIZE: usize = 16;
+// size of a Lua integer
+const INT_SIZE: usize = 8;
+// default maximum alignment for '!'
+const MAX_ALIGN: usize = 8;
+
+// what a format option of pack() packs
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PackKind {
+   Int,
+   Uint,
+   Float,
+   Double,
+   Char,      // fixed-size string
+   Str,       // string preceded by its length
+   Zstr,      // zero-terminated string
+   Padding,   // one byte
+   PadAlign,  // align to the next option
+   Nop,       // settings and spaces
+}
+
+// state of reading a format string of pack(), unpack() and packsize()
+struct PackFormat<'a> {
+   fmt: &'a [u8],
+   pos: usize,
+   little: bool,
+   max_align: usize,
+}
+
+impl<'a> PackFormat<'a> {
+   fn new(fmt: &'a [u8]) -> Self {
+      PackFormat { fmt, pos: 0, little: cfg!(target_endian = "little"), max_align: 1 }
+   }
+
+   fn done(&self) -> bool {
+      self.pos == self.fmt.len()
+   }
+
+   // an optional number following an option
+   fn num(&mut self) -> Option<usize> {
+      if !self.fmt.get(self.pos).is_some_and(u8::is_ascii_digit) {
+         return None;
+      }
+      let mut n = 0;
+      while let Some(&c) = self.fmt.get(self.pos) {
+         if !c.is_ascii_digit() || n > (i32::MAX as usize - 9) / 10 {
+            break;
+         }
+         n = n * 10 + (c - b'0') as usize;
+         self.pos += 1;
+      }
+      Some(n)
+   }
+
+   fn num_limit(&mut self, state: &ExeState, default: usize) -> Result<usize, LuaError> {
+      let n = self.num().unwrap_or(default);
+      if n == 0 || n > MAX_INT_SIZE {
+         let msg = format!("integral size ({n}) out of limits [1,{MAX_INT_SIZE}]");
+         return Err(state.error(&msg));
+      }
+      Ok(n)
+   }
+
+   // the next option and its size
+   fn option(&mut self, state: &ExeState) -> Result<(PackKind, usize), LuaError> {
+      let c = self.fmt[self.pos];
+      self.pos += 1;
+      let option = match c {
+         b'b' => (PackKind::Int, 1),
+         b'B' => (PackKind::Uint, 1),
+         b'h' => (PackKind::Int, 2),
+         b'H' => (PackKind::Uint, 2),
+         b'l' | b'j' => (PackKind::Int, 8),
+         b'L' | b'J' | b'T' => (PackKind::Uint, 8),
+         b'f' => (PackKind::Float, 4),
+         b'n' | b'd' => (PackKind::Double, 8),
+         b'i' => (PackKind::Int, self.num_limit(state, 4)?),
+         b'I' => (PackKind::Uint, self.num_limit(state, 4)?),
+         b's' => (PackKind::Str, self.num_limit(state, 8)?),
+         b'c' => match self.num() {
+            Some(n) => (PackKind::Char, n),
+            None => return Err(state.error("missing size for format option 'c'")),
+         },
+         b'z' => (PackKind::Zstr, 0),
+         b'x' => (PackKind::Padding, 1),
+         b'X' => (PackKind::PadAlign, 0),
+         b' ' => (PackKind::Nop, 0),
+         b'<' => {
+            self.little = true;
+            (PackKind::Nop, 0)
+         }
+         b'>' => {
+            self.little = false;
+            (PackKind::Nop, 0)
+         }
+         b'=' => {
+            self.little = cfg!(target_endian = "little");
+            (PackKind::Nop, 0)
+         }
+         b'!' => {
+            self.max_align = self.num_limit(state, MAX_ALIGN)?;
+            (PackKind::Nop, 0)
+         }
+         c => {
+            let msg = format!("invalid format option '{}'", c as char);
+            return Err(state.error(&msg));
+         }
+      };
+      Ok(option)
+   }
+
+   // the next option, its size and the padding that aligns it after
+   // `total` bytes
+   fn details(&mut self, state: &ExeState, total: usize) -> Result<(PackKind, usize, usize), LuaError> {
+      let (kind, size) = self.option(state)?;
+      let mut align = size;
+      if kind == PackKind::PadAlign {
+         // 'X' aligns as the option following it
+         if self.done() {
+            return Err(state.arg_error(1, "invalid next option for option 'X'"));
+         }
+         let (next, next_size) = self.option(state)?;
+         if next == PackKind::Char || next_size == 0 {
+            return Err(state.arg_error(1, "invalid next option for option 'X'"));
+         }
+         align = next_size;
+      }
+      if align <= 1 || kind == PackKind::Char {
+         return Ok((kind, size, 0));
+      }
+      let align = align.min(self.max_align);
+      if !align.is_power_of_two() {
+         return Err(state.arg_error(1, "format asks for alignment not power of 2"));
+      }
+      Ok((kind, size, (align - (total & (align - 1))) & (align - 1)))
+   }
+}
+
+// `size` bytes of n; the bytes past a Lua integer extend its sign
+fn pack_int(out: &mut Vec<u8>, n: u64, little: bool, size: usize, negative: bool) {
+   let start = out.len();
+   for i in 0..size {
+      out.push(if i < INT_SIZE {
+         (n >> (8 * i)) as u8
+      } else if negative {
+         0xff
+      } else {
+         0
+      });
+   }
+   if !little {
+      out[start..].reverse();
+   }
+}
+
+fn unpack_int(state: &ExeState, data: &[u8], little: bool, signed: bool) -> Result<i64, LuaError> {
+   let size = data.len();
+   let byte = |i: usize| data[if little { i } else { size - 1 - i }];
+   let limit = size.min(INT_SIZE);
+   let mut n = (0..limit).rev().fold(0u64, |n, i| n << 8 | byte(i) as u64);
+   if size < INT_SIZE {
+      if signed {
+         let mask = 1u64 << (size * 8 - 1);
+         n = (n ^ mask).wrapping_sub(mask);
+      }
+   } else if size > INT_SIZE {
+      // the extra bytes can only be a sign extension
+      let fill = if signed && (n as i64) < 0 { 0xff } else { 0 };
+      if (limit..size).any(|i| byte(i) != fill) {
+         let msg = format!("{size}-byte integer does not fit into Lua Integer");
+         return Err(state.error(&msg));
+      }
+   }
+   Ok(n as i64)
+}
+
+// pack(fmt, v1, v2, ...): the values in binary form, as the format says
+fn str_pack(state: &mut ExeState) -> Result<i32, LuaError> {
+   let fmt = state.check_string(1)?;
+   let mut fmt = PackFormat::new(fmt.as_bytes());
+   let mut out = Vec::new();
+   let mut arg = 1;
+   while !fmt.done() {
+      let (kind, size, align) = fmt.details(state, out.len())?;
+      out.extend(std::iter::repeat_n(0, align));
+      arg += 1;
+      match kind {
+         PackKind::Int => {
+            let n = state.check_integer(arg)?;
+            if size < INT_SIZE {
+               let limit = 1i64 << (size * 8 - 1);
+               if n < -limit || n >= limit {
+                  return Err(state.arg_error(arg, "integer overflow"));
+               }
+            }
+            pack_int(&mut out, n as u64, fmt.little, size, n < 0);
+         }
+         PackKind::Uint => {
+            let n = state.check_integer(arg)? as u64;
+            if size < INT_SIZE && n >= 1 << (size * 8) {
+               return Err(state.arg_error(arg, "unsigned overflow"));
+            }
+            pack_int(&mut out, n, fmt.little, size, false);
+         }
+         PackKind::Float => {
+            let f = state.check_float(arg)? as f32;
+            out.extend(if fmt.little { f.to_le_bytes() } else { f.to_be_bytes() });
+         }
+         PackKind::Double => {
+            let f = state.check_float(arg)?;
+            out.extend(if fmt.little { f.to_le_bytes() } else { f.to_be_bytes() });
+         }
+         PackKind::Char => {
+            let s = state.check_string(arg)?;
+            let s = s.as_bytes();
+            if s.len() > size {
+               return Err(state.arg_error(arg, "string longer than given size"));
+            }
+            out.extend_from_slice(s);
+            out.extend(std::iter::repeat_n(0, size - s.len()));
+         }
+         PackKind::Str => {
+            let s = state.check_string(arg)?;
+            let s = s.as_bytes();
+            if size < INT_SIZE && s.len() as u64 >= 1 << (size * 8) {
+               return Err(state.arg_error(arg, "string length does not fit in given size"));
+            }
+            pack_int(&mut out, s.len() as u64, fmt.little, size, false);
+            out.extend_from_slice(s);
+         }
+         PackKind::Zstr => {
+            let s = state.check_string(arg)?;
+            let s = s.as_bytes();
+            if s.contains(&0) {
+               return Err(state.arg_error(arg, "string contains zeros"));
+            }
+            out.extend_from_slice(s);
+            out.push(0);
+         }
+         PackKind::Padding => {
+            out.push(0);
+            arg -= 1;
+         }
+         PackKind::PadAlign | PackKind::Nop => arg -= 1,
+      }
+      if out.len() > MAX_STRING {
+         return Err(state.error("resulting string too large"));
+      }
+   }
+   state.push(Value::from(out));
+   Ok(1)
+}
+
+// packsize(fmt): the length of the result of pack(fmt, ...)
+fn str_packsize(state: &mut ExeState) -> Result<i32, LuaError> {
+   let fmt = state.check_string(1)?;
+   let mut fmt = PackFormat::new(fmt.as_bytes());
+   let mut total: usize = 0;
+   while !fmt.done() {
+      let (kind, size, align) = fmt.details(state, total)?;
+      if kind == PackKind::Str || kind == PackKind::Zstr {
+         return Err(state.arg_error(1, "variable-length format"));
+      }
+      total = match total.checked_add(size + align) {
+         Some(total) if total <= i64::MAX as usize => total,
+         _ => return Err(state.arg_error(1, "format result too large")),
+      };
+   }
+   state.push(Value::Integer(total as i64));
+   Ok(1)
+}
+
+// unpack(fmt, s [, pos]): the values packed in `s` from `pos`, followed
+// by the position after them
+fn str_unpack(state: &mut ExeState) -> Result<i32, LuaError> {
+   let fmt = state.check_string(1)?;
+   let mut fmt = PackFormat::new(fmt.as_bytes());
+   let data = state.check_string(2)?;
+   let data = data.as_bytes();
+   let mut pos = start_pos(state.opt_integer(3, 1)?, data.len()) - 1;
+   if pos > data.len() {
+      return Err(state.arg_error(3, "initial position out of string"));
+   }
+   let mut n = 0;
+   while !fmt.done() {
+      let (kind, size, align) = fmt.details(state, pos)?;
+      if align + size > data.len() - pos {
+         return Err(state.arg_error(2, "data string too short"));
+      }
+      pos += align;
+      let bytes = &data[pos .. pos + size];
+      let v = match kind {
+         PackKind::Int | PackKind::Uint =>
+            Value::Integer(unpack_int(state, bytes, fmt.little, kind == PackKind::Int)?),
+         PackKind::Float => {
+            let bytes = bytes.try_into().unwrap();
+            let f = if fmt.little { f32::from_le_bytes(bytes) } else { f32::from_be_bytes(bytes) };
+            Value::Float(f as f64)
+         }
+         PackKind::Double => {
+            let bytes = bytes.try_into().unwrap();
+            Value::Float(if fmt.little { f64::from_le_bytes(bytes) } else { f64::from_be_bytes(bytes) })
+         }
+         PackKind::Char => Value::from(bytes),
+         PackKind::Str => {
+            let len = unpack_int(state, bytes, fmt.little, false)? as u64 as usize;
+            if len > data.len() - pos - size {
+               return Err(state.arg_error(2, "data string too short"));
+            }
+            let s = Value::from(&data[pos + size .. pos + size + len]);
+            pos += len;
+            s
+         }
+         PackKind::Zstr => {
+            let Some(len) = data[pos..].iter().position(|&c| c == 0) else {
+               return Err(state.arg_error(2, "unfinished string for format 'z'"));
+            };
+            let s = Value::from(&data[pos .. pos + len]);
+            pos += len + 1;
+            s
+         }
+         PackKind::Padding | PackKind::PadAlign | PackKind::Nop => {
+            pos += size;
+            continue;
+         }
+      };
+      state.push(v);
+      n += 1;
+      pos += size;
+   }
+   state.push(Value::Integer(pos as i64 + 1));
+   Ok(n + 1)
+}
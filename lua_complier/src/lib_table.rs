@@ -0,0 +1,348 @@
+use std::{cell::RefCell, rc::Rc, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::{value::{Value, Table}, vm::ExeState, error::LuaError};
+
+
+// below this many elements, sort() always picks the middle as pivot
+const RANDOM_LIMIT: i64 = 100;
+
+
+// the `table` table
+pub fn open(state: &mut ExeState) {
+   let mut lib = Table::new(0, 8);
+   lib.set_str("insert", Value::Function(tab_insert));
+   lib.set_str("remove", Value::Function(tab_remove));
+   lib.set_str("concat", Value::Function(tab_concat));
+   lib.set_str("pack", Value::Function(tab_pack));
+   lib.set_str("unpack", Value::Function(tab_unpack));
+   lib.set_str("move", Value::Function(tab_move));
+   lib.set_str("sort", Value::Function(tab_sort));
+   state.set_global("table", Value::Table(Rc::new(RefCell::new(lib))));
+}
+
+// Check that argument `i` is a table, or has a metatable with all the
+// metamethods the function needs to use it as one.
+fn check_table(state: &ExeState, i: usize, events: &[&str]) -> Result<(), LuaError> {
+   let v = state.get(i);
+   if let Value::Table(_) = v {
+      return Ok(());
+   }
+   if events.iter().any(|e| state.metamethod(v, e) == Value::Nil) {
+      return Err(state.type_error(i, "table"));
+   }
+   Ok(())
+}
+
+// the length of the table argument `i`, which must be an integer
+fn table_len(state: &mut ExeState, i: usize, events: &[&str]) -> Result<i64, LuaError> {
+   check_table(state, i, events)?;
+   let t = state.get(i).clone();
+   match state.len(&t)? {
+      Value::Integer(n) => Ok(n),
+      _ => Err(state.error("object length is not an integer")),
+   }
+}
+
+fn get_int(state: &mut ExeState, t: &Value, i: i64) -> Result<Value, LuaError> {
+   state.index(t, &Value::Integer(i))
+}
+
+fn set_int(state: &mut ExeState, t: &Value, i: i64, v: Value) -> Result<(), LuaError> {
+   state.set_index(t, Value::Integer(i), v)
+}
+
+// insert(t, [pos,] v): insert `v` at `pos`, by default at the end,
+// shifting up the elements after it
+fn tab_insert(state: &mut ExeState) -> Result<i32, LuaError> {
+   let first_empty = table_len(state, 1, &["__index", "__newindex", "__len"])?.wrapping_add(1);
+   let t = state.get(1).clone();
+   let pos = match state.get_top() {
+      2 => first_empty,
+      3 => {
+         let pos = state.check_integer(2)?;
+         // pos in [1, first_empty]
+         if (pos as u64).wrapping_sub(1) >= first_empty as u64 {
+            return Err(state.arg_error(2, "position out of bounds"));
+         }
+         for i in (pos + 1 ..= first_empty).rev() {
+            let v = get_int(state, &t, i - 1)?;
+            set_int(state, &t, i, v)?;
+         }
+         pos
+      }
+      _ => return Err(state.error("wrong number of arguments to 'insert'")),
+   };
+   let v = state.get(state.get_top()).clone();
+   set_int(state, &t, pos, v)?;
+   Ok(0)
+}
+
+// remove(t [, pos]): remove and return the element at `pos`, by default
+// the last one, shifting down the elements after it
+fn tab_remove(state: &mut ExeState) -> Result<i32, LuaError> {
+   let size = table_len(state, 1, &["__index", "__newindex", "__len"])?;
+   let t = state.get(1).clone();
+   let mut pos = state.opt_integer(2, size)?;
+   // pos in [1, size + 1]
+   if pos != size && (pos as u64).wrapping_sub(1) > size as u64 {
+      return Err(state.arg_error(2, "position out of bounds"));
+   }
+   let removed = get_int(state, &t, pos)?;
+   while pos < size {
+      let v = get_int(state, &t, pos + 1)?;
+      set_int(state, &t, pos, v)?;
+      pos += 1;
+   }
+   set_int(state, &t, pos, Value::Nil)?;
+   state.push(removed);
+   Ok(1)
+}
+
+// concat(t [, sep [, i [, j]]]): t[i]..sep..t[i+1]..sep..t[j], where all
+// the elements are strings or numbers
+fn tab_concat(state: &mut ExeState) -> Result<i32, LuaError> {
+   let len = table_len(state, 1, &["__index", "__len"])?;
+   let t = state.get(1).clone();
+   let sep = match state.get(2) {
+      Value::Nil => Value::from(""),
+      _ => state.check_string(2)?,
+   };
+   let sep = sep.as_bytes();
+   let first = state.opt_integer(3, 1)?;
+   let last = state.opt_integer(4, len)?;
+
+   let mut out = Vec::new();
+   let mut i = first;
+   while i <= last {
+      match get_int(state, &t, i)? {
+         v if v.is_string() => out.extend_from_slice(v.as_bytes()),
+         v @ (Value::Integer(_) | Value::Float(_)) => out.extend_from_slice(format!("{v:?}").as_bytes()),
+         _ => {
+            let msg = format!("invalid value (at index {i}) in table for 'concat'");
+            return Err(state.error(&msg));
+         }
+      }
+      if i == last {
+         break;
+      }
+      out.extend_from_slice(sep);
+      i += 1;
+   }
+   state.push(Value::from(out));
+   Ok(1)
+}
+
+// pack(...): a table of the arguments, with their number in field "n"
+fn tab_pack(state: &mut ExeState) -> Result<i32, LuaError> {
+   let n = state.get_top();
+   let mut t = Table::new(n, 1);
+   for i in 1..=n {
+      t.set_int(i as i64, state.get(i).clone());
+   }
+   t.set_str("n", Value::Integer(n as i64));
+   state.push(Value::Table(Rc::new(RefCell::new(t))));
+   Ok(1)
+}
+
+// unpack(t [, i [, j]]): t[i], ..., t[j], by default the whole sequence
+fn tab_unpack(state: &mut ExeState) -> Result<i32, LuaError> {
+   let t = state.get(1).clone();
+   let first = state.opt_integer(2, 1)?;
+   let last = match state.get(3) {
+      Value::Nil => match state.len(&t)? {
+         Value::Integer(n) => n,
+         _ => return Err(state.error("object length is not an integer")),
+      },
+      _ => state.check_integer(3)?,
+   };
+   if first > last {
+      return Ok(0);
+   }
+   let n = (last as u64).wrapping_sub(first as u64);
+   if n >= i32::MAX as u64 || !state.check_stack(n as usize + 1) {
+      return Err(state.error("too many results to unpack"));
+   }
+   for i in first..=last {
+      let v = get_int(state, &t, i)?;
+      state.push(v);
+   }
+   Ok(n as i32 + 1)
+}
+
+// move(a1, f, e, t [, a2]): a2[t], ... = a1[f], ..., a1[e]; a2 is a1 by
+// default. Overlapping ranges are copied in the safe direction.
+fn tab_move(state: &mut ExeState) -> Result<i32, LuaError> {
+   let f = state.check_integer(2)?;
+   let e = state.check_integer(3)?;
+   let t = state.check_integer(4)?;
+   let dst_arg = if let Value::Nil = state.get(5) { 1 } else { 5 };
+   check_table(state, 1, &["__index"])?;
+   check_table(state, dst_arg, &["__newindex"])?;
+   let src = state.get(1).clone();
+   let dst = state.get(dst_arg).clone();
+
+   if e >= f {
+      if f <= 0 && e >= i64::MAX + f {
+         return Err(state.arg_error(3, "too many elements to move"));
+      }
+      let n = e - f + 1;
+      if t > i64::MAX - n + 1 {
+         return Err(state.arg_error(4, "destination wrap around"));
+      }
+      if t > e || t <= f || (dst_arg != 1 && src != dst) {
+         for i in 0..n {
+            let v = get_int(state, &src, f + i)?;
+            set_int(state, &dst, t + i, v)?;
+         }
+      } else {
+         for i in (0..n).rev() {
+            let v = get_int(state, &src, f + i)?;
+            set_int(state, &dst, t + i, v)?;
+         }
+      }
+   }
+   state.push(dst);
+   Ok(1)
+}
+
+// sort(t [, comp]): sort the sequence in place with `comp(a, b)` as
+// "a < b", by default the < operator.
+// This is the quicksort of the reference implementation; an inconsistent
+// `comp` is reported as an error instead of running out of bounds.
+fn tab_sort(state: &mut ExeState) -> Result<i32, LuaError> {
+   let n = table_len(state, 1, &["__index", "__newindex", "__len"])?;
+   if n > 1 {
+      if n >= i32::MAX as i64 {
+         return Err(state.arg_error(1, "array too big"));
+      }
+      let comp = state.get(2).clone();
+      match comp {
+         Value::Nil | Value::Function(_) | Value::LuaFunction(_) | Value::NativeClosure(_) => (),
+         _ => return Err(state.type_error(2, "function")),
+      }
+      let mut sort = Sort { t: state.get(1).clone(), comp };
+      sort.sort(state, 1, n, 0)?;
+   }
+   Ok(0)
+}
+
+struct Sort {
+   t: Value,
+   comp: Value,
+}
+
+impl Sort {
+   fn less(&self, state: &mut ExeState, a: &Value, b: &Value) -> Result<bool, LuaError> {
+      if let Value::Nil = self.comp {
+         state.less_than(a, b)
+      } else {
+         let r = state.call(&self.comp, &[a.clone(), b.clone()])?;
+         Ok(!r.first().unwrap_or(&Value::Nil).is_false())
+      }
+   }
+
+   fn get(&self, state: &mut ExeState, i: i64) -> Result<Value, LuaError> {
+      get_int(state, &self.t, i)
+   }
+
+   fn set(&self, state: &mut ExeState, i: i64, v: Value) -> Result<(), LuaError> {
+      set_int(state, &self.t, i, v)
+   }
+
+   // sort t[lo..=up]; `rnd` randomizes the pivot once partitions turn
+   // out unbalanced
+   fn sort(&mut self, state: &mut ExeState, mut lo: i64, mut up: i64, mut rnd: u64) -> Result<(), LuaError> {
+      while lo < up {
+         // sort t[lo], t[p] and t[up]
+         let (a_lo, a_up) = (self.get(state, lo)?, self.get(state, up)?);
+         if self.less(state, &a_up, &a_lo)? {
+            self.set(state, lo, a_up)?;
+            self.set(state, up, a_lo)?;
+         }
+         if up - lo == 1 {
+            break;
+         }
+         let p = if up - lo < RANDOM_LIMIT || rnd == 0 {
+            lo + (up - lo) / 2
+         } else {
+            let r4 = (up - lo) / 4;
+            (rnd % (r4 as u64 * 2)) as i64 + lo + r4
+         };
+         let (a_p, a_lo) = (self.get(state, p)?, self.get(state, lo)?);
+         if self.less(state, &a_p, &a_lo)? {
+            self.set(state, p, a_lo)?;
+            self.set(state, lo, a_p)?;
+         } else {
+            let a_up = self.get(state, up)?;
+            if self.less(state, &a_up, &a_p)? {
+               self.set(state, p, a_up)?;
+               self.set(state, up, a_p)?;
+            }
+         }
+         if up - lo == 2 {
+            break;
+         }
+
+         // the median goes to up - 1 as the pivot
+         let pivot = self.get(state, p)?;
+         let a = self.get(state, up - 1)?;
+         self.set(state, p, a)?;
+         self.set(state, up - 1, pivot.clone())?;
+         let p = self.partition(state, lo, up, &pivot)?;
+
+         // recurse into the smaller half, loop on the larger one
+         let n;
+         if p - lo < up - p {
+            self.sort(state, lo, p - 1, rnd)?;
+            n = p - lo;
+            lo = p + 1;
+         } else {
+            self.sort(state, p + 1, up, rnd)?;
+            n = up - p;
+            up = p - 1;
+         }
+         if (up - lo) / 128 > n {
+            rnd = random_pivot();
+         }
+      }
+      Ok(())
+   }
+
+   // Partition t[lo..=up] around the pivot, which is at up - 1, into
+   // t[lo..p] <= pivot == t[p] <= t[p+1..=up]. Return p.
+   fn partition(&mut self, state: &mut ExeState, lo: i64, up: i64, pivot: &Value) -> Result<i64, LuaError> {
+      let (mut i, mut j) = (lo, up - 1);
+      loop {
+         i += 1;
+         let mut a_i = self.get(state, i)?;
+         while self.less(state, &a_i, pivot)? {
+            if i == up - 1 {
+               return Err(state.error("invalid order function for sorting"));
+            }
+            i += 1;
+            a_i = self.get(state, i)?;
+         }
+         j -= 1;
+         let mut a_j = self.get(state, j)?;
+         while self.less(state, pivot, &a_j)? {
+            if j < i {
+               return Err(state.error("invalid order function for sorting"));
+            }
+            j -= 1;
+            a_j = self.get(state, j)?;
+         }
+         if j < i {
+            self.set(state, up - 1, a_i)?;
+            self.set(state, i, pivot.clone())?;
+            return Ok(i);
+         }
+         self.set(state, i, a_j)?;
+         self.set(state, j, a_i)?;
+      }
+   }
+}
+
+fn random_pivot() -> u64 {
+   let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+   now.as_secs() ^ now.subsec_nanos() as u64
+}
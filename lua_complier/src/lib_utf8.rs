@@ -0,0 +1,243 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{value::{Value, Table}, vm::ExeState, error::LuaError, lex::utf8_encode};
+
+
+const MAX_UNICODE: u32 = 0x10ffff;
+// largest value of the original, up to 6 bytes long, UTF-8
+const MAX_UTF: u32 = 0x7fffffff;
+
+// one UTF-8 sequence, as a pattern
+const CHAR_PATTERN: &[u8] = b"[\0-\x7F\xC2-\xFD][\x80-\xBF]*";
+
+
+// the `utf8` table
+pub fn open(state: &mut ExeState) {
+   let mut lib = Table::new(0, 8);
+   lib.set_str("char", Value::Function(utf8_char));
+   lib.set_str("charpattern", Value::from(CHAR_PATTERN));
+   lib.set_str("codes", Value::Function(utf8_codes));
+   lib.set_str("codepoint", Value::Function(utf8_codepoint));
+   lib.set_str("len", Value::Function(utf8_len));
+   lib.set_str("offset", Value::Function(utf8_offset));
+   state.set_global("utf8", Value::Table(Rc::new(RefCell::new(lib))));
+}
+
+fn is_cont(s: &[u8], i: usize) -> bool {
+   s.get(i).is_some_and(|c| c & 0xc0 == 0x80)
+}
+
+// Decode the sequence at s[i..]; return the code point and where the
+// next sequence starts. Strict decoding rejects surrogates and values
+// above MAX_UNICODE.
+fn decode(s: &[u8], i: usize, strict: bool) -> Option<(u32, usize)> {
+   // smallest value of a sequence with this many continuation bytes,
+   // which excludes overlong encodings
+   const LIMITS: [u32; 6] = [u32::MAX, 0x80, 0x800, 0x10000, 0x200000, 0x4000000];
+   let mut c = *s.get(i)? as u32;
+   let mut code = c;
+   let mut count = 0;
+   if c >= 0x80 {
+      code = 0;
+      while c & 0x40 != 0 {
+         count += 1;
+         if count > 5 || !is_cont(s, i + count) {
+            return None;
+         }
+         code = (code << 6) | (s[i + count] & 0x3f) as u32;
+         c <<= 1;
+      }
+      code |= (c & 0x7f) << (count * 5);
+      if code > MAX_UTF || code < LIMITS[count] {
+         return None;
+      }
+   }
+   if strict && (code > MAX_UNICODE || (0xd800..=0xdfff).contains(&code)) {
+      return None;
+   }
+   Some((code, i + count + 1))
+}
+
+// a position given to a utf8 function: negative ones count from the end
+fn rel_pos(pos: i64, len: usize) -> i64 {
+   if pos >= 0 {
+      pos
+   } else if pos.unsigned_abs() > len as u64 {
+      0
+   } else {
+      len as i64 + pos + 1
+   }
+}
+
+// char(c1, c2, ...): the UTF-8 encoding of the code points
+fn utf8_char(state: &mut ExeState) -> Result<i32, LuaError> {
+   let mut out = Vec::new();
+   for i in 1..=state.get_top() {
+      let code = state.check_integer(i)? as u64;
+      if code > MAX_UTF as u64 {
+         return Err(state.arg_error(i, "value out of range"));
+      }
+      utf8_encode(code as u32, &mut out);
+   }
+   state.push(Value::from(out));
+   Ok(1)
+}
+
+// len(s [, i [, j [, lax]]]): the number of characters starting between
+// i and j, or nil and the position of the first invalid byte
+fn utf8_len(state: &mut ExeState) -> Result<i32, LuaError> {
+   let s = state.check_string(1)?;
+   let s = s.as_bytes();
+   let i = rel_pos(state.opt_integer(2, 1)?, s.len());
+   let j = rel_pos(state.opt_integer(3, -1)?, s.len());
+   let strict = state.get(4).is_false();
+   if i < 1 || i - 1 > s.len() as i64 {
+      return Err(state.arg_error(2, "initial position out of bounds"));
+   }
+   if j > s.len() as i64 {
+      return Err(state.arg_error(3, "final position out of bounds"));
+   }
+
+   let mut pos = i - 1;
+   let mut n = 0;
+   while pos < j {
+      match decode(s, pos as usize, strict) {
+         Some((_, next)) => pos = next as i64,
+         None => {
+            state.push(Value::Nil);
+            state.push(Value::Integer(pos + 1));
+            return Ok(2);
+         }
+      }
+      n += 1;
+   }
+   state.push(Value::Integer(n));
+   Ok(1)
+}
+
+// codepoint(s [, i [, j [, lax]]]): the code points of the characters
+// starting between i and j
+fn utf8_codepoint(state: &mut ExeState) -> Result<i32, LuaError> {
+   let s = state.check_string(1)?;
+   let s = s.as_bytes();
+   let i = rel_pos(state.opt_integer(2, 1)?, s.len());
+   let j = rel_pos(state.opt_integer(3, i)?, s.len());
+   let strict = state.get(4).is_false();
+   if i < 1 {
+      return Err(state.arg_error(2, "out of bounds"));
+   }
+   if j > s.len() as i64 {
+      return Err(state.arg_error(3, "out of bounds"));
+   }
+   if i > j {
+      return Ok(0);
+   }
+   if j - i >= i32::MAX as i64 || !state.check_stack((j - i) as usize + 1) {
+      return Err(state.error("string slice too long"));
+   }
+
+   let mut pos = i as usize - 1;
+   let mut n = 0;
+   while pos < j as usize {
+      let Some((code, next)) = decode(s, pos, strict) else {
+         return Err(state.error("invalid UTF-8 code"));
+      };
+      state.push(Value::Integer(code as i64));
+      pos = next;
+      n += 1;
+   }
+   Ok(n)
+}
+
+// offset(s, n [, i]): the byte position where the n-th character
+// counted from position i starts; n = 0 finds the start of the
+// character containing byte i. nil if there is no such character.
+fn utf8_offset(state: &mut ExeState) -> Result<i32, LuaError> {
+   let s = state.check_string(1)?;
+   let s = s.as_bytes();
+   let mut n = state.check_integer(2)?;
+   let default = if n >= 0 { 1 } else { s.len() as i64 + 1 };
+   let i = rel_pos(state.opt_integer(3, default)?, s.len());
+   if i < 1 || i - 1 > s.len() as i64 {
+      return Err(state.arg_error(3, "position out of bounds"));
+   }
+
+   let mut pos = i as usize - 1;
+   if n == 0 {
+      while pos > 0 && is_cont(s, pos) {
+         pos -= 1;
+      }
+   } else {
+      if is_cont(s, pos) {
+         return Err(state.error("initial position is a continuation byte"));
+      }
+      if n < 0 {
+         while n < 0 && pos > 0 {
+            pos -= 1;
+            while pos > 0 && is_cont(s, pos) {
+               pos -= 1;
+            }
+            n += 1;
+         }
+      } else {
+         // the first character is the one at i
+         n -= 1;
+         while n > 0 && pos < s.len() {
+            pos += 1;
+            while is_cont(s, pos) {
+               pos += 1;
+            }
+            n -= 1;
+         }
+      }
+   }
+   state.push(if n == 0 { Value::Integer(pos as i64 + 1) } else { Value::Nil });
+   Ok(1)
+}
+
+// codes(s [, lax]): an iterator giving the position and code point of
+// each character
+fn utf8_codes(state: &mut ExeState) -> Result<i32, LuaError> {
+   let strict = state.get(2).is_false();
+   let s = state.check_string(1)?;
+   if is_cont(s.as_bytes(), 0) {
+      return Err(state.arg_error(1, "invalid UTF-8 code"));
+   }
+   state.push(Value::Function(if strict { codes_strict } else { codes_lax }));
+   state.push(s);
+   state.push(Value::Integer(0));
+   Ok(3)
+}
+
+fn codes_strict(state: &mut ExeState) -> Result<i32, LuaError> {
+   codes_next(state, true)
+}
+
+fn codes_lax(state: &mut ExeState) -> Result<i32, LuaError> {
+   codes_next(state, false)
+}
+
+// the character after the one at the position in argument 2
+fn codes_next(state: &mut ExeState, strict: bool) -> Result<i32, LuaError> {
+   let s = state.check_string(1)?;
+   let s = s.as_bytes();
+   let mut pos = match state.get(2) {
+      Value::Integer(i) => *i as u64,
+      _ => 0,
+   };
+   // skip the rest of the previous character
+   while pos < s.len() as u64 && is_cont(s, pos as usize) {
+      pos += 1;
+   }
+   if pos >= s.len() as u64 {
+      return Ok(0);
+   }
+   match decode(s, pos as usize, strict) {
+      Some((code, next)) if !is_cont(s, next) => {
+         state.push(Value::Integer(pos as i64 + 1));
+         state.push(Value::Integer(code as i64));
+         Ok(2)
+      }
+      _ => Err(state.error("invalid UTF-8 code")),
+   }
+}
@@ -0,0 +1,150 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{value::{Value, Table}, vm::ExeState, error::LuaError, convert::{FromLuaMulti, IntoLuaMulti}, userdata::UserData};
+
+
+// A Lua state for a host program: the global environment with the
+// standard libraries open, and everything the scripts run in it leave.
+//
+//     let mut lua = Lua::new();
+//     lua.load("x = 6 * 7", "=config").exec()?;
+//     let x: i64 = lua.load("x", "=expr").eval()?;
+//
+// Native functions get the ExeState underneath, see state().
+#[derive(Debug)]
+pub struct Lua {
+   state: ExeState,
+}
+
+impl Lua {
+   pub fn new() -> Self {
+      Lua { state: ExeState::new() }
+   }
+
+   // The chunk `source`, to run with exec or eval. `chunkname` names it
+   // in error messages, in Lua's convention: "=name" shows as name,
+   // "@file" as file, and anything else as [string "..."].
+   pub fn load(&mut self, source: impl AsRef<[u8]>, chunkname: &str) -> Chunk<'_> {
+      Chunk {
+         lua: self,
+         source: source.as_ref().to_vec(),
+         name: chunkname.to_string(),
+      }
+   }
+
+   // the global table, shared with the scripts
+   pub fn globals(&self) -> Rc<RefCell<Table>> {
+      match self.state.globals() {
+         Value::Table(t) => t,
+         _ => unreachable!("globals are a table"),
+      }
+   }
+
+   // Call `func`, a function or a value with a __call metamethod, and
+   // convert its results to R. Variadic<Value> passes or takes the values
+   // as they are.
+   pub fn call_function<A: IntoLuaMulti, R: FromLuaMulti>(&mut self, func: &Value, args: A) -> Result<R, LuaError> {
+      let results = self.state.call(func, &args.into_lua_multi()?)?;
+      R::from_lua_multi(results)
+   }
+
+   // A Lua function running `func`, which gets its arguments and gives
+   // its results as Rust types:
+   //
+   //     let add = lua.create_function(|_, (a, b): (i64, i64)| Ok(a + b));
+   //
+   // An argument that does not convert is reported as Lua functions do,
+   // as in "bad argument #2 to 'add' (number expected, got nil)".
+   pub fn create_function<A, R, F>(&self, func: F) -> Value
+   where A: FromLuaMulti, R: IntoLuaMulti, F: Fn(&mut ExeState, A) -> Result<R, LuaError> + 'static {
+      native_function(func)
+   }
+
+   // As create_function, for a function that changes what it captured.
+   // It cannot be called again while it runs.
+   pub fn create_function_mut<A, R, F>(&self, func: F) -> Value
+   where A: FromLuaMulti, R: IntoLuaMulti, F: FnMut(&mut ExeState, A) -> Result<R, LuaError> + 'static {
+      let func = RefCell::new(func);
+      self.create_function(move |state, args| {
+         let mut func = func.try_borrow_mut().map_err(|_| state.error("function called while it runs"))?;
+         func(state, args)
+      })
+   }
+
+   // A userdata holding `data`, whose methods and fields T declares. See
+   // UserData.
+   pub fn create_userdata<T: UserData>(&mut self, data: T) -> Value {
+      self.state.create_userdata(data)
+   }
+
+   // the state the scripts run in, to register native functions and
+   // modules, drive coroutines and so on
+   pub fn state(&mut self) -> &mut ExeState {
+      &mut self.state
+   }
+}
+
+impl Default for Lua {
+   fn default() -> Self {
+      Self::new()
+   }
+}
+
+
+// a native closure calling `func` with its arguments converted, and
+// returning its results converted back
+pub fn native_function<A, R, F>(func: F) -> Value
+where A: FromLuaMulti, R: IntoLuaMulti, F: Fn(&mut ExeState, A) -> Result<R, LuaError> + 'static {
+   Value::NativeClosure(Rc::new(move |state: &mut ExeState| {
+      let args = (1..=state.get_top()).map(|i| state.get(i).clone()).collect();
+      let args = A::from_lua_args(state, args, 1)?;
+      let results = func(state, args)?.into_lua_multi()?;
+      let n = results.len() as i32;
+      for v in results {
+         state.push(v);
+      }
+      Ok(n)
+   }))
+}
+
+
+// A chunk of Lua code Lua::load gave, not compiled yet.
+#[derive(Debug)]
+pub struct Chunk<'a> {
+   lua: &'a mut Lua,
+   source: Vec<u8>,
+   name: String,
+}
+
+impl Chunk<'_> {
+   // compile the chunk into a function, as `load` does
+   pub fn into_function(self) -> Result<Value, LuaError> {
+      self.lua.state.load(&self.source, &self.name, "t", None)
+   }
+
+   // run the chunk for its effects
+   pub fn exec(self) -> Result<(), LuaError> {
+      self.call(())
+   }
+
+   // run the chunk with `args` as its `...`, and return what it returns
+   pub fn call<A: IntoLuaMulti, R: FromLuaMulti>(self, args: A) -> Result<R, LuaError> {
+      let lua = &mut *self.lua;
+      let func = lua.state.load(&self.source, &self.name, "t", None)?;
+      lua.call_function(&func, args)
+   }
+
+   // Evaluate the chunk as an expression, or else run it as statements,
+   // and convert its results to R. So both "1 + 2" and "local x = 1;
+   // return x + 2" give 3.
+   pub fn eval<R: FromLuaMulti>(self) -> Result<R, LuaError> {
+      let lua = &mut *self.lua;
+      let mut expr = b"return ".to_vec();
+      expr.extend_from_slice(&self.source);
+      let func = match lua.state.load(&expr, &self.name, "t", None) {
+         Ok(func) => func,
+         Err(_) => lua.state.load(&self.source, &self.name, "t", None)?,
+      };
+      lua.call_function(&func, ())
+   }
+}
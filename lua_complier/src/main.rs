@@ -1,24 +1,245 @@
 use std::env;
-use std::fs::File;
-use std::io::BufReader;
+use std::io::{self, IsTerminal};
+use std::process;
 
 
-mod value;
-mod byte_code;
-mod lex;
-mod parse;
-mod vm;
+mod repl;
 
+use lua_llvm::{ExeState, LuaError, Value};
+
+
+const VERSION: &str = concat!("Lua 5.4 (lua_LLVM ", env!("CARGO_PKG_VERSION"), ")");
+const PROGNAME: &str = "lua";
+
+
+// options of the command line, in the style of the reference `lua`
+#[derive(Default)]
+struct Options {
+    interactive: bool,    // -i
+    version: bool,        // -v
+    no_env: bool,         // -E
+    warnings: bool,       // -W
+    // -e and -l, in command-line order
+    actions: Vec<Action>,
+    // index of the script in the arguments, if any
+    script: Option<usize>,
+}
+
+enum Action {
+    Exec(String),
+    Require(String),
+}
+
+fn usage(badoption: &str) {
+    if badoption.starts_with("-e") || badoption.starts_with("-l") {
+        eprintln!("{PROGNAME}: '{badoption}' needs argument");
+    } else {
+        eprintln!("{PROGNAME}: unrecognized option '{badoption}'");
+    }
+    eprintln!("usage: {PROGNAME} [options] [script [args]]
+Available options are:
+  -e stat   execute string 'stat'
+  -i        enter interactive mode after executing 'script'
+  -l mod    require library 'mod' into global 'mod'
+  -l g=mod  require library 'mod' into global 'g'
+  -v        show version information
+  -E        ignore environment variables
+  -W        turn warnings on
+  --        stop handling options
+  -         stop handling options and execute stdin");
+}
+
+// Scan the options before the script name. Return the option that
+// could not be handled on error.
+fn collect_args(args: &[String]) -> Result<Options, String> {
+    let mut opts = Options::default();
+    let mut i = 1;
+    while i < args.len() {
+        let arg = &args[i];
+        if !arg.starts_with('-') {
+            opts.script = Some(i);
+            break;
+        }
+        match arg.as_str() {
+            "-" => {
+                opts.script = Some(i);
+                break;
+            }
+            "--" => {
+                if i + 1 < args.len() {
+                    opts.script = Some(i + 1);
+                }
+                break;
+            }
+            "-i" => {
+                opts.interactive = true;
+                opts.version = true;
+            }
+            "-v" => opts.version = true,
+            "-E" => opts.no_env = true,
+            "-W" => opts.warnings = true,
+            _ if arg.starts_with("-e") || arg.starts_with("-l") => {
+                // the argument may be attached or the next one
+                let value = if arg.len() > 2 {
+                    arg[2..].to_string()
+                } else {
+                    i += 1;
+                    match args.get(i) {
+                        Some(next) if !next.starts_with('-') => next.clone(),
+                        _ => return Err(arg.clone()),
+                    }
+                };
+                opts.actions.push(if arg.starts_with("-e") {
+                    Action::Exec(value)
+                } else {
+                    Action::Require(value)
+                });
+            }
+            _ => return Err(arg.clone()),
+        }
+        i += 1;
+    }
+    Ok(opts)
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2{
-        println!("usage : {} script",args[0]);
-        return;
+    let opts = match collect_args(&args) {
+        Ok(opts) => opts,
+        Err(badoption) => {
+            usage(&badoption);
+            process::exit(1);
+        }
+    };
+
+    let mut state = ExeState::new();
+    let status = match run(&mut state, &args, &opts) {
+        Ok(()) => 0,
+        Err(LuaError::Exit(status)) => status,
+        Err(err) => {
+            eprintln!("{PROGNAME}: {}", error_message(&mut state, err));
+            1
+        }
+    };
+    // exiting would skip flushing the files left open
+    drop(state);
+    process::exit(status);
+}
+
+fn run(state: &mut ExeState, args: &[String], opts: &Options) -> Result<(), LuaError> {
+    if opts.version {
+        println!("{VERSION}");
+    }
+    if opts.warnings {
+        state.set_warnings(true);
     }
+    create_arg_table(state, args, opts.script);
 
-    let file = File::open(&args[1]).unwrap();
-    let proto = parse::ParseProto::load(BufReader::new(file));
-    vm::ExeState::new().execute(&proto);
+    if opts.no_env {
+        state.ignore_env();
+    } else {
+        run_init(state)?;
+    }
+
+    for action in &opts.actions {
+        match action {
+            Action::Exec(source) => {
+                run_chunk(state, source.as_bytes(), "=(command line)", &[])?;
+            }
+            Action::Require(spec) => require(state, spec)?,
+        }
+    }
+
+    if let Some(script) = opts.script {
+        let script_args: Vec<Value> = args[script+1..].iter()
+            .map(|a| Value::from(a.as_str()))
+            .collect();
+        run_script(state, &args[script], &script_args)?;
+    }
+
+    if opts.interactive {
+        repl::run(state)?;
+    } else if opts.script.is_none() && opts.actions.is_empty() && !opts.version {
+        // no script: interactive if there is someone at the terminal,
+        // otherwise run the standard input as a script
+        if io::stdin().is_terminal() {
+            println!("{VERSION}");
+            repl::run(state)?;
+        } else {
+            run_script(state, "-", &[])?;
+        }
+    }
+    Ok(())
+}
+
+// The global `arg` holds all command-line arguments: the script name at
+// index 0, its arguments at 1, 2, ..., and the interpreter and its options
+// at negative indices.
+fn create_arg_table(state: &mut ExeState, args: &[String], script: Option<usize>) {
+    let script = script.unwrap_or(0) as i64;
+    let table = Value::new_table(args.len(), 0);
+    if let Value::Table(t) = &table {
+        let mut t = t.borrow_mut();
+        for (i, a) in args.iter().enumerate() {
+            let _ = t.set(Value::Integer(i as i64 - script), Value::from(a.as_str()));
+        }
+    }
+    state.set_global("arg", table);
+}
+
+// LUA_INIT_5_4, or else LUA_INIT, is either "@filename" to run a file
+// or a chunk to run directly
+fn run_init(state: &mut ExeState) -> Result<(), LuaError> {
+    let (name, init) = match env::var("LUA_INIT_5_4") {
+        Ok(init) => ("=LUA_INIT_5_4", init),
+        Err(_) => match env::var("LUA_INIT") {
+            Ok(init) => ("=LUA_INIT", init),
+            Err(_) => return Ok(()),
+        },
+    };
+    match init.strip_prefix('@') {
+        Some(file) => run_script(state, file, &[]),
+        None => run_chunk(state, init.as_bytes(), name, &[]),
+    }
+}
+
+// -l [g=]mod: call `require` and store the module in a global
+fn require(state: &mut ExeState, spec: &str) -> Result<(), LuaError> {
+    let (global, module) = spec.split_once('=').unwrap_or((spec, spec));
+    let require = state.get_global("require");
+    let results = state.call(&require, &[Value::from(module)])?;
+    state.set_global(global, results.into_iter().next().unwrap_or(Value::Nil));
+    Ok(())
+}
+
+// run the script `name` ("-" for the standard input) with `args` as its `...`
+fn run_script(state: &mut ExeState, name: &str, args: &[Value]) -> Result<(), LuaError> {
+    let path = if name == "-" { None } else { Some(name) };
+    let main = state.load_file(path, "bt", None)?;
+    state.call(&main, args)?;
+    Ok(())
+}
+
+// run `source`, named `chunk` as `load` names chunks
+fn run_chunk(state: &mut ExeState, source: &[u8], chunk: &str, args: &[Value]) -> Result<(), LuaError> {
+    let main = state.load(source, chunk, "bt", None)?;
+    state.call(&main, args)?;
+    Ok(())
+}
+
+// as the reference `lua`, report error values that are not strings or
+// numbers by their __tostring metamethod, or else by their type
+fn error_message(state: &mut ExeState, err: LuaError) -> String {
+    match err {
+        LuaError::Runtime(v) if !v.is_string() && !matches!(v, Value::Integer(_) | Value::Float(_)) => {
+            if state.metamethod(&v, "__tostring") != Value::Nil {
+                if let Ok(s) = state.tostring(&v) {
+                    return format!("{s:?}");
+                }
+            }
+            format!("(error object is a {} value)", v.type_name())
+        }
+        err => err.to_string(),
+    }
 }
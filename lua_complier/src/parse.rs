@@ -1,203 +1,1581 @@
-use std:: io::Read;
+use std::{io::Read, mem, rc::Rc};
 
-use crate::{value::Value, byte_code::ByteCode, lex::{Lex, Token}};
+use crate::{value::Value, byte_code::{ByteCode, MULTI, FIELDS_PER_FLUSH}, lex::{Lex, Token}, error::LuaError};
 
 
+const MAX_LOCALS: usize = 200;
+const MAX_UPVALUES: usize = 255;
 
+
+// the compiled output, independent of the lexer that produced it.
+// `max_stack_size` is the number of registers the byte codes may touch;
+// verify.rs checks the byte codes against it.
 #[derive(Debug)]
-pub struct ParseProto<R :Read>{
+pub struct FuncProto {
+    pub source: String,
+    pub line_defined: u32,
+    pub params: usize,
+    pub is_vararg: bool,
     pub constants: Vec::<Value>,
+    pub upvalues: Vec::<UpvalDesc>,
+    pub protos: Vec::<Rc<FuncProto>>,
     pub byte_codes: Vec::<ByteCode>,
-    locals : Vec::<String>,
+    pub lines: Vec::<u32>,
+    pub locvars: Vec::<LocVar>,
+    pub max_stack_size: usize,
+}
+
+// where a closure finds an upvalue when it is created: a register of the
+// enclosing function if `in_stack`, one of its upvalues otherwise
+#[derive(Debug, Clone)]
+pub struct UpvalDesc {
+    pub name: String,
+    pub in_stack: bool,
+    pub index: usize,
+}
+
+// debug information: the register of a local variable and the
+// instructions where it is active
+#[derive(Debug, Clone)]
+pub struct LocVar {
+    pub name: String,
+    pub reg: usize,
+    pub start_pc: usize,
+    pub end_pc: usize,
+}
+
+impl FuncProto {
+    // name of the local variable in register `reg` at `pc`, if any
+    pub fn local_name(&self, reg: usize, pc: usize) -> Option<&str> {
+        self.locvars.iter()
+            .rfind(|v| v.reg == reg && v.start_pc <= pc && pc < v.end_pc)
+            .map(|v| v.name.as_str())
+    }
+}
+
+// where the value of a parsed expression is, before it is loaded
+// into a register by discharge()
+#[derive(Debug)]
+enum ExpDesc {
+    // constants
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    String(Vec<u8>),
+
+    // variables
+    Local(usize),
+    Upvalue(usize),
+    Index(usize, usize),
+    IndexField(usize, usize),
+    IndexUpField(usize, usize),
+
+    // closure of the prototype with this index
+    Function(usize),
+
+    // value already in a temporary register
+    Temp(usize),
+
+    // multiple values: Call(function register, argument count)
+    Call(usize, usize),
+    VarArgs,
+
+    // operators whose destination is not known yet
+    UnaryOp(fn(u8,u8)->ByteCode, usize),
+    BinaryOp(fn(u8,u8,u8)->ByteCode, usize, usize),
+}
+
+impl ExpDesc {
+    fn is_multi(&self) -> bool {
+        matches!(self, ExpDesc::Call(..) | ExpDesc::VarArgs)
+    }
+}
+
+#[derive(Debug)]
+struct Local {
+    name: String,
+    is_const: bool,
+    // referenced by an inner function, so leaving its scope must close it
+    captured: bool,
+    locvar: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    nlocals: usize,
+    is_loop: bool,
+    first_label: usize,
+    first_goto: usize,
+}
+
+// a label, or a pending goto waiting for its label
+#[derive(Debug)]
+struct Label {
+    name: String,
+    pc: usize,
+    line: usize,
+    nlocals: usize,
+    // the goto leaves the scope of captured locals
+    close: bool,
+}
+
+// the state of one function being parsed
+#[derive(Debug, Default)]
+struct FuncState {
+    line_defined: usize,
+    params: usize,
+    is_vararg: bool,
+    constants: Vec::<Value>,
+    upvalues: Vec::<UpvalDesc>,
+    protos: Vec::<Rc<FuncProto>>,
+    byte_codes: Vec::<ByteCode>,
+    lines: Vec::<u32>,
+    locvars: Vec::<LocVar>,
+    max_stack_size: usize,
+    // active locals; local i lives in register i
+    locals: Vec::<Local>,
+    blocks: Vec::<Block>,
+    labels: Vec::<Label>,
+    gotos: Vec::<Label>,
+    sp: usize,
+}
+
+impl FuncState {
+    fn new(line_defined: usize) -> Self {
+        FuncState { line_defined, ..Default::default() }
+    }
+
+    fn into_proto(self, source: &str) -> FuncProto {
+        FuncProto {
+            source: source.to_string(),
+            line_defined: self.line_defined as u32,
+            params: self.params,
+            is_vararg: self.is_vararg,
+            constants: self.constants,
+            upvalues: self.upvalues,
+            protos: self.protos,
+            byte_codes: self.byte_codes,
+            lines: self.lines,
+            locvars: self.locvars,
+            max_stack_size: self.max_stack_size,
+        }
+    }
+
+    fn add_upvalue(&mut self, name: &str, in_stack: bool, index: usize) -> usize {
+        self.upvalues.push(UpvalDesc { name: name.to_string(), in_stack, index });
+        self.upvalues.len() - 1
+    }
+
+    // "main function" or "function at line N", for limit errors
+    fn what(&self) -> String {
+        if self.line_defined == 0 {
+            String::from("main function")
+        } else {
+            format!("function at line {}", self.line_defined)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseProto<R :Read>{
+    fs: FuncState,
+    // the functions enclosing the one being parsed, innermost last
+    outer: Vec::<FuncState>,
     lex : Lex<R>,
 }
 
 impl<R:Read> ParseProto<R> {
- pub fn load(input:R)->Self{
+ pub fn load(input:R, chunk: &str)->Result<FuncProto, LuaError>{
 
     let mut proto = ParseProto{
-        constants: Vec::new(),
-        byte_codes: Vec::new(),
-        locals: Vec::new(),
-        lex : Lex::new(input),
+        fs: FuncState::new(0),
+        outer: Vec::new(),
+        lex : Lex::new(input, chunk),
     };
 
-    proto.chunk();
+    // the main chunk is a vararg function whose only upvalue is `_ENV`
+    proto.fs.is_vararg = true;
+    proto.fs.add_upvalue("_ENV", true, 0);
+
+    proto.chunk()?;
+
+    Ok(proto.fs.into_proto(chunk))
+}
 
-    print!("proto.constants : {:?}",&proto.constants);
-    println!("proto.byte_codes :");
-    for i in proto.byte_codes.iter(){
-      println!("{:?}",i);
+fn chunk(&mut self)->Result<(), LuaError>{
+    self.enter_block(false);
+    match self.block_scope()? {
+        Token::Eos => (),
+        t => return Err(self.lex.error_near("<eof> expected", &t)),
     }
-    proto
+    self.leave_block()?;
+    self.push_code(ByteCode::Return(0, 0));
+    Ok(())
 }
 
-fn chunk(&mut self){
+// parse statements until a token ending the block, and return that token
+fn block(&mut self)->Result<Token, LuaError>{
+    self.enter_block(false);
+    let end = self.block_scope()?;
+    self.leave_block()?;
+    Ok(end)
+}
 
-    loop {
-        match self.lex.next() {
+fn block_scope(&mut self)->Result<Token, LuaError>{
 
-           Token::Name(name) =>{
-              if self.lex.peek() == &Token::Assign{
-                self.assignment(name)
-              }
-              else {
-                self.function_call( name)
+    loop {
+        self.fs.sp = self.fs.locals.len();
+        let t = self.lex.next()?;
+        let line = self.lex.line();
+        match t {
+           Token::SemiColon=> (),
+           Token::Local=> {
+              if self.lex.peek()? == &Token::Function {
+                  self.lex.next()?;
+                  self.local_function(line)?;
+              } else {
+                  self.local()?;
               }
            }
-           Token::Local=> self.local(),
-           Token::Eos=>break,
-           t => panic!("unexpected token: {t:?}"),
+           Token::Function=> self.function_stat(line)?,
+           Token::If=> self.if_stat(line)?,
+           Token::While=> self.while_stat(line)?,
+           Token::Repeat=> self.repeat_stat(line)?,
+           Token::For=> self.for_stat(line)?,
+           Token::Do=> self.do_end(line)?,
+           Token::Break=> self.new_goto(String::from("break"), line),
+           Token::Goto=> self.goto_stat(line)?,
+           Token::DoubColon=> self.label_stat(line)?,
+           Token::Return=> {
+              self.ret_stat()?;
+              // the caller checks that the block really ends here
+              return self.lex.next();
+           }
+           t @ (Token::Eos | Token::End | Token::Else | Token::Elseif | Token::Until) => return Ok(t),
+           t => self.exp_stat(t)?,
+        }
+    }
+}
+
+fn enter_block(&mut self, is_loop: bool) {
+    self.fs.blocks.push(Block {
+        nlocals: self.fs.locals.len(),
+        is_loop,
+        first_label: self.fs.labels.len(),
+        first_goto: self.fs.gotos.len(),
+    });
+}
+
+fn leave_block(&mut self)->Result<(), LuaError>{
+    let block = *self.fs.blocks.last().unwrap();
+    let level = block.nlocals;
+    let captured = self.fs.locals[level..].iter().any(|l| l.captured);
+    self.remove_locals(level);
+
+    // pending breaks jump to the end of the loop
+    let mut has_close = false;
+    if block.is_loop {
+        has_close = self.create_label(String::from("break"), 0, false)?;
+    }
+    let nested = self.fs.blocks.len() > 1;
+    if !has_close && nested && captured {
+        self.push_code(ByteCode::Close(level as u8));
+    }
+
+    self.fs.sp = level;
+    self.fs.labels.truncate(block.first_label);
+    self.fs.blocks.pop();
+
+    if nested {
+        // the gotos still pending now leave this block
+        for gt in self.fs.gotos[block.first_goto..].iter_mut() {
+            if gt.nlocals > level {
+                gt.close |= captured;
+                gt.nlocals = level;
+            }
         }
+    } else if let Some(gt) = self.fs.gotos.get(block.first_goto) {
+        let msg = if gt.name == "break" {
+            format!("break outside a loop at line {}", gt.line)
+        } else {
+            format!("no visible label '{}' for <goto> at line {}", gt.name, gt.line)
+        };
+        return Err(self.lex.error(&msg));
     }
+    Ok(())
+}
+
+// `do block end`
+fn do_end(&mut self, line: usize)->Result<(), LuaError>{
+    let t = self.block()?;
+    self.check_end(t, Token::Do, line)
 }
 
-fn local(&mut self){
+// `local namelist [= explist]`
+fn local(&mut self)->Result<(), LuaError>{
 
-  let var = if let Token::Name(var) = self.lex.next(){
-    var
+  let mut vars = Vec::new();
+  loop {
+    let name = self.read_name()?;
+    let is_const = self.attrib()?;
+    vars.push((name, is_const));
+    if self.lex.peek()? != &Token::Comma {
+      break;
+    }
+    self.lex.next()?;
   }
-  else{
-    panic!("expected variable");
-  };
-  
-  if self.lex.next() != Token::Assign {
-    panic!("expected '=' ");
+
+  if self.lex.peek()? == &Token::Assign {
+    self.lex.next()?;
+    let (nexp, last) = self.explist()?;
+    self.adjust(vars.len(), nexp, last)?;
+  } else {
+    for _ in 0..vars.len() {
+      let dst = self.alloc()?;
+      self.push_code(ByteCode::LoadNil(dst as u8));
+    }
   }
-  self.load_exp(self.locals.len());
-  self.locals.push(var);
-
-}
-
-fn function_call(&mut self,
-                 name: String
-                 ){
-  let ifunc = self.locals.len();
-  let iarg = ifunc + 1;
-  let code = self.load_var(ifunc, name);
-  self.byte_codes.push(code);
-  match self.lex.next() {
-      Token::ParL =>{
-          self.load_exp(iarg);
-          if self.lex.next() != Token::ParR{
-              panic!("expected ')'");
-          }
-      } 
-      Token::Strng(s)=>{
-          let code = self.load_const(iarg,s);
-          self.byte_codes.push(code);
-      }
-
-      _=>panic!("expectd string"),
+
+  // the new locals are visible only after the statement
+  for (name, is_const) in vars {
+    self.add_local(name, is_const)?;
   }
+  Ok(())
+
+}
+
+// `<const>` after a local name
+fn attrib(&mut self)->Result<bool, LuaError>{
+    if self.lex.peek()? != &Token::Less {
+        return Ok(false);
+    }
+    self.lex.next()?;
+    let attr = self.read_name()?;
+    self.expect(Token::Greater, "'>' expected")?;
+    match attr.as_str() {
+        "const" => Ok(true),
+        "close" => Err(self.lex.error("to-be-closed variables are not supported yet")),
+        _ => Err(self.lex.error(&format!("unknown attribute '{attr}'"))),
+    }
+}
+
+// `local function Name funcbody`
+fn local_function(&mut self, line: usize)->Result<(), LuaError>{
+    let name = self.read_name()?;
+    // the function can refer to itself
+    let reg = self.add_local(name, false)?;
+    let f = self.func_body(false, line)?;
+    self.push_code(ByteCode::Closure(reg as u8, f as u16));
+    Ok(())
+}
+
+// `function Name {'.' Name} [':' Name] funcbody`
+fn function_stat(&mut self, line: usize)->Result<(), LuaError>{
+    let name = self.read_name()?;
+    let mut var = self.simple_name(name)?;
+    let mut has_self = false;
+    while let Token::Dot | Token::Colon = self.lex.peek()? {
+        has_self = self.lex.next()? == Token::Colon;
+        let table = self.discharge_any(var)?;
+        let key = self.read_name()?;
+        var = self.index(table, ExpDesc::String(key.into_bytes()))?;
+        if has_self {
+            break;
+        }
+    }
+    self.check_readonly(&var)?;
+    let f = self.func_body(has_self, line)?;
+    self.assign_var(var, ExpDesc::Function(f))
+}
+
+// `(parlist) block end`, compiled into a new prototype of the current
+// function; return the index of the prototype
+fn func_body(&mut self, has_self: bool, line: usize)->Result<usize, LuaError>{
+    let parent = mem::replace(&mut self.fs, FuncState::new(line));
+    self.outer.push(parent);
+
+    self.enter_block(false);
+    if has_self {
+        self.add_local(String::from("self"), false)?;
+    }
+    self.expect(Token::ParL, "'(' expected")?;
+    if self.lex.peek()? != &Token::ParR {
+        loop {
+            match self.lex.next()? {
+                Token::Name(name) => {
+                    self.add_local(name, false)?;
+                }
+                Token::Dots => {
+                    self.fs.is_vararg = true;
+                    break;
+                }
+                t => return Err(self.lex.error_near("<name> expected", &t)),
+            }
+            if self.lex.peek()? != &Token::Comma {
+                break;
+            }
+            self.lex.next()?;
+        }
+    }
+    self.fs.params = self.fs.locals.len();
+    self.expect(Token::ParR, "')' expected")?;
+
+    let t = self.block_scope()?;
+    self.check_end(t, Token::Function, line)?;
+    self.leave_block()?;
+    self.push_code(ByteCode::Return(0, 0));
+
+    let parent = self.outer.pop().unwrap();
+    let fs = mem::replace(&mut self.fs, parent);
+    let proto = fs.into_proto(self.lex.chunk());
+    if self.fs.protos.len() > u16::MAX as usize {
+        let t = self.lex.peek()?.clone();
+        return Err(self.lex.error_near("too many functions in a chunk", &t));
+    }
+    self.fs.protos.push(Rc::new(proto));
+    Ok(self.fs.protos.len() - 1)
+}
+
+// `if exp then block {elseif exp then block} [else block] end`
+fn if_stat(&mut self, line: usize)->Result<(), LuaError>{
+    let mut to_end = Vec::new();
+    loop {
+        let cond = self.exp()?;
+        self.expect(Token::Then, "'then' expected")?;
+        let skip = self.test_jump(cond)?;
+        let t = self.block()?;
+        if let Token::Elseif | Token::Else = t {
+            to_end.push(self.byte_codes_len());
+            self.push_code(ByteCode::Jump(0));
+        }
+        if let Some(skip) = skip {
+            self.patch_jump(skip, self.byte_codes_len())?;
+        }
+        match t {
+            Token::Elseif => (),
+            Token::Else => {
+                let t = self.block()?;
+                self.check_end(t, Token::If, line)?;
+                break;
+            }
+            t => {
+                self.check_end(t, Token::If, line)?;
+                break;
+            }
+        }
+    }
+    let end = self.byte_codes_len();
+    for jump in to_end {
+        self.patch_jump(jump, end)?;
+    }
+    Ok(())
+}
+
+// `while exp do block end`
+fn while_stat(&mut self, line: usize)->Result<(), LuaError>{
+    let start = self.byte_codes_len();
+    let cond = self.exp()?;
+    self.expect(Token::Do, "'do' expected")?;
+    let exit = self.test_jump(cond)?;
+
+    self.enter_block(true);
+    let t = self.block()?;
+    self.check_end(t, Token::While, line)?;
+    self.jump_back(start)?;
+    self.leave_block()?;
+
+    if let Some(exit) = exit {
+        self.patch_jump(exit, self.byte_codes_len())?;
+    }
+    Ok(())
+}
+
+// `repeat block until exp`, where exp can see the block's locals
+fn repeat_stat(&mut self, line: usize)->Result<(), LuaError>{
+    let start = self.byte_codes_len();
+    self.enter_block(true);
+    self.enter_block(false);
+    let t = self.block_scope()?;
+    if t != Token::Until {
+        return Err(self.match_error(Token::Until, Token::Repeat, line, &t));
+    }
+
+    let cond = self.exp()?;
+    let level = self.fs.blocks.last().unwrap().nlocals;
+    let captured = self.fs.locals[level..].iter().any(|l| l.captured);
+    match cond {
+        ExpDesc::Boolean(true) | ExpDesc::Integer(_) | ExpDesc::Float(_) | ExpDesc::String(_) => (),
+        cond => {
+            let test = match cond {
+                ExpDesc::Nil | ExpDesc::Boolean(false) => None,
+                cond => Some(self.discharge_any(cond)?),
+            };
+            // a new iteration gets fresh locals
+            if captured {
+                self.push_code(ByteCode::Close(level as u8));
+            }
+            let offset = self.jump_offset(start)?;
+            self.push_code(match test {
+                Some(r) => ByteCode::TestAndJump(r as u8, offset),
+                None => ByteCode::Jump(offset),
+            });
+        }
+    }
+    self.leave_block()?;
+    self.leave_block()
+}
+
+fn for_stat(&mut self, line: usize)->Result<(), LuaError>{
+    let name = self.read_name()?;
+    match self.lex.next()? {
+        Token::Assign => self.numeric_for(name, line),
+        t @ (Token::Comma | Token::In) => self.generic_for(name, t, line),
+        t => Err(self.lex.error_near("'=' or 'in' expected", &t)),
+    }
+}
+
+// `for Name = exp, exp [, exp] do block end`
+fn numeric_for(&mut self, name: String, line: usize)->Result<(), LuaError>{
+    self.enter_block(true);
+    let base = self.fs.sp;
+
+    let init = self.exp()?;
+    self.discharge(base, init)?;
+    self.expect(Token::Comma, "',' expected")?;
+    let limit = self.exp()?;
+    self.discharge(base + 1, limit)?;
+    if self.lex.peek()? == &Token::Comma {
+        self.lex.next()?;
+        let step = self.exp()?;
+        self.discharge(base + 2, step)?;
+    } else {
+        self.discharge(base + 2, ExpDesc::Integer(1))?;
+    }
+    self.expect(Token::Do, "'do' expected")?;
+
+    for _ in 0..3 {
+        self.add_local(String::from("(for state)"), false)?;
+    }
+    let prepare = self.byte_codes_len();
+    self.push_code(ByteCode::ForPrepare(base as u8, 0));
+
+    self.enter_block(false);
+    self.add_local(name, false)?;
+    let t = self.block_scope()?;
+    self.check_end(t, Token::For, line)?;
+    self.leave_block()?;
+
+    // both jump to the instruction following the other one
+    let offset = self.for_offset(self.byte_codes_len() - prepare)?;
+    self.fs.byte_codes[prepare] = ByteCode::ForPrepare(base as u8, offset);
+    self.push_code(ByteCode::ForLoop(base as u8, offset));
+
+    self.leave_block()
+}
+
+// `for namelist in explist do block end`
+fn generic_for(&mut self, name: String, mut t: Token, line: usize)->Result<(), LuaError>{
+    let mut names = vec![name];
+    while t == Token::Comma {
+        names.push(self.read_name()?);
+        t = self.lex.next()?;
+    }
+    if t != Token::In {
+        return Err(self.lex.error_near("'in' expected", &t));
+    }
+
+    self.enter_block(true);
+    let base = self.fs.sp;
+    let (nexp, last) = self.explist()?;
+    self.adjust(4, nexp, last)?;
+    self.expect(Token::Do, "'do' expected")?;
+
+    for _ in 0..4 {
+        self.add_local(String::from("(for state)"), false)?;
+    }
+    let prepare = self.byte_codes_len();
+    self.push_code(ByteCode::Jump(0));
+
+    self.enter_block(false);
+    let nvars = names.len();
+    for name in names {
+        self.add_local(name, false)?;
+    }
+    // TForCall copies the function and its 2 arguments above the state
+    self.use_register(base + 6)?;
+    let t = self.block_scope()?;
+    self.check_end(t, Token::For, line)?;
+    self.leave_block()?;
+
+    self.patch_jump(prepare, self.byte_codes_len())?;
+    self.push_code(ByteCode::TForCall(base as u8, nvars as u8));
+    let offset = self.for_offset(self.byte_codes_len() - prepare)?;
+    self.push_code(ByteCode::TForLoop(base as u8, offset));
+
+    self.leave_block()
+}
+
+// `goto Name`
+fn goto_stat(&mut self, line: usize)->Result<(), LuaError>{
+    let name = self.read_name()?;
+    match self.fs.labels.iter().rfind(|l| l.name == name) {
+        Some(label) => {
+            // backward jump, leaving the scope of the locals declared since
+            let (level, pc) = (label.nlocals, label.pc);
+            if self.fs.locals.len() > level {
+                self.push_code(ByteCode::Close(level as u8));
+            }
+            self.jump_back(pc)
+        }
+        None => {
+            self.new_goto(name, line);
+            Ok(())
+        }
+    }
+}
+
+// a forward jump, patched when its label shows up
+fn new_goto(&mut self, name: String, line: usize) {
+    self.fs.gotos.push(Label {
+        name,
+        pc: self.byte_codes_len(),
+        line,
+        nlocals: self.fs.locals.len(),
+        close: false,
+    });
+    self.push_code(ByteCode::Jump(0));
+}
+
+// `::Name::`
+fn label_stat(&mut self, line: usize)->Result<(), LuaError>{
+    let name = self.read_name()?;
+    self.expect(Token::DoubColon, "'::' expected")?;
+
+    // skip other no-op statements, labels following this one included
+    loop {
+        match self.lex.peek()? {
+            Token::SemiColon => {
+                self.lex.next()?;
+            }
+            Token::DoubColon => {
+                self.lex.next()?;
+                let line = self.lex.line();
+                self.label_stat(line)?;
+            }
+            _ => break,
+        }
+    }
+    if let Some(label) = self.fs.labels.iter().find(|l| l.name == name) {
+        let msg = format!("label '{name}' already defined on line {}", label.line);
+        return Err(self.lex.error(&msg));
+    }
+
+    // a label at the end of the block is outside the scope of its locals
+    let last = matches!(self.lex.peek()?, Token::End | Token::Else | Token::Elseif | Token::Eos);
+    self.create_label(name, line, last)?;
+    Ok(())
+}
+
+// Add a label here and patch the pending gotos of the current block
+// jumping to it. Return whether it needs to close upvalues, in which case
+// a Close is emitted at the label.
+fn create_label(&mut self, name: String, line: usize, last: bool)->Result<bool, LuaError>{
+    let block = *self.fs.blocks.last().unwrap();
+    let nlocals = if last { block.nlocals } else { self.fs.locals.len() };
+    let pc = self.byte_codes_len();
+
+    let mut close = false;
+    let mut i = block.first_goto;
+    while i < self.fs.gotos.len() {
+        if self.fs.gotos[i].name != name {
+            i += 1;
+            continue;
+        }
+        let gt = self.fs.gotos.remove(i);
+        if gt.nlocals < nlocals {
+            let msg = format!("<goto {}> at line {} jumps into the scope of local '{}'",
+                              gt.name, gt.line, self.fs.locals[gt.nlocals].name);
+            return Err(self.lex.error(&msg));
+        }
+        close |= gt.close;
+        self.patch_jump(gt.pc, pc)?;
+    }
+    if close {
+        self.push_code(ByteCode::Close(nlocals as u8));
+    }
+
+    self.fs.labels.push(Label { name, pc, line, nlocals, close: false });
+    Ok(close)
+}
+
+// `varlist = explist` or a function call
+fn exp_stat(&mut self, t: Token)->Result<(), LuaError>{
+    let desc = self.prefixexp(t)?;
+    match self.lex.peek()? {
+        Token::Assign | Token::Comma => self.assignment(desc),
+        _ => match desc {
+            ExpDesc::Call(ifunc, nargs) => {
+                self.push_code(ByteCode::Call(ifunc as u8, nargs as u8, 0));
+                Ok(())
+            }
+            _ => {
+                let t = self.lex.next()?;
+                Err(self.lex.error_near("syntax error", &t))
+            }
+        }
+    }
+}
+
+fn assignment(&mut self, first: ExpDesc)->Result<(), LuaError>{
+    let mut vars = vec![first];
+    loop {
+        // reported at the token following the variable
+        let var = vars.last().unwrap();
+        if !matches!(var, ExpDesc::Local(_) | ExpDesc::Upvalue(_) | ExpDesc::Index(..) |
+                          ExpDesc::IndexField(..) | ExpDesc::IndexUpField(..)) {
+            let t = self.lex.peek()?.clone();
+            return Err(self.lex.error_near("syntax error", &t));
+        }
+        self.check_readonly(var)?;
+
+        match self.lex.next()? {
+            Token::Comma => {
+                let t = self.lex.next()?;
+                vars.push(self.prefixexp(t)?);
+            }
+            Token::Assign => break,
+            t => return Err(self.lex.error_near("'=' expected", &t)),
+        }
+    }
+
+    let (nexp, last) = self.explist()?;
+
+    if vars.len() == 1 && nexp == 1 {
+        // a single assignment needs no temporary for the value
+        let var = vars.pop().unwrap();
+        return self.assign_var(var, last);
+    }
+
+    // evaluate all values into temporaries first, then assign
+    let first = self.fs.sp - (nexp - 1);
+    self.adjust(vars.len(), nexp, last)?;
+    for (i, var) in vars.into_iter().enumerate().rev() {
+        self.assign_var(var, ExpDesc::Temp(first + i))?;
+    }
+    Ok(())
+}
+
+fn check_readonly(&mut self, var: &ExpDesc)->Result<(), LuaError>{
+    let name = match *var {
+        ExpDesc::Local(i) if self.fs.locals[i].is_const => &self.fs.locals[i].name,
+        ExpDesc::Upvalue(u) if self.upvalue_is_const(u) => &self.fs.upvalues[u].name,
+        _ => return Ok(()),
+    };
+    let msg = format!("attempt to assign to const variable '{name}'");
+    Err(self.lex.error(&msg))
+}
 
-  self.byte_codes.push(ByteCode::Call(ifunc as u8, 1));
+// follow the upvalue out to the local it refers to
+fn upvalue_is_const(&self, u: usize) -> bool {
+    let mut desc = &self.fs.upvalues[u];
+    for fs in self.outer.iter().rev() {
+        if desc.in_stack {
+            return fs.locals[desc.index].is_const;
+        }
+        desc = &fs.upvalues[desc.index];
+    }
+    false
+}
 
+fn assign_var(&mut self, var: ExpDesc, value: ExpDesc)->Result<(), LuaError>{
+    let code = match var {
+        ExpDesc::Local(i) => return self.discharge(i, value),
+        ExpDesc::Upvalue(u) => {
+            let v = self.discharge_any(value)?;
+            ByteCode::SetUpvalue(u as u8, v as u8)
+        }
+        ExpDesc::Index(t, k) => {
+            let v = self.discharge_any(value)?;
+            ByteCode::SetTable(t as u8, k as u8, v as u8)
+        }
+        ExpDesc::IndexField(t, k) => {
+            let v = self.discharge_any(value)?;
+            ByteCode::SetField(t as u8, k as u8, v as u8)
+        }
+        ExpDesc::IndexUpField(u, k) => {
+            let v = self.discharge_any(value)?;
+            ByteCode::SetUpField(u as u8, k as u8, v as u8)
+        }
+        _ => panic!("assignment to an expression"),
+    };
+    self.push_code(code);
+    Ok(())
 }
-   
-fn load_exp(&mut self, dst: usize) {
 
-    let code = match self.lex.next() {
-       Token::Nil => ByteCode::LoadNil(dst as u8),
-       Token::Strng(s)=>self.load_const(dst, s),
-       Token::Name(var)=>self.load_var(dst,var),
-       Token::Integer(i)=>{
-          if let Ok(ii)=i16::try_from(i) {
-            ByteCode::LoadInt(dst as u8, ii)
-          }
-          else{
-            self.load_const(dst, i)
-          }
-       }
-       Token::True=>ByteCode::LoadBool(dst as u8, true),
-       Token::False=>ByteCode::LoadBool(dst as u8, false),
-       Token::Float(f)=>self.load_const(dst, f),
-       _=>panic!("invalid argument"),
+// `return [explist] [';']`, which must be the last statement of a block
+fn ret_stat(&mut self)->Result<(), LuaError>{
+    let code = match self.lex.peek()? {
+        Token::SemiColon | Token::Eos | Token::End |
+        Token::Else | Token::Elseif | Token::Until => ByteCode::Return(0, 0),
+        _ => {
+            let first = self.fs.sp;
+            let (nexp, last) = self.explist()?;
+            match last {
+                ExpDesc::Call(ifunc, nargs) if nexp == 1 => {
+                    // the Return is for native functions, which run above
+                    // this one and leave their results to return
+                    self.push_code(ByteCode::TailCall(ifunc as u8, nargs as u8));
+                    ByteCode::Return(ifunc as u8, MULTI)
+                }
+                last if last.is_multi() => {
+                    self.discharge_multi(last)?;
+                    ByteCode::Return(first as u8, MULTI)
+                }
+                last if nexp == 1 => ByteCode::Return(self.discharge_any(last)? as u8, 1),
+                last => {
+                    self.discharge_top(last)?;
+                    ByteCode::Return(first as u8, nexp as u8)
+                }
+            }
+        }
     };
-    self.byte_codes.push(code);
+    if self.lex.peek()? == &Token::SemiColon {
+        self.lex.next()?;
+    }
+    self.push_code(code);
+    Ok(())
 }
 
-fn load_var(&mut self,
-            dst: usize, 
-            name: String) -> ByteCode {
-    if let Some(i) = self.get_local(&name){
-      // local variable
-      ByteCode::Move(dst as u8,i as u8)
+// Parse comma separated expressions. All but the last are loaded into
+// consecutive registers; the last one is returned undischarged so the
+// caller can decide how many values it should produce.
+fn explist(&mut self)->Result<(usize, ExpDesc), LuaError>{
+    let mut n = 1;
+    loop {
+        let desc = self.exp()?;
+        if self.lex.peek()? != &Token::Comma {
+            return Ok((n, desc));
+        }
+        self.lex.next()?;
+        self.discharge_top(desc)?;
+        n += 1;
+    }
+}
+
+// make an explist of `nexp` expressions produce exactly `want` values
+fn adjust(&mut self, want: usize, nexp: usize, last: ExpDesc)->Result<(), LuaError>{
+    if last.is_multi() && want >= nexp {
+        // the values land where the last expression would
+        let extra = want - nexp + 1;
+        let dst = match last {
+            ExpDesc::Call(ifunc, nargs) => {
+                self.push_code(ByteCode::Call(ifunc as u8, nargs as u8, extra as u8));
+                ifunc
+            }
+            _ => {
+                let dst = self.fs.sp;
+                self.push_code(ByteCode::VarArgs(dst as u8, extra as u8));
+                dst
+            }
+        };
+        self.use_register(dst + extra - 1)?;
+        self.fs.sp = dst + extra;
+        return Ok(());
     }
-    else {
-      // global variable
-        let ic = self.add_const(name);
-        ByteCode::GetGlobal(dst as u8, ic as u8)
+
+    self.discharge_top(last)?;
+    for _ in nexp..want {
+        let dst = self.alloc()?;
+        self.push_code(ByteCode::LoadNil(dst as u8));
     }
+    // surplus values are just dropped
+    self.fs.sp = self.fs.sp.min(self.fs.sp - nexp + want);
+    Ok(())
 }
 
-fn load_const<T:Into<Value>>(&mut self,arg: usize, i:T) -> ByteCode {
-   let i_t = i.into();
-   ByteCode::LoadConst(arg as u8, self.add_const(i_t) as u8) 
+fn exp(&mut self)->Result<ExpDesc, LuaError>{
+    self.exp_limit(0)
 }
 
-fn add_const<T:Into<Value>>(&mut self,name: T) -> usize {
-    let name_t = name.into();
-    self.constants.iter()
-             .position(|v| v == &name_t)
-             .unwrap_or_else(||{
-                  self.constants.push(name_t);
-                  self.constants.len()-1
-             })
-}
-
-fn assignment(&mut self,
-              name: String
-            ){
-
-    self.lex.next();//'='
-
-    if let Some(i) = self.get_local(&name){
-      // local variable
-      self.load_exp(i);
-    }
-    else {
-      // global variable
-      let dst = self.add_const(name) as u8;
-
-      let code = match self.lex.next() {
-          // from const values
-          Token::Nil=>ByteCode::SetGlobalConst(dst, self.add_const(()) as u8),
-          Token::True=>ByteCode::SetGlobalConst(dst, self.add_const(true) as u8),
-          Token::False=>ByteCode::SetGlobalConst(dst, self.add_const(false) as u8),
-          Token::Integer(i)=>ByteCode::SetGlobalConst(dst, self.add_const(i) as u8),
-          Token::Float(f)=>ByteCode::SetGlobalConst(dst, self.add_const(f) as u8),
-          Token::Strng(s)=>ByteCode::SetGlobalConst(dst, self.add_const(s) as u8),
-          
-          // from variable
-          Token::Name(n)=>
-              if let Some(i) =  self.get_local(&n){
-                  ByteCode::SetGlobal(dst, i as u8)
-              }
-              else{
-                  ByteCode::SetGlobalGlobal(dst, self.add_const(n) as u8)
-              }
+// parse an expression whose binary operators bind tighter than `limit`
+fn exp_limit(&mut self, limit: i32)->Result<ExpDesc, LuaError>{
+    let t = self.lex.next()?;
+    self.exp_limit_from(t, limit)
+}
+
+// the same, with the first token of the expression already read
+fn exp_limit_from(&mut self, t: Token, limit: i32)->Result<ExpDesc, LuaError>{
+    let mut desc = match t {
+        Token::Not | Token::Sub | Token::Len | Token::BitXor => {
+            let operand = self.exp_limit(UNARY_PRIORITY)?;
+            self.unop(t, operand)?
+        }
+        t => self.simple_exp(t)?,
+    };
 
-          _=>panic!("invalid argument"),
-      }; 
-      
-      self.byte_codes.push(code);
+    loop {
+        let (left_pri, right_pri) = binop_priority(self.lex.peek()?);
+        if left_pri <= limit {
+            return Ok(desc);
+        }
+        let op = self.lex.next()?;
+        desc = match op {
+            Token::And | Token::Or => self.and_or(op, desc, right_pri)?,
+            _ => {
+                let left = self.discharge_any(desc)?;
+                let right = self.exp_limit(right_pri)?;
+                let right = self.discharge_any(right)?;
+                ExpDesc::BinaryOp(binop_code(&op), left, right)
+            }
+        };
     }
+}
 
+// short-circuit: `a and b` is a if a is false, b otherwise
+fn and_or(&mut self, op: Token, left: ExpDesc, right_pri: i32)->Result<ExpDesc, LuaError>{
+    let dst = match left {
+        ExpDesc::Temp(r) => r,
+        left => self.discharge_top(left)?,
+    };
+    let test = self.byte_codes_len();
+    self.push_code(ByteCode::Jump(0));
 
+    let right = self.exp_limit(right_pri)?;
+    self.discharge(dst, right)?;
+
+    let offset = self.jump_offset_from(test + 1, self.byte_codes_len())?;
+    self.fs.byte_codes[test] = match op {
+        Token::And => ByteCode::TestAndJump(dst as u8, offset),
+        _ => ByteCode::TestOrJump(dst as u8, offset),
+    };
+    Ok(ExpDesc::Temp(dst))
 }
 
-fn get_local(&self, name: &str) -> Option<usize> {
-   self.locals.iter().rposition(|v| v == &name)
+fn unop(&mut self, op: Token, operand: ExpDesc)->Result<ExpDesc, LuaError>{
+    // fold negation of numeric constants, so `-1` is a constant too
+    let desc = match (op, operand) {
+        (Token::Sub, ExpDesc::Integer(i)) => ExpDesc::Integer(i.wrapping_neg()),
+        (Token::Sub, ExpDesc::Float(f)) => ExpDesc::Float(-f),
+        (Token::Not, ExpDesc::Nil | ExpDesc::Boolean(false)) => ExpDesc::Boolean(true),
+        (Token::Not, ExpDesc::Boolean(true) | ExpDesc::Integer(_) |
+                     ExpDesc::Float(_) | ExpDesc::String(_)) => ExpDesc::Boolean(false),
+        (op, operand) => {
+            let code = match op {
+                Token::Sub => ByteCode::Neg,
+                Token::Not => ByteCode::Not,
+                Token::Len => ByteCode::Len,
+                _ => ByteCode::BitNot,
+            };
+            ExpDesc::UnaryOp(code, self.discharge_any(operand)?)
+        }
+    };
+    Ok(desc)
+}
+
+fn simple_exp(&mut self, t: Token)->Result<ExpDesc, LuaError>{
+    let desc = match t {
+        Token::Nil => ExpDesc::Nil,
+        Token::True => ExpDesc::Boolean(true),
+        Token::False => ExpDesc::Boolean(false),
+        Token::Integer(i) => ExpDesc::Integer(i),
+        Token::Float(f) => ExpDesc::Float(f),
+        Token::Strng(s) => ExpDesc::String(s),
+        Token::Dots => {
+            if !self.fs.is_vararg {
+                return Err(self.lex.error_near("cannot use '...' outside a vararg function", &t));
+            }
+            ExpDesc::VarArgs
+        }
+        Token::CurlyL => self.table_constructor()?,
+        Token::Function => {
+            let line = self.lex.line();
+            ExpDesc::Function(self.func_body(false, line)?)
+        }
+        t => self.prefixexp(t)?,
+    };
+    Ok(desc)
+}
+
+// prefixexp ::= Name | '(' exp ')' followed by any number of
+//               '[' exp ']' | '.' Name | ':' Name args | args
+fn prefixexp(&mut self, t: Token)->Result<ExpDesc, LuaError>{
+    let mut desc = match t {
+        Token::Name(name) => self.simple_name(name)?,
+        Token::ParL => {
+            let line = self.lex.line();
+            let desc = self.exp()?;
+            self.expect_match(Token::ParR, Token::ParL, line)?;
+            // parentheses cut multiple values down to one, and make a
+            // variable a value, which can not be assigned to
+            match desc {
+                ExpDesc::Call(..) | ExpDesc::VarArgs |
+                ExpDesc::Local(_) | ExpDesc::Upvalue(_) | ExpDesc::Index(..) |
+                ExpDesc::IndexField(..) | ExpDesc::IndexUpField(..) => ExpDesc::Temp(self.discharge_top(desc)?),
+                desc => desc,
+            }
+        }
+        t => return Err(self.lex.error_near("unexpected symbol", &t)),
+    };
+
+    loop {
+        desc = match self.lex.peek()? {
+            Token::SqurL => {
+                self.lex.next()?;
+                let itable = self.discharge_any(desc)?;
+                let key = self.exp()?;
+                self.expect(Token::SqurR, "']' expected")?;
+                self.index(itable, key)?
+            }
+            Token::Dot => {
+                self.lex.next()?;
+                let itable = self.discharge_any(desc)?;
+                let name = self.read_name()?;
+                self.index(itable, ExpDesc::String(name.into_bytes()))?
+            }
+            Token::Colon => {
+                self.lex.next()?;
+                let name = self.read_name()?;
+                let obj = self.discharge_any(desc)?;
+                self.free_temps(&ExpDesc::Temp(obj));
+                let ifunc = self.alloc()?;
+                self.alloc()?;
+                let k = self.add_const(name)?;
+                if k <= u8::MAX as usize {
+                    self.push_code(ByteCode::Method(ifunc as u8, obj as u8, k as u8));
+                } else {
+                    self.push_code(ByteCode::Move(ifunc as u8 + 1, obj as u8));
+                    let key = self.alloc()?;
+                    self.push_code(ByteCode::LoadConst(key as u8, k as u16));
+                    self.push_code(ByteCode::GetTable(ifunc as u8, ifunc as u8 + 1, key as u8));
+                }
+                self.args(ifunc, 1)?
+            }
+            Token::ParL | Token::CurlyL | Token::Strng(_) => {
+                let ifunc = self.discharge_top(desc)?;
+                self.args(ifunc, 0)?
+            }
+            _ => return Ok(desc),
+        };
+    }
+}
+
+fn index(&mut self, itable: usize, key: ExpDesc)->Result<ExpDesc, LuaError>{
+    if let ExpDesc::String(s) = &key {
+        let ic = self.add_const(s.clone())?;
+        if ic <= u8::MAX as usize {
+            return Ok(ExpDesc::IndexField(itable, ic));
+        }
+    }
+    Ok(ExpDesc::Index(itable, self.discharge_any(key)?))
+}
+
+// a local, an upvalue, or else a global, i.e. a field of `_ENV`
+fn simple_name(&mut self, name: String)->Result<ExpDesc, LuaError>{
+    if let Some(i) = self.get_local(&name) {
+        return Ok(ExpDesc::Local(i));
+    }
+    if let Some(u) = self.find_upvalue(&name)? {
+        return Ok(ExpDesc::Upvalue(u));
+    }
+
+    let key = name.into_bytes();
+    match self.simple_name(String::from("_ENV"))? {
+        ExpDesc::Upvalue(u) => {
+            let ic = self.add_const(key.clone())?;
+            if ic <= u8::MAX as usize && u <= u8::MAX as usize {
+                return Ok(ExpDesc::IndexUpField(u, ic));
+            }
+            let itable = self.discharge_top(ExpDesc::Upvalue(u))?;
+            self.index(itable, ExpDesc::String(key))
+        }
+        env => {
+            let itable = self.discharge_any(env)?;
+            self.index(itable, ExpDesc::String(key))
+        }
+    }
+}
+
+// Look `name` up in the enclosing functions. If found there, it becomes
+// an upvalue of every function in between, down to the current one.
+fn find_upvalue(&mut self, name: &str)->Result<Option<usize>, LuaError>{
+    if let Some(u) = self.fs.upvalues.iter().position(|u| u.name == name) {
+        return Ok(Some(u));
+    }
+
+    let mut found = None;
+    for (level, fs) in self.outer.iter_mut().enumerate().rev() {
+        if let Some(r) = fs.locals.iter().rposition(|l| l.name == name) {
+            fs.locals[r].captured = true;
+            found = Some((level, true, r));
+            break;
+        }
+        if let Some(u) = fs.upvalues.iter().position(|u| u.name == name) {
+            found = Some((level, false, u));
+            break;
+        }
+    }
+    let Some((level, mut in_stack, mut index)) = found else {
+        return Ok(None);
+    };
+
+    for fs in self.outer[level+1..].iter_mut().chain(std::iter::once(&mut self.fs)) {
+        if fs.upvalues.len() >= MAX_UPVALUES {
+            let msg = format!("too many upvalues (limit is {MAX_UPVALUES}) in {}", fs.what());
+            return Err(self.lex.error(&msg));
+        }
+        index = fs.add_upvalue(name, in_stack, index);
+        in_stack = false;
+    }
+    Ok(Some(index))
+}
+
+// function arguments, after the function has been put at register
+// `ifunc` followed by `nself` implicit arguments
+fn args(&mut self, ifunc: usize, nself: usize)->Result<ExpDesc, LuaError>{
+    let first = ifunc + 1 + nself;
+    self.fs.sp = first;
+    let nargs = match self.lex.next()? {
+        Token::ParL => {
+            let line = self.lex.line();
+            if self.lex.peek()? == &Token::ParR {
+                self.lex.next()?;
+                0
+            } else {
+                let (nexp, last) = self.explist()?;
+                self.expect_match(Token::ParR, Token::ParL, line)?;
+                if last.is_multi() {
+                    self.discharge_multi(last)?;
+                    return Ok(ExpDesc::Call(ifunc, MULTI as usize));
+                }
+                self.discharge_top(last)?;
+                nexp
+            }
+        }
+        Token::CurlyL => {
+            let table = self.table_constructor()?;
+            self.discharge(first, table)?;
+            1
+        }
+        Token::Strng(s) => {
+            self.discharge(first, ExpDesc::String(s))?;
+            1
+        }
+        t => return Err(self.lex.error_near("function arguments expected", &t)),
+    };
+    Ok(ExpDesc::Call(ifunc, nargs + nself))
+}
+
+// `{ [exp] = exp, name = exp, exp, ... }`
+fn table_constructor(&mut self)->Result<ExpDesc, LuaError>{
+    let table = self.alloc()?;
+    let inew = self.byte_codes_len();
+    self.push_code(ByteCode::NewTable(table as u8, 0, 0));
+
+    let mut narray = 0;
+    let mut nmap = 0;
+    let mut pending: Option<ExpDesc> = None; // last positional item
+    loop {
+        if self.lex.peek()? == &Token::CurlyR {
+            self.lex.next()?;
+            break;
+        }
+
+        // store the previous positional item, now known not to be the last
+        if let Some(desc) = pending.take() {
+            self.discharge_top(desc)?;
+            if self.fs.sp - table - 1 == FIELDS_PER_FLUSH {
+                self.flush_list(table, FIELDS_PER_FLUSH, narray)?;
+            }
+        }
+
+        match self.lex.next()? {
+            Token::SqurL => {
+                let key = self.exp()?;
+                self.expect(Token::SqurR, "']' expected")?;
+                self.expect(Token::Assign, "'=' expected")?;
+                self.table_field(table, key)?;
+                nmap += 1;
+            }
+            Token::Name(name) if self.lex.peek()? == &Token::Assign => {
+                self.lex.next()?;
+                self.table_field(table, ExpDesc::String(name.into_bytes()))?;
+                nmap += 1;
+            }
+            t => {
+                let desc = self.exp_limit_from(t, 0)?;
+                pending = Some(desc);
+                narray += 1;
+            }
+        }
+
+        match self.lex.next()? {
+            Token::Comma | Token::SemiColon => (),
+            Token::CurlyR => break,
+            t => return Err(self.lex.error_near("'}' expected", &t)),
+        }
+    }
+
+    let nitem = self.fs.sp - table - 1;
+    match pending {
+        Some(desc) if desc.is_multi() => {
+            self.discharge_multi(desc)?;
+            self.flush_list(table, MULTI as usize, narray - 1)?;
+        }
+        Some(desc) => {
+            self.discharge_top(desc)?;
+            self.flush_list(table, nitem + 1, narray)?;
+        }
+        None if nitem > 0 => self.flush_list(table, nitem, narray)?,
+        None => (),
+    }
+
+    self.fs.byte_codes[inew] = ByteCode::NewTable(table as u8,
+        narray.min(u8::MAX as usize) as u8, nmap.min(u8::MAX as usize) as u8);
+    self.fs.sp = table + 1;
+    Ok(ExpDesc::Temp(table))
+}
+
+fn table_field(&mut self, table: usize, key: ExpDesc)->Result<(), LuaError>{
+    let sp = self.fs.sp;
+    let value = self.exp()?;
+    let code = match self.index(table, key)? {
+        ExpDesc::IndexField(_, k) => ByteCode::SetField(table as u8, k as u8, self.discharge_any(value)? as u8),
+        ExpDesc::Index(_, k) => ByteCode::SetTable(table as u8, k as u8, self.discharge_any(value)? as u8),
+        _ => unreachable!(),
+    };
+    self.push_code(code);
+    self.fs.sp = sp;
+    Ok(())
+}
+
+// store the `n` items above `table`, the last of them being item `last`
+fn flush_list(&mut self, table: usize, n: usize, last: usize)->Result<(), LuaError>{
+    let count = if n == MULTI as usize { 0 } else { n };
+    let batch = (last - count) / FIELDS_PER_FLUSH;
+    if batch > u8::MAX as usize {
+        let t = self.lex.peek()?.clone();
+        return Err(self.lex.error_near("too many items in a table constructor", &t));
+    }
+    self.push_code(ByteCode::SetList(table as u8, n as u8, batch as u8));
+    self.fs.sp = table + 1;
+    Ok(())
+}
+
+// load `desc` into register `dst`, then free the registers above it
+fn discharge(&mut self, dst: usize, desc: ExpDesc)->Result<(), LuaError>{
+    self.use_register(dst)?;
+    let code = match desc {
+        ExpDesc::Nil => ByteCode::LoadNil(dst as u8),
+        ExpDesc::Boolean(b) => ByteCode::LoadBool(dst as u8, b),
+        ExpDesc::Integer(i) => match i16::try_from(i) {
+            Ok(i) => ByteCode::LoadInt(dst as u8, i),
+            Err(_) => self.load_const(dst, i)?,
+        }
+        ExpDesc::Float(f) => self.load_const(dst, f)?,
+        ExpDesc::String(s) => self.load_const(dst, s)?,
+
+        ExpDesc::Local(src) | ExpDesc::Temp(src) => {
+            if src == dst {
+                self.fs.sp = dst + 1;
+                return Ok(());
+            }
+            ByteCode::Move(dst as u8, src as u8)
+        }
+        ExpDesc::Upvalue(u) => ByteCode::GetUpvalue(dst as u8, u as u8),
+        ExpDesc::Index(t, k) => ByteCode::GetTable(dst as u8, t as u8, k as u8),
+        ExpDesc::IndexField(t, k) => ByteCode::GetField(dst as u8, t as u8, k as u8),
+        ExpDesc::IndexUpField(u, k) => ByteCode::GetUpField(dst as u8, u as u8, k as u8),
+        ExpDesc::Function(f) => ByteCode::Closure(dst as u8, f as u16),
+
+        ExpDesc::Call(ifunc, nargs) => {
+            self.push_code(ByteCode::Call(ifunc as u8, nargs as u8, 1));
+            if ifunc == dst {
+                self.fs.sp = dst + 1;
+                return Ok(());
+            }
+            ByteCode::Move(dst as u8, ifunc as u8)
+        }
+        ExpDesc::VarArgs => ByteCode::VarArgs(dst as u8, 1),
+
+        ExpDesc::UnaryOp(op, operand) => op(dst as u8, operand as u8),
+        ExpDesc::BinaryOp(op, left, right) => op(dst as u8, left as u8, right as u8),
+    };
+    self.push_code(code);
+    self.fs.sp = dst + 1;
+    Ok(())
+}
+
+// load `desc` into a new register, after freeing the temporaries it uses
+fn discharge_top(&mut self, desc: ExpDesc)->Result<usize, LuaError>{
+    self.free_temps(&desc);
+    let dst = self.fs.sp;
+    self.discharge(dst, desc)?;
+    Ok(dst)
+}
+
+// the register holding `desc`, loading it into a new one if needed
+fn discharge_any(&mut self, desc: ExpDesc)->Result<usize, LuaError>{
+    match desc {
+        ExpDesc::Local(i) | ExpDesc::Temp(i) => Ok(i),
+        desc => self.discharge_top(desc),
+    }
+}
+
+// leave all values of a multi-value expression at the stack top
+fn discharge_multi(&mut self, desc: ExpDesc)->Result<(), LuaError>{
+    match desc {
+        ExpDesc::Call(ifunc, nargs) =>
+            self.push_code(ByteCode::Call(ifunc as u8, nargs as u8, MULTI)),
+        ExpDesc::VarArgs => {
+            let dst = self.alloc()?;
+            self.push_code(ByteCode::VarArgs(dst as u8, MULTI));
+        }
+        _ => panic!("not a multi-value expression"),
+    }
+    Ok(())
 }
 
+// temporaries are allocated as a stack, so freeing the lowest one an
+// expression uses frees all of them
+fn free_temps(&mut self, desc: &ExpDesc) {
+    let (a, b) = match *desc {
+        ExpDesc::Temp(r) | ExpDesc::UnaryOp(_, r) |
+        ExpDesc::IndexField(r, _) | ExpDesc::Call(r, _) => (r, r),
+        ExpDesc::Index(a, b) | ExpDesc::BinaryOp(_, a, b) => (a, b),
+        _ => return,
+    };
+    let nlocals = self.fs.locals.len();
+    if let Some(lowest) = [a, b].into_iter().filter(|&r| r >= nlocals).min() {
+        self.fs.sp = self.fs.sp.min(lowest);
+    }
+}
+
+// the jump instruction to skip the block following a condition, or None
+// if the condition is a constant that never skips
+fn test_jump(&mut self, cond: ExpDesc)->Result<Option<usize>, LuaError>{
+    let code = match cond {
+        ExpDesc::Boolean(true) | ExpDesc::Integer(_) |
+        ExpDesc::Float(_) | ExpDesc::String(_) => return Ok(None),
+        ExpDesc::Nil | ExpDesc::Boolean(false) => ByteCode::Jump(0),
+        cond => ByteCode::TestAndJump(self.discharge_any(cond)? as u8, 0),
+    };
+    self.push_code(code);
+    Ok(Some(self.byte_codes_len() - 1))
+}
 
+// point the jump at `pc` to `target`
+fn patch_jump(&mut self, pc: usize, target: usize)->Result<(), LuaError>{
+    let offset = self.jump_offset_from(pc + 1, target)?;
+    self.fs.byte_codes[pc] = match self.fs.byte_codes[pc] {
+        ByteCode::Jump(_) => ByteCode::Jump(offset),
+        ByteCode::TestAndJump(r, _) => ByteCode::TestAndJump(r, offset),
+        ByteCode::TestOrJump(r, _) => ByteCode::TestOrJump(r, offset),
+        code => panic!("not a jump: {code:?}"),
+    };
+    Ok(())
 }
 
+fn jump_back(&mut self, target: usize)->Result<(), LuaError>{
+    let offset = self.jump_offset(target)?;
+    self.push_code(ByteCode::Jump(offset));
+    Ok(())
+}
 
+// offset to `target` from a jump about to be pushed
+fn jump_offset(&mut self, target: usize)->Result<i16, LuaError>{
+    self.jump_offset_from(self.byte_codes_len() + 1, target)
+}
 
+fn jump_offset_from(&mut self, from: usize, target: usize)->Result<i16, LuaError>{
+    i16::try_from(target as isize - from as isize).or_else(|_| {
+        let t = self.lex.peek()?.clone();
+        Err(self.lex.error_near("control structure too long", &t))
+    })
+}
 
+fn for_offset(&mut self, n: usize)->Result<u16, LuaError>{
+    u16::try_from(n).or_else(|_| {
+        let t = self.lex.peek()?.clone();
+        Err(self.lex.error_near("control structure too long", &t))
+    })
+}
 
+fn alloc(&mut self)->Result<usize, LuaError>{
+    let i = self.fs.sp;
+    self.use_register(i)?;
+    self.fs.sp += 1;
+    Ok(i)
+}
 
+// activate a local in the next register, which holds its value already
+fn add_local(&mut self, name: String, is_const: bool)->Result<usize, LuaError>{
+    let reg = self.fs.locals.len();
+    if reg >= MAX_LOCALS {
+        let msg = format!("too many local variables (limit is {MAX_LOCALS}) in {}", self.fs.what());
+        let t = self.lex.peek()?.clone();
+        return Err(self.lex.error_near(&msg, &t));
+    }
+    self.use_register(reg)?;
+    self.fs.locvars.push(LocVar {
+        name: name.clone(),
+        reg,
+        start_pc: self.byte_codes_len(),
+        end_pc: usize::MAX,
+    });
+    self.fs.locals.push(Local { name, is_const, captured: false, locvar: self.fs.locvars.len() - 1 });
+    self.fs.sp = self.fs.sp.max(reg + 1);
+    Ok(reg)
+}
 
+fn remove_locals(&mut self, level: usize) {
+    let pc = self.byte_codes_len();
+    for local in self.fs.locals.drain(level..) {
+        self.fs.locvars[local.locvar].end_pc = pc;
+    }
+}
 
+fn read_name(&mut self)->Result<String, LuaError>{
+    match self.lex.next()? {
+        Token::Name(name) => Ok(name),
+        t => Err(self.lex.error_near("<name> expected", &t)),
+    }
+}
+
+fn expect(&mut self, want: Token, msg: &str)->Result<(), LuaError>{
+    let t = self.lex.next()?;
+    if t == want {
+        Ok(())
+    } else {
+        Err(self.lex.error_near(msg, &t))
+    }
+}
+
+// expect the token closing `open`, which was at `line`
+fn expect_match(&mut self, want: Token, open: Token, line: usize)->Result<(), LuaError>{
+    let t = self.lex.next()?;
+    if t == want {
+        Ok(())
+    } else {
+        Err(self.match_error(want, open, line, &t))
+    }
+}
+
+// the token ending a block should be `end`
+fn check_end(&mut self, t: Token, open: Token, line: usize)->Result<(), LuaError>{
+    if t == Token::End {
+        Ok(())
+    } else {
+        Err(self.match_error(Token::End, open, line, &t))
+    }
+}
+
+fn match_error(&self, want: Token, open: Token, line: usize, near: &Token) -> LuaError {
+    if line == self.lex.line() {
+        self.lex.error_near(&format!("{want} expected"), near)
+    } else {
+        self.lex.error_near(&format!("{want} expected (to close {open} at line {line})"), near)
+    }
+}
+
+fn load_const<T:Into<Value>>(&mut self,dst: usize, c:T) -> Result<ByteCode, LuaError> {
+   Ok(ByteCode::LoadConst(dst as u8, self.add_const(c)? as u16))
+}
+
+fn add_const<T:Into<Value>>(&mut self,c: T) -> Result<usize, LuaError> {
+    let c = c.into();
+    let constants = &mut self.fs.constants;
+    let i = constants.iter()
+             .position(|v| same_const(v, &c))
+             .unwrap_or_else(||{
+                  constants.push(c);
+                  constants.len()-1
+             });
+    if i > u16::MAX as usize {
+        let t = self.lex.peek()?.clone();
+        return Err(self.lex.error_near("too many constants in a chunk", &t));
+    }
+    Ok(i)
+}
+
+fn byte_codes_len(&self) -> usize {
+    self.fs.byte_codes.len()
+}
+
+fn push_code(&mut self, code: ByteCode) {
+    self.fs.byte_codes.push(code);
+    self.fs.lines.push(self.lex.last_line() as u32);
+}
+
+// record that register `i` is written, growing the declared stack size
+fn use_register(&mut self, i: usize) -> Result<(), LuaError> {
+    if i >= u8::MAX as usize {
+        let t = self.lex.peek()?.clone();
+        return Err(self.lex.error_near("function or expression needs too many registers", &t));
+    }
+    self.fs.max_stack_size = self.fs.max_stack_size.max(i + 1);
+    Ok(())
+}
+
+fn get_local(&self, name: &str) -> Option<usize> {
+   self.fs.locals.iter().rposition(|v| v.name == name)
+}
+
+
+}
+
+// 1 == 1.0 and 0.0 == -0.0 in Lua, but constants must keep them apart
+fn same_const(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Float(f1), Value::Float(f2)) => f1.to_bits() == f2.to_bits(),
+        (Value::Integer(_), Value::Float(_)) | (Value::Float(_), Value::Integer(_)) => false,
+        (a, b) => a == b,
+    }
+}
+
+const UNARY_PRIORITY: i32 = 12;
+
+// (left, right) priorities of binary operators, 0 for other tokens
+fn binop_priority(t: &Token) -> (i32, i32) {
+    match t {
+        Token::Or => (1, 1),
+        Token::And => (2, 2),
+        Token::Equal | Token::NotEq | Token::Less | Token::LesEq |
+        Token::Greater | Token::GreEq => (3, 3),
+        Token::BitOr => (4, 4),
+        Token::BitXor => (5, 5),
+        Token::BitAnd => (6, 6),
+        Token::ShiftL | Token::ShiftR => (7, 7),
+        Token::Concat => (9, 8), // right associative
+        Token::Add | Token::Sub => (10, 10),
+        Token::Mul | Token::Div | Token::Idiv | Token::Mod => (11, 11),
+        Token::Pow => (14, 13), // right associative
+        _ => (0, 0),
+    }
+}
+
+fn binop_code(t: &Token) -> fn(u8,u8,u8)->ByteCode {
+    match t {
+        Token::Add => ByteCode::Add,
+        Token::Sub => ByteCode::Sub,
+        Token::Mul => ByteCode::Mul,
+        Token::Div => ByteCode::Div,
+        Token::Idiv => ByteCode::Idiv,
+        Token::Mod => ByteCode::Mod,
+        Token::Pow => ByteCode::Pow,
+        Token::BitAnd => ByteCode::BitAnd,
+        Token::BitXor => ByteCode::BitXor,
+        Token::BitOr => ByteCode::BitOr,
+        Token::ShiftL => ByteCode::ShiftL,
+        Token::ShiftR => ByteCode::ShiftR,
+        Token::Concat => ByteCode::Concat,
+        Token::Equal => ByteCode::Equal,
+        Token::NotEq => ByteCode::NotEq,
+        Token::Less => ByteCode::Less,
+        Token::LesEq => ByteCode::LesEq,
+        // a > b is b < a
+        Token::Greater => |dst, a, b| ByteCode::Less(dst, b, a),
+        Token::GreEq => |dst, a, b| ByteCode::LesEq(dst, b, a),
+        _ => panic!("not a binary operator: {t:?}"),
+    }
+}
@@ -0,0 +1,474 @@
+// Lua patterns, as used by string.find, match, gmatch and gsub.
+//
+// This follows the matcher of the reference implementation: a
+// backtracking match over bytes, recursing for repetitions and captures.
+// The recursion depth is bounded, so a pattern can not overflow the host
+// stack, and so is the work of a match at one start position, so that an
+// exponential pattern fails instead of running forever. Both report
+// "pattern too complex". The step limit is not a bound on the total time
+// of a search, which may try every start position of the subject.
+
+
+// nesting of match() calls
+const MAX_DEPTH: usize = 200;
+// match() calls and repetition steps of a match at one start position;
+// reset by match_at()
+const MAX_STEPS: usize = 100_000_000;
+const MAX_CAPTURES: usize = 32;
+
+const ESC: u8 = b'%';
+
+
+// a capture of a successful match
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capture {
+   // the captured bytes of the subject, start..end
+   Str(usize, usize),
+   // an empty capture `()`, at this position (counted from 1)
+   Position(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CaptureLen {
+   Len(usize),
+   Unfinished,
+   Position,
+}
+
+pub struct Matcher<'a> {
+   src: &'a [u8],
+   pat: &'a [u8],
+   level: usize,
+   captures: [(usize, CaptureLen); MAX_CAPTURES],
+   depth: usize,
+   steps: usize,
+}
+
+impl<'a> Matcher<'a> {
+   pub fn new(src: &'a [u8], pat: &'a [u8]) -> Self {
+      Matcher {
+         src,
+         pat,
+         level: 0,
+         captures: [(0, CaptureLen::Unfinished); MAX_CAPTURES],
+         depth: 0,
+         steps: 0,
+      }
+   }
+
+   // Match the pattern, from pattern index `p`, at subject index `s`.
+   // Return where the match ends, or None if it does not match there.
+   pub fn match_at(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+      self.level = 0;
+      self.depth = 0;
+      self.steps = 0;
+      self.do_match(s, p)
+   }
+
+   // The captures of the last match, which was of start..end. Without
+   // explicit captures, the whole match is the capture if `whole` is set.
+   pub fn captures(&self, start: usize, end: usize, whole: bool) -> Result<Vec<Capture>, String> {
+      let n = if self.level == 0 && whole { 1 } else { self.level };
+      (0..n).map(|i| self.capture(i, start, end)).collect()
+   }
+
+   // capture `i` (from 0) of the last match, which was of start..end
+   pub fn capture(&self, i: usize, start: usize, end: usize) -> Result<Capture, String> {
+      if i >= self.level {
+         if i != 0 {
+            return Err(format!("invalid capture index %{}", i + 1));
+         }
+         return Ok(Capture::Str(start, end));
+      }
+      match self.captures[i] {
+         (s, CaptureLen::Len(len)) => Ok(Capture::Str(s, s + len)),
+         (s, CaptureLen::Position) => Ok(Capture::Position(s + 1)),
+         (_, CaptureLen::Unfinished) => Err(String::from("unfinished capture")),
+      }
+   }
+
+   fn step(&mut self) -> Result<(), String> {
+      self.steps += 1;
+      if self.steps > MAX_STEPS {
+         Err(String::from("pattern too complex"))
+      } else {
+         Ok(())
+      }
+   }
+
+   fn do_match(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+      if self.depth >= MAX_DEPTH {
+         return Err(String::from("pattern too complex"));
+      }
+      self.depth += 1;
+      let result = self.match_here(s, p);
+      self.depth -= 1;
+      result
+   }
+
+   fn match_here(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>, String> {
+      // tail calls of the pattern are turned into this loop
+      loop {
+         self.step()?;
+         if p == self.pat.len() {
+            return Ok(Some(s));
+         }
+         match self.pat[p] {
+            b'(' => {
+               return if self.pat.get(p + 1) == Some(&b')') {
+                  self.start_capture(s, p + 2, CaptureLen::Position)
+               } else {
+                  self.start_capture(s, p + 1, CaptureLen::Unfinished)
+               };
+            }
+            b')' => return self.end_capture(s, p + 1),
+            b'$' if p + 1 == self.pat.len() => {
+               return Ok((s == self.src.len()).then_some(s));
+            }
+            ESC if self.pat.get(p + 1) == Some(&b'b') => {
+               match self.match_balance(s, p + 2)? {
+                  Some(e) => {
+                     s = e;
+                     p += 4;
+                  }
+                  None => return Ok(None),
+               }
+            }
+            ESC if self.pat.get(p + 1) == Some(&b'f') => {
+               // frontier: the previous byte is not in the set, this one is
+               p += 2;
+               if self.pat.get(p) != Some(&b'[') {
+                  return Err(String::from("missing '[' after '%f' in pattern"));
+               }
+               let ep = self.class_end(p)?;
+               let previous = if s == 0 { 0 } else { self.src[s - 1] };
+               let current = self.src.get(s).copied().unwrap_or(0);
+               if self.match_bracket_class(previous, p, ep - 1) ||
+                  !self.match_bracket_class(current, p, ep - 1) {
+                  return Ok(None);
+               }
+               p = ep;
+            }
+            ESC if self.pat.get(p + 1).is_some_and(u8::is_ascii_digit) => {
+               // a back reference %1-%9
+               match self.match_capture(s, self.pat[p + 1])? {
+                  Some(e) => {
+                     s = e;
+                     p += 2;
+                  }
+                  None => return Ok(None),
+               }
+            }
+            _ => {
+               // a single character class with an optional repetition
+               let ep = self.class_end(p)?;
+               let suffix = self.pat.get(ep).copied();
+               if !self.single_match(s, p, ep) {
+                  if let Some(b'*' | b'?' | b'-') = suffix {
+                     // zero repetitions are fine
+                     p = ep + 1;
+                     continue;
+                  }
+                  return Ok(None);
+               }
+               match suffix {
+                  Some(b'?') => {
+                     if let Some(e) = self.do_match(s + 1, ep + 1)? {
+                        return Ok(Some(e));
+                     }
+                     p = ep + 1;
+                  }
+                  Some(b'+') => return self.max_expand(s + 1, p, ep),
+                  Some(b'*') => return self.max_expand(s, p, ep),
+                  Some(b'-') => return self.min_expand(s, p, ep),
+                  _ => {
+                     s += 1;
+                     p = ep;
+                  }
+               }
+            }
+         }
+      }
+   }
+
+   // the longest repetition of the class at p that lets the rest match
+   fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> Result<Option<usize>, String> {
+      let mut i = 0;
+      while self.single_match(s + i, p, ep) {
+         self.step()?;
+         i += 1;
+      }
+      loop {
+         if let Some(e) = self.do_match(s + i, ep + 1)? {
+            return Ok(Some(e));
+         }
+         if i == 0 {
+            return Ok(None);
+         }
+         i -= 1;
+      }
+   }
+
+   // the shortest repetition of the class at p that lets the rest match
+   fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> Result<Option<usize>, String> {
+      loop {
+         if let Some(e) = self.do_match(s, ep + 1)? {
+            return Ok(Some(e));
+         }
+         if !self.single_match(s, p, ep) {
+            return Ok(None);
+         }
+         s += 1;
+      }
+   }
+
+   fn start_capture(&mut self, s: usize, p: usize, what: CaptureLen) -> Result<Option<usize>, String> {
+      if self.level >= MAX_CAPTURES {
+         return Err(String::from("too many captures"));
+      }
+      self.captures[self.level] = (s, what);
+      self.level += 1;
+      let result = self.do_match(s, p)?;
+      if result.is_none() {
+         self.level -= 1;
+      }
+      Ok(result)
+   }
+
+   fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+      // the innermost open capture
+      let Some(l) = (0..self.level).rev().find(|&l| self.captures[l].1 == CaptureLen::Unfinished) else {
+         return Err(String::from("invalid pattern capture"));
+      };
+      self.captures[l].1 = CaptureLen::Len(s - self.captures[l].0);
+      let result = self.do_match(s, p)?;
+      if result.is_none() {
+         self.captures[l].1 = CaptureLen::Unfinished;
+      }
+      Ok(result)
+   }
+
+   // %1-%9: the same text as a previous capture
+   fn match_capture(&self, s: usize, digit: u8) -> Result<Option<usize>, String> {
+      let l = (digit - b'0') as usize;
+      if l == 0 || l > self.level || self.captures[l - 1].1 == CaptureLen::Unfinished {
+         return Err(format!("invalid capture index %{l}"));
+      }
+      let (start, len) = match self.captures[l - 1] {
+         (start, CaptureLen::Len(len)) => (start, len),
+         _ => return Ok(None),
+      };
+      let text = &self.src[start .. start + len];
+      Ok(self.src[s..].starts_with(text).then_some(s + len))
+   }
+
+   // %bxy: a balanced run from x to y
+   fn match_balance(&self, s: usize, p: usize) -> Result<Option<usize>, String> {
+      if p + 1 >= self.pat.len() {
+         return Err(String::from("malformed pattern (missing arguments to '%b')"));
+      }
+      let (open, close) = (self.pat[p], self.pat[p + 1]);
+      if self.src.get(s) != Some(&open) {
+         return Ok(None);
+      }
+      let mut depth = 1;
+      for (i, &c) in self.src.iter().enumerate().skip(s + 1) {
+         if c == close {
+            depth -= 1;
+            if depth == 0 {
+               return Ok(Some(i + 1));
+            }
+         } else if c == open {
+            depth += 1;
+         }
+      }
+      Ok(None)
+   }
+
+   // the end of the single character class starting at p
+   fn class_end(&self, mut p: usize) -> Result<usize, String> {
+      let c = self.pat[p];
+      p += 1;
+      match c {
+         ESC => {
+            if p == self.pat.len() {
+               return Err(String::from("malformed pattern (ends with '%')"));
+            }
+            Ok(p + 1)
+         }
+         b'[' => {
+            if self.pat.get(p) == Some(&b'^') {
+               p += 1;
+            }
+            // the first character of a set is never its end, so "[]]"
+            // is the set of ']'
+            loop {
+               if p >= self.pat.len() {
+                  return Err(String::from("malformed pattern (missing ']')"));
+               }
+               let c = self.pat[p];
+               p += 1;
+               if c == ESC && p < self.pat.len() {
+                  p += 1;
+               }
+               if self.pat.get(p) == Some(&b']') {
+                  return Ok(p + 1);
+               }
+            }
+         }
+         _ => Ok(p),
+      }
+   }
+
+   // whether the subject byte at s is in the class p..ep
+   fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+      let Some(&c) = self.src.get(s) else {
+         return false;
+      };
+      match self.pat[p] {
+         b'.' => true,
+         ESC => match_class(c, self.pat[p + 1]),
+         b'[' => self.match_bracket_class(c, p, ep - 1),
+         pc => pc == c,
+      }
+   }
+
+   // whether c is in the set [...] from p to its closing ']' at ec
+   fn match_bracket_class(&self, c: u8, mut p: usize, ec: usize) -> bool {
+      let mut found = true;
+      if self.pat[p + 1] == b'^' {
+         found = false;
+         p += 1;
+      }
+      p += 1;
+      while p < ec {
+         if self.pat[p] == ESC {
+            p += 1;
+            if match_class(c, self.pat[p]) {
+               return found;
+            }
+         } else if self.pat[p + 1] == b'-' && p + 2 < ec {
+            if self.pat[p] <= c && c <= self.pat[p + 2] {
+               return found;
+            }
+            p += 2;
+         } else if self.pat[p] == c {
+            return found;
+         }
+         p += 1;
+      }
+      !found
+   }
+}
+
+// %a, %d, ... in the C locale; the upper case letter is the complement
+fn match_class(c: u8, class: u8) -> bool {
+   let found = match class.to_ascii_lowercase() {
+      b'a' => c.is_ascii_alphabetic(),
+      b'c' => c.is_ascii_control(),
+      b'd' => c.is_ascii_digit(),
+      b'g' => c.is_ascii_graphic(),
+      b'l' => c.is_ascii_lowercase(),
+      b'p' => c.is_ascii_punctuation(),
+      // isspace() also counts the vertical tab
+      b's' => c.is_ascii_whitespace() || c == 0x0b,
+      b'u' => c.is_ascii_uppercase(),
+      b'w' => c.is_ascii_alphanumeric(),
+      b'x' => c.is_ascii_hexdigit(),
+      _ => return class == c,
+   };
+   if class.is_ascii_uppercase() { !found } else { found }
+}
+
+// Find the first match at or after `init`. A leading '^' anchors the
+// pattern to `init`. Return the start and end of the match.
+pub fn find(m: &mut Matcher, init: usize) -> Result<Option<(usize, usize)>, String> {
+   let anchor = m.pat.first() == Some(&b'^');
+   let p = anchor as usize;
+   let mut s = init;
+   loop {
+      if let Some(e) = m.match_at(s, p)? {
+         return Ok(Some((s, e)));
+      }
+      s += 1;
+      if anchor || s > m.src.len() {
+         return Ok(None);
+      }
+   }
+}
+
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn find_in(src: &str, pat: &str) -> Result<Option<(usize, usize)>, String> {
+      find(&mut Matcher::new(src.as_bytes(), pat.as_bytes()), 0)
+   }
+
+   // the captures of the first match, the whole match if there are none
+   fn captures_in(src: &str, pat: &str) -> Result<Option<Vec<Capture>>, String> {
+      let mut m = Matcher::new(src.as_bytes(), pat.as_bytes());
+      match find(&mut m, 0)? {
+         Some((start, end)) => m.captures(start, end, true).map(Some),
+         None => Ok(None),
+      }
+   }
+
+   #[test]
+   fn depth_limit() {
+      let src = "a".repeat(300);
+      assert_eq!(find_in(&src, &"a?".repeat(300)), Err(String::from("pattern too complex")));
+      assert_eq!(find_in(&src, &"a?".repeat(100)), Ok(Some((0, 100))));
+   }
+
+   #[test]
+   fn steps_counted_per_position() {
+      // quadratic over the whole subject, but linear at each position
+      let src = "a".repeat(2000);
+      let mut m = Matcher::new(src.as_bytes(), b"a-b");
+      assert_eq!(find(&mut m, 0), Ok(None));
+      assert!(m.steps < src.len());
+   }
+
+   #[test]
+   fn balanced() {
+      assert_eq!(find_in("x(a(b)c)y", "%b()"), Ok(Some((1, 8))));
+      assert_eq!(find_in("x(a(b c", "%b()"), Ok(None));
+      assert_eq!(find_in("if [[x]] end", "%b[]"), Ok(Some((3, 8))));
+      assert_eq!(find_in("x", "%b("), Err(String::from("malformed pattern (missing arguments to '%b')")));
+   }
+
+   #[test]
+   fn frontier() {
+      assert_eq!(find_in("THE (quick) fox", "%f[%a]%a+%f[%A]"), Ok(Some((0, 3))));
+      assert_eq!(find(&mut Matcher::new(b"THE (quick) fox", b"%f[%a]%a+"), 3), Ok(Some((5, 10))));
+      // the end of the subject counts as '\0', in no class
+      assert_eq!(find_in("the end", "d%f[^%a]"), Ok(Some((6, 7))));
+      assert_eq!(find_in("x", "%fa"), Err(String::from("missing '[' after '%f' in pattern")));
+   }
+
+   #[test]
+   fn position_captures() {
+      assert_eq!(captures_in("hello", "()ll()"), Ok(Some(vec![Capture::Position(3), Capture::Position(5)])));
+      assert_eq!(captures_in("hello", "h(()e)"), Ok(Some(vec![Capture::Str(1, 2), Capture::Position(2)])));
+   }
+
+   #[test]
+   fn back_references() {
+      assert_eq!(captures_in("abcabc", "(%a+)%1"), Ok(Some(vec![Capture::Str(0, 3)])));
+      assert_eq!(captures_in("say \"hi\" and 'yo'", "([\"'])(.-)%1"),
+                 Ok(Some(vec![Capture::Str(4, 5), Capture::Str(5, 7)])));
+      assert_eq!(find_in("abcabd", "^(%a+)%1$"), Ok(None));
+      assert_eq!(find_in("aa", "(a)%2"), Err(String::from("invalid capture index %2")));
+      assert_eq!(find_in("aa", "(a%1)"), Err(String::from("invalid capture index %1")));
+   }
+
+   #[test]
+   fn capture_errors() {
+      // found when the captures are taken
+      assert_eq!(captures_in("a", "(a"), Err(String::from("unfinished capture")));
+      assert_eq!(find_in("a", "a)"), Err(String::from("invalid pattern capture")));
+      assert_eq!(find_in("a", &"()".repeat(33)), Err(String::from("too many captures")));
+   }
+}
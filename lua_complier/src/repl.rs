@@ -0,0 +1,114 @@
+use std::env;
+use std::path::PathBuf;
+
+use rustyline::{Config, DefaultEditor, error::ReadlineError};
+
+use lua_llvm::{ExeState, LuaError, Value};
+
+
+const PROMPT: &str = "> ";
+const PROMPT2: &str = ">> ";
+const HISTORY_MAX: usize = 1000;
+
+
+// The interactive loop of the `lua` program: read a statement, possibly
+// spread over several lines, run it in `state` and print what it returns.
+// Globals persist between statements since they all share one state.
+// Only os.exit ends it with an error, for the caller to exit with.
+pub fn run(state: &mut ExeState) -> Result<(), LuaError> {
+    let config = Config::builder().max_history_size(HISTORY_MAX).map(|b| b.build()).unwrap_or_default();
+    let Ok(mut editor) = DefaultEditor::with_config(config) else {
+        eprintln!("cannot read from the terminal");
+        return Ok(());
+    };
+    let history = history_path();
+    if let Some(path) = &history {
+        let _ = editor.load_history(path);
+    }
+
+    while let Some(line) = read_line(&mut editor, PROMPT) {
+        let Some((source, func)) = load_statement(state, &mut editor, line) else {
+            break;
+        };
+        if !source.trim().is_empty() && editor.add_history_entry(&source).unwrap_or(false) {
+            if let Some(path) = &history {
+                let _ = editor.append_history(path);
+            }
+        }
+
+        match func.and_then(|func| state.call(&func, &[])) {
+            Ok(results) => print_results(state, results)?,
+            Err(err) if err.is_exit() => return Err(err),
+            Err(err) => eprintln!("{}", crate::error_message(state, err)),
+        }
+    }
+    println!();
+    Ok(())
+}
+
+// Compile the statement starting with `line`. A bare expression (or the
+// old `=expr` form) is compiled as `return expr` so its values get printed.
+// While the parser stops at an unexpected <eof> the statement is
+// incomplete, so keep reading lines.
+//
+// Return the whole source text and the compile result, or None if the
+// input ended in the middle of a statement.
+fn load_statement(state: &mut ExeState, editor: &mut DefaultEditor, line: String)
+    -> Option<(String, Result<Value, LuaError>)> {
+
+    if let Some(exp) = line.strip_prefix('=') {
+        if let Ok(func) = compile(state, &format!("return {exp}")) {
+            return Some((line, Ok(func)));
+        }
+    }
+    if let Ok(func) = compile(state, &format!("return {line}")) {
+        return Some((line, Ok(func)));
+    }
+
+    let mut source = line;
+    loop {
+        match compile(state, &source) {
+            Err(err) if err.is_incomplete() => {
+                let more = read_line(editor, PROMPT2)?;
+                source.push('\n');
+                source.push_str(&more);
+            }
+            result => return Some((source, result)),
+        }
+    }
+}
+
+fn compile(state: &mut ExeState, source: &str) -> Result<Value, LuaError> {
+    state.load(source.as_bytes(), "=stdin", "t", None)
+}
+
+// print returned values with the global `print`, as the reference REPL does
+fn print_results(state: &mut ExeState, results: Vec<Value>) -> Result<(), LuaError> {
+    if results.is_empty() {
+        return Ok(());
+    }
+    let print = state.get_global("print");
+    match state.call(&print, &results) {
+        Err(err) if err.is_exit() => return Err(err),
+        Err(err) => eprintln!("error calling 'print' ({err})"),
+        Ok(_) => (),
+    }
+    Ok(())
+}
+
+// A line typed at the prompt, with line editing and the history of past
+// sessions. Ctrl-C drops the line; the end of the input gives None.
+fn read_line(editor: &mut DefaultEditor, prompt: &str) -> Option<String> {
+    loop {
+        match editor.readline(prompt) {
+            Ok(line) => return Some(line),
+            Err(ReadlineError::Interrupted) => continue,
+            Err(_) => return None,
+        }
+    }
+}
+
+// ~/.lua_history, where the statements entered are kept between sessions
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".lua_history"))
+}
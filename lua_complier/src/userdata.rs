@@ -0,0 +1,282 @@
+use std::{any::{self, Any, TypeId}, cell::RefCell, collections::HashMap, marker::PhantomData, rc::{Rc, Weak}};
+
+use crate::{value::{Value, Table, AnyUserData}, vm::ExeState, error::LuaError, convert::{FromLua, FromLuaMulti, IntoLua, IntoLuaMulti}};
+
+
+// A Rust type whose values scripts use as userdata, through methods and
+// fields it declares. All values of the type share one metatable, built
+// the first time ExeState::create_userdata gets one:
+//
+//     impl UserData for Point {
+//         fn add_fields(fields: &mut UserDataFields<Self>) {
+//             fields.add_field_method_get("x", |_, p| Ok(p.x));
+//         }
+//         fn add_methods(methods: &mut UserDataMethods<Self>) {
+//             methods.add_method("norm", |_, p, ()| Ok(p.x.hypot(p.y)));
+//             methods.add_meta_method("__tostring", |_, p, ()| Ok(format!("({}, {})", p.x, p.y)));
+//         }
+//     }
+//
+// Metamethods are set in the metatable as they are, but __index and
+// __newindex, which are tried after the methods and fields. A __gc
+// metamethod is called once the value is dropped, when the state next
+// runs its finalizers (see ExeState::run_finalizers).
+pub trait UserData: Any + Sized {
+   // the type name in messages, and the __name of the metatable
+   fn name() -> &'static str {
+      any::type_name::<Self>()
+   }
+
+   fn add_fields(_fields: &mut UserDataFields<Self>) {}
+
+   fn add_methods(_methods: &mut UserDataMethods<Self>) {}
+}
+
+// The methods of userdata type T, found by obj:name(...), and its
+// metamethods. The functions get the arguments after the object.
+pub struct UserDataMethods<T> {
+   methods: Table,
+   meta: Table,
+   marker: PhantomData<T>,
+}
+
+impl<T: UserData> UserDataMethods<T> {
+   pub fn add_method<A, R, F>(&mut self, name: &str, func: F)
+   where A: FromLuaMulti, R: IntoLuaMulti, F: Fn(&mut ExeState, &T, A) -> Result<R, LuaError> + 'static {
+      self.methods.set_str(name, method(func));
+   }
+
+   // a method changing the object; calling another method on the object
+   // while it runs is an error
+   pub fn add_method_mut<A, R, F>(&mut self, name: &str, func: F)
+   where A: FromLuaMulti, R: IntoLuaMulti, F: Fn(&mut ExeState, &mut T, A) -> Result<R, LuaError> + 'static {
+      self.methods.set_str(name, method_mut(func));
+   }
+
+   // a function in the methods, which gets all its arguments
+   pub fn add_function<A, R, F>(&mut self, name: &str, func: F)
+   where A: FromLuaMulti, R: IntoLuaMulti, F: Fn(&mut ExeState, A) -> Result<R, LuaError> + 'static {
+      self.methods.set_str(name, crate::lua::native_function(func));
+   }
+
+   // a metamethod whose first operand is the object, as __tostring,
+   // __len or __call
+   pub fn add_meta_method<A, R, F>(&mut self, event: &str, func: F)
+   where A: FromLuaMulti, R: IntoLuaMulti, F: Fn(&mut ExeState, &T, A) -> Result<R, LuaError> + 'static {
+      self.meta.set_str(event, method(func));
+   }
+
+   pub fn add_meta_method_mut<A, R, F>(&mut self, event: &str, func: F)
+   where A: FromLuaMulti, R: IntoLuaMulti, F: Fn(&mut ExeState, &mut T, A) -> Result<R, LuaError> + 'static {
+      self.meta.set_str(event, method_mut(func));
+   }
+
+   // a metamethod getting both operands, for arithmetic or comparisons
+   // where the object may be either one
+   pub fn add_meta_function<A, R, F>(&mut self, event: &str, func: F)
+   where A: FromLuaMulti, R: IntoLuaMulti, F: Fn(&mut ExeState, A) -> Result<R, LuaError> + 'static {
+      self.meta.set_str(event, crate::lua::native_function(func));
+   }
+}
+
+// The fields of userdata type T, read by obj.name and set by
+// obj.name = v. Fields without a setter are read only.
+pub struct UserDataFields<T> {
+   getters: HashMap<Vec<u8>, Box<Getter<T>>>,
+   setters: HashMap<Vec<u8>, Box<Setter<T>>>,
+}
+
+type Getter<T> = dyn Fn(&mut ExeState, &T) -> Result<Value, LuaError>;
+type Setter<T> = dyn Fn(&mut ExeState, &mut T, Value) -> Result<(), LuaError>;
+
+impl<T: UserData> UserDataFields<T> {
+   pub fn add_field_method_get<R, F>(&mut self, name: &str, func: F)
+   where R: IntoLua, F: Fn(&mut ExeState, &T) -> Result<R, LuaError> + 'static {
+      self.getters.insert(name.into(), Box::new(move |state, this| func(state, this)?.into_lua()));
+   }
+
+   // a value that does not convert to A is an error naming the field
+   pub fn add_field_method_set<A, F>(&mut self, name: &str, func: F)
+   where A: FromLua, F: Fn(&mut ExeState, &mut T, A) -> Result<(), LuaError> + 'static {
+      let field = name.to_string();
+      self.setters.insert(name.into(), Box::new(move |state, this, v| {
+         let v = A::from_lua(v).map_err(|err| match err {
+            LuaError::Runtime(msg) if msg.is_string() =>
+               state.error(&format!("bad value for field '{field}' of {} ({msg:?})", T::name())),
+            err => err,
+         })?;
+         func(state, this, v)
+      }));
+   }
+}
+
+
+// the object, argument 1 of a method
+fn check_self<T: UserData>(state: &ExeState) -> Result<Rc<AnyUserData>, LuaError> {
+   match state.get(1) {
+      Value::UserData(u) if u.is::<T>() => Ok(u.clone()),
+      _ => Err(state.type_error(1, T::name())),
+   }
+}
+
+// an error of the userdata, located as one of the running function
+fn located(state: &ExeState, err: LuaError) -> LuaError {
+   match err {
+      LuaError::Runtime(msg) if msg.is_string() => state.error(&msg.to_string_lossy()),
+      err => err,
+   }
+}
+
+fn method_args<A: FromLuaMulti>(state: &ExeState) -> Result<A, LuaError> {
+   let args = (2..=state.get_top()).map(|i| state.get(i).clone()).collect();
+   A::from_lua_args(state, args, 2)
+}
+
+fn push_results<R: IntoLuaMulti>(state: &mut ExeState, results: R) -> Result<i32, LuaError> {
+   let results = results.into_lua_multi()?;
+   let n = results.len() as i32;
+   for v in results {
+      state.push(v);
+   }
+   Ok(n)
+}
+
+fn method<T, A, R, F>(func: F) -> Value
+where T: UserData, A: FromLuaMulti, R: IntoLuaMulti, F: Fn(&mut ExeState, &T, A) -> Result<R, LuaError> + 'static {
+   Value::NativeClosure(Rc::new(move |state: &mut ExeState| {
+      let u = check_self::<T>(state)?;
+      let args = method_args(state)?;
+      let this = u.borrow::<T>().map_err(|err| located(state, err))?;
+      let results = func(state, &this, args)?;
+      drop(this);
+      push_results(state, results)
+   }))
+}
+
+fn method_mut<T, A, R, F>(func: F) -> Value
+where T: UserData, A: FromLuaMulti, R: IntoLuaMulti, F: Fn(&mut ExeState, &mut T, A) -> Result<R, LuaError> + 'static {
+   Value::NativeClosure(Rc::new(move |state: &mut ExeState| {
+      let u = check_self::<T>(state)?;
+      let args = method_args(state)?;
+      let mut this = u.borrow_mut::<T>().map_err(|err| located(state, err))?;
+      let results = func(state, &mut this, args)?;
+      drop(this);
+      push_results(state, results)
+   }))
+}
+
+// the metatable of userdata type T
+fn build_metatable<T: UserData>() -> Table {
+   let mut fields = UserDataFields::<T> { getters: HashMap::new(), setters: HashMap::new() };
+   T::add_fields(&mut fields);
+   let mut methods = UserDataMethods::<T> { methods: Table::new(0, 0), meta: Table::new(0, 0), marker: PhantomData };
+   T::add_methods(&mut methods);
+
+   let UserDataMethods { methods, mut meta, .. } = methods;
+   let index = meta.get_str("__index");
+   let newindex = meta.get_str("__newindex");
+   meta.set_str("__name", Value::from(T::name()));
+
+   // methods, then fields, then the __index given
+   let methods = Rc::new(RefCell::new(methods));
+   if fields.getters.is_empty() && index == Value::Nil {
+      meta.set_str("__index", Value::Table(methods));
+   } else {
+      let getters = fields.getters;
+      meta.set_str("__index", Value::NativeClosure(Rc::new(move |state: &mut ExeState| {
+         let u = check_self::<T>(state)?;
+         let key = state.get(2).clone();
+         let mut v = methods.borrow().get(&key);
+         if v == Value::Nil {
+            let getter = if key.is_string() { getters.get(key.as_bytes()) } else { None };
+            v = match (getter, &index) {
+               (Some(getter), _) => {
+                  let this = u.borrow::<T>().map_err(|err| located(state, err))?;
+                  getter(state, &this)?
+               }
+               (None, Value::Nil) => Value::Nil,
+               (None, Value::Table(_)) => state.index(&index, &key)?,
+               (None, index) => state.call(index, &[Value::UserData(u), key])?.into_iter().next().unwrap_or(Value::Nil),
+            };
+         }
+         state.push(v);
+         Ok(1)
+      })));
+   }
+
+   // fields with a setter, then the __newindex given
+   if !fields.setters.is_empty() || newindex != Value::Nil {
+      let setters = fields.setters;
+      meta.set_str("__newindex", Value::NativeClosure(Rc::new(move |state: &mut ExeState| {
+         let u = check_self::<T>(state)?;
+         let (key, v) = (state.get(2).clone(), state.get(3).clone());
+         let setter = if key.is_string() { setters.get(key.as_bytes()) } else { None };
+         match (setter, &newindex) {
+            (Some(setter), _) => {
+               let mut this = u.borrow_mut::<T>().map_err(|err| located(state, err))?;
+               setter(state, &mut this, v)?;
+            }
+            (None, Value::Nil) => {
+               let key = state.tostring(&key)?;
+               let msg = format!("cannot set field '{key:?}' of {}", T::name());
+               return Err(state.error(&msg));
+            }
+            (None, Value::Table(_)) => state.set_index(&newindex, key, v)?,
+            (None, newindex) => {
+               state.call(newindex, &[Value::UserData(u), key, v])?;
+            }
+         }
+         Ok(0)
+      })));
+   }
+   meta
+}
+
+
+// The userdata types of a state: their metatables, and the values with a
+// __gc metamethod, for the state to run it once they are dropped.
+#[derive(Debug, Default)]
+pub struct UserDataTypes {
+   metatables: HashMap<TypeId, Rc<RefCell<Table>>>,
+   // values with a __gc still alive
+   live: Vec<Weak<AnyUserData>>,
+   // dropped values whose __gc is still to run
+   pending: Rc<RefCell<Vec<AnyUserData>>>,
+}
+
+impl UserDataTypes {
+   pub fn create<T: UserData>(&mut self, data: T) -> Rc<AnyUserData> {
+      let meta = self.metatables.entry(TypeId::of::<T>())
+         .or_insert_with(|| Rc::new(RefCell::new(build_metatable::<T>())))
+         .clone();
+      let has_gc = meta.borrow().get_str("__gc") != Value::Nil;
+      let u = Rc::new(AnyUserData::new(data, Some(meta)));
+      if has_gc {
+         u.finalizers.set(Some(Rc::downgrade(&self.pending)));
+         // forget the dropped ones now and then, not at each creation
+         if self.live.len() == self.live.capacity() {
+            self.live.retain(|u| u.strong_count() > 0);
+         }
+         self.live.push(Rc::downgrade(&u));
+      }
+      u
+   }
+
+   pub fn has_pending(&self) -> bool {
+      !self.pending.borrow().is_empty()
+   }
+
+   // the dropped values to finalize, which are then forgotten
+   pub fn take_pending(&mut self) -> Vec<AnyUserData> {
+      self.pending.take()
+   }
+
+   // All values still alive with a __gc, for the state to finalize as it
+   // goes away, newest first. They are not finalized again when dropped.
+   pub fn take_live(&mut self) -> Vec<Rc<AnyUserData>> {
+      let live = std::mem::take(&mut self.live);
+      live.iter().rev().filter_map(Weak::upgrade)
+         .filter(|u| u.finalizers.take().is_some())
+         .collect()
+   }
+}
@@ -1,8 +1,14 @@
 
 use core::fmt;
-use std::rc::Rc;
+use std::any::{self, Any, TypeId};
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::rc::{Rc, Weak};
 
-use crate::vm::ExeState;
+use crate::{vm::{ExeState, Coroutine}, parse::FuncProto, error::LuaError, lib_string::format_general};
 
 const SHORT_STR_MAX: usize=14;
 const MID_STR_MAX: usize = 48 - 1;
@@ -15,11 +21,125 @@ pub enum Value{
    Integer(i64),
    Float(f64),
   //  String(String) ,
-   Function(fn (&mut ExeState)-> i32),
+   Function(fn (&mut ExeState)-> Result<i32, LuaError>),
+   LuaFunction(Rc<LuaClosure>),
+   // a native function with state of its own, see Lua::create_function
+   NativeClosure(Rc<NativeFn>),
 
    ShortStr(u8,[u8;SHORT_STR_MAX]),
    MidStr(Rc<(u8,[u8;MID_STR_MAX])>),
    LongStr(Rc<Vec<u8>>),
+
+   Table(Rc<RefCell<Table>>),
+   UserData(Rc<AnyUserData>),
+   // a pointer Lua only passes around and compares
+   LightUserData(*mut c_void),
+   Thread(Rc<RefCell<Coroutine>>),
+}
+
+
+pub type NativeFn = dyn Fn(&mut ExeState) -> Result<i32, LuaError>;
+
+// array part holds keys 1..=array.len(), everything else lives in
+// `nodes`, with `map` locating a key's node
+#[derive(Debug, Default)]
+pub struct Table {
+   pub array: Vec<Value>,
+   map: HashMap<Value, usize>,
+   nodes: Vec<(Value, Value)>,
+   dead: usize,
+   pub metatable: Option<Rc<RefCell<Table>>>,
+}
+
+// A Rust value handed to Lua code, which can only reach it through
+// its metatable, fixed when it is created.
+pub struct AnyUserData {
+   data: RefCell<Box<dyn Any>>,
+   type_id: TypeId,
+   metatable: Option<Rc<RefCell<Table>>>,
+   // with a __gc metamethod, where it goes when dropped for the state to
+   // run it, unless it has already been run
+   pub(crate) finalizers: Cell<Option<Weak<RefCell<Vec<AnyUserData>>>>>,
+}
+
+impl AnyUserData {
+   pub fn new<T: Any>(data: T, metatable: Option<Rc<RefCell<Table>>>) -> Self {
+      AnyUserData {
+         data: RefCell::new(Box::new(data)),
+         type_id: TypeId::of::<T>(),
+         metatable,
+         finalizers: Cell::new(None),
+      }
+   }
+
+   pub fn metatable(&self) -> Option<Rc<RefCell<Table>>> {
+      self.metatable.clone()
+   }
+
+   pub fn is<T: Any>(&self) -> bool {
+      self.type_id == TypeId::of::<T>()
+   }
+
+   // the data as a T, unless it is not one or is being changed
+   pub fn borrow<T: Any>(&self) -> Result<Ref<'_, T>, LuaError> {
+      self.check_type::<T>()?;
+      let data = self.data.try_borrow()
+         .map_err(|_| LuaError::Runtime(Value::from("userdata already mutably borrowed")))?;
+      Ok(Ref::map(data, |data| data.downcast_ref().unwrap()))
+   }
+
+   // the data as a T to change, unless it is not one or is in use
+   pub fn borrow_mut<T: Any>(&self) -> Result<RefMut<'_, T>, LuaError> {
+      self.check_type::<T>()?;
+      let data = self.data.try_borrow_mut()
+         .map_err(|_| LuaError::Runtime(Value::from("userdata already borrowed")))?;
+      Ok(RefMut::map(data, |data| data.downcast_mut().unwrap()))
+   }
+
+   fn check_type<T: Any>(&self) -> Result<(), LuaError> {
+      if !self.is::<T>() {
+         let msg = format!("userdata is not a {}", any::type_name::<T>());
+         return Err(LuaError::Runtime(Value::from(msg)));
+      }
+      Ok(())
+   }
+}
+
+// the data outlives the handle if the state has to run its __gc
+impl Drop for AnyUserData {
+   fn drop(&mut self) {
+      if let Some(finalizers) = self.finalizers.take().and_then(|f| f.upgrade()) {
+         let data = mem::replace(self.data.get_mut(), Box::new(()));
+         finalizers.borrow_mut().push(AnyUserData {
+            data: RefCell::new(data),
+            type_id: self.type_id,
+            metatable: self.metatable.take(),
+            finalizers: Cell::new(None),
+         });
+      }
+   }
+}
+
+impl fmt::Debug for AnyUserData {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "AnyUserData")
+   }
+}
+
+// a Lua function: its prototype and the variables it captured
+#[derive(Debug)]
+pub struct LuaClosure {
+   pub proto: Rc<FuncProto>,
+   pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+// A captured local variable. It stays in its register (by stack index)
+// while the enclosing function runs, and moves into the upvalue itself
+// when the function returns or the variable goes out of scope.
+#[derive(Debug)]
+pub enum Upvalue {
+   Open(usize),
+   Closed(Value),
 }
 
 
@@ -27,16 +147,68 @@ impl fmt::Debug for Value{
    fn fmt(&self,f:&mut fmt::Formatter) ->Result<( ), fmt::Error>{
       match self{
          Value::Nil=>write!(f,"nil"),
-         Value::Function(_)=>write!(f,"function"),
+         Value::Function(func)=>write!(f,"function: {:p}", *func as *const ()),
+         Value::LuaFunction(c)=>write!(f,"function: {:p}", Rc::as_ptr(c)),
+         Value::NativeClosure(c)=>write!(f,"function: {:p}", Rc::as_ptr(c) as *const ()),
          Value::Boolean(b) => write!(f,"{b}"),
          Value::Integer(i) => write!(f,"{i}"),
-         Value::Float(n) => write!(f,"{n:?}"),
+         Value::Float(n) => write!(f,"{}", float_to_string(*n)),
         //  Value::String(s) => write!(f, "{s}"),
         Value::ShortStr(len, buf) => write!(f, "{}", String::from_utf8_lossy(&buf[..*len as usize])),
         Value::MidStr(s) => write!(f, "{}", String::from_utf8_lossy(&s.1[..s.0 as usize])),
-        Value::LongStr(s) => write!(f, "{}", String::from_utf8_lossy(&s)),
+        Value::LongStr(s) => write!(f, "{}", String::from_utf8_lossy(s)),
+        Value::Table(t) => write!(f, "table: {:p}", Rc::as_ptr(t)),
+        Value::UserData(u) => write!(f, "userdata: {:p}", Rc::as_ptr(u)),
+        Value::LightUserData(p) => write!(f, "userdata: {p:p}"),
+        Value::Thread(co) => write!(f, "thread: {:p}", Rc::as_ptr(co)),
+      }
+   }
+}
+
+impl Value {
+   pub fn type_name(&self) -> &'static str {
+      match self {
+         Value::Nil => "nil",
+         Value::Boolean(_) => "boolean",
+         Value::Integer(_) | Value::Float(_) => "number",
+         Value::ShortStr(..) | Value::MidStr(_) | Value::LongStr(_) => "string",
+         Value::Function(_) | Value::LuaFunction(_) | Value::NativeClosure(_) => "function",
+         Value::Table(_) => "table",
+         Value::UserData(_) | Value::LightUserData(_) => "userdata",
+         Value::Thread(_) => "thread",
+      }
+   }
+
+   pub fn new_table(narray: usize, nmap: usize) -> Value {
+      Value::Table(Rc::new(RefCell::new(Table::new(narray, nmap))))
+   }
+
+   pub fn is_false(&self) -> bool {
+      matches!(self, Value::Nil | Value::Boolean(false))
+   }
+
+   pub fn is_string(&self) -> bool {
+      matches!(self, Value::ShortStr(..) | Value::MidStr(_) | Value::LongStr(_))
+   }
+
+   // the bytes of a string value, which the caller knows it is
+   pub(crate) fn as_bytes(&self) -> &[u8] {
+      match self {
+         Value::ShortStr(len, buf) => &buf[..*len as usize],
+         Value::MidStr(s) => &s.1[..s.0 as usize],
+         Value::LongStr(s) => s,
+         v => unreachable!("not a string: {v:?}"),
       }
    }
+
+   // a string value as text, bytes that are not UTF-8 being replaced
+   pub(crate) fn to_string_lossy(&self) -> String {
+      String::from_utf8_lossy(self.as_bytes()).into_owned()
+   }
+
+   pub fn is_function(&self) -> bool {
+      matches!(self, Value::Function(_) | Value::LuaFunction(_) | Value::NativeClosure(_))
+   }
 }
 
 impl PartialEq for Value {
@@ -46,27 +218,259 @@ impl PartialEq for Value {
             (Value::Boolean(b1), Value::Boolean(b2)) => *b1 == *b2,
             (Value::Integer(i1), Value::Integer(i2)) => *i1 == * i2,
             (Value::Float(f1), Value::Float(f2)) => *f1 == *f2,
+            (Value::Integer(i), Value::Float(f)) |
+            (Value::Float(f), Value::Integer(i)) => float_to_int(*f) == Some(*i),
             // (Value::String(s1), Value::String(s2)) => *s1 == *s2,
-            (Value::Function(f1), Value::Function(f2)) => std::ptr::eq(f1,f2),
+            (Value::Function(f1), Value::Function(f2)) => std::ptr::fn_addr_eq(*f1, *f2),
+            (Value::LuaFunction(c1), Value::LuaFunction(c2)) => Rc::ptr_eq(c1, c2),
+            (Value::NativeClosure(c1), Value::NativeClosure(c2)) => Rc::ptr_eq(c1, c2),
             (Value::ShortStr(len1, s1), Value::ShortStr(len2, s2)) => s1[..*len1 as usize] == s2[..*len2 as usize],
             (Value::MidStr(s1), Value::MidStr(s2)) => s1.1[..s1.0 as usize] == s2.1[..s2.0 as usize],
             (Value::LongStr(s1), Value::LongStr(s2)) => s1 == s2,
+            (Value::Table(t1), Value::Table(t2)) => Rc::ptr_eq(t1, t2),
+            (Value::UserData(u1), Value::UserData(u2)) => Rc::ptr_eq(u1, u2),
+            (Value::LightUserData(p1), Value::LightUserData(p2)) => p1 == p2,
+            (Value::Thread(c1), Value::Thread(c2)) => Rc::ptr_eq(c1, c2),
             (_, _)=> false,
         }
     }
 }
 
 
+// NaN is never a table key (Table::set rejects it), which makes this Eq
+// honest for every value that can end up in a map
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Value::Nil => (),
+            Value::Boolean(b) => b.hash(state),
+            Value::Integer(i) => i.hash(state),
+            // integral floats are equal to integers, so hash alike
+            Value::Float(f) => match float_to_int(*f) {
+                Some(i) => i.hash(state),
+                None => f.to_bits().hash(state),
+            }
+            Value::Function(f) => (*f as usize).hash(state),
+            Value::LuaFunction(c) => Rc::as_ptr(c).hash(state),
+            Value::NativeClosure(c) => (Rc::as_ptr(c) as *const ()).hash(state),
+            Value::ShortStr(len, buf) => buf[..*len as usize].hash(state),
+            Value::MidStr(s) => s.1[..s.0 as usize].hash(state),
+            Value::LongStr(s) => s.hash(state),
+            Value::Table(t) => Rc::as_ptr(t).hash(state),
+            Value::UserData(u) => Rc::as_ptr(u).hash(state),
+            Value::LightUserData(p) => p.hash(state),
+            Value::Thread(co) => Rc::as_ptr(co).hash(state),
+        }
+    }
+}
+
+
+impl Table {
+   pub fn new(narray: usize, nmap: usize) -> Self {
+      Table {
+         array: Vec::with_capacity(narray),
+         map: HashMap::with_capacity(nmap),
+         nodes: Vec::with_capacity(nmap),
+         dead: 0,
+         metatable: None,
+      }
+   }
+
+   pub fn get(&self, key: &Value) -> Value {
+      match key {
+         Value::Integer(i) => self.get_int(*i),
+         Value::Float(f) => match float_to_int(*f) {
+            Some(i) => self.get_int(i),
+            None => self.get_map(key),
+         }
+         _ => self.get_map(key),
+      }
+   }
+
+   pub fn get_int(&self, i: i64) -> Value {
+      if i >= 1 && i as usize <= self.array.len() {
+         self.array[i as usize - 1].clone()
+      } else {
+         self.get_map(&Value::Integer(i))
+      }
+   }
+
+   pub fn get_str(&self, key: &str) -> Value {
+      self.get_map(&Value::from(key))
+   }
+
+   fn get_map(&self, key: &Value) -> Value {
+      match self.map.get(key) {
+         Some(&i) => self.nodes[i].1.clone(),
+         None => Value::Nil,
+      }
+   }
+
+   // raw assignment; fails for the keys Lua forbids
+   pub fn set(&mut self, key: Value, value: Value) -> Result<(), &'static str> {
+      match key {
+         Value::Nil => return Err("index is nil"),
+         Value::Integer(i) => self.set_int(i, value),
+         Value::Float(f) if f.is_nan() => return Err("index is NaN"),
+         Value::Float(f) => match float_to_int(f) {
+            Some(i) => self.set_int(i, value),
+            None => self.set_map(key, value),
+         }
+         _ => self.set_map(key, value),
+      }
+      Ok(())
+   }
+
+   pub fn set_int(&mut self, i: i64, value: Value) {
+      let len = self.array.len();
+      if i >= 1 && i as usize <= len {
+         // the array part never shrinks here, so that clearing fields
+         // during a traversal does not break `next`
+         self.array[i as usize - 1] = value;
+      } else if i >= 1 && i as usize == len + 1 && value != Value::Nil {
+         self.set_map(Value::Integer(i), Value::Nil);
+         self.array.push(value);
+         // move the following integer keys from map into array
+         loop {
+            let key = Value::Integer(self.array.len() as i64 + 1);
+            match self.get_map(&key) {
+               Value::Nil => break,
+               v => {
+                  self.set_map(key, Value::Nil);
+                  self.array.push(v);
+               }
+            }
+         }
+      } else {
+         self.set_map(Value::Integer(i), value);
+      }
+   }
+
+   pub fn set_str(&mut self, key: &str, value: Value) {
+      self.set_map(Value::from(key), value);
+   }
+
+   // Removed keys stay in `nodes` with a nil value, so that clearing
+   // fields during a traversal does not break `next`. They are dropped
+   // when a new key is inserted, which Lua does not allow while traversing.
+   fn set_map(&mut self, key: Value, value: Value) {
+      if let Some(&i) = self.map.get(&key) {
+         let old = &mut self.nodes[i].1;
+         match (*old == Value::Nil, value == Value::Nil) {
+            (false, true) => self.dead += 1,
+            (true, false) => self.dead -= 1,
+            _ => (),
+         }
+         *old = value;
+      } else if value != Value::Nil {
+         if self.dead > 8 && self.dead > self.nodes.len() / 2 {
+            self.compact();
+         }
+         self.map.insert(key.clone(), self.nodes.len());
+         self.nodes.push((key, value));
+      }
+   }
+
+   fn compact(&mut self) {
+      self.nodes.retain(|(_, v)| *v != Value::Nil);
+      self.map.clear();
+      for (i, (k, _)) in self.nodes.iter().enumerate() {
+         self.map.insert(k.clone(), i);
+      }
+      self.dead = 0;
+   }
+
+   // a border of the table, what the `#` operator returns
+   pub fn len(&self) -> i64 {
+      if self.array.last() == Some(&Value::Nil) {
+         // binary search for a border within the array part
+         let (mut lo, mut hi) = (0, self.array.len());
+         while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if self.array[mid - 1] == Value::Nil {
+               hi = mid;
+            } else {
+               lo = mid;
+            }
+         }
+         return lo as i64;
+      }
+      // the sequence can continue in the map part
+      let mut n = self.array.len() as i64;
+      while self.get_map(&Value::Integer(n + 1)) != Value::Nil {
+         n += 1;
+      }
+      n
+   }
+
+   pub fn is_empty(&self) -> bool {
+      self.len() == 0
+   }
+
+   // the entry following `key` in traversal order: array part, then map
+   pub fn next(&self, key: &Value) -> Result<Option<(Value, Value)>, &'static str> {
+      let start = match key {
+         Value::Nil => 0,
+         Value::Integer(i) if *i >= 1 && *i as usize <= self.array.len() => *i as usize,
+         _ => match self.map.get(key) {
+            Some(&i) => return Ok(self.next_node(i + 1)),
+            None => return Err("invalid key to 'next'"),
+         }
+      };
+      for (i, v) in self.array.iter().enumerate().skip(start) {
+         if *v != Value::Nil {
+            return Ok(Some((Value::Integer(i as i64 + 1), v.clone())));
+         }
+      }
+      Ok(self.next_node(0))
+   }
+
+   fn next_node(&self, start: usize) -> Option<(Value, Value)> {
+      self.nodes[start..].iter()
+         .find(|(_, v)| *v != Value::Nil)
+         .cloned()
+   }
+}
+
+// the integer with exactly the value of `f`, if any
+pub fn float_to_int(f: f64) -> Option<i64> {
+   if f.fract() == 0.0 && f >= -(2f64.powi(63)) && f < 2f64.powi(63) {
+      Some(f as i64)
+   } else {
+      None
+   }
+}
+
+// how Lua writes a float: "%.14g", with ".0" added when that looks
+// like an integer, so that 1.0 and 1 stay apart
+pub fn float_to_string(f: f64) -> String {
+   let sign = if f.is_sign_negative() { "-" } else { "" };
+   if f.is_nan() {
+      return format!("{sign}nan");
+   }
+   if f.is_infinite() {
+      return format!("{sign}inf");
+   }
+   let s = format_general(f.abs(), Some(14), false);
+   if s.bytes().all(|b| b.is_ascii_digit()) {
+      format!("{sign}{s}.0")
+   } else {
+      format!("{sign}{s}")
+   }
+}
+
 fn vec_to_short_mid_str(v: &[u8]) -> Option<Value> {
     let len = v.len();
     if len <= SHORT_STR_MAX {
         let mut buf = [0; SHORT_STR_MAX];
-        buf[..len].copy_from_slice(&v);
+        buf[..len].copy_from_slice(v);
         Some(Value::ShortStr(len as u8, buf))
 
     } else if len <= MID_STR_MAX {
         let mut buf = [0; MID_STR_MAX];
-        buf[..len].copy_from_slice(&v);
+        buf[..len].copy_from_slice(v);
         Some(Value::MidStr(Rc::new((len as u8, buf))))
 
     } else {
@@ -104,6 +508,18 @@ impl From<String> for Value {
    } 
 }
 
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        s.as_bytes().into()
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(v: &[u8]) -> Self {
+        vec_to_short_mid_str(v).unwrap_or_else(|| Value::LongStr(Rc::new(v.to_vec())))
+    }
+}
+
 impl From<i64> for Value{
   fn from(value: i64) -> Self {
      Value::Integer(value) 
@@ -131,26 +547,61 @@ impl From<()> for Value {
 }
 
 
-// ANCHOR: to_vec_string
-impl<'a> From<&'a Value> for &'a [u8] {
-    fn from(v: &'a Value) -> Self {
+// The bytes of a string, for Rust code that takes strings of any
+// content. Other values are a conversion error.
+impl<'a> TryFrom<&'a Value> for &'a [u8] {
+    type Error = LuaError;
+
+    fn try_from(v: &'a Value) -> Result<Self, LuaError> {
         match v {
-            Value::ShortStr(len, buf) => &buf[..*len as usize],
-            Value::MidStr(s) => &s.1[..s.0 as usize],
-            Value::LongStr(s) => s,
-            _ => panic!("invalid string Value"),
+            Value::ShortStr(len, buf) => Ok(&buf[..*len as usize]),
+            Value::MidStr(s) => Ok(&s.1[..s.0 as usize]),
+            Value::LongStr(s) => Ok(s),
+            v => Err(LuaError::Runtime(Value::from(format!("string expected, got {}", v.type_name())))),
         }
     }
 }
 
-impl<'a> From<&'a Value> for &'a str {
-    fn from(v: &'a Value) -> Self {
-        std::str::from_utf8(v.into()).unwrap()
-    }
-}
 
-impl From<&Value> for String {
-    fn from(v: &Value) -> Self {
-        String::from_utf8_lossy(v.into()).to_string()
-    }
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn clear_during_traversal() {
+      let mut t = Table::new(0, 0);
+      for i in 1..=4 {
+         t.set_int(i, Value::Integer(i * 10));
+      }
+      t.set_str("x", Value::Integer(1));
+      let mut key = Value::Nil;
+      let mut seen = 0;
+      while let Some((k, _)) = t.next(&key).unwrap() {
+         t.set(k.clone(), Value::Nil).unwrap();
+         key = k;
+         seen += 1;
+      }
+      assert_eq!(seen, 5);
+      assert_eq!(t.next(&Value::Nil), Ok(None));
+      assert_eq!(t.len(), 0);
+   }
+
+   #[test]
+   fn border_after_nil_stores() {
+      let mut t = Table::new(0, 0);
+      for i in 1..=5 {
+         t.set_int(i, Value::Integer(i));
+      }
+      t.set_int(5, Value::Nil);
+      assert_eq!(t.len(), 4);
+      t.set_int(4, Value::Nil);
+      t.set_int(2, Value::Nil);
+      let n = t.len();
+      assert!(n == 1 || n == 3);
+      t.set_int(4, Value::Integer(4));
+      t.set_int(5, Value::Integer(5));
+      t.set_int(6, Value::Integer(6));
+      assert_eq!(t.get_int(6), Value::Integer(6));
+      assert!(matches!(t.len(), 1 | 6));
+   }
+}
@@ -0,0 +1,488 @@
+use std::{collections::HashSet, fmt};
+
+use crate::{value::Value, parse::FuncProto, byte_code::{ByteCode, MULTI}, error::LuaError};
+
+
+// The VM indexes the stack and the constant table with the raw operands.
+// `verify()` checks every operand of the parser's output against the sizes
+// declared by the prototype, so a code generator bug turns into an error
+// instead of an out-of-bounds panic. Precompiled binary chunks are not
+// loaded and are out of scope: this does not make untrusted byte code
+// safe to run.
+#[derive(Debug, PartialEq)]
+pub struct VerifyError {
+    pub pc: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bad chunk: instruction {}: {}", self.pc, self.reason)
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+
+pub fn verify(proto: &FuncProto) -> Result<(), VerifyError> {
+    // the VM gives a main function `_ENV` as its only upvalue
+    if proto.upvalues.len() > 1 {
+        return Err(VerifyError {
+            pc: 0,
+            reason: format!("main function has {} upvalues", proto.upvalues.len()),
+        });
+    }
+    verify_function(proto)
+}
+
+fn verify_function(proto: &FuncProto) -> Result<(), VerifyError> {
+    if proto.max_stack_size > u8::MAX as usize + 1 {
+        return Err(VerifyError {
+            pc: 0,
+            reason: format!("declared stack size {} exceeds the register limit", proto.max_stack_size),
+        });
+    }
+
+    if proto.lines.len() != proto.byte_codes.len() {
+        return Err(VerifyError {
+            pc: 0,
+            reason: format!("{} line entries for {} instructions",
+                            proto.lines.len(), proto.byte_codes.len()),
+        });
+    }
+
+    // so execution can not run off the end
+    if !matches!(proto.byte_codes.last(), Some(ByteCode::Return(..))) {
+        return Err(VerifyError {
+            pc: proto.byte_codes.len(),
+            reason: String::from("function does not end with a return"),
+        });
+    }
+
+    let checker = Checker { proto, jump_targets: jump_targets(proto) };
+    for (pc, code) in proto.byte_codes.iter().enumerate() {
+        checker.check(pc, code).map_err(|reason| VerifyError {
+            pc,
+            reason: format!("{code:?}: {reason}"),
+        })?;
+    }
+
+    // nested functions capture registers or upvalues of this one
+    for (i, child) in proto.protos.iter().enumerate() {
+        for (j, desc) in child.upvalues.iter().enumerate() {
+            let (limit, what) = if desc.in_stack {
+                (proto.max_stack_size, "register")
+            } else {
+                (proto.upvalues.len(), "upvalue")
+            };
+            if desc.index >= limit {
+                return Err(VerifyError {
+                    pc: 0,
+                    reason: format!("function {i}: upvalue {j} captures {what} {} out of range ({limit})",
+                                    desc.index),
+                });
+            }
+        }
+        verify_function(child).map_err(|err| VerifyError {
+            pc: err.pc,
+            reason: format!("in function at line {}: {}", child.line_defined, err.reason),
+        })?;
+    }
+    Ok(())
+}
+
+// every instruction some jump of the function lands on
+fn jump_targets(proto: &FuncProto) -> HashSet<usize> {
+    proto.byte_codes.iter().enumerate().filter_map(|(pc, code)| match *code {
+        ByteCode::Jump(offset) |
+        ByteCode::TestAndJump(_, offset) |
+        ByteCode::TestOrJump(_, offset) => Some((pc as isize + 1 + offset as isize) as usize),
+        ByteCode::ForPrepare(_, offset) => Some(pc + 1 + offset as usize),
+        ByteCode::ForLoop(_, offset) |
+        ByteCode::TForLoop(_, offset) => (pc + 1).checked_sub(offset as usize),
+        _ => None,
+    }).collect()
+}
+
+struct Checker<'a> {
+    proto: &'a FuncProto,
+    jump_targets: HashSet<usize>,
+}
+
+impl Checker<'_> {
+    fn check(&self, pc: usize, code: &ByteCode) -> Result<(), String> {
+        match *code {
+            ByteCode::GetUpvalue(dst, up) => {
+                self.register(dst)?;
+                self.upvalue(up)
+            }
+            ByteCode::SetUpvalue(up, src) => {
+                self.upvalue(up)?;
+                self.register(src)
+            }
+            ByteCode::GetUpField(dst, up, k) => {
+                self.register(dst)?;
+                self.upvalue(up)?;
+                self.constant(k as u16)
+            }
+            ByteCode::SetUpField(up, k, src) => {
+                self.upvalue(up)?;
+                self.constant(k as u16)?;
+                self.register(src)
+            }
+            ByteCode::LoadConst(dst, c) => {
+                self.register(dst)?;
+                self.constant(c)
+            }
+            ByteCode::LoadNil(dst) |
+            ByteCode::LoadBool(dst, _) |
+            ByteCode::LoadInt(dst, _) |
+            ByteCode::NewTable(dst, _, _) => self.register(dst),
+            ByteCode::Move(dst, src) |
+            ByteCode::Neg(dst, src) |
+            ByteCode::Not(dst, src) |
+            ByteCode::BitNot(dst, src) |
+            ByteCode::Len(dst, src) => {
+                self.register(dst)?;
+                self.register(src)
+            }
+            ByteCode::VarArgs(dst, n) => self.open_range(dst, n),
+
+            ByteCode::SetTable(t, k, v) => {
+                self.register(t)?;
+                self.register(k)?;
+                self.register(v)
+            }
+            ByteCode::SetField(t, k, v) => {
+                self.register(t)?;
+                self.constant(k as u16)?;
+                self.register(v)
+            }
+            ByteCode::GetTable(dst, t, k) => {
+                self.register(dst)?;
+                self.register(t)?;
+                self.register(k)
+            }
+            ByteCode::GetField(dst, t, k) => {
+                self.register(dst)?;
+                self.register(t)?;
+                self.constant(k as u16)
+            }
+            ByteCode::Method(dst, obj, k) => {
+                // the method and the object
+                self.register_range(dst, 2)?;
+                self.register(obj)?;
+                self.constant(k as u16)
+            }
+            ByteCode::SetList(t, n, _) => {
+                // the items sit in the registers following the table
+                self.register(t)?;
+                self.open_input(pc, t as usize + 1, n)?;
+                if n == MULTI {
+                    Ok(())
+                } else {
+                    self.register_range(t, n as usize + 1)
+                }
+            }
+
+            ByteCode::Jump(offset) |
+            ByteCode::TestAndJump(_, offset) |
+            ByteCode::TestOrJump(_, offset) => {
+                if let ByteCode::TestAndJump(r, _) | ByteCode::TestOrJump(r, _) = *code {
+                    self.register(r)?;
+                }
+                self.jump(pc, offset as i32)
+            }
+
+            // the loop state takes 4 registers from `a`
+            ByteCode::ForPrepare(a, offset) => {
+                self.register_range(a, 4)?;
+                self.jump(pc, offset as i32)
+            }
+            ByteCode::ForLoop(a, offset) => {
+                self.register_range(a, 4)?;
+                self.jump(pc, -(offset as i32))
+            }
+            // the iterator is called with copies of the first 3 registers,
+            // at `b` + 4, where its results go too
+            ByteCode::TForCall(b, nvars) => {
+                self.register_range(b, 7)?;
+                self.register_range(b, 4 + nvars as usize)
+            }
+            ByteCode::TForLoop(b, offset) => {
+                self.register_range(b, 5)?;
+                self.jump(pc, -(offset as i32))
+            }
+
+            ByteCode::Closure(dst, p) => {
+                self.register(dst)?;
+                if (p as usize) < self.proto.protos.len() {
+                    Ok(())
+                } else {
+                    Err(format!("function {p} out of range ({} functions)", self.proto.protos.len()))
+                }
+            }
+            ByteCode::Call(func, nargs, want) => {
+                // the function and its arguments sit in consecutive registers,
+                // and so do the results
+                self.register(func)?;
+                self.open_input(pc, func as usize + 1, nargs)?;
+                if nargs != MULTI {
+                    self.register_range(func, nargs as usize + 1)?;
+                }
+                self.open_range(func, want)
+            }
+            ByteCode::TailCall(func, nargs) => {
+                self.register(func)?;
+                self.open_input(pc, func as usize + 1, nargs)?;
+                if nargs == MULTI {
+                    Ok(())
+                } else {
+                    self.register_range(func, nargs as usize + 1)
+                }
+            }
+            ByteCode::Return(first, n) => {
+                self.open_input(pc, first as usize, n)?;
+                if n == MULTI {
+                    Ok(())
+                } else {
+                    self.register_range(first, n as usize)
+                }
+            }
+            // closing above the registers in use does nothing
+            ByteCode::Close(_) => Ok(()),
+
+            ByteCode::Add(dst, a, b) |
+            ByteCode::Sub(dst, a, b) |
+            ByteCode::Mul(dst, a, b) |
+            ByteCode::Div(dst, a, b) |
+            ByteCode::Idiv(dst, a, b) |
+            ByteCode::Mod(dst, a, b) |
+            ByteCode::Pow(dst, a, b) |
+            ByteCode::BitAnd(dst, a, b) |
+            ByteCode::BitXor(dst, a, b) |
+            ByteCode::BitOr(dst, a, b) |
+            ByteCode::ShiftL(dst, a, b) |
+            ByteCode::ShiftR(dst, a, b) |
+            ByteCode::Concat(dst, a, b) |
+            ByteCode::Equal(dst, a, b) |
+            ByteCode::NotEq(dst, a, b) |
+            ByteCode::Less(dst, a, b) |
+            ByteCode::LesEq(dst, a, b) => {
+                self.register(dst)?;
+                self.register(a)?;
+                self.register(b)
+            }
+        }
+    }
+
+    // An instruction reading an open count of values (up to the stack top)
+    // must directly follow the instruction leaving them there, and that
+    // one must have put them at or above `first`. Nothing may jump in
+    // between.
+    fn open_input(&self, pc: usize, first: usize, n: u8) -> Result<(), String> {
+        if n != MULTI {
+            return Ok(());
+        }
+        let start = match pc.checked_sub(1).map(|prev| self.proto.byte_codes[prev]) {
+            Some(ByteCode::Call(func, _, MULTI) | ByteCode::TailCall(func, _)) => func as usize,
+            Some(ByteCode::VarArgs(dst, MULTI)) => dst as usize,
+            _ => return Err(String::from("open value count without a preceding open call or '...'")),
+        };
+        if start < first {
+            return Err(format!("open values start at register {start}, below {first}"));
+        }
+        if self.jump_targets.contains(&pc) {
+            return Err(String::from("jump into an instruction taking open values"));
+        }
+        Ok(())
+    }
+
+    // the registers written by an instruction producing `n` values at `first`
+    fn open_range(&self, first: u8, n: u8) -> Result<(), String> {
+        if n == MULTI {
+            // the VM grows the stack for open results
+            self.register(first)
+        } else {
+            self.register_range(first, n as usize)
+        }
+    }
+
+    fn jump(&self, pc: usize, offset: i32) -> Result<(), String> {
+        let target = pc as isize + 1 + offset as isize;
+        if target < 0 || target >= self.proto.byte_codes.len() as isize {
+            Err(format!("jump target {target} out of range ({} instructions)",
+                        self.proto.byte_codes.len()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn register(&self, r: u8) -> Result<(), String> {
+        self.register_range(r, 1)
+    }
+
+    fn register_range(&self, first: u8, n: usize) -> Result<(), String> {
+        let end = first as usize + n;
+        if end > self.proto.max_stack_size {
+            Err(format!("register {} out of range (stack size {})",
+                        end - 1, self.proto.max_stack_size))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn constant(&self, c: u16) -> Result<(), String> {
+        self.get_constant(c).map(|_| ())
+    }
+
+    fn get_constant(&self, c: u16) -> Result<&Value, String> {
+        self.proto.constants.get(c as usize).ok_or_else(||
+            format!("constant {} out of range ({} constants)", c, self.proto.constants.len()))
+    }
+
+    fn upvalue(&self, up: u8) -> Result<(), String> {
+        if (up as usize) < self.proto.upvalues.len() {
+            Ok(())
+        } else {
+            Err(format!("upvalue {up} out of range ({} upvalues)", self.proto.upvalues.len()))
+        }
+    }
+}
+
+impl From<VerifyError> for LuaError {
+    fn from(err: VerifyError) -> Self {
+        LuaError::Syntax(err.to_string())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::parse::UpvalDesc;
+
+    // a main function with one constant, `_ENV` as upvalue and 2 registers
+    fn proto(byte_codes: Vec<ByteCode>) -> FuncProto {
+        FuncProto {
+            source: String::from("=test"),
+            line_defined: 0,
+            params: 0,
+            is_vararg: true,
+            constants: vec![Value::Integer(1)],
+            upvalues: vec![UpvalDesc { name: String::from("_ENV"), in_stack: true, index: 0 }],
+            protos: Vec::new(),
+            lines: vec![1; byte_codes.len()],
+            byte_codes,
+            locvars: Vec::new(),
+            max_stack_size: 2,
+        }
+    }
+
+    fn reason(proto: &FuncProto) -> String {
+        verify(proto).unwrap_err().reason
+    }
+
+    #[test]
+    fn accepts_well_formed() {
+        let p = proto(vec![
+            ByteCode::LoadConst(0, 0),
+            ByteCode::GetUpField(1, 0, 0),
+            ByteCode::Jump(0),
+            ByteCode::Return(0, 2),
+        ]);
+        assert_eq!(verify(&p), Ok(()));
+    }
+
+    #[test]
+    fn register_out_of_range() {
+        let p = proto(vec![ByteCode::Move(5, 0), ByteCode::Return(0, 0)]);
+        assert_eq!(verify(&p), Err(VerifyError {
+            pc: 0,
+            reason: String::from("Move(5, 0): register 5 out of range (stack size 2)"),
+        }));
+        assert_eq!(verify(&p).unwrap_err().to_string(),
+                   "bad chunk: instruction 0: Move(5, 0): register 5 out of range (stack size 2)");
+    }
+
+    #[test]
+    fn constant_out_of_range() {
+        let p = proto(vec![ByteCode::LoadConst(0, 3), ByteCode::Return(0, 0)]);
+        assert_eq!(reason(&p), "LoadConst(0, 3): constant 3 out of range (1 constants)");
+    }
+
+    #[test]
+    fn upvalue_out_of_range() {
+        let p = proto(vec![ByteCode::Return(0, 0), ByteCode::GetUpvalue(0, 1), ByteCode::Return(0, 0)]);
+        assert_eq!(verify(&p).unwrap_err().pc, 1);
+        assert_eq!(reason(&p), "GetUpvalue(0, 1): upvalue 1 out of range (1 upvalues)");
+    }
+
+    #[test]
+    fn captured_upvalue_out_of_range() {
+        let mut child = proto(vec![ByteCode::Return(0, 0)]);
+        child.line_defined = 3;
+        child.upvalues[0] = UpvalDesc { name: String::from("x"), in_stack: true, index: 7 };
+        let mut p = proto(vec![ByteCode::Closure(0, 0), ByteCode::Return(0, 0)]);
+        p.protos.push(Rc::new(child));
+        assert_eq!(reason(&p), "function 0: upvalue 0 captures register 7 out of range (2)");
+    }
+
+    #[test]
+    fn bad_jump_target() {
+        let p = proto(vec![ByteCode::Jump(5), ByteCode::Return(0, 0)]);
+        assert_eq!(reason(&p), "Jump(5): jump target 6 out of range (2 instructions)");
+        let p = proto(vec![ByteCode::Jump(-2), ByteCode::Return(0, 0)]);
+        assert_eq!(reason(&p), "Jump(-2): jump target -1 out of range (2 instructions)");
+    }
+
+    #[test]
+    fn open_consumer_without_producer() {
+        let p = proto(vec![ByteCode::LoadNil(0), ByteCode::Return(0, MULTI)]);
+        assert_eq!(reason(&p), format!("{:?}: open value count without a preceding open call or '...'",
+                                       ByteCode::Return(0, MULTI)));
+        // a jump may not land between the producer and the consumer
+        let p = proto(vec![
+            ByteCode::Jump(1),
+            ByteCode::VarArgs(0, MULTI),
+            ByteCode::Return(0, MULTI),
+        ]);
+        assert_eq!(reason(&p), format!("{:?}: jump into an instruction taking open values",
+                                       ByteCode::Return(0, MULTI)));
+    }
+
+    #[test]
+    fn missing_trailing_return() {
+        let p = proto(vec![ByteCode::LoadNil(0)]);
+        assert_eq!(verify(&p), Err(VerifyError {
+            pc: 1,
+            reason: String::from("function does not end with a return"),
+        }));
+        assert_eq!(reason(&proto(Vec::new())), "function does not end with a return");
+    }
+
+    #[test]
+    fn errors_in_nested_functions() {
+        let mut child = proto(vec![ByteCode::Move(0, 9), ByteCode::Return(0, 0)]);
+        child.line_defined = 4;
+        let mut p = proto(vec![ByteCode::Closure(0, 0), ByteCode::Return(0, 0)]);
+        p.protos.push(Rc::new(child));
+        assert_eq!(reason(&p), "in function at line 4: Move(0, 9): register 9 out of range (stack size 2)");
+    }
+
+    #[test]
+    fn lines_out_of_step() {
+        let mut p = proto(vec![ByteCode::LoadNil(0), ByteCode::Return(0, 1)]);
+        p.lines.pop();
+        assert_eq!(reason(&p), "1 line entries for 2 instructions");
+    }
+
+    #[test]
+    fn stack_size_too_large() {
+        let mut p = proto(vec![ByteCode::Return(0, 0)]);
+        p.max_stack_size = 300;
+        assert_eq!(reason(&p), "declared stack size 300 exceeds the register limit");
+    }
+}
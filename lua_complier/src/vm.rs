@@ -1,93 +1,2080 @@
-use std::{collections::HashMap, io::Read};
-use crate::{value::Value , parse::ParseProto, byte_code::ByteCode};
+use std::{cell::RefCell, cmp::Ordering, fmt, fs, future::{self, Future}, io::{self, Read}, mem, pin::Pin, rc::Rc};
+use crate::{value::{Value, Table, LuaClosure, NativeFn, Upvalue, float_to_int}, parse::{ParseProto, FuncProto}, verify,
+            byte_code::{ByteCode, MULTI, FIELDS_PER_FLUSH}, error::{LuaError, io_error_text}, lex::str2number, lib_base, lib_package, lib_string, lib_table, lib_math, lib_utf8, lib_io, lib_os, lib_coroutine, userdata::{self, UserData}};
 
 
+// nesting of calls from native functions back into Lua
+const MAX_C_DEPTH: usize = 200;
+// stack slots of all running functions together
+const MAX_STACK: usize = 1_000_000;
+// __index and __newindex handlers followed for one access
+const MAX_META_CHAIN: usize = 2000;
+
+// sets the globals of a library
+type OpenFn = fn(&mut ExeState);
+
+// the standard libraries by module name
+const STD_LIBS: &[(&str, OpenFn)] = &[
+   // first, as it holds package.loaded
+   ("package", lib_package::open),
+   ("_G", lib_base::open),
+   ("string", lib_string::open),
+   ("table", lib_table::open),
+   ("math", lib_math::open),
+   ("coroutine", lib_coroutine::open),
+   ("utf8", lib_utf8::open),
+   ("io", lib_io::open),
+   ("os", lib_os::open),
+];
+
+
+// a running function
+#[derive(Debug)]
+struct CallInfo {
+   // stack index of the function; its arguments, and for Lua functions
+   // its registers, follow it
+   func: usize,
+   // None for native functions
+   closure: Option<Rc<LuaClosure>>,
+   // next instruction to execute
+   pc: usize,
+   varargs: Vec<Value>,
+   // results the caller wants, MULTI for all of them
+   want: u8,
+   // what the function waits for before going on, see Wait
+   wait: Option<Wait>,
+}
+
+// A native function suspended until a call it asked for returns (see
+// call_k and pcall_k), or until the coroutine it yielded from is resumed
+// (see yield_k): the results of either go on the stack from `base`, then
+// to `k`. Also a Lua function whose instruction called a metamethod (see
+// call_op), which gets the results as the instruction's.
+#[derive(Debug)]
+struct Wait {
+   base: usize,
+   k: Option<Continuation>,
+   // errors of the call go to `k` too, as for pcall
+   protected: bool,
+}
+
+// what a native function does with the results of the call it waited for,
+// or with its error for a protected call; it returns as the function would
+type KFunction = dyn FnOnce(&mut ExeState, Result<Vec<Value>, LuaError>) -> Result<i32, LuaError>;
+
+pub struct Continuation(Box<KFunction>);
+
+impl fmt::Debug for Continuation {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "Continuation")
+   }
+}
+
+// what a native function awaits, see await_future
+type LuaFuture = Pin<Box<dyn Future<Output = Result<Vec<Value>, LuaError>>>>;
+
+struct Pending(LuaFuture);
+
+impl fmt::Debug for Pending {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "Pending")
+   }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoStatus {
+   // not started, or yielded
+   Suspended,
+   Running,
+   // resumed another coroutine
+   Normal,
+   // returned, or stopped by an error
+   Dead,
+}
+
+// A thread of execution, the main one or a coroutine. The running thread
+// has its stack, frames and open upvalues in ExeState; the others keep
+// theirs here.
+#[derive(Debug)]
+pub struct Coroutine {
+   status: CoStatus,
+   is_main: bool,
+   stack: Vec<Value>,
+   frames: Vec<CallInfo>,
+   open_upvalues: Vec<(usize, Rc<RefCell<Upvalue>>)>,
+   // c_depth when last resumed; it can yield only from that level
+   c_base: usize,
+   waiting: usize,
+   // the values a yield passes to resume
+   yielded: Vec<Value>,
+   // the error that killed it
+   error: Option<Value>,
+   // run by call_async, which awaits the futures it yields
+   is_async: bool,
+   // the future it yielded, until it is ready
+   future: Option<Pending>,
+   // what the future gave, for the native function that awaited it
+   awaited: Option<Result<Vec<Value>, LuaError>>,
+}
+
+impl Coroutine {
+   // a coroutine to run `func`, which is the bottom of its stack
+   pub fn new(func: Value) -> Self {
+      Coroutine {
+         status: CoStatus::Suspended,
+         is_main: false,
+         stack: vec![func],
+         frames: Vec::new(),
+         open_upvalues: Vec::new(),
+         c_base: 0,
+         waiting: 0,
+         yielded: Vec::new(),
+         error: None,
+         is_async: false,
+         future: None,
+         awaited: None,
+      }
+   }
+
+   pub fn status(&self) -> CoStatus {
+      self.status
+   }
+
+   pub fn is_main(&self) -> bool {
+      self.is_main
+   }
+}
+
+// where a bad operand came from, to name it in error messages
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+   Register(u8),
+   Upvalue(u8),
+}
+
 #[derive(Debug)]
 pub struct  ExeState {
-   globals: HashMap<String,Value>,
-   stack: Vec::<Value>, 
-   func_index :usize,
+   globals: Rc<RefCell<Table>>,
+   // values the libraries keep out of reach of Lua code
+   registry: Rc<RefCell<Table>>,
+   string_meta: Option<Rc<RefCell<Table>>>,
+   stack: Vec::<Value>,
+   frames: Vec::<CallInfo>,
+   // upvalues still pointing into the stack, by stack index
+   open_upvalues: Vec::<(usize, Rc<RefCell<Upvalue>>)>,
+   warnings: bool,
+   c_depth: usize,
+   // functions of the running thread with a Wait, which count
+   // toward MAX_C_DEPTH as nested calls from call() do
+   waiting: usize,
+   // state of math.random()
+   random: lib_math::Random,
+   // settings of collectgarbage()
+   collector: lib_base::Collector,
+   // modules for require, built from Rust
+   native_modules: lib_package::NativeModules,
+   // files io.open opened, flushed when the state goes away
+   open_files: lib_io::OpenFiles,
+   // metatables of UserData types, and the values to finalize
+   userdata_types: userdata::UserDataTypes,
+   // whether __gc metamethods are running; they do not nest
+   finalizing: bool,
+   // the running thread
+   thread: Rc<RefCell<Coroutine>>,
 }
 
 
 impl ExeState {
    pub fn new() ->Self{
-      let mut globals = HashMap::new();
-      globals.insert(String::from("print"), Value::Function(lib_print));
+      let mut state = ExeState {  globals: Rc::new(RefCell::new(Table::new(0, 0))),
+                  registry: Rc::new(RefCell::new(Table::new(0, 0))),
+                  string_meta: None,
+                  stack: Vec::new(),
+                  frames: Vec::new(),
+                  open_upvalues: Vec::new(),
+                  warnings: false,
+                  c_depth: 0,
+                  waiting: 0,
+                  random: lib_math::Random::new(),
+                  collector: lib_base::Collector::new(),
+                  native_modules: lib_package::NativeModules::default(),
+                  open_files: lib_io::OpenFiles::default(),
+                  userdata_types: userdata::UserDataTypes::default(),
+                  finalizing: false,
+                  thread: Rc::new(RefCell::new(Coroutine {
+                     status: CoStatus::Running,
+                     is_main: true,
+                     stack: Vec::new(),
+                     ..Coroutine::new(Value::Nil)
+                  })),
+               };
+      // each standard library is also recorded in package.loaded
+      for &(name, open) in STD_LIBS {
+         open(&mut state);
+         let module = state.get_global(name);
+         lib_package::set_loaded(&mut state, name, module);
+      }
+      state
+   }
 
-      ExeState {  globals, 
-                  stack: Vec::new(), 
-                  func_index:0,
-               }
+   pub fn get_global(&self, name: &str) -> Value {
+      self.globals.borrow().get_str(name)
+   }
+
+   pub fn set_global(&mut self, name: &str, value: Value) {
+      self.globals.borrow_mut().set_str(name, value);
+   }
+
+   // the global table, `_G`
+   pub fn globals(&self) -> Value {
+      Value::Table(self.globals.clone())
+   }
+
+   pub fn registry(&self) -> Rc<RefCell<Table>> {
+      self.registry.clone()
+   }
+
+   // the generator of math.random()
+   pub fn random(&mut self) -> &mut lib_math::Random {
+      &mut self.random
+   }
+
+   // the settings of collectgarbage()
+   pub fn collector(&mut self) -> &mut lib_base::Collector {
+      &mut self.collector
+   }
+
+   // Make `require(name)` return the value `loader` builds, typically a
+   // table of native functions. It is found after package.preload but
+   // before the Lua files of package.path, and runs once; its result is
+   // cached in package.loaded as for any module.
+   pub fn register_module(&mut self, name: &str,
+      loader: impl Fn(&mut ExeState) -> Result<Value, LuaError> + 'static) {
+      self.native_modules.insert(name, Rc::new(loader));
+   }
+
+   // set package.path to the default, as if LUA_PATH were not set
+   pub fn ignore_env(&mut self) {
+      lib_package::ignore_env(self);
+   }
+
+   pub fn native_modules(&self) -> &lib_package::NativeModules {
+      &self.native_modules
+   }
+
+   pub fn open_files(&mut self) -> &mut lib_io::OpenFiles {
+      &mut self.open_files
+   }
+
+   // A full userdata holding `data`, with the metatable of type T, which
+   // is made from its fields and methods the first time.
+   pub fn create_userdata<T: UserData>(&mut self, data: T) -> Value {
+      Value::UserData(self.userdata_types.create(data))
    }
-   
-   pub fn execute<R:Read>(&mut self,proto:&ParseProto<R>) {
-    for code in proto.byte_codes.iter(){
-      match *code {
-         ByteCode::GetGlobal(dst,name, )=>{
-            let name:&str = (&proto.constants[name as usize]).into();
-            let v = self.globals.get(name).unwrap_or(&Value::Nil).clone();
-            self.set_stack(dst.into(), v);
 
+   // Call the __gc metamethods of the userdata dropped since last time,
+   // and of those these drop. An error is only a warning. Besides when
+   // asked, as by collectgarbage(), this runs once a call from the host
+   // returns, and before calls of Lua code.
+   pub fn run_finalizers(&mut self) {
+      if self.finalizing {
+         return;
+      }
+      self.finalizing = true;
+      loop {
+         let pending = self.userdata_types.take_pending();
+         if pending.is_empty() {
+            break;
          }
-         ByteCode::SetGlobal(name, src)=>{
-            let name = &proto.constants[name as usize];
-            let value = self.stack[src as usize].clone();
-            self.globals.insert(name.into(), value);
+         for u in pending {
+            self.finalize(Value::UserData(Rc::new(u)));
+         }
+      }
+      self.finalizing = false;
+   }
 
+   fn finalize(&mut self, u: Value) {
+      let gc = self.metamethod(&u, "__gc");
+      if let Err(err) = self.call(&gc, &[u]) {
+         if self.warnings {
+            eprintln!("Lua warning: error in __gc ({err})");
          }
-         ByteCode::SetGlobalConst(name, src)=>{
-            let name = &proto.constants[name as usize];
-            let value = proto.constants[src as usize].clone();
-            self.globals.insert(name.into(), value);
+      }
+   }
+
+   // turn the messages of `warn()` on or off
+   pub fn set_warnings(&mut self, on: bool) {
+      self.warnings = on;
+   }
+
+   pub fn warnings(&self) -> bool {
+      self.warnings
+   }
+
+   // the metatable shared by all strings
+   pub fn set_string_metatable(&mut self, meta: Table) {
+      self.string_meta = Some(Rc::new(RefCell::new(meta)));
+   }
+
+   // run a main chunk with `args` as its `...` and return the values
+   // of its `return` statement
+   pub fn execute(&mut self, proto: FuncProto, args: &[Value]) -> Result<Vec<Value>, LuaError> {
+      let main = self.main_closure(proto, None);
+      self.call(&main, args)
+   }
+
+   // a main chunk as a function; its only upvalue is `_ENV`, by default
+   // the global table
+   fn main_closure(&self, proto: FuncProto, env: Option<Value>) -> Value {
+      let env = env.unwrap_or_else(|| self.globals());
+      let closure = LuaClosure {
+         proto: Rc::new(proto),
+         upvalues: vec![Rc::new(RefCell::new(Upvalue::Closed(env)))],
+      };
+      Value::LuaFunction(Rc::new(closure))
+   }
+
+   // Compile `source` into a function. `name` is a chunk name in Lua's
+   // convention: "=name" and "@file" show as name and file in messages,
+   // others as [string "..."]. `mode` holds the kinds of chunk accepted:
+   // 't' for text and 'b' for binary.
+   pub fn load(&mut self, source: &[u8], name: &str, mode: &str, env: Option<Value>)
+      -> Result<Value, LuaError> {
+      let (kind, binary) = if source.first() == Some(&0x1b) { ("binary", 'b') } else { ("text", 't') };
+      if !mode.contains(binary) {
+         return Err(LuaError::Syntax(format!("attempt to load a {kind} chunk (mode is '{mode}')")));
+      }
+      let chunk = chunk_id(name);
+      if binary == 'b' {
+         return Err(LuaError::Syntax(format!("{chunk}: bad binary format (precompiled chunks are not supported)")));
+      }
+      let proto = ParseProto::load(source, &chunk)?;
+      verify::verify(&proto)?;
+      Ok(self.main_closure(proto, env))
+   }
 
+   // Compile the file at `path`, or the standard input if None. A first
+   // line starting with '#', as in "#!/usr/bin/lua", is skipped.
+   pub fn load_file(&mut self, path: Option<&str>, mode: &str, env: Option<Value>)
+      -> Result<Value, LuaError> {
+      let (name, source) = match path {
+         Some(path) => {
+            let source = fs::read(path)
+               .map_err(|err| format!("cannot open {path}: {}", io_error_text(&err)));
+            (format!("@{path}"), source)
          }
-         ByteCode::SetGlobalGlobal(name, src)=>{
-            let name = &proto.constants[name as usize];
-            let src:&str = (&proto.constants[src as usize]).into();
-            let value = self.globals.get(src).unwrap_or(&Value::Nil).clone();
-            self.globals.insert(name.into(), value);
+         None => {
+            let mut source = Vec::new();
+            let read = io::stdin().read_to_end(&mut source)
+               .map_err(|err| format!("cannot read stdin: {}", io_error_text(&err)));
+            (String::from("=stdin"), read.map(|_| source))
+         }
+      };
+      let mut source = source.map_err(|msg| LuaError::Runtime(Value::from(msg)))?;
+      if source.starts_with(b"\xef\xbb\xbf") {
+         source.drain(..3);
+      }
+      if source.first() == Some(&b'#') {
+         // keep the newline so line numbers stay right
+         let end = source.iter().position(|&b| b == b'\n').unwrap_or(source.len());
+         source.drain(..end);
+      }
+      self.load(&source, &name, mode, env)
+   }
 
+   // call function `func` with `args` on top of the stack, return its results
+   pub fn call(&mut self, func: &Value, args: &[Value]) -> Result<Vec<Value>, LuaError> {
+      if self.c_depth + self.waiting >= MAX_C_DEPTH {
+         return Err(self.rt_error("C stack overflow"));
+      }
+      let depth = self.frames.len();
+      let ifunc = self.stack.len();
+      self.stack.push(func.clone());
+      self.stack.extend_from_slice(args);
+
+      self.c_depth += 1;
+      let result = match self.precall(ifunc, MULTI) {
+         Ok(true) => self.run(depth),
+         Ok(false) => Ok(()),
+         Err(err) => Err(err),
+      };
+      self.c_depth -= 1;
+
+      let result = match result {
+         Ok(()) => Ok(self.stack.split_off(ifunc)),
+         Err(err) => {
+            // drop the frames the error went through
+            self.close_upvalues(ifunc);
+            self.truncate_frames(depth);
+            self.stack.truncate(ifunc);
+            Err(err)
+         }
+      };
+      if self.c_depth == 0 {
+         self.run_finalizers();
+      }
+      result
+   }
+
+   // Execute Lua functions until the frame count drops back to `stop`,
+   // that is until the function called at that level returns. An error
+   // goes to the innermost protected call above `stop`, if any, and the
+   // execution goes on from there.
+   fn run(&mut self, stop: usize) -> Result<(), LuaError> {
+      let mut result = self.run_frames(stop);
+      loop {
+         let err = match result {
+            Err(err) if err.is_catchable() => err,
+            result => return result,
+         };
+         let protected = (stop..self.frames.len()).rev()
+            .find(|&i| self.frames[i].wait.as_ref().is_some_and(|wait| wait.protected));
+         let Some(i) = protected else {
+            return Err(err);
+         };
+         let k_result = self.recover(i, err);
+         result = match self.finish_native(k_result) {
+            Ok(_) if self.frames.len() > stop => self.run_frames(stop),
+            Ok(_) => Ok(()),
+            Err(err) => Err(err),
+         };
+      }
+   }
+
+   fn run_frames(&mut self, stop: usize) -> Result<(), LuaError> {
+    'frame: loop {
+      let frame = self.frames.last().unwrap();
+      let Some(closure) = frame.closure.clone() else {
+         // a native function whose call returned, or whose coroutine
+         // was resumed
+         let wait = self.take_wait(self.frames.len() - 1);
+         let results = self.stack.split_off(wait.base);
+         match wait.k {
+            Some(Continuation(k)) => {
+               let result = k(self, Ok(results));
+               self.finish_native(result)?;
+            }
+            None => {
+               let n = results.len();
+               self.stack.extend(results);
+               self.poscall(wait.base, n);
+            }
+         }
+         if self.frames.len() > stop {
+            continue 'frame;
+         }
+         return Ok(());
+      };
+      let proto = &closure.proto;
+      let base = frame.func + 1;
+      if frame.wait.is_some() {
+         // a metamethod that yielded returned
+         let code = proto.byte_codes[frame.pc - 1];
+         let wait = self.take_wait(self.frames.len() - 1);
+         let v = self.stack.split_off(wait.base).into_iter().next().unwrap_or(Value::Nil);
+         self.finish_op(code, v);
+      }
+
+      loop {
+      let frame = self.frames.last_mut().unwrap();
+      let code = proto.byte_codes[frame.pc];
+      frame.pc += 1;
+      match code {
+         ByteCode::GetUpvalue(dst, up) => {
+            let v = self.get_upvalue(&closure.upvalues[up as usize]);
+            self.set_reg(dst, v);
+         }
+         ByteCode::SetUpvalue(up, src) => {
+            let v = self.reg(src).clone();
+            self.set_upvalue(&closure.upvalues[up as usize], v);
+         }
+         ByteCode::GetUpField(dst, up, k) => {
+            let t = self.get_upvalue(&closure.upvalues[up as usize]);
+            let v = self.index_from(t, &proto.constants[k as usize], Some(Operand::Upvalue(up)))?;
+            self.set_reg(dst, v);
+         }
+         ByteCode::SetUpField(up, k, src) => {
+            let t = self.get_upvalue(&closure.upvalues[up as usize]);
+            let key = proto.constants[k as usize].clone();
+            let value = self.reg(src).clone();
+            self.set_index_from(t, key, value, Some(Operand::Upvalue(up)))?;
          }
          ByteCode::LoadConst(dst, c) =>{
-               let v = proto.constants[c as usize].clone();
-               self.set_stack(dst,v);
-         } 
-
-         ByteCode::LoadNil(det) => self.set_stack(det, Value::Nil),
-         ByteCode::LoadBool(dst, bol) => self.set_stack(dst, Value::Boolean(bol)),
-         ByteCode::LoadInt(dst, i) => self.set_stack(dst, Value::Integer(i.into())),
-         ByteCode::Call(func, _) => {
-               self.func_index = func as usize;
-               let func = &self.stack[self.func_index];
-               if let Value::Function(f) = func{
-                  f(self);
+            let v = proto.constants[c as usize].clone();
+            self.set_reg(dst,v);
+         }
+         ByteCode::LoadNil(dst) => self.set_reg(dst, Value::Nil),
+         ByteCode::LoadBool(dst, bol) => self.set_reg(dst, Value::Boolean(bol)),
+         ByteCode::LoadInt(dst, i) => self.set_reg(dst, Value::Integer(i.into())),
+         ByteCode::Move(dst, src) => {
+            let v = self.reg(src).clone();
+            self.set_reg(dst, v);
+         }
+         ByteCode::VarArgs(dst, n) => {
+            let dst = base + dst as usize;
+            let varargs = &self.frames.last().unwrap().varargs;
+            if n == MULTI {
+               // open results end at the stack top
+               self.stack.truncate(dst);
+               self.stack.extend_from_slice(varargs);
+            } else {
+               let n = n as usize;
+               let mut values = varargs.iter().cloned().chain(std::iter::repeat(Value::Nil));
+               for slot in &mut self.stack[dst .. dst + n] {
+                  *slot = values.next().unwrap();
                }
-               else {
-                  panic!("invalid function: {func:?}");
+            }
+         }
+
+         // tables
+         ByteCode::NewTable(dst, narray, nmap) => {
+            self.set_reg(dst, Value::new_table(narray as usize, nmap as usize));
+         }
+         ByteCode::SetTable(t, k, v) => {
+            let key = self.reg(k).clone();
+            let value = self.reg(v).clone();
+            self.set_index_from(self.reg(t).clone(), key, value, Some(Operand::Register(t)))?;
+         }
+         ByteCode::SetField(t, k, v) => {
+            let key = proto.constants[k as usize].clone();
+            let value = self.reg(v).clone();
+            self.set_index_from(self.reg(t).clone(), key, value, Some(Operand::Register(t)))?;
+         }
+         ByteCode::SetList(t, n, batch) => {
+            let t = base + t as usize;
+            let end = if n == MULTI { self.stack.len() } else { t + 1 + n as usize };
+            let Value::Table(table) = &self.stack[t] else {
+               return Err(self.rt_error("table constructor on a non-table value"));
+            };
+            let mut table = table.borrow_mut();
+            let first = batch as usize * FIELDS_PER_FLUSH + 1;
+            for (i, v) in self.stack[t+1 .. end].iter().enumerate() {
+               table.set_int((first + i) as i64, v.clone());
+            }
+            drop(table);
+            self.stack.resize(base + proto.max_stack_size, Value::Nil);
+         }
+         ByteCode::GetTable(dst, t, k) => {
+            let key = self.reg(k).clone();
+            let v = self.index_from(self.reg(t).clone(), &key, Some(Operand::Register(t)))?;
+            self.set_reg(dst, v);
+         }
+         ByteCode::GetField(dst, t, k) => {
+            let key = &proto.constants[k as usize];
+            let v = self.index_from(self.reg(t).clone(), key, Some(Operand::Register(t)))?;
+            self.set_reg(dst, v);
+         }
+         ByteCode::Method(dst, obj, k) => {
+            let obj_value = self.reg(obj).clone();
+            let key = &proto.constants[k as usize];
+            let f = self.index_from(obj_value.clone(), key, Some(Operand::Register(obj)))?;
+            self.set_reg(dst + 1, obj_value);
+            self.set_reg(dst, f);
+         }
+
+         // jumps
+         ByteCode::Jump(offset) => self.jump(offset),
+         ByteCode::TestAndJump(r, offset) => {
+            if self.reg(r).is_false() {
+               self.jump(offset);
+            }
+         }
+         ByteCode::TestOrJump(r, offset) => {
+            if !self.reg(r).is_false() {
+               self.jump(offset);
+            }
+         }
+
+         // loops
+         ByteCode::ForPrepare(a, offset) => {
+            if !self.for_prepare(a)? {
+               self.frames.last_mut().unwrap().pc += offset as usize;
+            }
+         }
+         ByteCode::ForLoop(a, offset) => {
+            if self.for_loop(a) {
+               self.frames.last_mut().unwrap().pc -= offset as usize;
+            }
+         }
+         ByteCode::TForCall(b, nvars) => {
+            // call the iterator with the state and the control variable
+            let b = base + b as usize;
+            for i in 0..3 {
+               self.stack[b + 4 + i] = self.stack[b + i].clone();
+            }
+            self.stack.truncate(b + 7);
+            if self.precall(b + 4, nvars)? {
+               continue 'frame;
+            }
+         }
+         ByteCode::TForLoop(b, offset) => {
+            let b = base + b as usize;
+            if self.stack[b + 4] != Value::Nil {
+               self.stack[b + 2] = self.stack[b + 4].clone();
+               self.frames.last_mut().unwrap().pc -= offset as usize;
+            }
+         }
+
+         // functions
+         ByteCode::Closure(dst, p) => {
+            let proto = proto.protos[p as usize].clone();
+            let upvalues = proto.upvalues.iter().map(|desc| {
+               if desc.in_stack {
+                  self.open_upvalue(base + desc.index)
+               } else {
+                  closure.upvalues[desc.index].clone()
                }
+            }).collect();
+            self.set_reg(dst, Value::LuaFunction(Rc::new(LuaClosure { proto, upvalues })));
+         }
+         ByteCode::Call(func, nargs, want) => {
+            let func = base + func as usize;
+            if nargs != MULTI {
+               self.stack.truncate(func + 1 + nargs as usize);
+            }
+            if self.userdata_types.has_pending() {
+               self.run_finalizers();
+            }
+            if self.precall(func, want)? {
+               continue 'frame;
+            }
+         }
+         ByteCode::TailCall(func, nargs) => {
+            let func = base + func as usize;
+            if nargs != MULTI {
+               self.stack.truncate(func + 1 + nargs as usize);
+            }
+            if self.userdata_types.has_pending() {
+               self.run_finalizers();
+            }
+            // A native function is called as usual, above this function so
+            // that its errors are located here, and the Return following
+            // returns its results. So is a value that can not be called.
+            let f = &self.stack[func];
+            let is_lua = |f: &Value| matches!(f, Value::LuaFunction(_));
+            if !is_lua(f) && !is_lua(&self.metamethod(f, "__call")) {
+               if self.precall(func, MULTI)? {
+                  continue 'frame;
+               }
+               continue;
+            }
+            // the callee takes the place of this function
+            self.close_upvalues(base);
+            let frame = self.frames.pop().unwrap();
+            self.stack.drain(frame.func .. func);
+            if self.precall(frame.func, frame.want)? || self.frames.len() > stop {
+               continue 'frame;
+            }
+            return Ok(());
+         }
+         ByteCode::Return(first, n) => {
+            let first = base + first as usize;
+            let n = if n == MULTI { self.stack.len() - first } else { n as usize };
+            self.poscall(first, n);
+            if self.frames.len() > stop {
+               continue 'frame;
+            }
+            return Ok(());
+         }
+         ByteCode::Close(r) => self.close_upvalues(base + r as usize),
+
+         // unary operators
+         ByteCode::Neg(dst, src) => self.unop(dst, src, "unm")?,
+         ByteCode::Not(dst, src) => {
+            let v = self.reg(src).is_false();
+            self.set_reg(dst, Value::Boolean(v));
+         }
+         ByteCode::BitNot(dst, src) => self.unop(dst, src, "bnot")?,
+         ByteCode::Len(dst, src) => self.unop(dst, src, "len")?,
+
+         // binary operators
+         ByteCode::Add(dst, a, b) => self.binop(dst, a, b, "add")?,
+         ByteCode::Sub(dst, a, b) => self.binop(dst, a, b, "sub")?,
+         ByteCode::Mul(dst, a, b) => self.binop(dst, a, b, "mul")?,
+         ByteCode::Div(dst, a, b) => self.binop(dst, a, b, "div")?,
+         ByteCode::Idiv(dst, a, b) => self.binop(dst, a, b, "idiv")?,
+         ByteCode::Mod(dst, a, b) => self.binop(dst, a, b, "mod")?,
+         ByteCode::Pow(dst, a, b) => self.binop(dst, a, b, "pow")?,
+         ByteCode::BitAnd(dst, a, b) => self.binop(dst, a, b, "band")?,
+         ByteCode::BitXor(dst, a, b) => self.binop(dst, a, b, "bxor")?,
+         ByteCode::BitOr(dst, a, b) => self.binop(dst, a, b, "bor")?,
+         ByteCode::ShiftL(dst, a, b) => self.binop(dst, a, b, "shl")?,
+         ByteCode::ShiftR(dst, a, b) => self.binop(dst, a, b, "shr")?,
+         ByteCode::Concat(dst, a, b) => self.binop(dst, a, b, "concat")?,
+         ByteCode::Equal(dst, a, b) => {
+            let v = self.equals(self.reg(a).clone(), self.reg(b).clone())?;
+            self.set_reg(dst, Value::Boolean(v));
          }
-         ByteCode::Move(dst, ic) => {
-            let v = self.stack[ic as usize].clone();
-            self.set_stack(dst, v)
+         ByteCode::NotEq(dst, a, b) => {
+            let v = self.equals(self.reg(a).clone(), self.reg(b).clone())?;
+            self.set_reg(dst, Value::Boolean(!v));
          }
+         ByteCode::Less(dst, a, b) => self.compare(dst, a, b, "lt")?,
+         ByteCode::LesEq(dst, a, b) => self.compare(dst, a, b, "le")?,
+      }
       }
     }
-   } 
+   }
+
+   // Start calling the value at stack index `func`, with the values above
+   // it as arguments. A Lua function gets a new frame for run() to execute,
+   // and the result is true. A native function runs to completion here,
+   // unless it waits for a call, which is then left to run() as well.
+   fn precall(&mut self, func: usize, want: u8) -> Result<bool, LuaError> {
+      match self.stack[func].clone() {
+         Value::LuaFunction(closure) => {
+            let proto = &closure.proto;
+            if func + 1 + proto.max_stack_size > MAX_STACK {
+               return Err(self.rt_error("stack overflow"));
+            }
+            let args = func + 1 + proto.params;
+            let varargs = if proto.is_vararg && self.stack.len() > args {
+               self.stack.split_off(args)
+            } else {
+               Vec::new()
+            };
+            // surplus arguments are dropped, missing ones are nil
+            self.stack.truncate(args);
+            self.stack.resize(func + 1 + proto.max_stack_size, Value::Nil);
+            self.frames.push(CallInfo { func, closure: Some(closure), pc: 0, varargs, want, wait: None });
+            Ok(true)
+         }
+         Value::Function(f) => self.precall_native(func, want, &f),
+         Value::NativeClosure(f) => self.precall_native(func, want, &*f),
+         v => {
+            // the __call handler gets the called value as first argument
+            let handler = self.metamethod(&v, "__call");
+            if !handler.is_function() {
+               return Err(self.call_error(func, &v));
+            }
+            self.stack.insert(func, handler);
+            self.precall(func, want)
+         }
+      }
+   }
+
+   fn precall_native(&mut self, func: usize, want: u8, f: &NativeFn) -> Result<bool, LuaError> {
+      self.frames.push(CallInfo { func, closure: None, pc: 0, varargs: Vec::new(), want, wait: None });
+      let result = f(self);
+      self.finish_native(result)
+   }
+
+   // A native function at the top returned `result`: pop its frame and
+   // leave its results in its place, unless it asked for a call first. That
+   // call is started and, as for a Lua function, the result is true for
+   // run() to carry on.
+   fn finish_native(&mut self, mut result: Result<i32, LuaError>) -> Result<bool, LuaError> {
+      loop {
+         let n = result? as usize;
+         let i = self.frames.len() - 1;
+         let Some(Wait { base, protected, .. }) = self.frames[i].wait else {
+            let first = self.stack.len() - n;
+            self.poscall(first, n);
+            return Ok(false);
+         };
+         match self.precall(base, MULTI) {
+            Ok(_) => return Ok(true),
+            Err(err) if protected && err.is_catchable() => result = self.recover(i, err),
+            Err(err) => return Err(err),
+         }
+      }
+   }
+
+   // Unwind to frame i, a native function waiting for a protected call
+   // that failed with `err`, and give the error to its continuation.
+   fn recover(&mut self, i: usize, err: LuaError) -> Result<i32, LuaError> {
+      let wait = self.take_wait(i);
+      self.close_upvalues(wait.base);
+      self.truncate_frames(i + 1);
+      self.stack.truncate(wait.base);
+      let Continuation(k) = wait.k.unwrap();
+      k(self, Err(err))
+   }
+
+   // Call `func` with `args` on behalf of the running native function,
+   // which must return what this returns. Once the call is over, `k` gets
+   // its results and finishes the work of the function; an error goes on
+   // up as with call(). Unlike with call(), the callee can yield.
+   pub fn call_k(&mut self, func: Value, args: &[Value],
+      k: impl FnOnce(&mut ExeState, Vec<Value>) -> Result<i32, LuaError> + 'static)
+      -> Result<i32, LuaError> {
+      let k = Continuation(Box::new(|state, result| k(state, result?)));
+      self.call_with(func, args, k, false)
+   }
+
+   // As call_k, in protected mode: `k` gets the results or the error.
+   pub fn pcall_k(&mut self, func: Value, args: &[Value],
+      k: impl FnOnce(&mut ExeState, Result<Vec<Value>, LuaError>) -> Result<i32, LuaError> + 'static)
+      -> Result<i32, LuaError> {
+      self.call_with(func, args, Continuation(Box::new(k)), true)
+   }
+
+   fn call_with(&mut self, func: Value, args: &[Value], k: Continuation, protected: bool)
+      -> Result<i32, LuaError> {
+      if self.c_depth + self.waiting >= MAX_C_DEPTH {
+         return Err(LuaError::Runtime(Value::from("C stack overflow")));
+      }
+      let base = self.stack.len();
+      self.stack.push(func);
+      self.stack.extend_from_slice(args);
+      self.set_wait(Wait { base, k: Some(k), protected });
+      Ok(0)
+   }
+
+   fn set_wait(&mut self, wait: Wait) {
+      self.waiting += 1;
+      self.frames.last_mut().unwrap().wait = Some(wait);
+   }
+
+   fn take_wait(&mut self, i: usize) -> Wait {
+      self.waiting -= 1;
+      self.frames[i].wait.take().unwrap()
+   }
+
+   // drop the frames above the first `n`, unwinding an error
+   fn truncate_frames(&mut self, n: usize) {
+      self.waiting -= self.frames[n..].iter().filter(|frame| frame.wait.is_some()).count();
+      self.frames.truncate(n);
+   }
+
+   // the running thread
+   pub fn running(&self) -> Rc<RefCell<Coroutine>> {
+      self.thread.clone()
+   }
+
+   // whether the running function can yield: it is in a coroutine, and
+   // not under a native function that called back into Lua
+   pub fn is_yieldable(&self) -> bool {
+      let thread = self.thread.borrow();
+      !thread.is_main && thread.c_base == self.c_depth
+   }
+
+   // Suspend the running coroutine from the running native function, which
+   // must return what this returns. `values` go to the resume, and what the
+   // next resume passes becomes the results of the function.
+   pub fn yield_values(&mut self, values: Vec<Value>) -> Result<i32, LuaError> {
+      self.yield_with(values, None)
+   }
+
+   // As yield_values, but once resumed the function goes on with `k`,
+   // which gets what the resume passes and returns as the function would.
+   pub fn yield_k(&mut self, values: Vec<Value>,
+      k: impl FnOnce(&mut ExeState, Vec<Value>) -> Result<i32, LuaError> + 'static)
+      -> Result<i32, LuaError> {
+      let k = Continuation(Box::new(|state, result| k(state, result?)));
+      self.yield_with(values, Some(k))
+   }
+
+   fn yield_with(&mut self, values: Vec<Value>, k: Option<Continuation>) -> Result<i32, LuaError> {
+      if self.thread.borrow().is_main {
+         return Err(LuaError::Runtime(Value::from("attempt to yield from outside a coroutine")));
+      }
+      if !self.is_yieldable() {
+         return Err(LuaError::Runtime(Value::from("attempt to yield across a C-call boundary")));
+      }
+      self.thread.borrow_mut().yielded = values;
+      let base = self.stack.len();
+      self.set_wait(Wait { base, k, protected: false });
+      Err(LuaError::Yield)
+   }
+
+   // Suspend the running coroutine, which call_async runs, until `future`
+   // is ready; what it gives is then the result of the running native
+   // function, which must return what this returns. Any other thread gets
+   // an error.
+   pub fn await_future(&mut self, future: impl Future<Output = Result<Vec<Value>, LuaError>> + 'static)
+      -> Result<i32, LuaError> {
+      if !self.thread.borrow().is_async {
+         return Err(LuaError::Runtime(Value::from("attempt to await outside an async call")));
+      }
+      let result = self.yield_k(Vec::new(), |state, _| {
+         let awaited = state.thread.borrow_mut().awaited.take();
+         let values = awaited.unwrap()?;
+         let n = values.len() as i32;
+         for v in values {
+            state.push(v);
+         }
+         Ok(n)
+      });
+      if let Err(LuaError::Yield) = result {
+         self.thread.borrow_mut().future = Some(Pending(Box::pin(future)));
+      }
+      result
+   }
+
+   // Call `func` with `args` in a coroutine of its own, awaiting the
+   // futures of the native functions it calls (see await_future) as they
+   // come, and return its results. The state stays borrowed until then, so
+   // calls are run one at a time. A yield other than of a future is an
+   // error, as it would be with call().
+   pub async fn call_async(&mut self, func: &Value, args: &[Value]) -> Result<Vec<Value>, LuaError> {
+      let co = Rc::new(RefCell::new(Coroutine { is_async: true, ..Coroutine::new(func.clone()) }));
+      let mut args = args.to_vec();
+      loop {
+         let values = self.resume(&co, args)?;
+         if co.borrow().status == CoStatus::Dead {
+            return Ok(values);
+         }
+         if co.borrow().future.is_none() {
+            let _ = self.close_thread(&co);
+            return Err(LuaError::Runtime(Value::from("attempt to yield from outside a coroutine")));
+         }
+         // left in the coroutine while pending, so that if this call is
+         // dropped it can never be resumed
+         let result = future::poll_fn(|cx| {
+            co.borrow_mut().future.as_mut().unwrap().0.as_mut().poll(cx)
+         }).await;
+         co.borrow_mut().future = None;
+         co.borrow_mut().awaited = Some(result);
+         args = Vec::new();
+      }
+   }
+
+   // Run coroutine `co` with `args`, until it returns, yields or fails.
+   // The results are its return values or the values it yielded; whether
+   // it returned shows in its status.
+   pub fn resume(&mut self, co: &Rc<RefCell<Coroutine>>, args: Vec<Value>) -> Result<Vec<Value>, LuaError> {
+      let awaiting = co.borrow().future.is_some();
+      match co.borrow().status {
+         // one awaiting a future goes on only once call_async has it
+         CoStatus::Suspended if !awaiting => (),
+         CoStatus::Dead => return Err(LuaError::Runtime(Value::from("cannot resume dead coroutine"))),
+         _ => return Err(LuaError::Runtime(Value::from("cannot resume non-suspended coroutine"))),
+      }
+      if self.c_depth + self.waiting >= MAX_C_DEPTH {
+         return Err(LuaError::Runtime(Value::from("C stack overflow")));
+      }
+      self.c_depth += 1;
+      let caller = self.switch_thread(co.clone());
+      caller.borrow_mut().status = CoStatus::Normal;
+      co.borrow_mut().status = CoStatus::Running;
+      co.borrow_mut().c_base = self.c_depth;
+
+      let started = !self.frames.is_empty();
+      self.stack.extend(args);
+      let result = if started {
+         // the yield returns what the resume passes
+         self.run(0)
+      } else {
+         match self.precall(0, MULTI) {
+            Ok(true) => self.run(0),
+            Ok(false) => Ok(()),
+            Err(err) => Err(err),
+         }
+      };
+      let result = match result {
+         Ok(()) => {
+            co.borrow_mut().status = CoStatus::Dead;
+            Ok(mem::take(&mut self.stack))
+         }
+         Err(LuaError::Yield) => {
+            co.borrow_mut().status = CoStatus::Suspended;
+            Ok(mem::take(&mut co.borrow_mut().yielded))
+         }
+         Err(err) => {
+            self.close_upvalues(0);
+            self.frames.clear();
+            self.stack.clear();
+            self.waiting = 0;
+            let mut co = co.borrow_mut();
+            co.status = CoStatus::Dead;
+            if err.is_catchable() {
+               co.error = Some(err.clone().into_value());
+            }
+            Err(err)
+         }
+      };
+
+      self.switch_thread(caller);
+      self.thread.borrow_mut().status = CoStatus::Running;
+      self.c_depth -= 1;
+      result
+   }
+
+   // Kill coroutine `co`, suspended or dead. The error that killed it, if
+   // any, is the result.
+   pub fn close_thread(&mut self, co: &Rc<RefCell<Coroutine>>) -> Result<(), Value> {
+      let mut co = co.borrow_mut();
+      // its upvalues already hold their values, as it is not running
+      co.open_upvalues.clear();
+      co.frames.clear();
+      co.waiting = 0;
+      co.stack.clear();
+      co.future = None;
+      co.awaited = None;
+      co.status = CoStatus::Dead;
+      match co.error.take() {
+         Some(err) => Err(err),
+         None => Ok(()),
+      }
+   }
+
+   // Make `to` the running thread, and return the one that was. The open
+   // upvalues of a thread that is not running hold their values themselves,
+   // since its stack is out of reach.
+   fn switch_thread(&mut self, to: Rc<RefCell<Coroutine>>) -> Rc<RefCell<Coroutine>> {
+      for (i, upvalue) in &self.open_upvalues {
+         *upvalue.borrow_mut() = Upvalue::Closed(self.stack[*i].clone());
+      }
+      {
+         let mut from = self.thread.borrow_mut();
+         from.stack = mem::take(&mut self.stack);
+         from.frames = mem::take(&mut self.frames);
+         from.open_upvalues = mem::take(&mut self.open_upvalues);
+         from.waiting = self.waiting;
+      }
+      {
+         let mut to = to.borrow_mut();
+         self.stack = mem::take(&mut to.stack);
+         self.frames = mem::take(&mut to.frames);
+         self.open_upvalues = mem::take(&mut to.open_upvalues);
+         self.waiting = to.waiting;
+      }
+      for (i, upvalue) in &self.open_upvalues {
+         let v = mem::replace(&mut *upvalue.borrow_mut(), Upvalue::Open(*i));
+         if let Upvalue::Closed(v) = v {
+            self.stack[*i] = v;
+         }
+      }
+      mem::replace(&mut self.thread, to)
+   }
+
+   // Return from the top frame with the `n` values at `first`. They move
+   // to where the function was, adjusted to the count the caller wants.
+   fn poscall(&mut self, first: usize, n: usize) {
+      let frame = self.frames.pop().unwrap();
+      self.close_upvalues(frame.func);
+      self.stack.drain(frame.func .. first);
+      self.stack.truncate(frame.func + n);
+
+      if frame.want != MULTI {
+         self.stack.resize(frame.func + frame.want as usize, Value::Nil);
+         // back to the registers of the calling Lua function
+         if let Some(CallInfo { func, closure: Some(c), .. }) = self.frames.last() {
+            self.stack.resize(func + 1 + c.proto.max_stack_size, Value::Nil);
+         }
+      }
+   }
+
+   // number of arguments passed to the running native function
+   pub fn get_top(&self) -> usize {
+      self.stack.len() - self.frames.last().unwrap().func - 1
+   }
+
+   // the i-th argument (counted from 1) of the running native function,
+   // nil if there are fewer
+   pub fn get(&self, i: usize) -> &Value {
+      let func = self.frames.last().unwrap().func;
+      if i <= self.get_top() {
+         &self.stack[func + i]
+      } else {
+         &Value::Nil
+      }
+   }
+
+   // whether `n` more values fit on the stack
+   pub fn check_stack(&self, n: usize) -> bool {
+      self.stack.len() + n <= MAX_STACK
+   }
+
+   // push a result of the running native function
+   pub fn push(&mut self, v: Value) {
+      self.stack.push(v);
+   }
+
+   // fails if argument i is absent; nil counts as present
+   pub fn check_any(&self, i: usize) -> Result<(), LuaError> {
+      if i > self.get_top() {
+         return Err(self.arg_error(i, "value expected"));
+      }
+      Ok(())
+   }
+
+   pub fn check_integer(&self, i: usize) -> Result<i64, LuaError> {
+      match to_number(self.get(i)) {
+         Some(Value::Integer(n)) => Ok(n),
+         Some(Value::Float(f)) => float_to_int(f)
+            .ok_or_else(|| self.arg_error(i, "number has no integer representation")),
+         _ => Err(self.type_error(i, "number")),
+      }
+   }
+
+   pub fn opt_integer(&self, i: usize, default: i64) -> Result<i64, LuaError> {
+      match self.get(i) {
+         Value::Nil => Ok(default),
+         _ => self.check_integer(i),
+      }
+   }
+
+   // an integer or a float argument, strings being converted
+   pub fn check_number(&self, i: usize) -> Result<Value, LuaError> {
+      to_number(self.get(i)).ok_or_else(|| self.type_error(i, "number"))
+   }
+
+   // a number argument as a float
+   pub fn check_float(&self, i: usize) -> Result<f64, LuaError> {
+      self.check_number(i).map(|n| to_float(&n).unwrap())
+   }
+
+   // a string argument; numbers are converted
+   pub fn check_string(&self, i: usize) -> Result<Value, LuaError> {
+      match self.get(i) {
+         v if v.is_string() => Ok(v.clone()),
+         v @ (Value::Integer(_) | Value::Float(_)) => Ok(Value::from(format!("{v:?}"))),
+         _ => Err(self.type_error(i, "string")),
+      }
+   }
+
+   // "bad argument #i to 'f' (msg)", for the running native function
+   pub fn arg_error(&self, i: usize, msg: &str) -> LuaError {
+      let (kind, name) = self.native_name()
+         .or_else(|| Some(("global", self.global_func_name()?)))
+         .unwrap_or(("", String::from("?")));
+      if kind == "method" {
+         // the object is argument 0
+         if i == 1 {
+            return self.error(&format!("calling '{name}' on bad self ({msg})"));
+         }
+         return self.error(&format!("bad argument #{} to '{name}' ({msg})", i - 1));
+      }
+      self.error(&format!("bad argument #{i} to '{name}' ({msg})"))
+   }
+
+   pub fn type_error(&self, i: usize, expected: &str) -> LuaError {
+      let got = if i > self.get_top() { "no value" } else { self.get(i).type_name() };
+      self.arg_error(i, &format!("{expected} expected, got {got}"))
+   }
+
+   // an error raised by the running native function, located at the line
+   // of the Lua code calling it
+   pub fn error(&self, msg: &str) -> LuaError {
+      LuaError::Runtime(Value::from(format!("{}{msg}", self.location(1))))
+   }
+
+   // an error of the running Lua instruction
+   fn rt_error(&self, msg: &str) -> LuaError {
+      LuaError::Runtime(Value::from(format!("{}{msg}", self.location(0))))
+   }
+
+   // "chunk:line: " of the frame `level` below the top, if it is a Lua function
+   pub fn location(&self, level: usize) -> String {
+      let frame = self.frames.len().checked_sub(level + 1).map(|i| &self.frames[i]);
+      match frame {
+         Some(CallInfo { closure: Some(c), pc, .. }) =>
+            format!("{}:{}: ", c.proto.source, c.proto.lines[pc.saturating_sub(1)]),
+         _ => String::new(),
+      }
+   }
+
+   // how the calling Lua code named the running native function, as
+   // ("global", "print") or ("method", "upper")
+   fn native_name(&self) -> Option<(&'static str, String)> {
+      let [.., caller, native] = &self.frames[..] else {
+         return None;
+      };
+      let c = caller.closure.as_ref()?;
+      match c.proto.byte_codes[caller.pc - 1] {
+         ByteCode::Call(r, ..) | ByteCode::TailCall(r, _) if caller.func + 1 + r as usize == native.func =>
+            obj_name(&c.proto, caller.pc - 1, r),
+         ByteCode::TForCall(..) => Some(("for iterator", String::from("for iterator"))),
+         _ => None,
+      }
+   }
+
+   // Where the running native function can be found among the globals,
+   // as "print" or "string.rep", for when its caller gives no name for
+   // it, as a native caller does.
+   fn global_func_name(&self) -> Option<String> {
+      let func = &self.stack[self.frames.last()?.func];
+      let globals = self.globals.borrow();
+      let mut key = Value::Nil;
+      let mut libs = Vec::new();
+      while let Ok(Some((k, v))) = globals.next(&key) {
+         if k.is_string() {
+            if v == *func {
+               return Some(format!("{k:?}"));
+            }
+            if let Value::Table(t) = &v {
+               libs.push((k.clone(), t.clone()));
+            }
+         }
+         key = k;
+      }
+      for (name, lib) in libs {
+         let lib = lib.borrow();
+         let mut key = Value::Nil;
+         while let Ok(Some((k, v))) = lib.next(&key) {
+            if k.is_string() && v == *func {
+               return Some(format!("{name:?}.{k:?}"));
+            }
+            key = k;
+         }
+      }
+      None
+   }
+
+   // " (global 'x')", the description of an operand of the running instruction
+   fn varinfo(&self, operand: Option<Operand>) -> String {
+      let Some(CallInfo { closure: Some(c), pc, .. }) = self.frames.last() else {
+         return String::new();
+      };
+      let name = match operand {
+         Some(Operand::Register(r)) => obj_name(&c.proto, pc - 1, r),
+         Some(Operand::Upvalue(u)) => Some(("upvalue", c.proto.upvalues[u as usize].name.clone())),
+         None => None,
+      };
+      name.map_or(String::new(), |(kind, name)| format!(" ({kind} '{name}')"))
+   }
+
+   fn call_error(&self, func: usize, v: &Value) -> LuaError {
+      let info = match self.frames.last() {
+         Some(CallInfo { func: f, closure: Some(c), pc, .. }) => match c.proto.byte_codes[pc - 1] {
+            ByteCode::Call(r, ..) | ByteCode::TailCall(r, _) if f + 1 + r as usize == func =>
+               self.varinfo(Some(Operand::Register(r))),
+            ByteCode::TForCall(..) => String::from(" (for iterator 'for iterator')"),
+            _ => String::new(),
+         },
+         _ => String::new(),
+      };
+      self.rt_error(&format!("attempt to call a {} value{info}", v.type_name()))
+   }
+
+   fn reg(&self, r: u8) -> &Value {
+      &self.stack[self.frames.last().unwrap().func + 1 + r as usize]
+   }
+
+   fn set_reg(&mut self,dst:u8,v:Value){
+      let base = self.frames.last().unwrap().func + 1;
+      self.stack[base + dst as usize] = v;
+   }
+
+   fn jump(&mut self, offset: i16) {
+      let frame = self.frames.last_mut().unwrap();
+      frame.pc = (frame.pc as isize + offset as isize) as usize;
+   }
+
+   fn get_upvalue(&self, upvalue: &Rc<RefCell<Upvalue>>) -> Value {
+      match &*upvalue.borrow() {
+         Upvalue::Open(i) => self.stack[*i].clone(),
+         Upvalue::Closed(v) => v.clone(),
+      }
+   }
+
+   fn set_upvalue(&mut self, upvalue: &Rc<RefCell<Upvalue>>, value: Value) {
+      match &mut *upvalue.borrow_mut() {
+         Upvalue::Open(i) => self.stack[*i] = value,
+         Upvalue::Closed(v) => *v = value,
+      }
+   }
+
+   // the upvalue for stack slot `i`, shared by all closures capturing it
+   fn open_upvalue(&mut self, i: usize) -> Rc<RefCell<Upvalue>> {
+      if let Some((_, upvalue)) = self.open_upvalues.iter().find(|(j, _)| *j == i) {
+         return upvalue.clone();
+      }
+      let upvalue = Rc::new(RefCell::new(Upvalue::Open(i)));
+      self.open_upvalues.push((i, upvalue.clone()));
+      upvalue
+   }
+
+   // move the values of the stack slots from `level` up into their upvalues
+   fn close_upvalues(&mut self, level: usize) {
+      let stack = &self.stack;
+      self.open_upvalues.retain(|(i, upvalue)| {
+         if *i < level {
+            return true;
+         }
+         let v = stack.get(*i).cloned().unwrap_or(Value::Nil);
+         *upvalue.borrow_mut() = Upvalue::Closed(v);
+         false
+      });
+   }
+
+   pub fn metatable(&self, v: &Value) -> Option<Rc<RefCell<Table>>> {
+      match v {
+         Value::Table(t) => t.borrow().metatable.clone(),
+         Value::UserData(u) => u.metatable(),
+         v if v.is_string() => self.string_meta.clone(),
+         _ => None,
+      }
+   }
 
-   fn set_stack(&mut self,dst:u8,v:Value){
-      let dst = dst as usize;
-      match dst.cmp(&self.stack.len()) {
-        std::cmp::Ordering::Less =>self.stack[dst] = v,
-        std::cmp::Ordering::Equal => self.stack.push(v),
-        std::cmp::Ordering::Greater => panic!("fall in stack"),
+   pub fn metamethod(&self, v: &Value, event: &str) -> Value {
+      self.metatable(v).map_or(Value::Nil, |meta| meta.borrow().get_str(event))
+   }
+
+   // Call a metamethod and keep its first result. For an instruction of a
+   // Lua function the call can yield, see call_op.
+   fn call_meta(&mut self, handler: &Value, args: &[Value]) -> Result<Value, LuaError> {
+      let results = match self.frames.last() {
+         Some(CallInfo { closure: Some(_), .. }) => self.call_op(handler, args)?,
+         _ => self.call(handler, args)?,
+      };
+      Ok(results.into_iter().next().unwrap_or(Value::Nil))
+   }
+
+   // As call(), for a metamethod of the running Lua instruction. The frame
+   // of the function waits for the results meanwhile, so that if the call
+   // yields, all is left in place for finish_op to complete the
+   // instruction once the coroutine is resumed.
+   fn call_op(&mut self, handler: &Value, args: &[Value]) -> Result<Vec<Value>, LuaError> {
+      if self.c_depth + self.waiting >= MAX_C_DEPTH {
+         return Err(self.rt_error("C stack overflow"));
+      }
+      let depth = self.frames.len();
+      let ifunc = self.stack.len();
+      self.stack.push(handler.clone());
+      self.stack.extend_from_slice(args);
+      self.set_wait(Wait { base: ifunc, k: None, protected: false });
+
+      let result = match self.precall(ifunc, MULTI) {
+         Ok(true) => self.run(depth),
+         Ok(false) => Ok(()),
+         Err(err) => Err(err),
+      };
+      match result {
+         Err(LuaError::Yield) => Err(LuaError::Yield),
+         Ok(()) => {
+            self.take_wait(depth - 1);
+            Ok(self.stack.split_off(ifunc))
+         }
+         Err(err) => {
+            self.close_upvalues(ifunc);
+            self.truncate_frames(depth);
+            self.take_wait(depth - 1);
+            self.stack.truncate(ifunc);
+            Err(err)
+         }
+      }
+   }
+
+   // Complete instruction `code` of the running Lua function, whose
+   // metamethod yielded and then returned `v`.
+   fn finish_op(&mut self, code: ByteCode, v: Value) {
+      match code {
+         ByteCode::Method(dst, obj, _) => {
+            let obj = self.reg(obj).clone();
+            self.set_reg(dst + 1, obj);
+            self.set_reg(dst, v);
+         }
+         ByteCode::Equal(dst, ..) | ByteCode::Less(dst, ..) | ByteCode::LesEq(dst, ..) =>
+            self.set_reg(dst, Value::Boolean(!v.is_false())),
+         ByteCode::NotEq(dst, ..) => self.set_reg(dst, Value::Boolean(v.is_false())),
+         ByteCode::SetTable(..) | ByteCode::SetField(..) | ByteCode::SetUpField(..) => (),
+         ByteCode::GetUpField(dst, ..) | ByteCode::GetTable(dst, ..) | ByteCode::GetField(dst, ..)
+            | ByteCode::Neg(dst, _) | ByteCode::BitNot(dst, _) | ByteCode::Len(dst, _)
+            | ByteCode::Add(dst, ..) | ByteCode::Sub(dst, ..) | ByteCode::Mul(dst, ..)
+            | ByteCode::Div(dst, ..) | ByteCode::Idiv(dst, ..) | ByteCode::Mod(dst, ..)
+            | ByteCode::Pow(dst, ..) | ByteCode::BitAnd(dst, ..) | ByteCode::BitXor(dst, ..)
+            | ByteCode::BitOr(dst, ..) | ByteCode::ShiftL(dst, ..) | ByteCode::ShiftR(dst, ..)
+            | ByteCode::Concat(dst, ..) => self.set_reg(dst, v),
+         code => unreachable!("no metamethod for {code:?}"),
+      }
+   }
+
+   // tostring(v): the result of the __tostring metamethod if there is one;
+   // tables whose metatable has a string __name show that as their type
+   pub fn tostring(&mut self, v: &Value) -> Result<Value, LuaError> {
+      let handler = self.metamethod(v, "__tostring");
+      if handler != Value::Nil {
+         return match self.call_meta(&handler, std::slice::from_ref(v))? {
+            s if s.is_string() => Ok(s),
+            n @ (Value::Integer(_) | Value::Float(_)) => Ok(Value::from(format!("{n:?}"))),
+            _ => Err(self.error("'__tostring' must return a string")),
+         };
+      }
+      match v {
+         v if v.is_string() => Ok(v.clone()),
+         Value::Table(_) | Value::UserData(_) => {
+            let name = match self.metatable(v) {
+               Some(meta) => meta.borrow().get_str("__name"),
+               None => Value::Nil,
+            };
+            let text = format!("{v:?}");
+            if name.is_string() {
+               // the address after the type name
+               let address = text.split_once(": ").map_or("", |(_, address)| address);
+               Ok(Value::from(format!("{name:?}: {address}")))
+            } else {
+               Ok(Value::from(text))
+            }
+         }
+         v => Ok(Value::from(format!("{v:?}"))),
+      }
+   }
+
+   // #v, with the __len metamethod
+   pub fn len(&mut self, v: &Value) -> Result<Value, LuaError> {
+      if v.is_string() {
+         return Ok(Value::Integer(v.as_bytes().len() as i64));
+      }
+      match (v, self.metamethod(v, "__len")) {
+         (Value::Table(t), Value::Nil) => Ok(Value::Integer(t.borrow().len())),
+         (v, Value::Nil) => Err(self.rt_error(&operand_error("len", v, v))),
+         (v, handler) => self.call_meta(&handler, &[v.clone(), v.clone()]),
       }
    }
+
+   // obj[key], with the __index metamethod
+   pub fn index(&mut self, obj: &Value, key: &Value) -> Result<Value, LuaError> {
+      self.index_from(obj.clone(), key, None)
+   }
+
+   fn index_from(&mut self, mut obj: Value, key: &Value, mut operand: Option<Operand>) -> Result<Value, LuaError> {
+      for _ in 0..MAX_META_CHAIN {
+         let handler = match &obj {
+            Value::Table(t) => {
+               let t = t.borrow();
+               let v = t.get(key);
+               if v != Value::Nil {
+                  return Ok(v);
+               }
+               match &t.metatable {
+                  Some(meta) => meta.borrow().get_str("__index"),
+                  None => return Ok(Value::Nil),
+               }
+            }
+            v => self.metamethod(v, "__index"),
+         };
+         match handler {
+            Value::Nil if matches!(obj, Value::Table(_)) => return Ok(Value::Nil),
+            Value::Nil => {
+               let msg = format!("attempt to index a {} value{}", obj.type_name(), self.varinfo(operand));
+               return Err(self.rt_error(&msg));
+            }
+            Value::Function(_) | Value::LuaFunction(_) | Value::NativeClosure(_) =>
+               return self.call_meta(&handler, &[obj, key.clone()]),
+            handler => obj = handler,
+         }
+         operand = None;
+      }
+      Err(self.rt_error("'__index' chain too long; possible loop"))
+   }
+
+   // obj[key] = value, with the __newindex metamethod
+   pub fn set_index(&mut self, obj: &Value, key: Value, value: Value) -> Result<(), LuaError> {
+      self.set_index_from(obj.clone(), key, value, None)
+   }
+
+   fn set_index_from(&mut self, mut obj: Value, key: Value, value: Value, mut operand: Option<Operand>)
+      -> Result<(), LuaError> {
+      for _ in 0..MAX_META_CHAIN {
+         let handler = match &obj {
+            Value::Table(t) => {
+               let meta = t.borrow().metatable.clone();
+               let handler = meta.map_or(Value::Nil, |meta| meta.borrow().get_str("__newindex"));
+               // __newindex is only for absent keys
+               if handler == Value::Nil || t.borrow().get(&key) != Value::Nil {
+                  let result = t.borrow_mut().set(key, value);
+                  return result.map_err(|msg| self.rt_error(msg));
+               }
+               handler
+            }
+            v => self.metamethod(v, "__newindex"),
+         };
+         match handler {
+            Value::Nil => {
+               let msg = format!("attempt to index a {} value{}", obj.type_name(), self.varinfo(operand));
+               return Err(self.rt_error(&msg));
+            }
+            Value::Function(_) | Value::LuaFunction(_) | Value::NativeClosure(_) => {
+               self.call_meta(&handler, &[obj, key, value])?;
+               return Ok(());
+            }
+            handler => obj = handler,
+         }
+         operand = None;
+      }
+      Err(self.rt_error("'__newindex' chain too long; possible loop"))
+   }
+
+   // Set up a numeric for loop at register `a`, and return whether it
+   // runs at all. An integer loop keeps its iteration count in place of
+   // the limit.
+   fn for_prepare(&mut self, a: u8) -> Result<bool, LuaError> {
+      let (init, limit, step) = (self.reg(a).clone(), self.reg(a + 1).clone(), self.reg(a + 2).clone());
+      if let (Value::Integer(init), Value::Integer(step)) = (&init, &step) {
+         let (init, step) = (*init, *step);
+         if step == 0 {
+            return Err(self.rt_error("'for' step is zero"));
+         }
+         let limit = match for_limit(&limit, step) {
+            Ok(Some(limit)) => limit,
+            Ok(None) => return Ok(false),
+            Err(msg) => return Err(self.rt_error(msg)),
+         };
+         if if step > 0 { init > limit } else { init < limit } {
+            return Ok(false);
+         }
+         let count = if step > 0 {
+            (limit as u64).wrapping_sub(init as u64) / step as u64
+         } else {
+            // -(step + 1) + 1 avoids overflowing on the minimum integer
+            (init as u64).wrapping_sub(limit as u64) / ((-(step + 1)) as u64 + 1)
+         };
+         self.set_reg(a + 1, Value::Integer(count as i64));
+         self.set_reg(a + 3, Value::Integer(init));
+         return Ok(true);
+      }
+
+      let Some(limit) = to_float(&limit) else {
+         return Err(self.rt_error("'for' limit must be a number"));
+      };
+      let Some(step) = to_float(&step) else {
+         return Err(self.rt_error("'for' step must be a number"));
+      };
+      let Some(init) = to_float(&init) else {
+         return Err(self.rt_error("'for' initial value must be a number"));
+      };
+      if step == 0.0 {
+         return Err(self.rt_error("'for' step is zero"));
+      }
+      if if step > 0.0 { limit < init } else { init < limit } {
+         return Ok(false);
+      }
+      self.set_reg(a, Value::Float(init));
+      self.set_reg(a + 1, Value::Float(limit));
+      self.set_reg(a + 2, Value::Float(step));
+      self.set_reg(a + 3, Value::Float(init));
+      Ok(true)
+   }
+
+   // advance a numeric for loop, and return whether it goes on
+   fn for_loop(&mut self, a: u8) -> bool {
+      match (self.reg(a).clone(), self.reg(a + 1).clone(), self.reg(a + 2).clone()) {
+         (Value::Integer(i), Value::Integer(count), Value::Integer(step)) => {
+            if count as u64 == 0 {
+               return false;
+            }
+            let i = i.wrapping_add(step);
+            self.set_reg(a, Value::Integer(i));
+            self.set_reg(a + 1, Value::Integer(count - 1));
+            self.set_reg(a + 3, Value::Integer(i));
+            true
+         }
+         (Value::Float(i), Value::Float(limit), Value::Float(step)) => {
+            let i = i + step;
+            if if step > 0.0 { i > limit } else { i < limit } {
+               return false;
+            }
+            self.set_reg(a, Value::Float(i));
+            self.set_reg(a + 3, Value::Float(i));
+            true
+         }
+         _ => false,
+      }
+   }
+
+   fn unop(&mut self, dst: u8, src: u8, event: &str) -> Result<(), LuaError> {
+      let v = self.reg(src).clone();
+      let result = match event {
+         "unm" => to_number(&v).map(|n| match n {
+            Value::Integer(i) => Value::Integer(i.wrapping_neg()),
+            n => Value::Float(-to_float(&n).unwrap()),
+         }),
+         "bnot" => bitwise(&v, &Value::Integer(-1), |a, b| a ^ b),
+         _ => match &v {
+            Value::Table(t) if self.metamethod(&v, "__len") == Value::Nil =>
+               Some(Value::Integer(t.borrow().len())),
+            v if v.is_string() => Some(Value::Integer(v.as_bytes().len() as i64)),
+            _ => None,
+         }
+      };
+      let r = match result {
+         Some(r) => r,
+         None => {
+            let handler = self.metamethod(&v, &format!("__{event}"));
+            if handler == Value::Nil {
+               let msg = operand_error(event, &v, &v) + &self.varinfo(Some(Operand::Register(src)));
+               return Err(self.rt_error(&msg));
+            }
+            self.call_meta(&handler, &[v.clone(), v])?
+         }
+      };
+      self.set_reg(dst, r);
+      Ok(())
+   }
+
+   fn binop(&mut self, dst: u8, a: u8, b: u8, event: &str) -> Result<(), LuaError> {
+      let (va, vb) = (self.reg(a).clone(), self.reg(b).clone());
+      let result = match event {
+         "add" => arith(&va, &vb, |a, b| Some(a.wrapping_add(b)), |a, b| a + b),
+         "sub" => arith(&va, &vb, |a, b| Some(a.wrapping_sub(b)), |a, b| a - b),
+         "mul" => arith(&va, &vb, |a, b| Some(a.wrapping_mul(b)), |a, b| a * b),
+         "div" => arith_float(&va, &vb, |a, b| a / b),
+         "pow" => arith_float(&va, &vb, f64::powf),
+         "idiv" => arith(&va, &vb, int_idiv, |a, b| (a / b).floor()),
+         "mod" => arith(&va, &vb, int_mod, float_mod),
+         "band" => bitwise(&va, &vb, |a, b| a & b),
+         "bor" => bitwise(&va, &vb, |a, b| a | b),
+         "bxor" => bitwise(&va, &vb, |a, b| a ^ b),
+         "shl" => bitwise(&va, &vb, shift_left),
+         "shr" => bitwise(&va, &vb, |a, b| shift_left(a, b.wrapping_neg())),
+         _ => concat(&va, &vb),
+      };
+      let r = match result {
+         Some(Value::Nil) => return Err(self.rt_error(&format!("attempt to perform 'n{}0'",
+                                          if event == "mod" { "%" } else { "//" }))),
+         Some(r) => r,
+         None => {
+            let event_name = format!("__{event}");
+            let handler = match self.metamethod(&va, &event_name) {
+               Value::Nil => self.metamethod(&vb, &event_name),
+               handler => handler,
+            };
+            if handler == Value::Nil {
+               let bad = if bad_operand(event, &va) { a } else { b };
+               let msg = operand_error(event, &va, &vb) + &self.varinfo(Some(Operand::Register(bad)));
+               return Err(self.rt_error(&msg));
+            }
+            self.call_meta(&handler, &[va, vb])?
+         }
+      };
+      self.set_reg(dst, r);
+      Ok(())
+   }
+
+   // a == b, with the __eq metamethod for tables and userdata
+   fn equals(&mut self, a: Value, b: Value) -> Result<bool, LuaError> {
+      if a == b {
+         return Ok(true);
+      }
+      if let (Value::Table(_), Value::Table(_)) | (Value::UserData(_), Value::UserData(_)) = (&a, &b) {
+         let handler = match self.metamethod(&a, "__eq") {
+            Value::Nil => self.metamethod(&b, "__eq"),
+            handler => handler,
+         };
+         if handler != Value::Nil {
+            return Ok(!self.call_meta(&handler, &[a, b])?.is_false());
+         }
+      }
+      Ok(false)
+   }
+
+   fn compare(&mut self, dst: u8, a: u8, b: u8, event: &str) -> Result<(), LuaError> {
+      let (va, vb) = (self.reg(a).clone(), self.reg(b).clone());
+      let v = self.compare_values(va, vb, event)?;
+      self.set_reg(dst, Value::Boolean(v));
+      Ok(())
+   }
+
+   // a < b, with the __lt metamethod
+   pub fn less_than(&mut self, a: &Value, b: &Value) -> Result<bool, LuaError> {
+      self.compare_values(a.clone(), b.clone(), "lt")
+   }
+
+   // a < b for event "lt", a <= b for "le"
+   fn compare_values(&mut self, va: Value, vb: Value, event: &str) -> Result<bool, LuaError> {
+      let ord = match (&va, &vb) {
+         (Value::Integer(i1), Value::Integer(i2)) => Some(i1.cmp(i2)),
+         (Value::Integer(i), Value::Float(f)) => int_float_cmp(*i, *f),
+         (Value::Float(f), Value::Integer(i)) => int_float_cmp(*i, *f).map(Ordering::reverse),
+         (Value::Float(f1), Value::Float(f2)) => f1.partial_cmp(f2),
+         (s1, s2) if s1.is_string() && s2.is_string() =>
+            Some(s1.as_bytes().cmp(s2.as_bytes())),
+         _ => {
+            let event_name = format!("__{event}");
+            let handler = match self.metamethod(&va, &event_name) {
+               Value::Nil => self.metamethod(&vb, &event_name),
+               handler => handler,
+            };
+            if handler == Value::Nil {
+               let msg = if va.type_name() == vb.type_name() {
+                  format!("attempt to compare two {} values", va.type_name())
+               } else {
+                  format!("attempt to compare {} with {}", va.type_name(), vb.type_name())
+               };
+               return Err(self.rt_error(&msg));
+            }
+            return Ok(!self.call_meta(&handler, &[va, vb])?.is_false());
+         }
+      };
+      // comparisons with NaN are always false
+      Ok(match event {
+         "lt" => ord == Some(Ordering::Less),
+         _ => matches!(ord, Some(Ordering::Less | Ordering::Equal)),
+      })
+   }
+}
+
+impl Default for ExeState {
+   fn default() -> Self {
+      Self::new()
+   }
 }
 
+impl Drop for ExeState {
+   fn drop(&mut self) {
+      // all userdata are finalized, alive or not
+      self.run_finalizers();
+      for u in self.userdata_types.take_live() {
+         self.finalize(Value::UserData(u));
+      }
+      self.run_finalizers();
+      lib_io::flush_all(self);
+   }
+}
+
+
+// The name of the value in register `reg` at instruction `pc`, found from
+// local variable names or else from the instruction that loaded it.
+fn obj_name(proto: &FuncProto, pc: usize, reg: u8) -> Option<(&'static str, String)> {
+   if let Some(name) = proto.local_name(reg as usize, pc) {
+      return Some(("local", name.to_string()));
+   }
+   let code = proto.byte_codes[..pc].iter().rev().find(|code| writes_register(code, reg))?;
+   let const_name = |k: u8| match &proto.constants[k as usize] {
+      v if v.is_string() => Some(v.to_string_lossy()),
+      _ => None,
+   };
+   match *code {
+      ByteCode::GetUpField(_, up, k) => {
+         let kind = if proto.upvalues[up as usize].name == "_ENV" { "global" } else { "field" };
+         Some((kind, const_name(k)?))
+      }
+      ByteCode::GetField(_, t, k) => {
+         let kind = if proto.local_name(t as usize, pc) == Some("_ENV") { "global" } else { "field" };
+         Some((kind, const_name(k)?))
+      }
+      ByteCode::GetUpvalue(_, up) => Some(("upvalue", proto.upvalues[up as usize].name.clone())),
+      ByteCode::Method(_, _, k) => Some(("method", const_name(k)?)),
+      ByteCode::LoadConst(_, k) => match &proto.constants[k as usize] {
+         v if v.is_string() => Some(("constant", v.to_string_lossy())),
+         _ => None,
+      },
+      ByteCode::Move(_, src) if src < reg => obj_name(proto, pc, src),
+      _ => None,
+   }
+}
 
-fn lib_print(state: &mut ExeState) -> i32{
-   println!("{:?}",state.stack[state.func_index + 1]);
-   0
+fn writes_register(code: &ByteCode, reg: u8) -> bool {
+   match *code {
+      ByteCode::GetUpvalue(dst, ..) | ByteCode::GetUpField(dst, ..) |
+      ByteCode::LoadConst(dst, _) | ByteCode::LoadBool(dst, _) | ByteCode::LoadInt(dst, _) |
+      ByteCode::LoadNil(dst) | ByteCode::Move(dst, _) | ByteCode::NewTable(dst, ..) |
+      ByteCode::GetTable(dst, ..) | ByteCode::GetField(dst, ..) | ByteCode::Closure(dst, _) |
+      ByteCode::Neg(dst, _) | ByteCode::Not(dst, _) | ByteCode::BitNot(dst, _) | ByteCode::Len(dst, _) |
+      ByteCode::Add(dst, ..) | ByteCode::Sub(dst, ..) | ByteCode::Mul(dst, ..) |
+      ByteCode::Div(dst, ..) | ByteCode::Idiv(dst, ..) | ByteCode::Mod(dst, ..) |
+      ByteCode::Pow(dst, ..) | ByteCode::BitAnd(dst, ..) | ByteCode::BitXor(dst, ..) |
+      ByteCode::BitOr(dst, ..) | ByteCode::ShiftL(dst, ..) | ByteCode::ShiftR(dst, ..) |
+      ByteCode::Concat(dst, ..) | ByteCode::Equal(dst, ..) | ByteCode::NotEq(dst, ..) |
+      ByteCode::Less(dst, ..) | ByteCode::LesEq(dst, ..) => dst == reg,
+      ByteCode::Method(dst, ..) => reg == dst || reg == dst.wrapping_add(1),
+      ByteCode::VarArgs(dst, n) | ByteCode::Call(dst, _, n) =>
+         reg >= dst && (n == MULTI || (reg as usize) < dst as usize + n as usize),
+      ByteCode::ForPrepare(a, _) | ByteCode::ForLoop(a, _) =>
+         reg >= a && (reg as usize) < a as usize + 4,
+      ByteCode::TForCall(b, _) => reg >= b.saturating_add(4),
+      _ => false,
+   }
 }
 
+// The integer limit of a numeric for loop with an integer step. A float
+// limit is rounded towards the loop start; None if the loop is empty
+// because of it.
+fn for_limit(limit: &Value, step: i64) -> Result<Option<i64>, &'static str> {
+   match limit {
+      Value::Integer(limit) => Ok(Some(*limit)),
+      Value::Float(f) if f.is_nan() => Ok(None),
+      Value::Float(f) => {
+         let f = if step < 0 { f.ceil() } else { f.floor() };
+         match float_to_int(f) {
+            Some(limit) => Ok(Some(limit)),
+            // out of the integer range: clip, or skip the loop
+            None if f > 0.0 => Ok(if step < 0 { None } else { Some(i64::MAX) }),
+            None => Ok(if step > 0 { None } else { Some(i64::MIN) }),
+         }
+      }
+      _ => Err("'for' limit must be a number"),
+   }
+}
+
+// a string operand of arithmetic is converted to a number
+fn bad_operand(event: &str, v: &Value) -> bool {
+   match event {
+      "concat" => !(v.is_string() || is_number(v)),
+      _ => to_number(v).is_none(),
+   }
+}
+
+fn operand_error(event: &str, a: &Value, b: &Value) -> String {
+   // blame the operand that is not a number (or string, for concat)
+   let bad = if bad_operand(event, a) { a } else { b };
+   match event {
+      "concat" => format!("attempt to concatenate a {} value", bad.type_name()),
+      "len" => format!("attempt to get length of a {} value", bad.type_name()),
+      "band" | "bor" | "bxor" | "shl" | "shr" | "bnot" if !bad_operand(event, bad) =>
+         "number has no integer representation".to_string(),
+      "band" | "bor" | "bxor" | "shl" | "shr" | "bnot" =>
+         format!("attempt to perform bitwise operation on a {} value", bad.type_name()),
+      _ => format!("attempt to perform arithmetic on a {} value", bad.type_name()),
+   }
+}
+
+fn is_number(v: &Value) -> bool {
+   matches!(v, Value::Integer(_) | Value::Float(_))
+}
+
+// the number value of `v`, converting strings as arithmetic does
+pub fn to_number(v: &Value) -> Option<Value> {
+   match v {
+      Value::Integer(_) | Value::Float(_) => Some(v.clone()),
+      v if v.is_string() => str2number(v.as_bytes()),
+      _ => None,
+   }
+}
+
+fn to_float(v: &Value) -> Option<f64> {
+   match v {
+      Value::Integer(i) => Some(*i as f64),
+      Value::Float(f) => Some(*f),
+      _ => None,
+   }
+}
+
+fn to_int_exact(v: &Value) -> Option<i64> {
+   match to_number(v)? {
+      Value::Integer(i) => Some(i),
+      Value::Float(f) => float_to_int(f),
+      _ => None,
+   }
+}
+
+// integer op for two integers, float op for other numbers.
+// An integer op returning None means division by zero, reported as Nil.
+fn arith(a: &Value, b: &Value, iop: fn(i64, i64) -> Option<i64>, fop: fn(f64, f64) -> f64)
+   -> Option<Value> {
+   match (to_number(a)?, to_number(b)?) {
+      (Value::Integer(i1), Value::Integer(i2)) => Some(iop(i1, i2).map_or(Value::Nil, Value::Integer)),
+      (a, b) => Some(Value::Float(fop(to_float(&a)?, to_float(&b)?))),
+   }
+}
+
+fn arith_float(a: &Value, b: &Value, fop: fn(f64, f64) -> f64) -> Option<Value> {
+   Some(Value::Float(fop(to_float(&to_number(a)?)?, to_float(&to_number(b)?)?)))
+}
+
+fn bitwise(a: &Value, b: &Value, op: fn(i64, i64) -> i64) -> Option<Value> {
+   Some(Value::Integer(op(to_int_exact(a)?, to_int_exact(b)?)))
+}
+
+fn int_idiv(a: i64, b: i64) -> Option<i64> {
+   match b {
+      0 => None,
+      -1 => Some(a.wrapping_neg()),
+      _ => {
+         let q = a / b;
+         // round towards minus infinity
+         Some(if a % b != 0 && (a ^ b) < 0 { q - 1 } else { q })
+      }
+   }
+}
+
+fn int_mod(a: i64, b: i64) -> Option<i64> {
+   match b {
+      0 => None,
+      -1 => Some(0),
+      _ => {
+         let m = a % b;
+         // the result takes the sign of the divisor
+         Some(if m != 0 && (m ^ b) < 0 { m + b } else { m })
+      }
+   }
+}
+
+fn float_mod(a: f64, b: f64) -> f64 {
+   let m = a % b;
+   if (m > 0.0 && b < 0.0) || (m < 0.0 && b > 0.0) { m + b } else { m }
+}
+
+// logical shift; shifting by 64 or more bits in either direction gives 0
+fn shift_left(a: i64, n: i64) -> i64 {
+   if n <= -64 || n >= 64 {
+      0
+   } else if n >= 0 {
+      ((a as u64) << n) as i64
+   } else {
+      ((a as u64) >> -n) as i64
+   }
+}
+
+// exact comparison of an integer with a float, None if it is NaN
+fn int_float_cmp(i: i64, f: f64) -> Option<Ordering> {
+   if f.is_nan() {
+      None
+   } else if f >= 2f64.powi(63) {
+      Some(Ordering::Less)
+   } else if f < -(2f64.powi(63)) {
+      Some(Ordering::Greater)
+   } else {
+      let floor = f.floor();
+      match i.cmp(&(floor as i64)) {
+         Ordering::Equal if f > floor => Some(Ordering::Less),
+         o => Some(o),
+      }
+   }
+}
+
+fn concat(a: &Value, b: &Value) -> Option<Value> {
+   let mut s = concat_piece(a)?;
+   s.extend_from_slice(&concat_piece(b)?);
+   Some(s.into())
+}
+
+fn concat_piece(v: &Value) -> Option<Vec<u8>> {
+   match v {
+      Value::Integer(_) | Value::Float(_) => Some(format!("{v:?}").into_bytes()),
+      v if v.is_string() => Some(v.as_bytes().to_vec()),
+      _ => None,
+   }
+}
+
+
+// how a chunk name shows in messages, at most LUA_IDSIZE - 1 bytes
+fn chunk_id(name: &str) -> String {
+   const ID_SIZE: usize = 60 - 1;
+   // the longest prefix of `s` of at most `n` bytes that is still UTF-8
+   fn prefix(s: &str, n: usize) -> &str {
+      let mut n = n.min(s.len());
+      while !s.is_char_boundary(n) {
+         n -= 1;
+      }
+      &s[..n]
+   }
+   if let Some(name) = name.strip_prefix('=') {
+      prefix(name, ID_SIZE).to_string()
+   } else if let Some(file) = name.strip_prefix('@') {
+      if file.len() <= ID_SIZE {
+         file.to_string()
+      } else {
+         // keep the end of the path
+         let mut start = file.len() - (ID_SIZE - 3);
+         while !file.is_char_boundary(start) {
+            start += 1;
+         }
+         format!("...{}", &file[start..])
+      }
+   } else {
+      // the first line of the source itself
+      let line = name.split('\n').next().unwrap_or("");
+      let room = ID_SIZE - "[string \"...\"]".len();
+      if line.len() == name.len() && name.len() < room {
+         format!("[string \"{name}\"]")
+      } else {
+         format!("[string \"{}...\"]", prefix(line, room))
+      }
+   }
+}
+
+
+#[cfg(test)]
+mod tests {
+   use crate::{value::Value, vm::ExeState, error::LuaError};
+
+   // ask(x) yields x, then returns what the resume passes plus one
+   fn ask(state: &mut ExeState) -> Result<i32, LuaError> {
+      let x = state.get(1).clone();
+      state.yield_k(vec![x], |state, values| {
+         let Some(Value::Integer(n)) = values.first() else {
+            return Err(state.error("integer expected"));
+         };
+         state.push(Value::Integer(n + 1));
+         Ok(1)
+      })
+   }
+
+   // twice(f, ...) calls f with the other arguments and doubles its result
+   fn twice(state: &mut ExeState) -> Result<i32, LuaError> {
+      let args: Vec<Value> = (2..=state.get_top()).map(|i| state.get(i).clone()).collect();
+      state.call_k(state.get(1).clone(), &args, |state, results| {
+         let Some(Value::Integer(n)) = results.first() else {
+            return Err(state.error("integer expected"));
+         };
+         state.push(Value::Integer(n * 2));
+         Ok(1)
+      })
+   }
+
+   // guard(f) calls f in protected mode, and returns "ok" and its first
+   // result or "caught" and the error
+   fn guard(state: &mut ExeState) -> Result<i32, LuaError> {
+      state.pcall_k(state.get(1).clone(), &[], |state, result| {
+         let (tag, v) = match result {
+            Ok(values) => ("ok", values.into_iter().next().unwrap_or(Value::Nil)),
+            Err(LuaError::Runtime(err)) => ("caught", err),
+            Err(err) => return Err(err),
+         };
+         state.push(Value::from(tag));
+         state.push(v);
+         Ok(2)
+      })
+   }
+
+   fn run(source: &str) -> Result<Vec<Value>, LuaError> {
+      let mut state = ExeState::new();
+      let Value::Table(globals) = state.globals() else { unreachable!() };
+      globals.borrow_mut().set_str("ask", Value::Function(ask));
+      globals.borrow_mut().set_str("twice", Value::Function(twice));
+      globals.borrow_mut().set_str("guard", Value::Function(guard));
+      let f = state.load(source.as_bytes(), "=test", "t", None)?;
+      state.call(&f, &[])
+   }
+
+   #[test]
+   fn yield_k_finishes_in_continuation() {
+      let results = run("
+         local co = coroutine.wrap(function() return 'got', ask(1) end)
+         local asked = co()
+         return asked, co(41)").unwrap();
+      assert_eq!(results, [Value::Integer(1), Value::from("got"), Value::Integer(42)]);
+   }
+
+   #[test]
+   fn continuation_error_is_located() {
+      let results = run("
+         local co = coroutine.create(function() return ask(1) end)
+         coroutine.resume(co)
+         return coroutine.resume(co, 'not a number')").unwrap();
+      assert_eq!(results, [Value::Boolean(false), Value::from("test:2: integer expected")]);
+   }
+
+   #[test]
+   fn yield_k_outside_coroutine() {
+      let err = run("ask(1)").unwrap_err();
+      assert_eq!(err.to_string(), "attempt to yield from outside a coroutine");
+   }
+
+   #[test]
+   fn call_k_callee_yields() {
+      let results = run("
+         local co = coroutine.wrap(function()
+            return twice(function(a) return coroutine.yield(a) + 1 end, 5)
+         end)
+         local yielded = co()
+         return yielded, co(20)").unwrap();
+      assert_eq!(results, [Value::Integer(5), Value::Integer(42)]);
+   }
+
+   #[test]
+   fn pcall_k_gets_results_and_errors() {
+      let results = run("return guard(function() return 7 end)").unwrap();
+      assert_eq!(results, [Value::from("ok"), Value::Integer(7)]);
+
+      // the error raised after the callee was resumed goes to the continuation
+      let results = run("
+         local co = coroutine.wrap(function()
+            return guard(function() coroutine.yield('paused'); error('boom') end)
+         end)
+         local paused = co()
+         return paused, co()").unwrap();
+      assert_eq!(results, [Value::from("paused"), Value::from("caught"), Value::from("test:3: boom")]);
+
+      // and so does one from a native function the callee resumed into
+      let results = run("
+         local co = coroutine.wrap(function() return guard(function() return ask(1) end) end)
+         co()
+         return co('x')").unwrap();
+      assert_eq!(results, [Value::from("caught"), Value::from("test:2: integer expected")]);
+   }
+}
@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+
+use lua_llvm::{Lua, Value, Variadic};
+
+
+#[test]
+fn exec_then_eval() {
+   let mut lua = Lua::new();
+   lua.load("x = 6 * 7", "=config").exec().unwrap();
+   let x: i64 = lua.load("x", "=expr").eval().unwrap();
+   assert_eq!(x, 42);
+
+   // statements are run when the chunk is not an expression
+   let y: i64 = lua.load("local y = x + 1; return y", "=expr").eval().unwrap();
+   assert_eq!(y, 43);
+}
+
+#[test]
+fn eval_conversions() {
+   let mut lua = Lua::new();
+   let r: (String, f64, bool, Option<i64>) = lua.load("'a' .. 1, 1 / 2, not nil, nil", "=expr").eval().unwrap();
+   assert_eq!(r, (String::from("a1"), 0.5, true, None));
+   let v: Vec<i64> = lua.load("{1, 2, 3}", "=expr").eval().unwrap();
+   assert_eq!(v, [1, 2, 3]);
+   let m: HashMap<String, i64> = lua.load("{a = 1, b = 2}", "=expr").eval().unwrap();
+   assert_eq!(m, HashMap::from([(String::from("a"), 1), (String::from("b"), 2)]));
+}
+
+#[test]
+fn chunk_call_passes_varargs() {
+   let mut lua = Lua::new();
+   let r: (i64, i64) = lua.load("local a, b = ...; return b, a", "=swap").call((1, 2)).unwrap();
+   assert_eq!(r, (2, 1));
+   let n: i64 = lua.load("return select('#', ...)", "=count").call(Variadic(vec![Value::Nil; 3])).unwrap();
+   assert_eq!(n, 3);
+}
+
+#[test]
+fn errors_name_the_chunk() {
+   let mut lua = Lua::new();
+   let err = lua.load("x = = 1", "=config").exec().unwrap_err();
+   assert_eq!(err.to_string(), "config:1: unexpected symbol near '='");
+   let err = lua.load("\nerror('failed')", "@init.lua").exec().unwrap_err();
+   assert_eq!(err.to_string(), "init.lua:2: failed");
+   let err = lua.load("local t = nil; return t.x", "return t.x").exec().unwrap_err();
+   assert_eq!(err.to_string(), "[string \"return t.x\"]:1: attempt to index a nil value (local 't')");
+
+   // a result that does not convert
+   let err = lua.load("'text'", "=expr").eval::<i64>().unwrap_err();
+   assert_eq!(err.to_string(), "number expected, got string");
+}
+
+#[test]
+fn globals_are_shared() {
+   let mut lua = Lua::new();
+   lua.globals().borrow_mut().set_str("limit", Value::Integer(10));
+   lua.load("doubled = limit * 2", "=test").exec().unwrap();
+   assert_eq!(lua.globals().borrow().get_str("doubled"), Value::Integer(20));
+   assert!(lua.globals().borrow().get_str("print").is_function());
+}
+
+#[test]
+fn call_function_converts() {
+   let mut lua = Lua::new();
+   let f = lua.load("return function(a, b) return a .. b, #a end", "=test").eval().unwrap();
+   let r: (String, i64) = lua.call_function(&f, ("ab", "c")).unwrap();
+   assert_eq!(r, (String::from("abc"), 2));
+
+   // a value with __call, and all the results as they are
+   let t: Value = lua.load("setmetatable({}, {__call = function(self, ...) return ... end})", "=test").eval().unwrap();
+   let Variadic(r) = lua.call_function::<_, Variadic<Value>>(&t, (1, "two", 3.5)).unwrap();
+   assert_eq!(r, [Value::Integer(1), Value::from("two"), Value::Float(3.5)]);
+
+   let err = lua.call_function::<_, ()>(&Value::Integer(1), ()).unwrap_err();
+   assert_eq!(err.to_string(), "attempt to call a number value");
+}
@@ -0,0 +1,126 @@
+use std::{cell::RefCell, rc::Rc};
+
+use lua_llvm::{Lua, LuaError, Value, Variadic};
+
+
+fn set_global(lua: &Lua, name: &str, v: Value) {
+   lua.globals().borrow_mut().set_str(name, v);
+}
+
+#[test]
+fn typed_arguments_and_results() {
+   let mut lua = Lua::new();
+   let foo = lua.create_function(|_, (n, s): (i64, String)| Ok(s.repeat(n as usize)));
+   set_global(&lua, "foo", foo);
+   let r: String = lua.load("foo(3, 'ab')", "=test").eval().unwrap();
+   assert_eq!(r, "ababab");
+
+   // numbers and strings convert as for the standard functions
+   let r: String = lua.load("foo('2', 7)", "=test").eval().unwrap();
+   assert_eq!(r, "77");
+}
+
+#[test]
+fn bad_arguments() {
+   let mut lua = Lua::new();
+   let foo = lua.create_function(|_, (_, _): (i64, String)| Ok(()));
+   set_global(&lua, "foo", foo);
+   let err = lua.load("foo(1)", "=test").exec().unwrap_err();
+   assert_eq!(err.to_string(), "test:1: bad argument #2 to 'foo' (string expected, got nil)");
+   let err = lua.load("foo({}, 'x')", "=test").exec().unwrap_err();
+   assert_eq!(err.to_string(), "test:1: bad argument #1 to 'foo' (number expected, got table)");
+   let err = lua.load("foo(1.5, 'x')", "=test").exec().unwrap_err();
+   assert_eq!(err.to_string(), "test:1: bad argument #1 to 'foo' (number has no integer representation)");
+
+   // named as the caller names it
+   let err = lua.load("local t = {f = foo}; t.f(1)", "=test").exec().unwrap_err();
+   assert_eq!(err.to_string(), "test:1: bad argument #2 to 'f' (string expected, got nil)");
+   let err = lua.load("local obj = {m = foo}; obj:m()", "=test").exec().unwrap_err();
+   assert_eq!(err.to_string(), "test:1: calling 'm' on bad self (number expected, got table)");
+}
+
+#[test]
+fn errors_of_the_function() {
+   let mut lua = Lua::new();
+   let check = lua.create_function(|state, n: i64| {
+      if n < 0 {
+         return Err(state.error("negative"));
+      }
+      Ok(n)
+   });
+   set_global(&lua, "check", check);
+   let r: (bool, String) = lua.load("pcall(check, -1)", "=test").eval().unwrap();
+   assert_eq!(r, (false, String::from("negative")));
+   let err = lua.load("\ncheck(-1)", "=test").exec().unwrap_err();
+   assert_eq!(err.to_string(), "test:2: negative");
+
+   let raw = lua.create_function(|_, ()| Err::<(), _>(LuaError::Runtime(Value::Integer(7))));
+   set_global(&lua, "raw", raw);
+   let r: (bool, i64) = lua.load("pcall(raw)", "=test").eval().unwrap();
+   assert_eq!(r, (false, 7));
+}
+
+#[test]
+fn variadic_and_multiple_results() {
+   let mut lua = Lua::new();
+   let sum = lua.create_function(|_, Variadic(ns): Variadic<f64>| Ok(ns.iter().sum::<f64>()));
+   set_global(&lua, "sum", sum);
+   let r: f64 = lua.load("sum(1, 2, 3.5)", "=test").eval().unwrap();
+   assert_eq!(r, 6.5);
+   let r: f64 = lua.load("sum()", "=test").eval().unwrap();
+   assert_eq!(r, 0.0);
+   let err = lua.load("sum(1, 'x')", "=test").exec().unwrap_err();
+   assert_eq!(err.to_string(), "test:1: bad argument #2 to 'sum' (number expected, got string)");
+
+   // a fixed head, then the rest
+   let tag = lua.create_function(|_, (tag, Variadic(rest)): (String, Variadic<Value>)| {
+      Ok((tag, rest.len() as i64, Variadic(rest)))
+   });
+   set_global(&lua, "tag", tag);
+   let r: (String, i64, i64, String) = lua.load("tag('t', 1, 'two')", "=test").eval().unwrap();
+   assert_eq!(r, (String::from("t"), 2, 1, String::from("two")));
+   let n: i64 = lua.load("select('#', tag('t', nil, nil))", "=test").eval().unwrap();
+   assert_eq!(n, 4);
+
+   let divmod = lua.create_function(|_, (a, b): (i64, i64)| Ok((a / b, a % b)));
+   set_global(&lua, "divmod", divmod);
+   let r: (i64, i64, i64) = lua.load("local q, r = divmod(17, 5); return q, r, select('#', divmod(1, 1))", "=test")
+      .eval().unwrap();
+   assert_eq!(r, (3, 2, 2));
+}
+
+#[test]
+fn closures_capture_host_state() {
+   let mut lua = Lua::new();
+   let log = Rc::new(RefCell::new(Vec::new()));
+   let sink = log.clone();
+   let record = lua.create_function(move |_, line: String| {
+      sink.borrow_mut().push(line);
+      Ok(())
+   });
+   set_global(&lua, "record", record);
+   lua.load("record('a'); record('b')", "=test").exec().unwrap();
+   assert_eq!(*log.borrow(), ["a", "b"]);
+}
+
+#[test]
+fn create_function_mut_keeps_state() {
+   let mut lua = Lua::new();
+   let mut count = 0;
+   let counter = lua.create_function_mut(move |_, step: Option<i64>| {
+      count += step.unwrap_or(1);
+      Ok(count)
+   });
+   set_global(&lua, "counter", counter);
+   let r: (i64, i64, i64) = lua.load("counter(), counter(10), counter()", "=test").eval().unwrap();
+   assert_eq!(r, (1, 11, 12));
+
+   // calling it again while it runs is an error
+   let reenter = lua.create_function_mut(|state, f: Value| {
+      state.call(&f, std::slice::from_ref(&f))?;
+      Ok(())
+   });
+   set_global(&lua, "reenter", reenter);
+   let err = lua.load("reenter(reenter)", "=test").exec().unwrap_err();
+   assert_eq!(err.to_string(), "function called while it runs");
+}
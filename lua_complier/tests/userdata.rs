@@ -0,0 +1,171 @@
+use std::{cell::Cell, ffi::c_void, rc::Rc};
+
+use lua_llvm::{AnyUserData, Lua, UserData, UserDataFields, UserDataMethods, Value};
+
+
+#[derive(Debug, Clone, PartialEq)]
+struct Point {
+   x: f64,
+   y: f64,
+}
+
+impl UserData for Point {
+   fn name() -> &'static str {
+      "Point"
+   }
+
+   fn add_fields(fields: &mut UserDataFields<Self>) {
+      fields.add_field_method_get("x", |_, p| Ok(p.x));
+      fields.add_field_method_get("y", |_, p| Ok(p.y));
+      fields.add_field_method_set("x", |_, p, x: f64| {
+         p.x = x;
+         Ok(())
+      });
+   }
+
+   fn add_methods(methods: &mut UserDataMethods<Self>) {
+      methods.add_method("norm", |_, p, ()| Ok(p.x.hypot(p.y)));
+      methods.add_method_mut("translate", |_, p, (dx, dy): (f64, f64)| {
+         p.x += dx;
+         p.y += dy;
+         Ok(())
+      });
+      methods.add_function("origin", |state, ()| Ok(state.create_userdata(Point { x: 0.0, y: 0.0 })));
+      methods.add_meta_method("__tostring", |_, p, ()| Ok(format!("({}, {})", p.x, p.y)));
+      methods.add_meta_method("__len", |_, _, ()| Ok(2));
+      methods.add_meta_function("__add", |state, (a, b): (Rc<AnyUserData>, Rc<AnyUserData>)| {
+         let (a, b) = (a.borrow::<Point>()?, b.borrow::<Point>()?);
+         Ok(state.create_userdata(Point { x: a.x + b.x, y: a.y + b.y }))
+      });
+      methods.add_meta_function("__eq", |_, (a, b): (Rc<AnyUserData>, Rc<AnyUserData>)| {
+         Ok(*a.borrow::<Point>()? == *b.borrow::<Point>()?)
+      });
+   }
+}
+
+fn lua_with_point(x: f64, y: f64) -> Lua {
+   let mut lua = Lua::new();
+   let p = lua.create_userdata(Point { x, y });
+   lua.globals().borrow_mut().set_str("p", p);
+   lua
+}
+
+#[test]
+fn methods_and_fields() {
+   let mut lua = lua_with_point(3.0, 4.0);
+   let r: (f64, f64, f64, String) = lua.load("p.x, p.y, p:norm(), type(p)", "=test").eval().unwrap();
+   assert_eq!(r, (3.0, 4.0, 5.0, String::from("userdata")));
+
+   lua.load("p:translate(1, -1); p.x = p.x * 2", "=test").exec().unwrap();
+   let p: Rc<AnyUserData> = lua.load("p", "=test").eval().unwrap();
+   assert_eq!(*p.borrow::<Point>().unwrap(), Point { x: 8.0, y: 3.0 });
+
+   let r: (f64, f64) = lua.load("local o = p.origin(); return o.x, o.y", "=test").eval().unwrap();
+   assert_eq!(r, (0.0, 0.0));
+   let r: Option<i64> = lua.load("p.z", "=test").eval().unwrap();
+   assert_eq!(r, None);
+}
+
+#[test]
+fn bad_uses() {
+   let mut lua = lua_with_point(1.0, 2.0);
+   let err = |lua: &mut Lua, code: &str| lua.load(code, "=test").exec().unwrap_err().to_string();
+   assert_eq!(err(&mut lua, "p.y = 1"), "test:1: cannot set field 'y' of Point");
+   assert_eq!(err(&mut lua, "p.x = 'far'"), "test:1: bad value for field 'x' of Point (number expected, got string)");
+   assert_eq!(err(&mut lua, "p:translate(1)"), "test:1: bad argument #2 to 'translate' (number expected, got nil)");
+   assert_eq!(err(&mut lua, "p.norm({})"), "test:1: bad argument #1 to 'norm' (Point expected, got table)");
+}
+
+#[test]
+fn metamethods() {
+   let mut lua = lua_with_point(1.0, 2.0);
+   let r: (String, i64, bool, bool, String) = lua.load("
+      local q = p + p
+      return tostring(q), #p, q == p + p, q == p, getmetatable(p).__name", "=test").eval().unwrap();
+   assert_eq!(r, (String::from("(2, 4)"), 2, true, false, String::from("Point")));
+}
+
+struct Guard(Rc<Cell<u32>>);
+
+impl UserData for Guard {
+   fn add_methods(methods: &mut UserDataMethods<Self>) {
+      methods.add_meta_method("__gc", |_, guard, ()| {
+         guard.0.set(guard.0.get() + 1);
+         Ok(())
+      });
+   }
+}
+
+fn lua_with_guards(dropped: &Rc<Cell<u32>>) -> Lua {
+   let lua = Lua::new();
+   let counter = dropped.clone();
+   let guard = lua.create_function(move |state, ()| Ok(state.create_userdata(Guard(counter.clone()))));
+   lua.globals().borrow_mut().set_str("guard", guard);
+   let counter = dropped.clone();
+   let count = lua.create_function(move |_, ()| Ok(counter.get()));
+   lua.globals().borrow_mut().set_str("count", count);
+   lua
+}
+
+#[test]
+fn gc_runs_without_collectgarbage() {
+   let dropped = Rc::new(Cell::new(0));
+   let mut lua = lua_with_guards(&dropped);
+
+   // once the chunk returns
+   lua.load("do local g = guard() end", "=test").exec().unwrap();
+   assert_eq!(dropped.get(), 1);
+   let kept: Value = lua.load("guard()", "=test").eval().unwrap();
+   assert_eq!(dropped.get(), 1);
+   drop(kept);
+   lua.load("", "=test").exec().unwrap();
+   assert_eq!(dropped.get(), 2);
+
+   // and while the script goes on
+   let n: u32 = lua.load("local function make() guard() end
+      for i = 1, 3 do make() end
+      return count()", "=test").eval().unwrap();
+   assert_eq!(n, 5);
+
+   // the live ones go with the state
+   lua.load("keep = guard()", "=test").exec().unwrap();
+   drop(lua);
+   assert_eq!(dropped.get(), 6);
+}
+
+#[test]
+fn gc_errors_are_warnings() {
+   struct Faulty;
+
+   impl UserData for Faulty {
+      fn add_methods(methods: &mut UserDataMethods<Self>) {
+         methods.add_meta_method("__gc", |state, _, ()| Err::<(), _>(state.error("gc failed")));
+      }
+   }
+
+   let mut lua = Lua::new();
+   let faulty = lua.create_function(|state, ()| Ok(state.create_userdata(Faulty)));
+   lua.globals().borrow_mut().set_str("faulty", faulty);
+   let r: i64 = lua.load("faulty(); local x = faulty() and 1; return x + 1", "=test").eval().unwrap();
+   assert_eq!(r, 2);
+}
+
+#[test]
+fn light_userdata() {
+   let mut lua = Lua::new();
+   let mut target = 42;
+   let ptr = &mut target as *mut i32 as *mut c_void;
+   let same = lua.create_function(|_, (a, b): (*mut c_void, *mut c_void)| Ok(a == b));
+   lua.globals().borrow_mut().set_str("same", same);
+   lua.globals().borrow_mut().set_str("ptr", Value::LightUserData(ptr));
+
+   let r: (String, bool, bool, *mut c_void) = lua.load("
+      local t = {[ptr] = 'found'}
+      return type(ptr), t[ptr] == 'found', same(ptr, ptr), ptr", "=test").eval().unwrap();
+   assert_eq!(r, (String::from("userdata"), true, true, ptr));
+
+   let err = lua.load("return ptr.field", "=test").exec().unwrap_err();
+   assert_eq!(err.to_string(), "test:1: attempt to index a userdata value (global 'ptr')");
+   let err = lua.load("same(ptr, 1)", "=test").exec().unwrap_err();
+   assert_eq!(err.to_string(), "test:1: bad argument #2 to 'same' (light userdata expected, got number)");
+}